@@ -0,0 +1,362 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use super::{CpuidPatch, CpuidReg};
+use hypervisor::CpuId;
+
+/// A CPU feature, advertised to the guest through a single CPUID bit.
+pub struct CpuidFeature {
+    /// Name of the feature, as reported by Linux in /proc/cpuinfo.
+    pub name: &'static str,
+    pub function: u32,
+    pub index: u32,
+    pub reg: CpuidReg,
+    pub bit: u8,
+}
+
+macro_rules! feature {
+    ($name:expr, $function:expr, $index:expr, $reg:ident, $bit:expr) => {
+        CpuidFeature {
+            name: $name,
+            function: $function,
+            index: $index,
+            reg: CpuidReg::$reg,
+            bit: $bit,
+        }
+    };
+}
+
+// CPUID registers holding feature bits. When a CPU model is selected, any
+// bit from these registers which is not part of the model is cleared.
+const FEATURE_REGISTERS: [(u32, u32, CpuidReg); 8] = [
+    (0x1, 0, CpuidReg::ECX),
+    (0x1, 0, CpuidReg::EDX),
+    (0x7, 0, CpuidReg::EBX),
+    (0x7, 0, CpuidReg::ECX),
+    (0x7, 0, CpuidReg::EDX),
+    (0xd, 1, CpuidReg::EAX),
+    (0x8000_0001, 0, CpuidReg::ECX),
+    (0x8000_0001, 0, CpuidReg::EDX),
+];
+
+static CPUID_FEATURES: [CpuidFeature; 107] = [
+    // Leaf 0x1, EDX
+    feature!("fpu", 0x1, 0, EDX, 0),
+    feature!("vme", 0x1, 0, EDX, 1),
+    feature!("de", 0x1, 0, EDX, 2),
+    feature!("pse", 0x1, 0, EDX, 3),
+    feature!("tsc", 0x1, 0, EDX, 4),
+    feature!("msr", 0x1, 0, EDX, 5),
+    feature!("pae", 0x1, 0, EDX, 6),
+    feature!("mce", 0x1, 0, EDX, 7),
+    feature!("cx8", 0x1, 0, EDX, 8),
+    feature!("apic", 0x1, 0, EDX, 9),
+    feature!("sep", 0x1, 0, EDX, 11),
+    feature!("mtrr", 0x1, 0, EDX, 12),
+    feature!("pge", 0x1, 0, EDX, 13),
+    feature!("mca", 0x1, 0, EDX, 14),
+    feature!("cmov", 0x1, 0, EDX, 15),
+    feature!("pat", 0x1, 0, EDX, 16),
+    feature!("pse36", 0x1, 0, EDX, 17),
+    feature!("clflush", 0x1, 0, EDX, 19),
+    feature!("mmx", 0x1, 0, EDX, 23),
+    feature!("fxsr", 0x1, 0, EDX, 24),
+    feature!("sse", 0x1, 0, EDX, 25),
+    feature!("sse2", 0x1, 0, EDX, 26),
+    feature!("ss", 0x1, 0, EDX, 27),
+    feature!("ht", 0x1, 0, EDX, 28),
+    // Leaf 0x1, ECX
+    feature!("pni", 0x1, 0, ECX, 0),
+    feature!("pclmulqdq", 0x1, 0, ECX, 1),
+    feature!("monitor", 0x1, 0, ECX, 3),
+    feature!("vmx", 0x1, 0, ECX, 5),
+    feature!("ssse3", 0x1, 0, ECX, 9),
+    feature!("fma", 0x1, 0, ECX, 12),
+    feature!("cx16", 0x1, 0, ECX, 13),
+    feature!("pdcm", 0x1, 0, ECX, 15),
+    feature!("pcid", 0x1, 0, ECX, 17),
+    feature!("sse4_1", 0x1, 0, ECX, 19),
+    feature!("sse4_2", 0x1, 0, ECX, 20),
+    feature!("x2apic", 0x1, 0, ECX, 21),
+    feature!("movbe", 0x1, 0, ECX, 22),
+    feature!("popcnt", 0x1, 0, ECX, 23),
+    feature!("tsc_deadline_timer", 0x1, 0, ECX, 24),
+    feature!("aes", 0x1, 0, ECX, 25),
+    feature!("xsave", 0x1, 0, ECX, 26),
+    feature!("avx", 0x1, 0, ECX, 28),
+    feature!("f16c", 0x1, 0, ECX, 29),
+    feature!("rdrand", 0x1, 0, ECX, 30),
+    feature!("hypervisor", 0x1, 0, ECX, 31),
+    // Leaf 0x7, EBX
+    feature!("fsgsbase", 0x7, 0, EBX, 0),
+    feature!("tsc_adjust", 0x7, 0, EBX, 1),
+    feature!("sgx", 0x7, 0, EBX, 2),
+    feature!("bmi1", 0x7, 0, EBX, 3),
+    feature!("hle", 0x7, 0, EBX, 4),
+    feature!("avx2", 0x7, 0, EBX, 5),
+    feature!("smep", 0x7, 0, EBX, 7),
+    feature!("bmi2", 0x7, 0, EBX, 8),
+    feature!("erms", 0x7, 0, EBX, 9),
+    feature!("invpcid", 0x7, 0, EBX, 10),
+    feature!("rtm", 0x7, 0, EBX, 11),
+    feature!("mpx", 0x7, 0, EBX, 14),
+    feature!("avx512f", 0x7, 0, EBX, 16),
+    feature!("avx512dq", 0x7, 0, EBX, 17),
+    feature!("rdseed", 0x7, 0, EBX, 18),
+    feature!("adx", 0x7, 0, EBX, 19),
+    feature!("smap", 0x7, 0, EBX, 20),
+    feature!("avx512ifma", 0x7, 0, EBX, 21),
+    feature!("clflushopt", 0x7, 0, EBX, 23),
+    feature!("clwb", 0x7, 0, EBX, 24),
+    feature!("avx512pf", 0x7, 0, EBX, 26),
+    feature!("avx512er", 0x7, 0, EBX, 27),
+    feature!("avx512cd", 0x7, 0, EBX, 28),
+    feature!("sha_ni", 0x7, 0, EBX, 29),
+    feature!("avx512bw", 0x7, 0, EBX, 30),
+    feature!("avx512vl", 0x7, 0, EBX, 31),
+    // Leaf 0x7, ECX
+    feature!("avx512vbmi", 0x7, 0, ECX, 1),
+    feature!("umip", 0x7, 0, ECX, 2),
+    feature!("pku", 0x7, 0, ECX, 3),
+    feature!("waitpkg", 0x7, 0, ECX, 5),
+    feature!("avx512_vbmi2", 0x7, 0, ECX, 6),
+    feature!("gfni", 0x7, 0, ECX, 8),
+    feature!("vaes", 0x7, 0, ECX, 9),
+    feature!("vpclmulqdq", 0x7, 0, ECX, 10),
+    feature!("avx512_vnni", 0x7, 0, ECX, 11),
+    feature!("avx512_bitalg", 0x7, 0, ECX, 12),
+    feature!("avx512_vpopcntdq", 0x7, 0, ECX, 14),
+    feature!("la57", 0x7, 0, ECX, 16),
+    feature!("rdpid", 0x7, 0, ECX, 22),
+    feature!("cldemote", 0x7, 0, ECX, 25),
+    feature!("movdiri", 0x7, 0, ECX, 27),
+    feature!("movdir64b", 0x7, 0, ECX, 28),
+    // Leaf 0x7, EDX
+    feature!("avx512_4vnniw", 0x7, 0, EDX, 2),
+    feature!("avx512_4fmaps", 0x7, 0, EDX, 3),
+    feature!("fsrm", 0x7, 0, EDX, 4),
+    feature!("md_clear", 0x7, 0, EDX, 10),
+    feature!("serialize", 0x7, 0, EDX, 14),
+    feature!("flush_l1d", 0x7, 0, EDX, 28),
+    feature!("arch_capabilities", 0x7, 0, EDX, 29),
+    // Leaf 0xd, index 1, EAX
+    feature!("xsaveopt", 0xd, 1, EAX, 0),
+    feature!("xsavec", 0xd, 1, EAX, 1),
+    feature!("xgetbv1", 0xd, 1, EAX, 2),
+    feature!("xsaves", 0xd, 1, EAX, 3),
+    // Leaf 0x8000_0001, ECX
+    feature!("lahf_lm", 0x8000_0001, 0, ECX, 0),
+    feature!("abm", 0x8000_0001, 0, ECX, 5),
+    feature!("sse4a", 0x8000_0001, 0, ECX, 6),
+    feature!("3dnowprefetch", 0x8000_0001, 0, ECX, 8),
+    // Leaf 0x8000_0001, EDX
+    feature!("syscall", 0x8000_0001, 0, EDX, 11),
+    feature!("nx", 0x8000_0001, 0, EDX, 20),
+    feature!("pdpe1gb", 0x8000_0001, 0, EDX, 26),
+    feature!("rdtscp", 0x8000_0001, 0, EDX, 27),
+    feature!("lm", 0x8000_0001, 0, EDX, 29),
+];
+
+/// A named CPU model, exposing the same set of features on any host able
+/// to provide them.
+pub struct CpuModel {
+    pub name: &'static str,
+    // Features of the model, made of the features of the model it extends,
+    // followed by its own.
+    features: &'static [&'static [&'static str]],
+}
+
+impl CpuModel {
+    /// Iterate over the names of the features exposed by this model.
+    pub fn features(&self) -> impl Iterator<Item = &'static str> {
+        self.features.iter().flat_map(|f| f.iter().copied())
+    }
+}
+
+// Features every model provides. Besides the x86-64 baseline, this includes
+// the features KVM emulates regardless of the host (x2apic, hypervisor and
+// tsc_deadline_timer).
+const X86_64_V1_FEATURES: &[&str] = &[
+    "fpu",
+    "vme",
+    "de",
+    "pse",
+    "tsc",
+    "msr",
+    "pae",
+    "mce",
+    "cx8",
+    "apic",
+    "sep",
+    "mtrr",
+    "pge",
+    "mca",
+    "cmov",
+    "pat",
+    "pse36",
+    "clflush",
+    "mmx",
+    "fxsr",
+    "sse",
+    "sse2",
+    "syscall",
+    "nx",
+    "lm",
+    "x2apic",
+    "hypervisor",
+    "tsc_deadline_timer",
+];
+
+const X86_64_V2_FEATURES: &[&str] = &[
+    "cx16", "lahf_lm", "popcnt", "pni", "sse4_1", "sse4_2", "ssse3",
+];
+
+const X86_64_V3_FEATURES: &[&str] = &[
+    "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "abm", "movbe", "xsave",
+];
+
+const X86_64_V4_FEATURES: &[&str] = &["avx512f", "avx512bw", "avx512cd", "avx512dq", "avx512vl"];
+
+// The models follow the x86-64 micro-architecture levels defined by the
+// x86-64 psABI.
+static CPU_MODELS: [CpuModel; 4] = [
+    CpuModel {
+        name: "x86-64-v1",
+        features: &[X86_64_V1_FEATURES],
+    },
+    CpuModel {
+        name: "x86-64-v2",
+        features: &[X86_64_V1_FEATURES, X86_64_V2_FEATURES],
+    },
+    CpuModel {
+        name: "x86-64-v3",
+        features: &[X86_64_V1_FEATURES, X86_64_V2_FEATURES, X86_64_V3_FEATURES],
+    },
+    CpuModel {
+        name: "x86-64-v4",
+        features: &[
+            X86_64_V1_FEATURES,
+            X86_64_V2_FEATURES,
+            X86_64_V3_FEATURES,
+            X86_64_V4_FEATURES,
+        ],
+    },
+];
+
+/// Look up a CPU feature by its name.
+pub fn cpuid_feature(name: &str) -> Option<&'static CpuidFeature> {
+    CPUID_FEATURES.iter().find(|f| f.name == name)
+}
+
+/// Look up a CPU model by its name.
+pub fn cpu_model(name: &str) -> Option<&'static CpuModel> {
+    CPU_MODELS.iter().find(|m| m.name == name)
+}
+
+/// Check if `feature` is advertised through `cpuid`.
+pub fn is_cpuid_feature_enabled(cpuid: &CpuId, feature: &CpuidFeature) -> bool {
+    CpuidPatch::is_feature_enabled(
+        cpuid,
+        feature.function,
+        feature.index,
+        feature.reg,
+        feature.bit as usize,
+    )
+}
+
+fn register(entry: &mut hypervisor::CpuIdEntry, reg: CpuidReg) -> &mut u32 {
+    match reg {
+        CpuidReg::EAX => &mut entry.eax,
+        CpuidReg::EBX => &mut entry.ebx,
+        CpuidReg::ECX => &mut entry.ecx,
+        CpuidReg::EDX => &mut entry.edx,
+    }
+}
+
+/// Advertise or hide `feature` through `cpuid`.
+pub fn set_cpuid_feature(cpuid: &mut CpuId, feature: &CpuidFeature, enabled: bool) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == feature.function && entry.index == feature.index {
+            let value = register(entry, feature.reg);
+            if enabled {
+                *value |= 1 << feature.bit;
+            } else {
+                *value &= !(1 << feature.bit);
+            }
+        }
+    }
+}
+
+/// Hide every feature from `cpuid` which is not part of `model`. The
+/// features of the model missing from `cpuid` are not added.
+pub fn apply_cpu_model(cpuid: &mut CpuId, model: &CpuModel) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        for (function, index, reg) in FEATURE_REGISTERS.iter() {
+            if entry.function != *function || entry.index != *index {
+                continue;
+            }
+
+            let mask = model
+                .features()
+                .filter_map(cpuid_feature)
+                .filter(|f| f.function == *function && f.index == *index)
+                .filter(|f| f.reg == *reg)
+                .fold(0u32, |mask, f| mask | (1 << f.bit));
+            *register(entry, *reg) &= mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hypervisor::CpuIdEntry;
+
+    #[test]
+    fn test_cpu_models() {
+        for model in CPU_MODELS.iter() {
+            for name in model.features() {
+                assert!(cpuid_feature(name).is_some(), "Unknown feature {}", name);
+            }
+        }
+        assert!(cpu_model("x86-64-v3").is_some());
+        assert!(cpu_model("x86-64-v5").is_none());
+    }
+
+    #[test]
+    fn test_cpuid_features() {
+        let mut cpuid = CpuId::from_entries(&[
+            CpuIdEntry {
+                function: 0x1,
+                index: 0,
+                ecx: 0xffff_ffff,
+                edx: 0xffff_ffff,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x7,
+                index: 0,
+                eax: 0x1,
+                ebx: 0xffff_ffff,
+                ..Default::default()
+            },
+        ]);
+
+        apply_cpu_model(&mut cpuid, cpu_model("x86-64-v2").unwrap());
+        let sse4_2 = cpuid_feature("sse4_2").unwrap();
+        let avx = cpuid_feature("avx").unwrap();
+        let avx512f = cpuid_feature("avx512f").unwrap();
+        assert!(is_cpuid_feature_enabled(&cpuid, sse4_2));
+        assert!(!is_cpuid_feature_enabled(&cpuid, avx));
+        assert!(!is_cpuid_feature_enabled(&cpuid, avx512f));
+        // Leaf 0x7 EAX is not a feature register.
+        assert_eq!(cpuid.as_slice()[1].eax, 0x1);
+
+        set_cpuid_feature(&mut cpuid, avx, true);
+        set_cpuid_feature(&mut cpuid, sse4_2, false);
+        assert!(is_cpuid_feature_enabled(&cpuid, avx));
+        assert!(!is_cpuid_feature_enabled(&cpuid, sse4_2));
+    }
+}
@@ -7,6 +7,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 use std::sync::Arc;
+pub mod cpuid;
 mod gdt;
 pub mod interrupts;
 pub mod layout;
@@ -183,7 +184,7 @@ impl From<Error> for super::Error {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum CpuidReg {
     EAX,
     EBX,
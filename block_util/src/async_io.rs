@@ -0,0 +1,71 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use std::io;
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Debug)]
+pub enum DiskFileError {
+    /// Failed getting disk file size.
+    Size(io::Error),
+    /// Failed creating a new AsyncIo.
+    NewAsyncIo(io::Error),
+}
+
+pub type DiskFileResult<T> = std::result::Result<T, DiskFileError>;
+
+/// Disk image from which an asynchronous I/O context can be created for each
+/// queue of the device.
+pub trait DiskFile: Send + Sync {
+    fn size(&mut self) -> DiskFileResult<u64>;
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>>;
+}
+
+#[derive(Debug)]
+pub enum AsyncIoError {
+    /// Failed vectored reading from file.
+    ReadVectored(io::Error),
+    /// Failed vectored writing to file.
+    WriteVectored(io::Error),
+    /// Failed synchronizing file.
+    Fsync(io::Error),
+    /// Failed punching a hole in the file.
+    PunchHole(io::Error),
+    /// Failed writing zeroes to the file.
+    WriteZeroes(io::Error),
+    /// No room left for the request, which must be submitted again once
+    /// some of the pending requests have completed.
+    SubmissionQueueFull,
+}
+
+pub type AsyncIoResult<T> = std::result::Result<T, AsyncIoError>;
+
+/// Asynchronous I/O on a disk image. Each request is identified by its user
+/// data, returned along with the result of the request once completed. The
+/// notifier is signaled whenever requests complete. Requests are refused
+/// with SubmissionQueueFull while too many of them are pending.
+pub trait AsyncIo: Send + Sync {
+    fn notifier(&self) -> &EventFd;
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()>;
+    /// Writes the data, synchronizing the file before the request completes
+    /// if sync is set.
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+        sync: bool,
+    ) -> AsyncIoResult<()>;
+    fn fsync(&mut self, user_data: u64) -> AsyncIoResult<()>;
+    /// Deallocates the given range, synchronously.
+    fn punch_hole(&mut self, offset: u64, length: u64) -> AsyncIoResult<()>;
+    /// Zeroes the given range synchronously, deallocating it if allowed.
+    fn write_zeroes(&mut self, offset: u64, length: u64, unmap: bool) -> AsyncIoResult<()>;
+    fn complete(&mut self) -> Vec<(u64, i32)>;
+}
@@ -13,19 +13,43 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod async_io;
+#[cfg(feature = "io_uring")]
+pub mod raw_async;
+
+use crate::async_io::{AsyncIo, AsyncIoError};
+#[cfg(feature = "io_uring")]
+use io_uring::{opcode, IoUring, Probe};
+use rate_limiter::{RateLimiter, TokenType};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::cmp;
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
 use std::os::linux::fs::MetadataExt;
+#[cfg(feature = "io_uring")]
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
 use vm_virtio::DescriptorChain;
+#[cfg(feature = "io_uring")]
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroes};
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 
+// Size of the buffer used to write zeroes without deallocating the sectors.
+const WRITE_ZEROES_BUFFER_SIZE: usize = 1 << 20;
+
+// Largest range of a discard or write zeroes request, so that a single
+// request doesn't hold the queue for too long.
+pub const MAX_DISCARD_WRITE_ZEROES_SECTORS: u32 = (4 << 20) >> SECTOR_SHIFT;
+
 #[derive(Debug)]
 pub enum Error {
     /// Guest gave us bad memory addresses.
@@ -44,6 +68,10 @@ pub enum Error {
     GetFileMetadata,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// Guest gave us a data buffer not made of whole segments.
+    InvalidDataLength,
+    /// Guest gave us a discard or write zeroes range larger than advertised.
+    RangeTooLarge,
 }
 
 fn build_device_id(disk_path: &PathBuf) -> result::Result<String, Error> {
@@ -85,6 +113,14 @@ pub enum ExecuteError {
     Read(GuestMemoryError),
     Seek(io::Error),
     Write(GuestMemoryError),
+    Discard(io::Error),
+    WriteZeroes(io::Error),
+    GetHostAddress(GuestMemoryError),
+    AsyncRead(AsyncIoError),
+    AsyncWrite(AsyncIoError),
+    AsyncFlush(AsyncIoError),
+    AsyncDiscard(AsyncIoError),
+    AsyncWriteZeroes(AsyncIoError),
     Unsupported(u32),
 }
 
@@ -96,9 +132,28 @@ impl ExecuteError {
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::GetHostAddress(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncRead(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncWrite(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncFlush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncDiscard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncWriteZeroes(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
+
+    /// Whether the request couldn't be submitted yet, and must be submitted
+    /// again once some of the pending requests have completed.
+    pub fn submission_queue_full(&self) -> bool {
+        matches!(
+            self,
+            ExecuteError::AsyncRead(AsyncIoError::SubmissionQueueFull)
+                | ExecuteError::AsyncWrite(AsyncIoError::SubmissionQueueFull)
+                | ExecuteError::AsyncFlush(AsyncIoError::SubmissionQueueFull)
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -107,6 +162,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -120,6 +177,8 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -134,6 +193,17 @@ fn sector(mem: &GuestMemoryMmap, desc_addr: GuestAddress) -> result::Result<u64,
     mem.read_obj(addr).map_err(Error::GuestMemory)
 }
 
+// Range of sectors of a discard or write zeroes request.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct DiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for DiscardWriteZeroes {}
+
 pub struct Request {
     pub request_type: RequestType,
     sector: u64,
@@ -180,7 +250,11 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::In {
@@ -209,13 +283,19 @@ impl Request {
     }
 
     #[allow(clippy::ptr_arg)]
-    pub fn execute<T: Seek + Read + Write>(
+    pub fn execute<T: Seek + Read + Write + PunchHole>(
         &self,
         disk: &mut T,
         disk_nsectors: u64,
         mem: &GuestMemoryMmap,
         disk_id: &Vec<u8>,
     ) -> result::Result<u32, ExecuteError> {
+        // Discard and write zeroes requests carry their own ranges of
+        // sectors instead of a single one.
+        if let RequestType::Discard | RequestType::WriteZeroes = self.request_type {
+            return self.execute_discard_write_zeroes(disk, disk_nsectors, mem);
+        }
+
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -251,14 +331,309 @@ impl Request {
                 mem.write_slice(&disk_id.as_slice(), self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Discard | RequestType::WriteZeroes => unreachable!(),
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
 
+    // Reads and validates the ranges of sectors of a discard or write
+    // zeroes request.
+    fn discard_write_zeroes_ranges(
+        &self,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<Vec<DiscardWriteZeroes>, ExecuteError> {
+        let segment_size = size_of::<DiscardWriteZeroes>() as u32;
+        if self.data_len < segment_size {
+            return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+        }
+        if self.data_len % segment_size != 0 {
+            return Err(ExecuteError::BadRequest(Error::InvalidDataLength));
+        }
+
+        let mut ranges = Vec::new();
+        for index in 0..self.data_len / segment_size {
+            let offset = (index * segment_size) as usize;
+            let addr = mem.checked_offset(self.data_addr, offset).ok_or_else(|| {
+                ExecuteError::BadRequest(Error::CheckedOffset(self.data_addr, offset))
+            })?;
+            let range: DiscardWriteZeroes = mem.read_obj(addr).map_err(ExecuteError::Read)?;
+
+            let top = range
+                .sector
+                .checked_add(u64::from(range.num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+            if range.num_sectors > MAX_DISCARD_WRITE_ZEROES_SECTORS {
+                return Err(ExecuteError::BadRequest(Error::RangeTooLarge));
+            }
+
+            match self.request_type {
+                RequestType::Discard if range.flags != 0 => {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD));
+                }
+                RequestType::WriteZeroes
+                    if range.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 =>
+                {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+                }
+                _ => {}
+            }
+
+            // Nothing to do for an empty range.
+            if range.num_sectors > 0 {
+                ranges.push(range);
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    fn execute_discard_write_zeroes<T: Seek + Write + PunchHole>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        for range in self.discard_write_zeroes_ranges(disk_nsectors, mem)? {
+            let offset = range.sector << SECTOR_SHIFT;
+            let length = u64::from(range.num_sectors) << SECTOR_SHIFT;
+
+            if self.request_type == RequestType::Discard {
+                disk.punch_hole(offset, length)
+                    .map_err(ExecuteError::Discard)?;
+                continue;
+            }
+
+            disk.seek(SeekFrom::Start(offset))
+                .map_err(ExecuteError::Seek)?;
+            // The sectors can only be deallocated if the guest allows it.
+            if range.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+                disk.write_all_zeroes(length as usize)
+                    .map_err(ExecuteError::WriteZeroes)?;
+            } else {
+                let zeroes = vec![0u8; WRITE_ZEROES_BUFFER_SIZE];
+                let mut remaining = length as usize;
+                while remaining > 0 {
+                    let count = cmp::min(remaining, zeroes.len());
+                    disk.write_all(&zeroes[..count])
+                        .map_err(ExecuteError::WriteZeroes)?;
+                    remaining -= count;
+                }
+            }
+            if !self.writeback {
+                disk.flush().map_err(ExecuteError::Flush)?;
+            }
+        }
+
+        Ok(0)
+    }
+
+    // Host address of a guest buffer, which must be contiguous in the host
+    // address space.
+    fn host_address(
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        len: u32,
+    ) -> result::Result<*mut u8, ExecuteError> {
+        let invalid_range = || ExecuteError::BadRequest(Error::CheckedOffset(addr, len as usize));
+
+        let region = mem.find_region(addr).ok_or_else(invalid_range)?;
+        let region_addr = region.to_region_addr(addr).ok_or_else(invalid_range)?;
+        region
+            .checked_offset(region_addr, len.saturating_sub(1) as usize)
+            .ok_or_else(invalid_range)?;
+
+        region
+            .get_host_address(region_addr)
+            .map_err(ExecuteError::GetHostAddress)
+    }
+
+    /// Submits the request to the asynchronous disk I/O, identified by the
+    /// given user data once completed. Returns false if the request has
+    /// been completed synchronously instead.
+    pub fn execute_async(
+        &self,
+        mem: &GuestMemoryMmap,
+        disk_nsectors: u64,
+        disk_image: &mut dyn AsyncIo,
+        disk_id: &[u8],
+        user_data: u64,
+    ) -> result::Result<bool, ExecuteError> {
+        // Discard and write zeroes requests only update the file metadata,
+        // which is why they are handled synchronously.
+        if let RequestType::Discard | RequestType::WriteZeroes = self.request_type {
+            for range in self.discard_write_zeroes_ranges(disk_nsectors, mem)? {
+                let offset = range.sector << SECTOR_SHIFT;
+                let length = u64::from(range.num_sectors) << SECTOR_SHIFT;
+
+                if self.request_type == RequestType::Discard {
+                    disk_image
+                        .punch_hole(offset, length)
+                        .map_err(ExecuteError::AsyncDiscard)?;
+                } else {
+                    disk_image
+                        .write_zeroes(
+                            offset,
+                            length,
+                            range.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+                        )
+                        .map_err(ExecuteError::AsyncWriteZeroes)?;
+                }
+            }
+            return Ok(false);
+        }
+
+        let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
+        if u64::from(self.data_len) % SECTOR_SIZE != 0 {
+            top += 1;
+        }
+        top = top
+            .checked_add(self.sector)
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+
+        let offset = (self.sector << SECTOR_SHIFT) as libc::off_t;
+
+        match self.request_type {
+            RequestType::In => {
+                let iovecs = vec![libc::iovec {
+                    iov_base: Request::host_address(mem, self.data_addr, self.data_len)?
+                        as *mut libc::c_void,
+                    iov_len: self.data_len as libc::size_t,
+                }];
+                disk_image
+                    .read_vectored(offset, iovecs, user_data)
+                    .map_err(ExecuteError::AsyncRead)?;
+            }
+            RequestType::Out => {
+                let iovecs = vec![libc::iovec {
+                    iov_base: Request::host_address(mem, self.data_addr, self.data_len)?
+                        as *mut libc::c_void,
+                    iov_len: self.data_len as libc::size_t,
+                }];
+                // The data must reach the disk before the request completes
+                // in writethrough mode.
+                disk_image
+                    .write_vectored(offset, iovecs, user_data, !self.writeback)
+                    .map_err(ExecuteError::AsyncWrite)?;
+            }
+            RequestType::Flush => {
+                disk_image
+                    .fsync(user_data)
+                    .map_err(ExecuteError::AsyncFlush)?;
+            }
+            RequestType::GetDeviceID => {
+                if (self.data_len as usize) < disk_id.len() {
+                    return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+                }
+                mem.write_slice(disk_id, self.data_addr)
+                    .map_err(ExecuteError::Write)?;
+                return Ok(false);
+            }
+            RequestType::Discard | RequestType::WriteZeroes => unreachable!(),
+            RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
+        }
+
+        Ok(true)
+    }
+
+    pub fn writeback(&self) -> bool {
+        self.writeback
+    }
+
     pub fn set_writeback(&mut self, writeback: bool) {
         self.writeback = writeback
     }
+
+    /// Consume the tokens needed by the request from the rate limiter: one
+    /// operation, and the bytes transferred for reads and writes. Returns
+    /// false if the request must be deferred.
+    pub fn consume_rate_limiter_tokens(&self, rate_limiter: &mut RateLimiter) -> bool {
+        if !rate_limiter.consume(1, TokenType::Ops) {
+            return false;
+        }
+
+        let bytes = match self.request_type {
+            RequestType::In | RequestType::Out => u64::from(self.data_len),
+            _ => 0,
+        };
+        if bytes > 0 && !rate_limiter.consume(bytes, TokenType::Bytes) {
+            // The whole request is deferred, give the operation back.
+            rate_limiter.manual_replenish(1, TokenType::Ops);
+            return false;
+        }
+
+        true
+    }
+
+    /// Give back the tokens consumed by a request which is deferred after
+    /// all.
+    pub fn replenish_rate_limiter_tokens(&self, rate_limiter: &mut RateLimiter) {
+        rate_limiter.manual_replenish(1, TokenType::Ops);
+        if let RequestType::In | RequestType::Out = self.request_type {
+            rate_limiter.manual_replenish(u64::from(self.data_len), TokenType::Bytes);
+        }
+    }
+}
+
+/// Checks the host kernel supports io_uring along with the operations
+/// needed by the virtio-blk device.
+#[cfg(feature = "io_uring")]
+pub fn block_io_uring_is_supported() -> bool {
+    let error_msg = "io_uring not supported:";
+
+    // Creating an io_uring instance validates the io_uring_setup() syscall.
+    let io_uring = match IoUring::new(1) {
+        Ok(io_uring) => io_uring,
+        Err(e) => {
+            info!("{} failed to create io_uring instance: {}", error_msg, e);
+            return false;
+        }
+    };
+
+    let submitter = io_uring.submitter();
+
+    let event_fd = match EventFd::new(libc::EFD_NONBLOCK) {
+        Ok(fd) => fd,
+        Err(e) => {
+            info!("{} failed to create eventfd: {}", error_msg, e);
+            return false;
+        }
+    };
+
+    // The completions are notified through an eventfd, which also validates
+    // the io_uring_register() syscall.
+    if let Err(e) = submitter.register_eventfd(event_fd.as_raw_fd()) {
+        info!("{} failed to register eventfd: {}", error_msg, e);
+        return false;
+    }
+
+    let mut probe = Probe::new();
+    if let Err(e) = submitter.register_probe(&mut probe) {
+        info!("{} failed to register a probe: {}", error_msg, e);
+        return false;
+    }
+
+    for (name, code) in [
+        ("IORING_OP_READV", opcode::Readv::CODE),
+        ("IORING_OP_WRITEV", opcode::Writev::CODE),
+        ("IORING_OP_FSYNC", opcode::Fsync::CODE),
+    ]
+    .iter()
+    {
+        if !probe.is_supported(*code) {
+            info!("{} {} operation not supported", error_msg, name);
+            return false;
+        }
+    }
+
+    true
 }
 
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
@@ -374,3 +749,256 @@ impl Serialize for VirtioBlockGeometry {
 }
 
 unsafe impl ByteValued for VirtioBlockGeometry {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use vm_memory::Address;
+
+    const DISK_NSECTORS: u64 = 16;
+    const SEGMENTS_ADDR: GuestAddress = GuestAddress(0x1000);
+
+    fn segment(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroes {
+        DiscardWriteZeroes {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+
+    fn create_disk() -> File {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.write_all(&[0xffu8; (DISK_NSECTORS * SECTOR_SIZE) as usize])
+            .unwrap();
+        disk
+    }
+
+    fn read_disk(disk: &mut File) -> Vec<u8> {
+        let mut content = Vec::new();
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    // Executes a discard or write zeroes request reading data_len bytes of
+    // segments from data_addr.
+    fn execute_segments(
+        request_type: RequestType,
+        segments: &[DiscardWriteZeroes],
+        data_addr: GuestAddress,
+        data_len: u32,
+        disk: &mut File,
+    ) -> result::Result<u32, ExecuteError> {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        for (index, segment) in segments.iter().enumerate() {
+            let offset = index * size_of::<DiscardWriteZeroes>();
+            mem.write_obj(*segment, data_addr.unchecked_add(offset as u64))
+                .unwrap();
+        }
+
+        let request = Request {
+            request_type,
+            sector: 0,
+            data_addr,
+            data_len,
+            status_addr: GuestAddress(0),
+            writeback: true,
+        };
+        request.execute(disk, DISK_NSECTORS, &mem, &Vec::new())
+    }
+
+    fn execute(
+        request_type: RequestType,
+        segments: &[DiscardWriteZeroes],
+        disk: &mut File,
+    ) -> result::Result<u32, ExecuteError> {
+        let data_len = (segments.len() * size_of::<DiscardWriteZeroes>()) as u32;
+        execute_segments(request_type, segments, SEGMENTS_ADDR, data_len, disk)
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_range_too_large() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let max = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+
+        for request_type in [RequestType::Discard, RequestType::WriteZeroes].iter() {
+            let request = Request {
+                request_type: *request_type,
+                sector: 0,
+                data_addr: SEGMENTS_ADDR,
+                data_len: size_of::<DiscardWriteZeroes>() as u32,
+                status_addr: GuestAddress(0),
+                writeback: true,
+            };
+
+            mem.write_obj(segment(0, max, 0), SEGMENTS_ADDR).unwrap();
+            assert_eq!(
+                request
+                    .discard_write_zeroes_ranges(u64::MAX, &mem)
+                    .unwrap()
+                    .len(),
+                1
+            );
+
+            mem.write_obj(segment(0, max + 1, 0), SEGMENTS_ADDR)
+                .unwrap();
+            assert!(matches!(
+                request.discard_write_zeroes_ranges(u64::MAX, &mem),
+                Err(ExecuteError::BadRequest(Error::RangeTooLarge))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_flags() {
+        let mut disk = create_disk();
+        let unmap = VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP;
+
+        // Discard requests don't take any flag.
+        assert!(execute(RequestType::Discard, &[segment(0, 1, 0)], &mut disk).is_ok());
+        assert!(matches!(
+            execute(RequestType::Discard, &[segment(0, 1, unmap)], &mut disk),
+            Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD))
+        ));
+
+        // Write zeroes requests can only allow unmapping the sectors.
+        assert!(execute(RequestType::WriteZeroes, &[segment(1, 1, 0)], &mut disk).is_ok());
+        assert!(execute(RequestType::WriteZeroes, &[segment(2, 1, unmap)], &mut disk).is_ok());
+        assert!(matches!(
+            execute(
+                RequestType::WriteZeroes,
+                &[segment(3, 1, 1 << 1)],
+                &mut disk
+            ),
+            Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
+        ));
+
+        let content = read_disk(&mut disk);
+        let sector =
+            |s: u64| &content[(s * SECTOR_SIZE) as usize..((s + 1) * SECTOR_SIZE) as usize];
+        assert!(sector(0).iter().all(|b| *b == 0));
+        assert!(sector(1).iter().all(|b| *b == 0));
+        assert!(sector(2).iter().all(|b| *b == 0));
+        assert!(sector(3).iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_out_of_range() {
+        let mut disk = create_disk();
+
+        for request_type in [RequestType::Discard, RequestType::WriteZeroes].iter() {
+            // The last sectors of the disk can be targeted.
+            assert!(execute(
+                *request_type,
+                &[segment(DISK_NSECTORS - 2, 2, 0)],
+                &mut disk
+            )
+            .is_ok());
+            assert!(execute(*request_type, &[segment(DISK_NSECTORS, 0, 0)], &mut disk).is_ok());
+
+            assert!(matches!(
+                execute(
+                    *request_type,
+                    &[segment(DISK_NSECTORS - 1, 2, 0)],
+                    &mut disk
+                ),
+                Err(ExecuteError::BadRequest(Error::InvalidOffset))
+            ));
+            assert!(matches!(
+                execute(*request_type, &[segment(DISK_NSECTORS, 1, 0)], &mut disk),
+                Err(ExecuteError::BadRequest(Error::InvalidOffset))
+            ));
+            assert!(matches!(
+                execute(*request_type, &[segment(u64::MAX, u32::MAX, 0)], &mut disk),
+                Err(ExecuteError::BadRequest(Error::InvalidOffset))
+            ));
+        }
+
+        // All the segments are checked before any of them gets executed.
+        let mut disk = create_disk();
+        assert!(matches!(
+            execute(
+                RequestType::WriteZeroes,
+                &[segment(0, 4, 0), segment(DISK_NSECTORS, 1, 0)],
+                &mut disk
+            ),
+            Err(ExecuteError::BadRequest(Error::InvalidOffset))
+        ));
+        assert!(read_disk(&mut disk).iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_segments() {
+        let mut disk = create_disk();
+        let segment_size = size_of::<DiscardWriteZeroes>() as u32;
+        let segments = [segment(0, 1, 0), segment(4, 2, 0)];
+
+        // Several segments can be carried by a single request.
+        assert!(execute(RequestType::WriteZeroes, &segments, &mut disk).is_ok());
+        let content = read_disk(&mut disk);
+        for (index, byte) in content.iter().enumerate() {
+            let sector = index as u64 / SECTOR_SIZE;
+            let zeroed = sector == 0 || sector == 4 || sector == 5;
+            assert_eq!(*byte == 0, zeroed, "byte {}", index);
+        }
+
+        // The buffer must hold at least one segment, and whole segments.
+        for data_len in [0, segment_size - 1].iter() {
+            assert!(matches!(
+                execute_segments(
+                    RequestType::Discard,
+                    &segments,
+                    SEGMENTS_ADDR,
+                    *data_len,
+                    &mut disk
+                ),
+                Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall))
+            ));
+        }
+        assert!(matches!(
+            execute_segments(
+                RequestType::Discard,
+                &segments,
+                SEGMENTS_ADDR,
+                segment_size + 8,
+                &mut disk
+            ),
+            Err(ExecuteError::BadRequest(Error::InvalidDataLength))
+        ));
+
+        // The segments don't need to be aligned in the guest memory.
+        let mut disk = create_disk();
+        assert!(execute_segments(
+            RequestType::WriteZeroes,
+            &segments,
+            SEGMENTS_ADDR.unchecked_add(3),
+            2 * segment_size,
+            &mut disk
+        )
+        .is_ok());
+        assert_eq!(read_disk(&mut disk), content);
+
+        // But they must be within the guest memory.
+        assert!(matches!(
+            execute_segments(
+                RequestType::Discard,
+                &[],
+                GuestAddress(0x10000 - u64::from(segment_size) / 2),
+                segment_size,
+                &mut disk
+            ),
+            Err(ExecuteError::Read(_))
+        ));
+        assert!(matches!(
+            execute_segments(
+                RequestType::Discard,
+                &[],
+                GuestAddress(0x10000),
+                segment_size,
+                &mut disk
+            ),
+            Err(ExecuteError::BadRequest(Error::CheckedOffset(..)))
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use io_uring::{opcode, squeue, IoUring};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, RawFd};
+use vmm_sys_util::eventfd::EventFd;
+
+// Whether the kernel couldn't take the submitted entries for now.
+fn is_busy(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EBUSY) || e.raw_os_error() == Some(libc::EAGAIN)
+}
+
+/// Raw disk image relying on io_uring for its asynchronous I/O.
+pub struct RawFileDisk {
+    file: File,
+}
+
+impl RawFileDisk {
+    pub fn new(file: File) -> Self {
+        RawFileDisk { file }
+    }
+}
+
+impl DiskFile for RawFileDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.file
+            .seek(SeekFrom::End(0))
+            .map_err(DiskFileError::Size)
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(
+            RawFileAsync::new(self.file.as_raw_fd(), ring_depth)
+                .map_err(DiskFileError::NewAsyncIo)?,
+        ) as Box<dyn AsyncIo>)
+    }
+}
+
+pub struct RawFileAsync {
+    fd: RawFd,
+    io_uring: IoUring,
+    eventfd: EventFd,
+    // Writes followed by a linked fsync, indexed by their user data, along
+    // with the result of the write once completed.
+    synced_writes: HashMap<u64, Option<i32>>,
+}
+
+impl RawFileAsync {
+    pub fn new(fd: RawFd, ring_depth: u32) -> io::Result<Self> {
+        let io_uring = IoUring::new(ring_depth)?;
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
+
+        // Register the io_uring eventfd that will notify when something in
+        // the completion queue is ready.
+        io_uring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        Ok(RawFileAsync {
+            fd,
+            io_uring,
+            eventfd,
+            synced_writes: HashMap::new(),
+        })
+    }
+
+    // Submits the entries of a single request, all of them or none.
+    fn submit(
+        &mut self,
+        entries: Vec<squeue::Entry>,
+        error: fn(io::Error) -> AsyncIoError,
+    ) -> AsyncIoResult<()> {
+        let (submitter, sq, _) = self.io_uring.split();
+        let mut avail_sq = sq.available();
+
+        if avail_sq.capacity() - avail_sq.len() < entries.len() {
+            return Err(AsyncIoError::SubmissionQueueFull);
+        }
+
+        for entry in entries {
+            // Safe because the file descriptor is valid, and the buffers
+            // come from the guest memory which outlives the request. There
+            // is room for the entry as checked above.
+            unsafe { avail_sq.push(entry) }.map_err(|_| AsyncIoError::SubmissionQueueFull)?;
+        }
+
+        // Update the submission queue and submit the new operation to the
+        // io_uring instance.
+        avail_sq.sync();
+        match submitter.submit() {
+            Ok(_) => Ok(()),
+            // The entries stay in the submission queue until the kernel
+            // can take them, once some requests have completed.
+            Err(e) if is_busy(&e) => Ok(()),
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, length: u64) -> io::Result<()> {
+        // Safe because the file descriptor is valid and the return value is
+        // checked.
+        let ret = unsafe {
+            libc::fallocate64(
+                self.fd,
+                mode | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off64_t,
+                length as libc::off64_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl AsyncIo for RawFileAsync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let entry = opcode::Readv::new(
+            opcode::types::Fd(self.fd),
+            iovecs.as_ptr(),
+            iovecs.len() as u32,
+        )
+        .offset(offset)
+        .build()
+        .user_data(user_data);
+
+        self.submit(vec![entry], AsyncIoError::ReadVectored)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+        sync: bool,
+    ) -> AsyncIoResult<()> {
+        let entry = opcode::Writev::new(
+            opcode::types::Fd(self.fd),
+            iovecs.as_ptr(),
+            iovecs.len() as u32,
+        )
+        .offset(offset)
+        .build()
+        .user_data(user_data);
+
+        if !sync {
+            return self.submit(vec![entry], AsyncIoError::WriteVectored);
+        }
+
+        // The fsync only starts once the write has succeeded, and completes
+        // the request.
+        let fsync_entry = opcode::Fsync::new(opcode::types::Fd(self.fd))
+            .build()
+            .user_data(user_data);
+        self.synced_writes.insert(user_data, None);
+        self.submit(
+            vec![entry.flags(squeue::Flags::IO_LINK), fsync_entry],
+            AsyncIoError::WriteVectored,
+        )
+        .map_err(|e| {
+            self.synced_writes.remove(&user_data);
+            e
+        })
+    }
+
+    fn fsync(&mut self, user_data: u64) -> AsyncIoResult<()> {
+        let entry = opcode::Fsync::new(opcode::types::Fd(self.fd))
+            .build()
+            .user_data(user_data);
+
+        self.submit(vec![entry], AsyncIoError::Fsync)
+    }
+
+    fn punch_hole(&mut self, offset: u64, length: u64) -> AsyncIoResult<()> {
+        self.fallocate(libc::FALLOC_FL_PUNCH_HOLE, offset, length)
+            .map_err(AsyncIoError::PunchHole)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, length: u64, unmap: bool) -> AsyncIoResult<()> {
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE
+        };
+
+        self.fallocate(mode, offset, length)
+            .map_err(AsyncIoError::WriteZeroes)
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        let mut completion_list = Vec::new();
+
+        let cq = self.io_uring.completion();
+        for cq_entry in cq.available() {
+            let (user_data, mut result) = (cq_entry.user_data(), cq_entry.result());
+
+            // A synchronized write completes with its fsync. The result of
+            // the write is reported unless only the fsync failed, as the
+            // fsync is cancelled when the write fails.
+            match self.synced_writes.get_mut(&user_data) {
+                Some(write_result @ None) => {
+                    *write_result = Some(result);
+                    continue;
+                }
+                Some(Some(write_result)) => {
+                    if *write_result < 0 || result >= 0 {
+                        result = *write_result;
+                    }
+                    self.synced_writes.remove(&user_data);
+                }
+                None => {}
+            }
+
+            completion_list.push((user_data, result));
+        }
+
+        // Submit the entries the kernel couldn't take until now.
+        let (submitter, mut sq, _) = self.io_uring.split();
+        if !sq.available().is_empty() {
+            match submitter.submit() {
+                Ok(_) => {}
+                Err(e) if is_busy(&e) => {}
+                Err(e) => error!("Failed to submit pending requests: {}", e),
+            }
+        }
+
+        completion_list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_io_uring_is_supported;
+    use std::thread;
+    use std::time::Duration;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    // Waits for the given number of requests to complete.
+    fn wait_completions(async_io: &mut RawFileAsync, count: usize) -> Vec<(u64, i32)> {
+        let mut completion_list = Vec::new();
+        for _ in 0..1000 {
+            completion_list.append(&mut async_io.complete());
+            if completion_list.len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        completion_list
+    }
+
+    #[test]
+    fn test_write_sync_read() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(4096).unwrap();
+        let mut async_io = RawFileAsync::new(file.as_raw_fd(), 4).unwrap();
+
+        let mut data = [0xaau8; 512];
+        async_io
+            .write_vectored(512, vec![iovec(&mut data)], 1, true)
+            .unwrap();
+        // The write and its fsync complete as a single request.
+        assert_eq!(wait_completions(&mut async_io, 1), vec![(1, 512)]);
+        assert!(async_io.synced_writes.is_empty());
+
+        async_io.fsync(2).unwrap();
+        assert_eq!(wait_completions(&mut async_io, 1), vec![(2, 0)]);
+
+        let mut buf = [0u8; 1024];
+        async_io.read_vectored(0, vec![iovec(&mut buf)], 3).unwrap();
+        assert_eq!(wait_completions(&mut async_io, 1), vec![(3, 1024)]);
+        assert_eq!(&buf[..512], &[0u8; 512][..]);
+        assert_eq!(&buf[512..], &data[..]);
+    }
+
+    #[test]
+    fn test_submission_queue_full() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(4096).unwrap();
+        let mut async_io = RawFileAsync::new(file.as_raw_fd(), 1).unwrap();
+
+        // A synchronized write needs two entries, which never fit.
+        let mut data = [0xaau8; 512];
+        assert!(matches!(
+            async_io.write_vectored(0, vec![iovec(&mut data)], 1, true),
+            Err(AsyncIoError::SubmissionQueueFull)
+        ));
+        assert!(async_io.synced_writes.is_empty());
+
+        // Nothing was submitted for the refused request.
+        async_io
+            .write_vectored(0, vec![iovec(&mut data)], 2, false)
+            .unwrap();
+        assert_eq!(wait_completions(&mut async_io, 1), vec![(2, 512)]);
+        thread::sleep(Duration::from_millis(10));
+        assert!(async_io.complete().is_empty());
+    }
+}
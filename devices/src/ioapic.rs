@@ -400,6 +400,8 @@ impl InterruptController for Ioapic {
     }
 }
 
+const IOAPIC_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Ioapic {
     fn id(&self) -> String {
         self.id.clone()
@@ -412,6 +414,7 @@ impl Snapshottable for Ioapic {
         let mut ioapic_snapshot = Snapshot::new(self.id.as_str());
         ioapic_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: IOAPIC_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -420,6 +423,8 @@ impl Snapshottable for Ioapic {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(ioapic_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            ioapic_section.check_version(IOAPIC_SNAPSHOT_VERSION)?;
+
             let ioapic_state = match serde_json::from_slice(&ioapic_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
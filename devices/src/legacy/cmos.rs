@@ -2,9 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use anyhow::anyhow;
 use libc::{clock_gettime, gmtime_r, time_t, timespec, tm, CLOCK_REALTIME};
 use std::cmp::min;
 use std::mem;
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable,
+};
 
 use crate::BusDevice;
 
@@ -15,15 +20,22 @@ const DATA_LEN: usize = 128;
 
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
+    id: String,
     index: u8,
     data: [u8; DATA_LEN],
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct CmosState {
+    index: u8,
+    data: Vec<u8>,
+}
+
 impl Cmos {
     /// Constructs a CMOS/RTC device with initial data.
     /// `mem_below_4g` is the size of memory in bytes below the 32-bit gap.
     /// `mem_above_4g` is the size of memory in bytes above the 32-bit gap.
-    pub fn new(mem_below_4g: u64, mem_above_4g: u64) -> Cmos {
+    pub fn new(id: String, mem_below_4g: u64, mem_above_4g: u64) -> Cmos {
         let mut data = [0u8; DATA_LEN];
 
         // Extended memory from 16 MB to 4 GB in units of 64 KB
@@ -40,7 +52,28 @@ impl Cmos {
         data[0x5c] = (high_mem >> 8) as u8;
         data[0x5d] = (high_mem >> 16) as u8;
 
-        Cmos { index: 0, data }
+        Cmos { id, index: 0, data }
+    }
+
+    fn state(&self) -> CmosState {
+        CmosState {
+            index: self.index,
+            data: self.data.to_vec(),
+        }
+    }
+
+    fn set_state(&mut self, state: &CmosState) -> Result<(), MigratableError> {
+        if state.data.len() != DATA_LEN {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid CMOS data length {}",
+                state.data.len()
+            )));
+        }
+
+        self.index = state.index & INDEX_MASK;
+        self.data.copy_from_slice(&state.data);
+
+        Ok(())
     }
 }
 
@@ -123,3 +156,83 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+// The time and date registers are not saved, since they always reflect the
+// host clock. Only the index and the NVRAM content, which includes the RTC
+// control registers, are part of the snapshot.
+const CMOS_SNAPSHOT_VERSION: u16 = 1;
+
+impl Snapshottable for Cmos {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let snapshot =
+            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut cmos_snapshot = Snapshot::new(self.id.as_str());
+        cmos_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id),
+            version: CMOS_SNAPSHOT_VERSION,
+            snapshot,
+        });
+
+        Ok(cmos_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(cmos_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            cmos_section.check_version(CMOS_SNAPSHOT_VERSION)?;
+
+            let cmos_state = match serde_json::from_slice(&cmos_section.snapshot) {
+                Ok(state) => state,
+                Err(error) => {
+                    return Err(MigratableError::Restore(anyhow!(
+                        "Could not deserialize CMOS {}",
+                        error
+                    )))
+                }
+            };
+
+            return self.set_state(&cmos_state);
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find the CMOS snapshot section"
+        )))
+    }
+}
+
+impl Pausable for Cmos {}
+impl Transportable for Cmos {}
+impl Migratable for Cmos {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmos_snapshot_restore() {
+        let mut cmos = Cmos::new(String::from("cmos"), 1 << 30, 0);
+        cmos.write(0, INDEX_OFFSET, &[0x40]);
+        cmos.write(0, DATA_OFFSET, &[0xab]);
+        cmos.write(0, INDEX_OFFSET, &[0x0b]);
+        cmos.write(0, DATA_OFFSET, &[0x02]);
+
+        let snapshot = cmos.snapshot().unwrap();
+
+        let mut restored = Cmos::new(String::from("cmos"), 0, 0);
+        restored.restore(snapshot).unwrap();
+
+        let mut data = [0u8];
+        restored.read(0, INDEX_OFFSET, &mut data);
+        assert_eq!(data[0], 0x0b);
+        restored.read(0, DATA_OFFSET, &mut data);
+        assert_eq!(data[0], 0x02);
+        restored.write(0, INDEX_OFFSET, &[0x40]);
+        restored.read(0, DATA_OFFSET, &mut data);
+        assert_eq!(data[0], 0xab);
+        assert_eq!(&restored.data[0x34..0x36], &cmos.data[0x34..0x36]);
+    }
+}
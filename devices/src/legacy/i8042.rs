@@ -2,19 +2,21 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 use BusDevice;
 
 /// A i8042 PS/2 controller that emulates just enough to shutdown the machine.
 pub struct I8042Device {
+    id: String,
     reset_evt: EventFd,
 }
 
 impl I8042Device {
     /// Constructs a i8042 device that will signal the given event when the guest requests it.
-    pub fn new(reset_evt: EventFd) -> I8042Device {
-        I8042Device { reset_evt }
+    pub fn new(id: String, reset_evt: EventFd) -> I8042Device {
+        I8042Device { id, reset_evt }
     }
 }
 
@@ -41,3 +43,18 @@ impl BusDevice for I8042Device {
         }
     }
 }
+
+// The device is stateless, an empty snapshot is enough to restore it.
+impl Snapshottable for I8042Device {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Ok(Snapshot::new(self.id.as_str()))
+    }
+}
+
+impl Pausable for I8042Device {}
+impl Transportable for I8042Device {}
+impl Migratable for I8042Device {}
@@ -284,6 +284,8 @@ impl BusDevice for Serial {
     }
 }
 
+const SERIAL_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Serial {
     fn id(&self) -> String {
         self.id.clone()
@@ -296,6 +298,7 @@ impl Snapshottable for Serial {
         let mut serial_snapshot = Snapshot::new(self.id.as_str());
         serial_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: SERIAL_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -304,6 +307,8 @@ impl Snapshottable for Serial {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(serial_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            serial_section.check_version(SERIAL_SNAPSHOT_VERSION)?;
+
             let serial_state = match serde_json::from_slice(&serial_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
@@ -92,6 +92,7 @@ fuzz_target!(|bytes| {
         false,
         2,
         256,
+        None,
     )
     .unwrap();
 
@@ -18,6 +18,8 @@ use crate::x86_64::{
     StandardRegisters, VcpuEvents, Xsave,
 };
 use thiserror::Error;
+#[cfg(target_arch = "x86_64")]
+use vm_memory::GuestAddress;
 
 #[derive(Error, Debug)]
 ///
@@ -148,6 +150,11 @@ pub enum HypervisorCpuError {
     ///
     #[error("Failed to notify guest its clock was paused: {0}")]
     NotifyGuestClockPaused(#[source] anyhow::Error),
+    ///
+    /// Setting guest debug error
+    ///
+    #[error("Failed to set guest debug: {0}")]
+    SetGuestDebug(#[source] anyhow::Error),
 }
 
 #[derive(Debug)]
@@ -158,6 +165,8 @@ pub enum VmExit<'a> {
     IoIn(u16 /* port */, &'a mut [u8] /* data */),
     #[cfg(target_arch = "x86_64")]
     IoapicEoi(u8 /* vector */),
+    #[cfg(target_arch = "x86_64")]
+    Debug,
     MmioRead(u64 /* address */, &'a mut [u8]),
     MmioWrite(u64 /* address */, &'a [u8]),
     Ignore,
@@ -279,6 +288,18 @@ pub trait Vcpu: Send + Sync {
     /// potential soft lockups when being resumed.
     ///
     fn notify_guest_clock_paused(&self) -> Result<()>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Enables or disables guest debugging, with up to 4 hardware breakpoints
+    /// and optional single-stepping. Software breakpoints (int3) are trapped
+    /// as long as debugging is enabled.
+    ///
+    fn set_guest_debug(
+        &self,
+        enable: bool,
+        hw_breakpoints: &[GuestAddress],
+        singlestep: bool,
+    ) -> Result<()>;
     ///
     /// Sets the type of CPU to be exposed to the guest and optional features.
     ///
@@ -9,11 +9,13 @@
 //
 
 use kvm_ioctls::{NoDatamatch, VcpuFd, VmFd};
+use std::collections::HashMap;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 #[cfg(target_arch = "x86_64")]
-use vm_memory::Address;
+use vm_memory::{Address, GuestAddress};
+use vmm_sys_util::errno;
 use vmm_sys_util::eventfd::EventFd;
 
 #[cfg(target_arch = "aarch64")]
@@ -38,7 +40,19 @@ pub use x86_64::{
 };
 
 #[cfg(target_arch = "x86_64")]
-use kvm_bindings::{kvm_enable_cap, MsrList, KVM_CAP_SPLIT_IRQCHIP};
+use kvm_bindings::{
+    kvm_enable_cap, kvm_guest_debug, MsrList, KVMIO, KVM_CAP_SPLIT_IRQCHIP, KVM_GUESTDBG_ENABLE,
+    KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+};
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl::ioctl_with_ref;
+
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvm_guest_debug);
+
+// Number of debug registers (DR0-DR3) usable as hardware breakpoints.
+#[cfg(target_arch = "x86_64")]
+const MAX_HW_BREAKPOINTS: usize = 4;
 
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86::NUM_IOAPIC_PINS;
@@ -50,7 +64,8 @@ pub mod aarch64;
 pub use kvm_bindings;
 pub use kvm_bindings::{
     kvm_create_device, kvm_device_type_KVM_DEV_TYPE_VFIO, kvm_irq_routing, kvm_irq_routing_entry,
-    kvm_userspace_memory_region, KVM_IRQ_ROUTING_MSI, KVM_MEM_READONLY, KVM_MSI_VALID_DEVID,
+    kvm_userspace_memory_region, KVM_IRQ_ROUTING_MSI, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY,
+    KVM_MSI_VALID_DEVID,
 };
 pub use kvm_ioctls;
 pub use kvm_ioctls::{Cap, Kvm};
@@ -67,11 +82,37 @@ pub use {
     kvm_ioctls::VcpuExit,
 };
 
+/// Writable memory slots, which can be switched to dirty pages logging.
+#[derive(Default)]
+struct KvmMemorySlots {
+    regions: HashMap<u32, MemoryRegion>,
+    dirty_log: bool,
+}
+
 /// Wrapper over KVM VM ioctls.
 pub struct KvmVm {
     fd: Arc<VmFd>,
     #[cfg(target_arch = "x86_64")]
     msrs: MsrEntries,
+    memory_slots: Mutex<KvmMemorySlots>,
+}
+
+impl KvmVm {
+    fn set_memory_slot_flags(
+        &self,
+        region: &MemoryRegion,
+        dirty_log: bool,
+    ) -> result::Result<(), errno::Error> {
+        let mut region = *region;
+        if dirty_log {
+            region.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        } else {
+            region.flags &= !KVM_MEM_LOG_DIRTY_PAGES;
+        }
+
+        // Safe because the region is an existing slot being updated in place.
+        unsafe { self.fd.set_user_memory_region(region) }
+    }
 }
 
 // Returns a `Vec<T>` with a size in bytes at least as large as `size_in_bytes`.
@@ -240,12 +281,31 @@ impl vm::Vm for KvmVm {
     /// Creates/modifies a guest physical memory slot.
     ///
     fn set_user_memory_region(&self, user_memory_region: MemoryRegion) -> vm::Result<()> {
+        let mut user_memory_region = user_memory_region;
+        let mut memory_slots = self.memory_slots.lock().unwrap();
+
+        // Slots created while dirty pages are being logged must be logged
+        // as well, otherwise the guest writes to them would be missed.
+        if memory_slots.dirty_log && user_memory_region.flags & KVM_MEM_READONLY == 0 {
+            user_memory_region.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        }
+
         // Safe because guest regions are guaranteed not to overlap.
         unsafe {
             self.fd
                 .set_user_memory_region(user_memory_region)
-                .map_err(|e| vm::HypervisorVmError::SetUserMemory(e.into()))
+                .map_err(|e| vm::HypervisorVmError::SetUserMemory(e.into()))?;
         }
+
+        if user_memory_region.memory_size == 0 {
+            memory_slots.regions.remove(&user_memory_region.slot);
+        } else if user_memory_region.flags & KVM_MEM_READONLY == 0 {
+            memory_slots
+                .regions
+                .insert(user_memory_region.slot, user_memory_region);
+        }
+
+        Ok(())
     }
     ///
     /// Creates an emulated device in the kernel.
@@ -314,6 +374,41 @@ impl vm::Vm for KvmVm {
         self.create_device(&mut vfio_dev)
             .map_err(|e| vm::HypervisorVmError::CreatePassthroughDevice(e.into()))
     }
+    ///
+    /// Start logging dirty pages for all writable memory slots.
+    ///
+    fn start_dirty_log(&self) -> vm::Result<()> {
+        let mut memory_slots = self.memory_slots.lock().unwrap();
+        for region in memory_slots.regions.values() {
+            self.set_memory_slot_flags(region, true)
+                .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
+        }
+        memory_slots.dirty_log = true;
+
+        Ok(())
+    }
+    ///
+    /// Stop logging dirty pages for all writable memory slots.
+    ///
+    fn stop_dirty_log(&self) -> vm::Result<()> {
+        let mut memory_slots = self.memory_slots.lock().unwrap();
+        for region in memory_slots.regions.values() {
+            self.set_memory_slot_flags(region, false)
+                .map_err(|e| vm::HypervisorVmError::StopDirtyLog(e.into()))?;
+        }
+        memory_slots.dirty_log = false;
+
+        Ok(())
+    }
+    ///
+    /// Get and reset the dirty pages bitmap for the given memory slot.
+    ///
+    /// See the documentation for `KVM_GET_DIRTY_LOG`.
+    fn get_dirty_log(&self, slot: u32, _base_gpa: u64, memory_size: u64) -> vm::Result<Vec<u64>> {
+        self.fd
+            .get_dirty_log(slot, memory_size as usize)
+            .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()))
+    }
 }
 /// Wrapper over KVM system ioctls.
 pub struct KvmHypervisor {
@@ -381,12 +476,19 @@ impl hypervisor::Hypervisor for KvmHypervisor {
                 msr_entries[pos].index = *index;
             }
 
-            Ok(Arc::new(KvmVm { fd: vm_fd, msrs }))
+            Ok(Arc::new(KvmVm {
+                fd: vm_fd,
+                msrs,
+                memory_slots: Mutex::new(KvmMemorySlots::default()),
+            }))
         }
 
         #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
         {
-            Ok(Arc::new(KvmVm { fd: vm_fd }))
+            Ok(Arc::new(KvmVm {
+                fd: vm_fd,
+                memory_slots: Mutex::new(KvmMemorySlots::default()),
+            }))
         }
     }
 
@@ -639,6 +741,8 @@ impl cpu::Vcpu for KvmVcpu {
                 VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Debug => Ok(cpu::VmExit::Debug),
 
                 #[cfg(target_arch = "aarch64")]
                 VcpuExit::SystemEvent(event_type, flags) => {
@@ -704,6 +808,48 @@ impl cpu::Vcpu for KvmVcpu {
             .kvmclock_ctrl()
             .map_err(|e| cpu::HypervisorCpuError::NotifyGuestClockPaused(e.into()))
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets the guest debugging state using the `KVM_SET_GUEST_DEBUG` ioctl.
+    /// Hardware breakpoints are programmed through the DR0-DR3 debug registers.
+    ///
+    fn set_guest_debug(
+        &self,
+        enable: bool,
+        hw_breakpoints: &[GuestAddress],
+        singlestep: bool,
+    ) -> cpu::Result<()> {
+        if hw_breakpoints.len() > MAX_HW_BREAKPOINTS {
+            return Err(cpu::HypervisorCpuError::SetGuestDebug(anyhow!(
+                "Only {} hardware breakpoints are supported",
+                MAX_HW_BREAKPOINTS
+            )));
+        }
+
+        let mut dbg = kvm_guest_debug::default();
+        if enable {
+            dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP | KVM_GUESTDBG_USE_HW_BP;
+            if singlestep {
+                dbg.control |= KVM_GUESTDBG_SINGLESTEP;
+            }
+            for (i, addr) in hw_breakpoints.iter().enumerate() {
+                dbg.arch.debugreg[i] = addr.raw_value();
+                // Set the global enable bit of the breakpoint in DR7.
+                dbg.arch.debugreg[7] |= 2 << (i * 2);
+            }
+        }
+
+        // Safe because we know the vCPU file descriptor is valid and the
+        // kvm_guest_debug structure is properly initialized.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_SET_GUEST_DEBUG(), &dbg) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::SetGuestDebug(
+                errno::Error::last().into(),
+            ));
+        }
+
+        Ok(())
+    }
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     fn vcpu_init(&self, kvi: &VcpuInit) -> cpu::Result<()> {
         self.fd
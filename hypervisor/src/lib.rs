@@ -24,10 +24,17 @@ extern crate serde_json;
 extern crate thiserror;
 #[macro_use]
 extern crate anyhow;
+#[cfg(target_arch = "x86_64")]
+#[macro_use]
+extern crate vmm_sys_util;
 
 /// KVM implementation module
 pub mod kvm;
 
+/// Mock implementation module
+#[cfg(feature = "mock")]
+pub mod mock;
+
 /// Hypevisor related module
 pub mod hypervisor;
 
@@ -53,6 +60,8 @@ use std::sync::Arc;
 pub fn new() -> std::result::Result<Arc<dyn Hypervisor>, HypervisorError> {
     #[cfg(feature = "kvm")]
     let hv = kvm::KvmHypervisor::new()?;
+    #[cfg(all(feature = "mock", not(feature = "kvm")))]
+    let hv = mock::MockHypervisor::new();
 
     Ok(Arc::new(hv))
 }
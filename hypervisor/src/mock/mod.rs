@@ -0,0 +1,1098 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Mock hypervisor backend
+//!
+//! This backend does not run any guest code. It records the memory regions,
+//! irqfds, I/O events and GSI routes programmed by the VMM, and lets vCPUs
+//! return scripted sequences of VM exits, so that the VMM can be unit tested
+//! on hosts where `/dev/kvm` is not available.
+
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+#[cfg(target_arch = "x86_64")]
+use vm_memory::GuestAddress;
+use vmm_sys_util::eventfd::EventFd;
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::{VcpuInit, VcpuKvmState as CpuState};
+use crate::cpu;
+use crate::device;
+use crate::hypervisor;
+use crate::vm;
+use crate::{CreateDevice, DeviceAttr, IoEventAddress, IrqRoutingEntry, MemoryRegion, MpState};
+use crate::{KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY};
+use kvm_ioctls::Cap;
+
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::{
+    CpuId, ExtendedControlRegisters, FpuState, LapicState, MsrEntries, MsrList, SpecialRegisters,
+    StandardRegisters, VcpuEvents, VcpuKvmState as CpuState, Xsave,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::ClockData;
+
+// Value returned by KVM_GET_API_VERSION on any recent kernel.
+const MOCK_API_VERSION: i32 = 12;
+// Number of vCPUs the mock hypervisor claims to support.
+const MOCK_MAX_VCPUS: usize = 255;
+// Page size used to size the dirty pages bitmaps.
+const MOCK_PAGE_SIZE: u64 = 4096;
+
+/// Mock hypervisor, creating `MockVm` instances.
+pub struct MockHypervisor {
+    #[cfg(target_arch = "x86_64")]
+    cpuid: CpuId,
+}
+
+impl MockHypervisor {
+    /// Create a mock hypervisor, exposing an empty CPUID.
+    pub fn new() -> MockHypervisor {
+        MockHypervisor {
+            #[cfg(target_arch = "x86_64")]
+            cpuid: CpuId::new(0),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Create a mock hypervisor, exposing the given supported CPUID.
+    pub fn with_cpuid(cpuid: CpuId) -> MockHypervisor {
+        MockHypervisor { cpuid }
+    }
+}
+
+impl Default for MockHypervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl hypervisor::Hypervisor for MockHypervisor {
+    ///
+    /// Creates a mock VM, with no memory and no vCPU.
+    ///
+    fn create_vm(&self) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+        Ok(Arc::new(MockVm::new()))
+    }
+    ///
+    /// Returns the KVM API version the mock hypervisor emulates.
+    ///
+    fn get_api_version(&self) -> i32 {
+        MOCK_API_VERSION
+    }
+    ///
+    /// Returns the size of the memory mapping required to use the vcpu's structures.
+    ///
+    fn get_vcpu_mmap_size(&self) -> hypervisor::Result<usize> {
+        Ok(MOCK_PAGE_SIZE as usize)
+    }
+    ///
+    /// Gets the recommended maximum number of VCPUs per VM.
+    ///
+    fn get_max_vcpus(&self) -> hypervisor::Result<usize> {
+        Ok(MOCK_MAX_VCPUS)
+    }
+    ///
+    /// Gets the recommended number of VCPUs per VM.
+    ///
+    fn get_nr_vcpus(&self) -> hypervisor::Result<usize> {
+        Ok(MOCK_MAX_VCPUS)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// All capabilities are reported as available.
+    ///
+    fn check_capability(&self, _c: Cap) -> bool {
+        true
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the CPUID the mock hypervisor was created with.
+    ///
+    fn get_cpuid(&self) -> hypervisor::Result<CpuId> {
+        Ok(self.cpuid.clone())
+    }
+    ///
+    /// No extension is required by the mock hypervisor.
+    ///
+    fn check_required_extensions(&self) -> hypervisor::Result<()> {
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns an empty list of supported MSRs.
+    ///
+    fn get_msr_list(&self) -> hypervisor::Result<MsrList> {
+        Ok(MsrList::new(0))
+    }
+}
+
+/// I/O event registered with `register_ioevent`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockIoEvent {
+    /// Whether the event is triggered by a port I/O or an MMIO access.
+    pub pio: bool,
+    /// Address of the access triggering the event.
+    pub addr: u64,
+    /// Value the written data must match, if any.
+    pub datamatch: Option<u64>,
+    /// File descriptor of the event.
+    pub fd: RawFd,
+}
+
+#[derive(Default)]
+struct MockVmState {
+    memory_regions: HashMap<u32, MemoryRegion>,
+    irqfds: Vec<(RawFd, u32)>,
+    ioevents: Vec<MockIoEvent>,
+    gsi_routing: Vec<IrqRoutingEntry>,
+    irq_chip: bool,
+    dirty_log: bool,
+    dirty_bitmaps: HashMap<u32, Vec<u64>>,
+    devices: Vec<u32>,
+    vcpus: HashMap<u8, Arc<MockVcpu>>,
+    #[cfg(target_arch = "x86_64")]
+    clock: ClockData,
+}
+
+/// Mock VM, recording the resources programmed by the VMM.
+#[derive(Default)]
+pub struct MockVm {
+    state: Mutex<MockVmState>,
+}
+
+fn io_event_address(addr: &IoEventAddress) -> (bool, u64) {
+    match addr {
+        IoEventAddress::Pio(addr) => (true, *addr),
+        IoEventAddress::Mmio(addr) => (false, *addr),
+    }
+}
+
+impl MockVm {
+    /// Create an empty mock VM.
+    pub fn new() -> MockVm {
+        MockVm::default()
+    }
+
+    /// Returns the memory slots currently set, sorted by slot.
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        let mut regions: Vec<MemoryRegion> = self
+            .state
+            .lock()
+            .unwrap()
+            .memory_regions
+            .values()
+            .cloned()
+            .collect();
+        regions.sort_by_key(|r| r.slot);
+        regions
+    }
+
+    /// Returns the registered irqfds, as (file descriptor, GSI) pairs.
+    pub fn irqfds(&self) -> Vec<(RawFd, u32)> {
+        self.state.lock().unwrap().irqfds.clone()
+    }
+
+    /// Returns the registered I/O events.
+    pub fn ioevents(&self) -> Vec<MockIoEvent> {
+        self.state.lock().unwrap().ioevents.clone()
+    }
+
+    /// Returns the last GSI routing table set.
+    pub fn gsi_routing(&self) -> Vec<IrqRoutingEntry> {
+        self.state.lock().unwrap().gsi_routing.clone()
+    }
+
+    /// Returns the types of the devices created through `create_device`.
+    pub fn devices(&self) -> Vec<u32> {
+        self.state.lock().unwrap().devices.clone()
+    }
+
+    /// Whether dirty pages logging is currently enabled.
+    pub fn dirty_log(&self) -> bool {
+        self.state.lock().unwrap().dirty_log
+    }
+
+    /// Returns the vCPU created with the given id, so that VM exits can be
+    /// scripted or its state inspected.
+    pub fn vcpu(&self, id: u8) -> Option<Arc<MockVcpu>> {
+        self.state.lock().unwrap().vcpus.get(&id).cloned()
+    }
+
+    /// Set the dirty pages bitmap returned by the next `get_dirty_log` call
+    /// for the given slot.
+    pub fn set_dirty_bitmap(&self, slot: u32, bitmap: Vec<u64>) {
+        self.state
+            .lock()
+            .unwrap()
+            .dirty_bitmaps
+            .insert(slot, bitmap);
+    }
+}
+
+impl vm::Vm for MockVm {
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Nothing to set, the mock VM does not run any guest code.
+    ///
+    fn set_tss_address(&self, _offset: usize) -> vm::Result<()> {
+        Ok(())
+    }
+    ///
+    /// Records the creation of the interrupt controller.
+    ///
+    fn create_irq_chip(&self) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.irq_chip {
+            return Err(vm::HypervisorVmError::CreateIrq(anyhow!(
+                "Interrupt controller already created"
+            )));
+        }
+        state.irq_chip = true;
+        Ok(())
+    }
+    ///
+    /// Records an event that will, when signaled, trigger the `gsi` IRQ.
+    ///
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let irqfd = (fd.as_raw_fd(), gsi);
+        if state.irqfds.contains(&irqfd) {
+            return Err(vm::HypervisorVmError::RegisterIrqFd(anyhow!(
+                "irqfd {} already registered for GSI {}",
+                irqfd.0,
+                gsi
+            )));
+        }
+        state.irqfds.push(irqfd);
+        Ok(())
+    }
+    ///
+    /// Removes an event previously registered with `register_irqfd`.
+    ///
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let irqfd = (fd.as_raw_fd(), gsi);
+        match state.irqfds.iter().position(|i| *i == irqfd) {
+            Some(index) => {
+                state.irqfds.remove(index);
+                Ok(())
+            }
+            None => Err(vm::HypervisorVmError::UnregisterIrqFd(anyhow!(
+                "irqfd {} not registered for GSI {}",
+                irqfd.0,
+                gsi
+            ))),
+        }
+    }
+    ///
+    /// Creates a mock vCPU, retrievable through `vcpu()`.
+    ///
+    fn create_vcpu(&self, id: u8) -> vm::Result<Arc<dyn cpu::Vcpu>> {
+        let mut state = self.state.lock().unwrap();
+        if state.vcpus.contains_key(&id) {
+            return Err(vm::HypervisorVmError::CreateVcpu(anyhow!(
+                "vCPU {} already created",
+                id
+            )));
+        }
+        let vcpu = Arc::new(MockVcpu::new(id));
+        state.vcpus.insert(id, vcpu.clone());
+        Ok(vcpu)
+    }
+    ///
+    /// Records an event to be signaled whenever a certain address is written to.
+    ///
+    fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Option<vm::DataMatch>,
+    ) -> vm::Result<()> {
+        let (pio, addr) = io_event_address(addr);
+        let ioevent = MockIoEvent {
+            pio,
+            addr,
+            datamatch: datamatch.map(|dm| dm.into()),
+            fd: fd.as_raw_fd(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state
+            .ioevents
+            .iter()
+            .any(|e| e.pio == pio && e.addr == addr && e.datamatch == ioevent.datamatch)
+        {
+            return Err(vm::HypervisorVmError::RegisterIoEvent(anyhow!(
+                "I/O event already registered at {:#x}",
+                addr
+            )));
+        }
+        state.ioevents.push(ioevent);
+        Ok(())
+    }
+    ///
+    /// Removes an event previously registered with `register_ioevent`.
+    ///
+    fn unregister_ioevent(&self, fd: &EventFd, addr: &IoEventAddress) -> vm::Result<()> {
+        let (pio, addr) = io_event_address(addr);
+        let mut state = self.state.lock().unwrap();
+        match state
+            .ioevents
+            .iter()
+            .position(|e| e.pio == pio && e.addr == addr && e.fd == fd.as_raw_fd())
+        {
+            Some(index) => {
+                state.ioevents.remove(index);
+                Ok(())
+            }
+            None => Err(vm::HypervisorVmError::UnregisterIoEvent(anyhow!(
+                "No I/O event registered at {:#x}",
+                addr
+            ))),
+        }
+    }
+    ///
+    /// Records the GSI routing table entries, overwriting any previously set
+    /// entries.
+    ///
+    fn set_gsi_routing(&self, entries: &[IrqRoutingEntry]) -> vm::Result<()> {
+        self.state.lock().unwrap().gsi_routing = entries.to_vec();
+        Ok(())
+    }
+    ///
+    /// Creates a memory region structure that can be used with set_user_memory_region
+    ///
+    fn make_user_memory_region(
+        &self,
+        slot: u32,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+        readonly: bool,
+    ) -> MemoryRegion {
+        MemoryRegion {
+            slot,
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            flags: if readonly { KVM_MEM_READONLY } else { 0 },
+        }
+    }
+    ///
+    /// Records a guest physical memory slot. A zero sized region removes
+    /// the slot.
+    ///
+    fn set_user_memory_region(&self, user_memory_region: MemoryRegion) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let slot = user_memory_region.slot;
+
+        if user_memory_region.memory_size == 0 {
+            state.memory_regions.remove(&slot);
+            return Ok(());
+        }
+
+        let start = user_memory_region.guest_phys_addr;
+        let end = start + user_memory_region.memory_size;
+        if let Some(r) = state.memory_regions.values().find(|r| {
+            r.slot != slot && start < r.guest_phys_addr + r.memory_size && r.guest_phys_addr < end
+        }) {
+            return Err(vm::HypervisorVmError::SetUserMemory(anyhow!(
+                "Region {:#x}-{:#x} overlaps with slot {}",
+                start,
+                end,
+                r.slot
+            )));
+        }
+
+        let mut region = user_memory_region;
+        if state.dirty_log && (region.flags & KVM_MEM_READONLY) == 0 {
+            region.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        }
+        state.memory_regions.insert(slot, region);
+        Ok(())
+    }
+    ///
+    /// Creates a mock device, recording its type.
+    ///
+    fn create_device(&self, device: &mut CreateDevice) -> vm::Result<Arc<dyn device::Device>> {
+        let mock_device =
+            MockDevice::new().map_err(|e| vm::HypervisorVmError::CreateDevice(e.into()))?;
+        device.fd = mock_device.as_raw_fd() as u32;
+        self.state.lock().unwrap().devices.push(device.type_);
+        Ok(Arc::new(mock_device))
+    }
+    ///
+    /// Leaves the default CPU target type untouched.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn get_preferred_target(&self, _kvi: &mut VcpuInit) -> vm::Result<()> {
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Nothing to enable, the interrupt controllers are emulated by the VMM.
+    ///
+    fn enable_split_irq(&self) -> vm::Result<()> {
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Retrieves the last clock set with `set_clock`.
+    ///
+    fn get_clock(&self) -> vm::Result<ClockData> {
+        Ok(self.state.lock().unwrap().clock)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Records the guest clock.
+    ///
+    fn set_clock(&self, data: &ClockData) -> vm::Result<()> {
+        self.state.lock().unwrap().clock = *data;
+        Ok(())
+    }
+    ///
+    /// All extensions are reported as available.
+    ///
+    fn check_extension(&self, _c: Cap) -> bool {
+        true
+    }
+    ///
+    /// Creates a mock device standing for the VFIO device.
+    ///
+    fn create_passthrough_device(&self) -> vm::Result<Arc<dyn device::Device>> {
+        let mock_device = MockDevice::new()
+            .map_err(|e| vm::HypervisorVmError::CreatePassthroughDevice(e.into()))?;
+        Ok(Arc::new(mock_device))
+    }
+    ///
+    /// Enables dirty pages logging for all writable memory slots.
+    ///
+    fn start_dirty_log(&self) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for region in state.memory_regions.values_mut() {
+            if (region.flags & KVM_MEM_READONLY) == 0 {
+                region.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+            }
+        }
+        state.dirty_log = true;
+        Ok(())
+    }
+    ///
+    /// Disables dirty pages logging for all memory slots.
+    ///
+    fn stop_dirty_log(&self) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for region in state.memory_regions.values_mut() {
+            region.flags &= !KVM_MEM_LOG_DIRTY_PAGES;
+        }
+        state.dirty_log = false;
+        Ok(())
+    }
+    ///
+    /// Returns and resets the bitmap set with `set_dirty_bitmap` for the
+    /// given slot, or a clean bitmap if none was set.
+    ///
+    fn get_dirty_log(&self, slot: u32, _base_gpa: u64, memory_size: u64) -> vm::Result<Vec<u64>> {
+        let mut state = self.state.lock().unwrap();
+        match state.memory_regions.get(&slot) {
+            Some(region) if (region.flags & KVM_MEM_LOG_DIRTY_PAGES) != 0 => {}
+            _ => {
+                return Err(vm::HypervisorVmError::GetDirtyLog(anyhow!(
+                    "Dirty pages logging not enabled for slot {}",
+                    slot
+                )))
+            }
+        }
+
+        let pages = (memory_size + MOCK_PAGE_SIZE - 1) / MOCK_PAGE_SIZE;
+        let bitmap_size = ((pages + 63) / 64) as usize;
+        let mut bitmap = state.dirty_bitmaps.remove(&slot).unwrap_or_default();
+        bitmap.resize(bitmap_size, 0);
+        Ok(bitmap)
+    }
+}
+
+/// VM exit returned by `MockVcpu::run()`, as scripted with `push_exits()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockVmExit {
+    #[cfg(target_arch = "x86_64")]
+    /// Port I/O write of the given data.
+    IoOut(u16, Vec<u8>),
+    #[cfg(target_arch = "x86_64")]
+    /// Port I/O read of the given size.
+    IoIn(u16, usize),
+    #[cfg(target_arch = "x86_64")]
+    /// End of interrupt for the given vector.
+    IoapicEoi(u8),
+    #[cfg(target_arch = "x86_64")]
+    /// Guest debug exit.
+    Debug,
+    /// MMIO read of the given size.
+    MmioRead(u64, usize),
+    /// MMIO write of the given data.
+    MmioWrite(u64, Vec<u8>),
+    /// Exit which must be ignored.
+    Ignore,
+    /// Guest reset or shutdown.
+    Reset,
+}
+
+#[derive(Default)]
+struct MockVcpuRegs {
+    mp_state: MpState,
+    #[cfg(target_arch = "x86_64")]
+    regs: StandardRegisters,
+    #[cfg(target_arch = "x86_64")]
+    sregs: SpecialRegisters,
+    #[cfg(target_arch = "x86_64")]
+    fpu: FpuState,
+    #[cfg(target_arch = "x86_64")]
+    lapic: LapicState,
+    #[cfg(target_arch = "x86_64")]
+    xsave: Xsave,
+    #[cfg(target_arch = "x86_64")]
+    xcrs: ExtendedControlRegisters,
+    #[cfg(target_arch = "x86_64")]
+    vcpu_events: VcpuEvents,
+    #[cfg(target_arch = "x86_64")]
+    msrs: HashMap<u32, u64>,
+    #[cfg(target_arch = "x86_64")]
+    cpuid: Option<CpuId>,
+    #[cfg(target_arch = "x86_64")]
+    guest_debug: Option<(Vec<GuestAddress>, bool)>,
+    #[cfg(target_arch = "aarch64")]
+    one_regs: HashMap<u64, u64>,
+}
+
+/// Mock vCPU, returning scripted VM exits and recording its registers.
+pub struct MockVcpu {
+    id: u8,
+    regs: Mutex<MockVcpuRegs>,
+    exits: Mutex<VecDeque<MockVmExit>>,
+}
+
+impl MockVcpu {
+    fn new(id: u8) -> MockVcpu {
+        MockVcpu {
+            id,
+            regs: Mutex::new(MockVcpuRegs::default()),
+            exits: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the id the vCPU was created with.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Queue VM exits to be returned by the next calls to `run()`. Once all
+    /// of them have been returned, `run()` returns `VmExit::Reset`.
+    pub fn push_exits(&self, exits: &[MockVmExit]) {
+        self.exits.lock().unwrap().extend(exits.iter().cloned());
+    }
+
+    /// Number of scripted VM exits not returned yet.
+    pub fn pending_exits(&self) -> usize {
+        self.exits.lock().unwrap().len()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the hardware breakpoints and single-stepping set through
+    /// `set_guest_debug`, if guest debugging is enabled.
+    pub fn guest_debug(&self) -> Option<(Vec<GuestAddress>, bool)> {
+        self.regs.lock().unwrap().guest_debug.clone()
+    }
+}
+
+impl cpu::Vcpu for MockVcpu {
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the vCPU general purpose registers.
+    ///
+    fn get_regs(&self) -> cpu::Result<StandardRegisters> {
+        Ok(self.regs.lock().unwrap().regs)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets the vCPU general purpose registers.
+    ///
+    fn set_regs(&self, regs: &StandardRegisters) -> cpu::Result<()> {
+        self.regs.lock().unwrap().regs = *regs;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the vCPU special registers.
+    ///
+    fn get_sregs(&self) -> cpu::Result<SpecialRegisters> {
+        Ok(self.regs.lock().unwrap().sregs)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets the vCPU special registers.
+    ///
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> cpu::Result<()> {
+        self.regs.lock().unwrap().sregs = *sregs;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the floating point state (FPU) from the vCPU.
+    ///
+    fn get_fpu(&self) -> cpu::Result<FpuState> {
+        Ok(self.regs.lock().unwrap().fpu)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Set the floating point state (FPU) of a vCPU.
+    ///
+    fn set_fpu(&self, fpu: &FpuState) -> cpu::Result<()> {
+        self.regs.lock().unwrap().fpu = *fpu;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Records the CPUID entries.
+    ///
+    fn set_cpuid2(&self, cpuid: &CpuId) -> cpu::Result<()> {
+        self.regs.lock().unwrap().cpuid = Some(cpuid.clone());
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the CPUID entries previously set.
+    ///
+    fn get_cpuid2(&self, num_entries: usize) -> cpu::Result<CpuId> {
+        match &self.regs.lock().unwrap().cpuid {
+            Some(cpuid) if cpuid.as_slice().len() <= num_entries => Ok(cpuid.clone()),
+            Some(_) => Err(cpu::HypervisorCpuError::GetCpuid(anyhow!(
+                "More than {} CPUID entries",
+                num_entries
+            ))),
+            None => Ok(CpuId::new(0)),
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the state of the LAPIC (Local Advanced Programmable Interrupt Controller).
+    ///
+    fn get_lapic(&self) -> cpu::Result<LapicState> {
+        Ok(self.regs.lock().unwrap().lapic)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets the state of the LAPIC (Local Advanced Programmable Interrupt Controller).
+    ///
+    fn set_lapic(&self, klapic: &LapicState) -> cpu::Result<()> {
+        self.regs.lock().unwrap().lapic = *klapic;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the model-specific registers (MSR) previously set. MSRs never
+    /// set read as zero.
+    ///
+    fn get_msrs(&self, msrs: &mut MsrEntries) -> cpu::Result<usize> {
+        let regs = self.regs.lock().unwrap();
+        for entry in msrs.as_mut_slice().iter_mut() {
+            entry.data = regs.msrs.get(&entry.index).copied().unwrap_or(0);
+        }
+        Ok(msrs.as_slice().len())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Setup the model-specific registers (MSR) for this vCPU.
+    ///
+    fn set_msrs(&self, msrs: &MsrEntries) -> cpu::Result<usize> {
+        let mut regs = self.regs.lock().unwrap();
+        for entry in msrs.as_slice().iter() {
+            regs.msrs.insert(entry.index, entry.data);
+        }
+        Ok(msrs.as_slice().len())
+    }
+    ///
+    /// Returns the vcpu's current "multiprocessing state".
+    ///
+    fn get_mp_state(&self) -> cpu::Result<MpState> {
+        Ok(self.regs.lock().unwrap().mp_state)
+    }
+    ///
+    /// Sets the vcpu's current "multiprocessing state".
+    ///
+    fn set_mp_state(&self, mp_state: MpState) -> cpu::Result<()> {
+        self.regs.lock().unwrap().mp_state = mp_state;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call that returns the vcpu's current "xsave struct".
+    ///
+    fn get_xsave(&self) -> cpu::Result<Xsave> {
+        Ok(self.regs.lock().unwrap().xsave)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call that sets the vcpu's current "xsave struct".
+    ///
+    fn set_xsave(&self, xsave: &Xsave) -> cpu::Result<()> {
+        self.regs.lock().unwrap().xsave = *xsave;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call that returns the vcpu's current "xcrs".
+    ///
+    fn get_xcrs(&self) -> cpu::Result<ExtendedControlRegisters> {
+        Ok(self.regs.lock().unwrap().xcrs)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call that sets the vcpu's current "xcrs".
+    ///
+    fn set_xcrs(&self, xcrs: &ExtendedControlRegisters) -> cpu::Result<()> {
+        self.regs.lock().unwrap().xcrs = *xcrs;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns currently pending exceptions, interrupts, and NMIs as well as related
+    /// states of the vcpu.
+    ///
+    fn get_vcpu_events(&self) -> cpu::Result<VcpuEvents> {
+        Ok(self.regs.lock().unwrap().vcpu_events)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets pending exceptions, interrupts, and NMIs as well as related states
+    /// of the vcpu.
+    ///
+    fn set_vcpu_events(&self, events: &VcpuEvents) -> cpu::Result<()> {
+        self.regs.lock().unwrap().vcpu_events = *events;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Nothing to notify, the mock guest has no clock.
+    ///
+    fn notify_guest_clock_paused(&self) -> cpu::Result<()> {
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Records the guest debugging configuration.
+    ///
+    fn set_guest_debug(
+        &self,
+        enable: bool,
+        hw_breakpoints: &[GuestAddress],
+        singlestep: bool,
+    ) -> cpu::Result<()> {
+        if hw_breakpoints.len() > 4 {
+            return Err(cpu::HypervisorCpuError::SetGuestDebug(anyhow!(
+                "Too many hardware breakpoints: {}",
+                hw_breakpoints.len()
+            )));
+        }
+        self.regs.lock().unwrap().guest_debug = if enable {
+            Some((hw_breakpoints.to_vec(), singlestep))
+        } else {
+            None
+        };
+        Ok(())
+    }
+    ///
+    /// Nothing to initialize, the mock vCPU accepts any CPU type.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn vcpu_init(&self, _kvi: &VcpuInit) -> cpu::Result<()> {
+        Ok(())
+    }
+    ///
+    /// Sets the value of one register for this vCPU.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn set_one_reg(&self, reg_id: u64, data: u64) -> cpu::Result<()> {
+        self.regs.lock().unwrap().one_regs.insert(reg_id, data);
+        Ok(())
+    }
+    ///
+    /// Gets the value of one register for this vCPU, zero if it was never set.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn get_one_reg(&self, reg_id: u64) -> cpu::Result<u64> {
+        Ok(self
+            .regs
+            .lock()
+            .unwrap()
+            .one_regs
+            .get(&reg_id)
+            .copied()
+            .unwrap_or(0))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the current CPU state, including all the MSRs previously set.
+    ///
+    fn state(&self) -> cpu::Result<CpuState> {
+        let regs = self.regs.lock().unwrap();
+        let mut msr_entries: Vec<(u32, u64)> = regs.msrs.iter().map(|(i, d)| (*i, *d)).collect();
+        msr_entries.sort();
+        let mut msrs = MsrEntries::new(msr_entries.len());
+        for (entry, (index, data)) in msrs.as_mut_slice().iter_mut().zip(msr_entries) {
+            entry.index = index;
+            entry.data = data;
+        }
+
+        Ok(CpuState {
+            msrs,
+            vcpu_events: regs.vcpu_events,
+            regs: regs.regs,
+            sregs: regs.sregs,
+            fpu: regs.fpu,
+            lapic_state: regs.lapic,
+            xsave: regs.xsave,
+            xcrs: regs.xcrs,
+            mp_state: regs.mp_state,
+        })
+    }
+    #[cfg(target_arch = "aarch64")]
+    fn state(&self) -> cpu::Result<CpuState> {
+        Ok(CpuState {})
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Restore the previously saved CPU state.
+    ///
+    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
+        self.set_mp_state(state.mp_state)?;
+        self.set_regs(&state.regs)?;
+        self.set_sregs(&state.sregs)?;
+        self.set_xsave(&state.xsave)?;
+        self.set_xcrs(&state.xcrs)?;
+        self.set_lapic(&state.lapic_state)?;
+        self.set_fpu(&state.fpu)?;
+        self.set_msrs(&state.msrs)?;
+        self.set_vcpu_events(&state.vcpu_events)?;
+
+        Ok(())
+    }
+    #[cfg(target_arch = "aarch64")]
+    fn set_state(&self, _state: &CpuState) -> cpu::Result<()> {
+        Ok(())
+    }
+    ///
+    /// Returns the next scripted VM exit, or `VmExit::Reset` once the script
+    /// is exhausted.
+    ///
+    fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
+        let exit = match self.exits.lock().unwrap().pop_front() {
+            Some(exit) => exit,
+            None => return Ok(cpu::VmExit::Reset),
+        };
+
+        match exit {
+            #[cfg(target_arch = "x86_64")]
+            MockVmExit::IoOut(port, out) => Ok(cpu::VmExit::IoOut(port, exit_data(out))),
+            #[cfg(target_arch = "x86_64")]
+            MockVmExit::IoIn(port, size) => Ok(cpu::VmExit::IoIn(port, exit_data(vec![0; size]))),
+            #[cfg(target_arch = "x86_64")]
+            MockVmExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
+            #[cfg(target_arch = "x86_64")]
+            MockVmExit::Debug => Ok(cpu::VmExit::Debug),
+            MockVmExit::MmioRead(addr, size) => {
+                Ok(cpu::VmExit::MmioRead(addr, exit_data(vec![0; size])))
+            }
+            MockVmExit::MmioWrite(addr, out) => Ok(cpu::VmExit::MmioWrite(addr, exit_data(out))),
+            MockVmExit::Ignore => Ok(cpu::VmExit::Ignore),
+            MockVmExit::Reset => Ok(cpu::VmExit::Reset),
+        }
+    }
+}
+
+// The data of a VM exit is borrowed for as long as the vCPU, as it would be
+// from the kvm_run structure. Rather than sharing a single buffer between
+// exits, each exit gets its own, which is leaked: scripts are short, and this
+// keeps the mock free of any aliasing.
+fn exit_data(data: Vec<u8>) -> &'static mut [u8] {
+    Box::leak(data.into_boxed_slice())
+}
+
+/// Mock device, backed by an EventFd to provide a file descriptor.
+pub struct MockDevice {
+    fd: EventFd,
+    attributes: Mutex<Vec<(u32, u64)>>,
+}
+
+impl MockDevice {
+    fn new() -> std::io::Result<MockDevice> {
+        Ok(MockDevice {
+            fd: EventFd::new(0)?,
+            attributes: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns the (group, attribute) pairs set on the device.
+    pub fn attributes(&self) -> Vec<(u32, u64)> {
+        self.attributes.lock().unwrap().clone()
+    }
+}
+
+impl device::Device for MockDevice {
+    ///
+    /// Records the device attribute.
+    ///
+    fn set_device_attr(&self, attr: &DeviceAttr) -> device::Result<()> {
+        self.attributes
+            .lock()
+            .unwrap()
+            .push((attr.group, attr.attr));
+        Ok(())
+    }
+}
+
+impl AsRawFd for MockDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{DataMatch, Vm};
+    use crate::{Hypervisor, VmExit};
+
+    #[test]
+    fn test_memory_regions() {
+        let vm = MockVm::new();
+
+        let region = vm.make_user_memory_region(0, 0, 0x1000, 0x7f00_0000_0000, false);
+        vm.set_user_memory_region(region).unwrap();
+        let region = vm.make_user_memory_region(1, 0x1000, 0x1000, 0x7f00_0000_1000, true);
+        vm.set_user_memory_region(region).unwrap();
+        assert_eq!(vm.memory_regions().len(), 2);
+
+        // Overlapping slots are refused.
+        let region = vm.make_user_memory_region(2, 0x1800, 0x1000, 0x7f00_0000_2000, false);
+        assert!(vm.set_user_memory_region(region).is_err());
+
+        // Dirty pages logging only applies to writable slots.
+        vm.start_dirty_log().unwrap();
+        assert!(vm.get_dirty_log(0, 0, 0x1000).is_ok());
+        assert!(vm.get_dirty_log(1, 0x1000, 0x1000).is_err());
+        vm.set_dirty_bitmap(0, vec![0x1]);
+        assert_eq!(vm.get_dirty_log(0, 0, 0x1000).unwrap(), vec![0x1]);
+        assert_eq!(vm.get_dirty_log(0, 0, 0x1000).unwrap(), vec![0x0]);
+        vm.stop_dirty_log().unwrap();
+        assert!(vm.get_dirty_log(0, 0, 0x1000).is_err());
+
+        // A zero sized region removes the slot.
+        let region = vm.make_user_memory_region(1, 0x1000, 0, 0x7f00_0000_1000, false);
+        vm.set_user_memory_region(region).unwrap();
+        let regions = vm.memory_regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].slot, 0);
+    }
+
+    #[test]
+    fn test_irqfds_and_ioevents() {
+        let vm = MockVm::new();
+        let irqfd = EventFd::new(0).unwrap();
+        let ioevent = EventFd::new(0).unwrap();
+
+        vm.register_irqfd(&irqfd, 5).unwrap();
+        assert!(vm.register_irqfd(&irqfd, 5).is_err());
+        assert_eq!(vm.irqfds(), vec![(irqfd.as_raw_fd(), 5)]);
+        vm.unregister_irqfd(&irqfd, 5).unwrap();
+        assert!(vm.unregister_irqfd(&irqfd, 5).is_err());
+        assert!(vm.irqfds().is_empty());
+
+        let addr = IoEventAddress::Mmio(0xd000_0000);
+        vm.register_ioevent(&ioevent, &addr, Some(DataMatch::DataMatch32(1)))
+            .unwrap();
+        assert_eq!(
+            vm.ioevents(),
+            vec![MockIoEvent {
+                pio: false,
+                addr: 0xd000_0000,
+                datamatch: Some(1),
+                fd: ioevent.as_raw_fd(),
+            }]
+        );
+        vm.unregister_ioevent(&ioevent, &addr).unwrap();
+        assert!(vm.unregister_ioevent(&ioevent, &addr).is_err());
+        assert!(vm.ioevents().is_empty());
+    }
+
+    #[test]
+    fn test_scripted_exits() {
+        let hv = MockHypervisor::new();
+        assert!(hv.create_vm().is_ok());
+
+        let vm = MockVm::new();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vm.create_vcpu(0).is_err());
+        let mock_vcpu = vm.vcpu(0).unwrap();
+        mock_vcpu.push_exits(&[
+            MockVmExit::MmioWrite(0x1000, vec![0xaa]),
+            MockVmExit::MmioRead(0x1008, 4),
+            MockVmExit::Ignore,
+        ]);
+
+        match vcpu.run().unwrap() {
+            VmExit::MmioWrite(addr, data) => {
+                assert_eq!(addr, 0x1000);
+                assert_eq!(data, &[0xaa]);
+            }
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        match vcpu.run().unwrap() {
+            VmExit::MmioRead(addr, data) => {
+                assert_eq!(addr, 0x1008);
+                data.copy_from_slice(&[1, 2, 3, 4]);
+            }
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        assert!(matches!(vcpu.run().unwrap(), VmExit::Ignore));
+        assert!(matches!(vcpu.run().unwrap(), VmExit::Reset));
+        assert_eq!(mock_vcpu.pending_exits(), 0);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_state() {
+        use crate::x86_64::MsrEntry;
+
+        let vm = MockVm::new();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rax = 2;
+        vcpu.set_regs(&regs).unwrap();
+        vcpu.set_msrs(&MsrEntries::from_entries(&[MsrEntry {
+            index: 0x10,
+            data: 0x1234,
+            ..Default::default()
+        }]))
+        .unwrap();
+
+        let state = vcpu.state().unwrap();
+        assert_eq!(state.regs.rip, 0x1000);
+        assert_eq!(state.msrs.as_slice().len(), 1);
+
+        let other = vm.create_vcpu(1).unwrap();
+        other.set_state(&state).unwrap();
+        assert_eq!(other.get_regs().unwrap().rax, 2);
+        let mut msrs = MsrEntries::from_entries(&[MsrEntry {
+            index: 0x10,
+            ..Default::default()
+        }]);
+        assert_eq!(other.get_msrs(&mut msrs).unwrap(), 1);
+        assert_eq!(msrs.as_slice()[0].data, 0x1234);
+    }
+}
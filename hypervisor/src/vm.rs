@@ -116,6 +116,21 @@ pub enum HypervisorVmError {
     ///
     #[error("Failed to create passthrough device: {0}")]
     CreatePassthroughDevice(#[source] anyhow::Error),
+    ///
+    /// Start dirty log error
+    ///
+    #[error("Failed to start dirty log: {0}")]
+    StartDirtyLog(#[source] anyhow::Error),
+    ///
+    /// Stop dirty log error
+    ///
+    #[error("Failed to stop dirty log: {0}")]
+    StopDirtyLog(#[source] anyhow::Error),
+    ///
+    /// Get dirty log error
+    ///
+    #[error("Failed to get dirty log: {0}")]
+    GetDirtyLog(#[source] anyhow::Error),
 }
 ///
 /// Result type for returning from a function
@@ -179,4 +194,10 @@ pub trait Vm: Send + Sync {
     fn check_extension(&self, c: Cap) -> bool;
     /// Create a device that is used for passthrough
     fn create_passthrough_device(&self) -> Result<Arc<dyn Device>>;
+    /// Start logging the pages the guest writes to, for all writable memory slots.
+    fn start_dirty_log(&self) -> Result<()>;
+    /// Stop logging the pages the guest writes to.
+    fn stop_dirty_log(&self) -> Result<()>;
+    /// Get and reset the dirty pages bitmap of a memory slot.
+    fn get_dirty_log(&self, slot: u32, base_gpa: u64, memory_size: u64) -> Result<Vec<u64>>;
 }
@@ -15,6 +15,7 @@ extern crate libc;
 extern crate log;
 extern crate net_gen;
 extern crate rand;
+extern crate rate_limiter;
 extern crate serde;
 extern crate virtio_bindings;
 extern crate vm_memory;
@@ -4,21 +4,39 @@
 
 use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
 use libc::EAGAIN;
+use rate_limiter::{RateLimiter, TokenType};
 use std::cmp;
 use std::io;
 use std::io::{Read, Write};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_virtio::{DescriptorChain, Queue};
+use vmm_sys_util::eventfd::EventFd;
 
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 
+// Consumes the tokens needed to transfer a frame: one operation, and the
+// bytes of the frame. Returns false if the frame must be deferred.
+fn consume_rate_limiter_tokens(rate_limiter: &mut RateLimiter, frame_len: u64) -> bool {
+    if !rate_limiter.consume(1, TokenType::Ops) {
+        return false;
+    }
+
+    if !rate_limiter.consume(frame_len, TokenType::Bytes) {
+        // The whole frame is deferred, give the operation back.
+        rate_limiter.manual_replenish(1, TokenType::Ops);
+        return false;
+    }
+
+    true
+}
+
 #[derive(Clone)]
 pub struct TxVirtio {
     pub iovec: Vec<(GuestAddress, usize)>,
@@ -43,7 +61,13 @@ impl TxVirtio {
         }
     }
 
-    pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
+    pub fn process_desc_chain(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut Tap,
+        queue: &mut Queue,
+        rate_limiter: &mut RateLimiter,
+    ) {
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
             let mut read_count = 0;
@@ -59,6 +83,12 @@ impl TxVirtio {
                 next_desc = desc.next_descriptor();
             }
 
+            if !consume_rate_limiter_tokens(rate_limiter, read_count as u64) {
+                // The frame is sent once the rate limiter is unblocked.
+                queue.go_to_previous_position();
+                break;
+            }
+
             read_count = 0;
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
@@ -217,6 +247,12 @@ pub struct NetQueuePair {
     pub rx_tap_listening: bool,
     pub counters: NetCounters,
     pub tap_event_id: u16,
+    pub rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    pub tx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    // Notified once the rate limiters, which may be shared with other queue
+    // pairs, are unblocked.
+    pub rx_rate_limiter_unblocked_evt: EventFd,
+    pub tx_rate_limiter_unblocked_evt: EventFd,
 }
 
 impl NetQueuePair {
@@ -233,23 +269,40 @@ impl NetQueuePair {
 
         if next_desc.is_none() {
             // Queue has no available descriptors
-            if self.rx_tap_listening {
-                unregister_listener(
-                    self.epoll_fd.unwrap(),
-                    self.tap.as_raw_fd(),
-                    epoll::Events::EPOLLIN,
-                    u64::from(self.tap_event_id),
-                )
-                .map_err(NetQueuePairError::UnregisterListener)?;
-                self.rx_tap_listening = false;
-                info!("Listener unregistered");
-            }
+            self.stop_rx_tap_listening()?;
+            return Ok(false);
+        }
+
+        if !consume_rate_limiter_tokens(
+            &mut self.rx_rate_limiter.lock().unwrap(),
+            self.rx.bytes_read as u64,
+        ) {
+            // Stop reading from the tap until the rate limiter is unblocked,
+            // the frame is received from resume_rx() at that time.
+            queue.go_to_previous_position();
+            self.stop_rx_tap_listening()?;
             return Ok(false);
         }
 
         Ok(self.rx.process_desc_chain(&mem, next_desc, &mut queue))
     }
 
+    fn stop_rx_tap_listening(&mut self) -> Result<(), NetQueuePairError> {
+        if self.rx_tap_listening {
+            unregister_listener(
+                self.epoll_fd.unwrap(),
+                self.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(self.tap_event_id),
+            )
+            .map_err(NetQueuePairError::UnregisterListener)?;
+            self.rx_tap_listening = false;
+            info!("Listener unregistered");
+        }
+
+        Ok(())
+    }
+
     fn process_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
         // Read as many frames as possible.
         loop {
@@ -341,7 +394,12 @@ impl NetQueuePair {
             .as_ref()
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
-        self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        self.tx.process_desc_chain(
+            &mem,
+            &mut self.tap,
+            &mut queue,
+            &mut self.tx_rate_limiter.lock().unwrap(),
+        );
 
         self.counters
             .tx_bytes
@@ -378,3 +436,108 @@ impl NetQueuePair {
         self.tap.read(&mut self.rx.frame_buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rate_limiter::{RateLimiterConfig, TokenBucketConfig};
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    const MEM_SIZE: usize = 0x100_0000;
+    const GUEST_QUEUE_SIZE: u16 = 16;
+    const FRAME_ADDR: u64 = 0x80_0000;
+    const FRAME_LEN: u32 = 64;
+
+    // Rate limiter whose buckets are only refilled after an hour.
+    fn rate_limiter(bytes: Option<u64>, ops: Option<u64>) -> RateLimiter {
+        let bucket = |size| TokenBucketConfig {
+            size,
+            one_time_burst: None,
+            refill_time: 3_600_000,
+        };
+        RateLimiter::new(&RateLimiterConfig {
+            bandwidth: bytes.map(bucket),
+            ops: ops.map(bucket),
+        })
+        .unwrap()
+    }
+
+    fn create_queue_pair(
+        mem: &GuestMemoryMmap,
+        rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+        tx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    ) -> NetQueuePair {
+        let rx_rate_limiter_unblocked_evt =
+            rx_rate_limiter.lock().unwrap().new_unblocked_evt().unwrap();
+        let tx_rate_limiter_unblocked_evt =
+            tx_rate_limiter.lock().unwrap().new_unblocked_evt().unwrap();
+
+        NetQueuePair {
+            mem: Some(GuestMemoryAtomic::new(mem.clone())),
+            tap: Tap::new(1).unwrap(),
+            rx: RxVirtio::new(),
+            tx: TxVirtio::new(),
+            epoll_fd: None,
+            rx_tap_listening: false,
+            counters: NetCounters::default(),
+            tap_event_id: 0,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            rx_rate_limiter_unblocked_evt,
+            tx_rate_limiter_unblocked_evt,
+        }
+    }
+
+    // Queues a single frame for the device to send.
+    fn queue_tx_frame(guest_queue: &GuestQ) {
+        guest_queue.dtable[0].set(FRAME_ADDR, FRAME_LEN, 0, 0);
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.idx.set(1);
+    }
+
+    #[test]
+    fn test_consume_rate_limiter_tokens() {
+        let mut rate_limiter = rate_limiter(Some(100), Some(2));
+
+        // The operation is given back when the frame doesn't fit in the
+        // bandwidth bucket.
+        assert!(!consume_rate_limiter_tokens(&mut rate_limiter, 200));
+        assert!(consume_rate_limiter_tokens(&mut rate_limiter, 50));
+        assert!(consume_rate_limiter_tokens(&mut rate_limiter, 50));
+
+        // Both buckets are empty now.
+        assert!(!consume_rate_limiter_tokens(&mut rate_limiter, 0));
+        assert!(rate_limiter.is_blocked());
+    }
+
+    #[test]
+    fn test_shared_tx_rate_limiter() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_queues = [
+            GuestQ::new(GuestAddress(0x10_0000), &mem, GUEST_QUEUE_SIZE),
+            GuestQ::new(GuestAddress(0x20_0000), &mem, GUEST_QUEUE_SIZE),
+        ];
+        let rx_rate_limiter = Arc::new(Mutex::new(rate_limiter(None, None)));
+        let tx_rate_limiter = Arc::new(Mutex::new(rate_limiter(None, Some(1))));
+        let mut queue_pairs = [
+            create_queue_pair(&mem, rx_rate_limiter.clone(), tx_rate_limiter.clone()),
+            create_queue_pair(&mem, rx_rate_limiter, tx_rate_limiter.clone()),
+        ];
+
+        for (queue_pair, guest_queue) in queue_pairs.iter_mut().zip(guest_queues.iter()) {
+            queue_tx_frame(guest_queue);
+            queue_pair
+                .process_tx(&mut guest_queue.create_queue())
+                .unwrap();
+        }
+
+        // The first queue pair used up the only frame allowed for the whole
+        // device, the frame of the second one is deferred.
+        assert_eq!(guest_queues[0].used.idx.get(), 1);
+        assert_eq!(guest_queues[1].used.idx.get(), 0);
+        assert!(tx_rate_limiter.lock().unwrap().is_blocked());
+        assert_eq!(queue_pairs[0].counters.tx_frames.load(Ordering::Acquire), 1);
+        assert_eq!(queue_pairs[1].counters.tx_frames.load(Ordering::Acquire), 0);
+    }
+}
@@ -37,6 +37,42 @@ impl fmt::Display for OptionParserError {
 }
 type OptionParserResult<T> = std::result::Result<T, OptionParserError>;
 
+// Split the options on commas, except for the ones found inside brackets,
+// which separate the elements of a list value.
+fn split_commas(s: &str) -> OptionParserResult<Vec<String>> {
+    let mut list = Vec::new();
+    let mut opened_brackets = 0;
+    let mut current = String::new();
+
+    for c in s.trim().chars() {
+        match c {
+            '[' => {
+                opened_brackets += 1;
+                current.push(c);
+            }
+            ']' => {
+                if opened_brackets == 0 {
+                    return Err(OptionParserError::InvalidSyntax(s.to_owned()));
+                }
+                opened_brackets -= 1;
+                current.push(c);
+            }
+            ',' if opened_brackets == 0 => {
+                list.push(current);
+                current = String::new();
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if opened_brackets != 0 {
+        return Err(OptionParserError::InvalidSyntax(s.to_owned()));
+    }
+    list.push(current);
+
+    Ok(list)
+}
+
 impl OptionParser {
     pub fn new() -> Self {
         Self {
@@ -49,17 +85,17 @@ impl OptionParser {
             return Ok(());
         }
 
-        let options_list: Vec<&str> = input.trim().split(',').collect();
+        let options_list = split_commas(input)?;
 
         for option in options_list.iter() {
-            let parts: Vec<&str> = option.split('=').collect();
+            let parts: Vec<&str> = option.splitn(2, '=').collect();
 
             match self.options.get_mut(parts[0]) {
                 None => return Err(OptionParserError::UnknownOption(parts[0].to_owned())),
                 Some(value) => {
                     if value.requires_value {
                         if parts.len() != 2 {
-                            return Err(OptionParserError::InvalidSyntax((*option).to_owned()));
+                            return Err(OptionParserError::InvalidSyntax(option.to_owned()));
                         }
                         value.value = Some(parts[1].trim().to_owned());
                     } else {
@@ -170,3 +206,169 @@ impl FromStr for ByteSized {
         }))
     }
 }
+
+pub struct StringList(pub Vec<String>);
+
+pub enum StringListParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for StringList {
+    type Err = StringListParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.starts_with('[') || !s.ends_with(']') {
+            return Err(StringListParseError::InvalidValue(s.to_owned()));
+        }
+
+        let s = s[1..s.len() - 1].trim();
+        if s.is_empty() {
+            return Ok(StringList(Vec::new()));
+        }
+
+        Ok(StringList(
+            s.split(',').map(|e| e.trim().to_owned()).collect(),
+        ))
+    }
+}
+
+pub struct IntegerList(pub Vec<u64>);
+
+pub enum IntegerListParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for IntegerList {
+    type Err = IntegerListParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut integer_list = Vec::new();
+        let ranges_list: Vec<&str> = s
+            .trim()
+            .trim_matches(|c| c == '[' || c == ']')
+            .split(',')
+            .collect();
+
+        for range in ranges_list.iter() {
+            let items: Vec<&str> = range.split('-').collect();
+
+            if items.len() > 2 {
+                return Err(IntegerListParseError::InvalidValue(range.to_string()));
+            }
+
+            let start_range = items[0]
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| IntegerListParseError::InvalidValue(items[0].to_owned()))?;
+
+            integer_list.push(start_range);
+
+            if items.len() == 2 {
+                let end_range = items[1]
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| IntegerListParseError::InvalidValue(items[1].to_owned()))?;
+                if start_range >= end_range {
+                    return Err(IntegerListParseError::InvalidValue(range.to_string()));
+                }
+
+                for i in start_range..end_range {
+                    integer_list.push(i + 1);
+                }
+            }
+        }
+
+        Ok(IntegerList(integer_list))
+    }
+}
+
+pub struct Tuple<S, T>(pub Vec<(S, T)>);
+
+pub enum TupleError {
+    InvalidValue(String),
+}
+
+impl<S: FromStr, T: FromStr> FromStr for Tuple<S, T> {
+    type Err = TupleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut list: Vec<(S, T)> = Vec::new();
+
+        let s = s.trim();
+        if !s.starts_with('[') || !s.ends_with(']') {
+            return Err(TupleError::InvalidValue(s.to_owned()));
+        }
+
+        let tuples_list =
+            split_commas(&s[1..s.len() - 1]).map_err(|_| TupleError::InvalidValue(s.to_owned()))?;
+        for tuple in tuples_list.iter() {
+            let items: Vec<&str> = tuple.splitn(2, '@').collect();
+
+            if items.len() != 2 {
+                return Err(TupleError::InvalidValue(tuple.to_owned()));
+            }
+
+            let item1 = items[0]
+                .trim()
+                .parse::<S>()
+                .map_err(|_| TupleError::InvalidValue(items[0].to_owned()))?;
+            let item2 = items[1]
+                .trim()
+                .parse::<T>()
+                .map_err(|_| TupleError::InvalidValue(items[1].to_owned()))?;
+
+            list.push((item1, item2));
+        }
+
+        Ok(Tuple(list))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_affinity(value: &str) -> OptionParserResult<Option<Tuple<u8, IntegerList>>> {
+        let mut parser = OptionParser::new();
+        parser.add("boot").add("affinity");
+        parser.parse(&format!("boot=2,affinity={}", value))?;
+        parser.convert::<Tuple<u8, IntegerList>>("affinity")
+    }
+
+    #[test]
+    fn test_integer_list() {
+        let list: IntegerList = "[0,2-4,7]".parse().ok().unwrap();
+        assert_eq!(list.0, vec![0, 2, 3, 4, 7]);
+        let list: IntegerList = " 3 ".parse().ok().unwrap();
+        assert_eq!(list.0, vec![3]);
+
+        // Ranges must be increasing and made of two integers.
+        assert!("[4-2]".parse::<IntegerList>().is_err());
+        assert!("[2-2]".parse::<IntegerList>().is_err());
+        assert!("[1-2-3]".parse::<IntegerList>().is_err());
+        assert!("[1-]".parse::<IntegerList>().is_err());
+        assert!("[a]".parse::<IntegerList>().is_err());
+        assert!("[1,,2]".parse::<IntegerList>().is_err());
+        assert!("[]".parse::<IntegerList>().is_err());
+    }
+
+    #[test]
+    fn test_tuple() {
+        let affinity = parse_affinity("[0@[0-1],1@[2,4]]").unwrap().unwrap();
+        let affinity: Vec<(u8, Vec<u64>)> = affinity.0.into_iter().map(|(v, h)| (v, h.0)).collect();
+        assert_eq!(affinity, vec![(0, vec![0, 1]), (1, vec![2, 4])]);
+        assert!(parse_affinity("[]").is_err());
+
+        // Lists must be within brackets and brackets must be balanced.
+        assert!(parse_affinity("0@[1]").is_err());
+        assert!(parse_affinity("[0@[1]").is_err());
+        assert!(parse_affinity("[0@1]]").is_err());
+        // Each element needs both values, of the expected types.
+        assert!(parse_affinity("[0]").is_err());
+        assert!(parse_affinity("[0@]").is_err());
+        assert!(parse_affinity("[@[1]]").is_err());
+        assert!(parse_affinity("[256@[1]]").is_err());
+        assert!(parse_affinity("[0@[1-0]]").is_err());
+    }
+}
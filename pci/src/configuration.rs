@@ -884,6 +884,8 @@ impl PciConfiguration {
 
 impl Pausable for PciConfiguration {}
 
+const PCI_CONFIGURATION_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for PciConfiguration {
     fn id(&self) -> String {
         String::from("pci_configuration")
@@ -896,6 +898,7 @@ impl Snapshottable for PciConfiguration {
         let mut config_snapshot = Snapshot::new(self.id().as_str());
         config_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id()),
+            version: PCI_CONFIGURATION_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -907,6 +910,8 @@ impl Snapshottable for PciConfiguration {
             .snapshot_data
             .get(&format!("{}-section", self.id()))
         {
+            config_section.check_version(PCI_CONFIGURATION_SNAPSHOT_VERSION)?;
+
             let config_state = match serde_json::from_slice(&config_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
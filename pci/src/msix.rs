@@ -432,6 +432,8 @@ impl MsixConfig {
 
 impl Pausable for MsixConfig {}
 
+const MSIX_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for MsixConfig {
     fn id(&self) -> String {
         String::from("msix_config")
@@ -444,6 +446,7 @@ impl Snapshottable for MsixConfig {
         let mut msix_snapshot = Snapshot::new(self.id().as_str());
         msix_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id()),
+            version: MSIX_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -455,6 +458,8 @@ impl Snapshottable for MsixConfig {
             .snapshot_data
             .get(&format!("{}-section", self.id()))
         {
+            msix_section.check_version(MSIX_SNAPSHOT_VERSION)?;
+
             let msix_state = match serde_json::from_slice(&msix_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
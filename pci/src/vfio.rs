@@ -26,8 +26,8 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
 use vm_memory::{
-    Address, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap, GuestRegionMmap,
-    GuestUsize,
+    Address, Error as MmapError, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryMmap, GuestRegionMmap, GuestUsize,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -42,6 +42,7 @@ pub enum VfioPciError {
     SetGsiRouting(hypervisor::HypervisorVmError),
     MsiNotConfigured,
     MsixNotConfigured,
+    RemoveMemoryRegion(MmapError),
     UpdateMemory(VfioError),
     UpdateMsiEventFd,
     UpdateMsixEventFd,
@@ -64,6 +65,9 @@ impl fmt::Display for VfioPciError {
             VfioPciError::SetGsiRouting(e) => write!(f, "failed to set GSI routes for KVM: {}", e),
             VfioPciError::MsiNotConfigured => write!(f, "MSI interrupt not yet configured"),
             VfioPciError::MsixNotConfigured => write!(f, "MSI-X interrupt not yet configured"),
+            VfioPciError::RemoveMemoryRegion(e) => {
+                write!(f, "failed to describe the removed memory region: {}", e)
+            }
             VfioPciError::UpdateMemory(e) => write!(f, "failed to update memory: {}", e),
             VfioPciError::UpdateMsiEventFd => write!(f, "failed to update MSI eventfd"),
             VfioPciError::UpdateMsixEventFd => write!(f, "failed to update MSI-X eventfd"),
@@ -620,6 +624,14 @@ impl VfioPciDevice {
             .extend_dma_map(new_region)
             .map_err(VfioPciError::UpdateMemory)
     }
+
+    pub fn remove_memory(&self, old_region: &Arc<GuestRegionMmap>) -> Result<()> {
+        let old_mem = GuestMemoryMmap::from_arc_regions(vec![Arc::clone(old_region)])
+            .map_err(VfioPciError::RemoveMemoryRegion)?;
+        self.device
+            .unset_dma_map(&old_mem)
+            .map_err(VfioPciError::UpdateMemory)
+    }
 }
 
 impl Drop for VfioPciDevice {
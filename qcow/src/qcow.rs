@@ -18,8 +18,12 @@ use libc::{EINVAL, ENOSPC, ENOTSUP};
 use remain::sorted;
 use std::cmp::{max, min};
 use std::fmt::{self, Display};
+use std::fs::{self, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str;
 use vmm_sys_util::{
     file_traits::FileSetLen, file_traits::FileSync, seek_hole::SeekHole, write_zeroes::PunchHole,
     write_zeroes::WriteZeroes,
@@ -30,12 +34,13 @@ pub use crate::raw_file::RawFile;
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
-    BackingFilesNotSupported,
+    BackingFileTooLong(usize),
     CompressedBlocksNotSupported,
     EvictingCache(io::Error),
     FileTooBig(u64),
     GettingFileSize(io::Error),
     GettingRefcount(refcount::Error),
+    InvalidBackingFileName(str::Utf8Error),
     InvalidClusterIndex,
     InvalidClusterSize,
     InvalidIndex,
@@ -45,9 +50,11 @@ pub enum Error {
     InvalidOffset(u64),
     InvalidRefcountTableOffset,
     InvalidRefcountTableSize(u64),
+    MaxNestingDepthExceeded,
     NoFreeClusters,
     NoRefcountClusters,
     NotEnoughSpaceForRefcounts,
+    OpeningBackingFile(io::Error),
     OpeningFile(io::Error),
     ReadingData(io::Error),
     ReadingHeader(io::Error),
@@ -63,6 +70,7 @@ pub enum Error {
     SizeTooSmallForNumberOfClusters,
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    UnsupportedBackingFileFormat(String),
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
     WritingData(io::Error),
@@ -78,7 +86,7 @@ impl Display for Error {
 
         #[sorted]
         match self {
-            BackingFilesNotSupported => write!(f, "backing files not supported"),
+            BackingFileTooLong(len) => write!(f, "backing file name is too long: {}", len),
             CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
             FileTooBig(size) => write!(
@@ -88,6 +96,7 @@ impl Display for Error {
             ),
             GettingFileSize(e) => write!(f, "failed to get file size: {}", e),
             GettingRefcount(e) => write!(f, "failed to get refcount: {}", e),
+            InvalidBackingFileName(e) => write!(f, "backing file name is invalid: {}", e),
             InvalidClusterIndex => write!(f, "invalid cluster index"),
             InvalidClusterSize => write!(f, "invalid cluster size"),
             InvalidIndex => write!(f, "invalid index"),
@@ -97,9 +106,11 @@ impl Display for Error {
             InvalidOffset(_) => write!(f, "invalid offset"),
             InvalidRefcountTableOffset => write!(f, "invalid refcount table offset"),
             InvalidRefcountTableSize(size) => write!(f, "invalid refcount table size: {}", size),
+            MaxNestingDepthExceeded => write!(f, "max backing file nesting depth exceeded"),
             NoFreeClusters => write!(f, "no free clusters"),
             NoRefcountClusters => write!(f, "no refcount clusters"),
             NotEnoughSpaceForRefcounts => write!(f, "not enough space for refcounts"),
+            OpeningBackingFile(e) => write!(f, "failed to open backing file: {}", e),
             OpeningFile(e) => write!(f, "failed to open file: {}", e),
             ReadingData(e) => write!(f, "failed to read data: {}", e),
             ReadingHeader(e) => write!(f, "failed to read header: {}", e),
@@ -115,6 +126,9 @@ impl Display for Error {
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            UnsupportedBackingFileFormat(format) => {
+                write!(f, "unsupported backing file format: {}", format)
+            }
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
             WritingData(e) => write!(f, "failed to write data: {}", e),
@@ -123,6 +137,7 @@ impl Display for Error {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ImageType {
     Raw,
     Qcow2,
@@ -145,16 +160,27 @@ const MAX_CLUSTER_BITS: u32 = 21;
 const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
 // Only support 2 byte refcounts, 2^refcount_order bits.
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+// Same limit on the length of the backing file name as qemu.
+const MAX_BACKING_FILE_NAME_SIZE: usize = 1023;
+// Limit the length of backing file chains, which also catches images backed by themselves.
+const MAX_BACKING_FILE_NESTING_DEPTH: u32 = 10;
 
 const V2_BARE_HEADER_SIZE: u32 = 72;
 const V3_BARE_HEADER_SIZE: u32 = 104;
 
+// Header extension recording the format of the backing file.
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+// Header extension type marking the end of the header extensions.
+const HEADER_EXT_END: u32 = 0;
+
 // bits 0-8 and 56-63 are reserved.
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 // Flags
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
+// The cluster reads as zeros, whether storage is allocated for it or not. Only valid in v3 images.
+const ZERO_FLAG: u64 = 1;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1;
 
 /// Contains the information from the header of a qcow file.
@@ -360,6 +386,183 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+// Reads the name of the backing file stored in the header cluster of `file`.
+fn read_backing_file_name(file: &mut RawFile, header: &QcowHeader) -> Result<String> {
+    let mut name = vec![0u8; header.backing_file_size as usize];
+    file.seek(SeekFrom::Start(header.backing_file_offset))
+        .map_err(Error::ReadingHeader)?;
+    file.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+    String::from_utf8(name).map_err(|e| Error::InvalidBackingFileName(e.utf8_error()))
+}
+
+// Gets the name of the backing file `format` in the backing format header extension.
+fn backing_file_format_name(format: ImageType) -> &'static str {
+    match format {
+        ImageType::Raw => "raw",
+        ImageType::Qcow2 => "qcow2",
+    }
+}
+
+// Reads the format of the backing file from the header extensions of `file`, returning None if it
+// isn't recorded.
+fn read_backing_file_format(
+    file: &mut RawFile,
+    header: &QcowHeader,
+    cluster_size: u64,
+) -> Result<Option<ImageType>> {
+    // The header extensions follow the header in the header cluster, each of them made of its
+    // type, the length of its data and the data padded to a multiple of 8 bytes.
+    let mut offset = u64::from(header.header_size);
+    loop {
+        if offset + 8 > cluster_size {
+            return Err(Error::InvalidOffset(offset));
+        }
+        file.seek(SeekFrom::Start(offset))
+            .map_err(Error::ReadingHeader)?;
+        let ext_type = file.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+        if ext_type == HEADER_EXT_END {
+            return Ok(None);
+        }
+        let ext_length = file.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+        let ext_end = offset + 8 + u64::from(ext_length);
+        if ext_end > cluster_size {
+            return Err(Error::InvalidOffset(offset));
+        }
+
+        if ext_type == HEADER_EXT_BACKING_FORMAT {
+            let mut name = vec![0u8; ext_length as usize];
+            file.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+            return [ImageType::Raw, ImageType::Qcow2]
+                .iter()
+                .find(|format| backing_file_format_name(**format).as_bytes() == name.as_slice())
+                .map(|format| Some(*format))
+                .ok_or_else(|| {
+                    Error::UnsupportedBackingFileFormat(String::from_utf8_lossy(&name).into_owned())
+                });
+        }
+
+        offset = div_round_up_u64(ext_end, 8) * 8;
+    }
+}
+
+// Builds the header extensions recording the backing file `format`, followed by the end of the
+// header extensions.
+fn backing_file_format_extensions(format: ImageType) -> Vec<u8> {
+    let name = backing_file_format_name(format).as_bytes();
+    let mut extensions = Vec::new();
+    extensions.extend_from_slice(&HEADER_EXT_BACKING_FORMAT.to_be_bytes());
+    extensions.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    extensions.extend_from_slice(name);
+    extensions.resize(div_round_up_u64(extensions.len() as u64, 8) as usize * 8, 0);
+    extensions.extend_from_slice(&HEADER_EXT_END.to_be_bytes());
+    extensions.extend_from_slice(&0u32.to_be_bytes());
+    extensions
+}
+
+// Resolves the backing file `name` of the image `file`. As with qemu, a relative name is relative
+// to the directory holding the image rather than to the current directory.
+fn backing_file_path(file: &RawFile, name: &str) -> PathBuf {
+    let path = Path::new(name);
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .ok()
+        .and_then(|image_path| image_path.parent().map(|dir| dir.join(path)))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+// Reads `buf.len()` bytes at `address` from `file`, returning zeros past the `size` of `file`.
+fn read_exact_at<F: Read + Seek>(
+    file: &mut F,
+    size: u64,
+    address: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let count = if address < size {
+        min(buf.len() as u64, size - address) as usize
+    } else {
+        0
+    };
+    if count > 0 {
+        file.seek(SeekFrom::Start(address))?;
+        file.read_exact(&mut buf[..count])?;
+    }
+    for b in &mut buf[count..] {
+        *b = 0;
+    }
+    Ok(())
+}
+
+/// Image providing the content of the clusters a qcow2 file hasn't allocated. Backing files are
+/// only ever read, the clusters being copied to the qcow2 file on their first write.
+#[derive(Clone, Debug)]
+enum BackingFile {
+    Raw(RawFile),
+    Qcow(Box<QcowFile>),
+}
+
+impl BackingFile {
+    // Opens the image at `path` read-only, along with up to `max_nesting_depth` backing files it
+    // may itself rely on. The `format` of the image is probed if it isn't known.
+    fn open(path: &Path, format: Option<ImageType>, max_nesting_depth: u32) -> Result<BackingFile> {
+        if max_nesting_depth == 0 {
+            return Err(Error::MaxNestingDepthExceeded);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::OpeningBackingFile)?;
+        let mut raw_file = RawFile::new(file, false);
+        let format = match format {
+            Some(format) => format,
+            None => detect_image_type(&mut raw_file)?,
+        };
+        match format {
+            ImageType::Raw => Ok(BackingFile::Raw(raw_file)),
+            ImageType::Qcow2 => Ok(BackingFile::Qcow(Box::new(
+                QcowFile::from_with_nesting_depth(raw_file, max_nesting_depth - 1)?,
+            ))),
+        }
+    }
+
+    // Gets the format of the backing file.
+    fn format(&self) -> ImageType {
+        match self {
+            BackingFile::Raw(_) => ImageType::Raw,
+            BackingFile::Qcow(_) => ImageType::Qcow2,
+        }
+    }
+
+    // Gets the size of the data the backing file provides.
+    fn size(&self) -> io::Result<u64> {
+        match self {
+            BackingFile::Raw(file) => Ok(file.metadata()?.len()),
+            BackingFile::Qcow(qcow) => Ok(qcow.virtual_size()),
+        }
+    }
+
+    // Reads `buf.len()` bytes at `address`, reading zeros past the end of the backing file.
+    fn read_at(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        let size = self.size()?;
+        match self {
+            BackingFile::Raw(file) => read_exact_at(file, size, address, buf),
+            BackingFile::Qcow(qcow) => read_exact_at(qcow.as_mut(), size, address, buf),
+        }
+    }
+
+    // Returns true if the backing file holds data in the `length` bytes starting at `address`.
+    fn has_data(&mut self, address: u64, length: u64) -> io::Result<bool> {
+        let next_data = match self {
+            BackingFile::Raw(file) => file.seek_data(address)?,
+            BackingFile::Qcow(qcow) => qcow.seek_data(address)?,
+        };
+        Ok(next_data.map_or(false, |offset| offset < address + length))
+    }
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -390,12 +593,19 @@ pub struct QcowFile {
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
-    //TODO(dgreid) Add support for backing files. - backing_file: Option<Box<QcowFile<T>>>,
+    backing_file: Option<BackingFile>,
 }
 
 impl QcowFile {
-    /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: RawFile) -> Result<QcowFile> {
+    /// Creates a QcowFile from `file`. File must be a valid qcow2 image. Its backing file, if any,
+    /// is opened read-only, a relative backing file name being resolved from the directory holding
+    /// `file`. The format of the backing file is only probed if `file` doesn't record it.
+    pub fn from(file: RawFile) -> Result<QcowFile> {
+        Self::from_with_nesting_depth(file, MAX_BACKING_FILE_NESTING_DEPTH)
+    }
+
+    // Creates a QcowFile from `file`, allowing up to `max_nesting_depth` backing files behind it.
+    fn from_with_nesting_depth(mut file: RawFile, max_nesting_depth: u32) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v2 and v3 files are supported.
@@ -419,9 +629,18 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
-        // No current support for backing files.
+        // The backing file name must fit in the header cluster.
         if header.backing_file_offset != 0 {
-            return Err(Error::BackingFilesNotSupported);
+            if header.backing_file_size as usize > MAX_BACKING_FILE_NAME_SIZE {
+                return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
+            }
+            let backing_file_end = header
+                .backing_file_offset
+                .checked_add(u64::from(header.backing_file_size))
+                .ok_or(Error::InvalidOffset(header.backing_file_offset))?;
+            if backing_file_end > cluster_size {
+                return Err(Error::InvalidOffset(header.backing_file_offset));
+            }
         }
 
         // Only support two byte refcounts.
@@ -437,7 +656,6 @@ impl QcowFile {
         if header.refcount_table_clusters == 0 {
             return Err(Error::NoRefcountClusters);
         }
-        offset_is_cluster_boundary(header.backing_file_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.l1_table_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.snapshots_offset, header.cluster_bits)?;
         // refcount table must be a cluster boundary, and within the file's virtual or actual size.
@@ -467,6 +685,15 @@ impl QcowFile {
             refcount_rebuild_required = true;
         }
 
+        let backing_file = if header.backing_file_offset != 0 {
+            let name = read_backing_file_name(&mut file, &header)?;
+            let format = read_backing_file_format(&mut file, &header, cluster_size)?;
+            let path = backing_file_path(&file, &name);
+            Some(BackingFile::open(&path, format, max_nesting_depth)?)
+        } else {
+            None
+        };
+
         let mut raw_file =
             QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
         if refcount_rebuild_required {
@@ -526,6 +753,7 @@ impl QcowFile {
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
+            backing_file,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -544,10 +772,60 @@ impl QcowFile {
     }
 
     /// Creates a new QcowFile at the given path.
-    pub fn new(mut file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
+    pub fn new(file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
+        let header = QcowHeader::create_for_size(version, virtual_size);
+        Self::new_from_header(file, header, None)
+    }
+
+    /// Creates a new QcowFile reading through to the image `backing_file_name`, with the same
+    /// virtual size. A relative name is resolved from the directory holding `file`. The format of
+    /// the backing file is probed and recorded in the new image.
+    pub fn new_from_backing(
+        file: RawFile,
+        version: u32,
+        backing_file_name: &str,
+    ) -> Result<QcowFile> {
+        if backing_file_name.len() > MAX_BACKING_FILE_NAME_SIZE {
+            return Err(Error::BackingFileTooLong(backing_file_name.len()));
+        }
+        let path = backing_file_path(&file, backing_file_name);
+        let backing_file = BackingFile::open(&path, None, MAX_BACKING_FILE_NESTING_DEPTH)?;
+        let virtual_size = backing_file.size().map_err(Error::GettingFileSize)?;
+
         let header = QcowHeader::create_for_size(version, virtual_size);
+        Self::new_from_header(
+            file,
+            header,
+            Some((backing_file_name, backing_file.format())),
+        )
+    }
+
+    // Creates a new QcowFile with the given `header`, backed by the image named in `backing_file`
+    // along with its format.
+    fn new_from_header(
+        mut file: RawFile,
+        mut header: QcowHeader,
+        backing_file: Option<(&str, ImageType)>,
+    ) -> Result<QcowFile> {
+        // The backing file name follows the header extensions recording its format.
+        let mut header_extensions = Vec::new();
+        if let Some((name, format)) = backing_file {
+            header_extensions = backing_file_format_extensions(format);
+            header.backing_file_offset =
+                u64::from(header.header_size) + header_extensions.len() as u64;
+            header.backing_file_size = name.len() as u32;
+        }
+
         file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
+        if let Some((name, _)) = backing_file {
+            file.seek(SeekFrom::Start(u64::from(header.header_size)))
+                .map_err(Error::SeekingFile)?;
+            file.write_all(&header_extensions)
+                .map_err(Error::WritingHeader)?;
+            file.write_all(name.as_bytes())
+                .map_err(Error::WritingHeader)?;
+        }
 
         let mut qcow = Self::from(file)?;
 
@@ -579,7 +857,8 @@ impl QcowFile {
         &self.l1_table.get_values()
     }
 
-    /// Returns an L2_table of cluster addresses, only used for debugging.
+    /// Returns an L2_table of cluster addresses, along with their zero flag, only used for
+    /// debugging.
     pub fn l2_table(&mut self, l1_index: usize) -> Result<Option<&[u64]>> {
         let l2_addr_disk = *self.l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
 
@@ -980,7 +1259,7 @@ impl QcowFile {
         };
 
         let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
+        if cluster_addr == 0 || cluster_addr & ZERO_FLAG != 0 {
             return Ok(None);
         }
         Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
@@ -994,13 +1273,63 @@ impl QcowFile {
         }
 
         let l1_index = self.l1_table_index(address) as usize;
+        let l2_index = self.l2_table_index(address) as usize;
+
+        let mut set_refcounts = Vec::new();
+        self.cache_l2_table_for_write(l1_index, &mut set_refcounts)?;
+
+        let cluster_addr = match self.l2_cache.get(l1_index).unwrap()[l2_index] {
+            0 => {
+                // Need to allocate a data cluster
+                let cluster_addr = self.append_data_cluster()?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                if let Some(backing_file) = self.backing_file.as_mut() {
+                    // Copy the cluster from the backing file so that the parts of it not being
+                    // written keep reading the same data.
+                    let mut data = vec![0u8; self.raw_file.cluster_size() as usize];
+                    let backing_addr = address - self.raw_file.cluster_offset(address);
+                    backing_file.read_at(backing_addr, &mut data)?;
+                    self.raw_file
+                        .file_mut()
+                        .seek(SeekFrom::Start(cluster_addr))?;
+                    self.raw_file.file_mut().write_all(&data)?;
+                }
+                cluster_addr
+            }
+            a if a & ZERO_FLAG != 0 => {
+                // The cluster reads as zeros rather than through to the backing file, so its
+                // storage only needs zeroing before the zero flag is cleared.
+                let cluster_addr = match a & !ZERO_FLAG {
+                    0 => self.append_data_cluster()?,
+                    a => {
+                        self.raw_file.zero_cluster(a)?;
+                        a
+                    }
+                };
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                cluster_addr
+            }
+            a => a,
+        };
+
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+    }
+
+    // Makes sure the L2 table at `l1_index` is cached, allocating it if needed.
+    fn cache_l2_table_for_write(
+        &mut self,
+        l1_index: usize,
+        set_refcounts: &mut Vec<(u64, u16)>,
+    ) -> std::io::Result<()> {
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
             .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
-        let l2_index = self.l2_table_index(address) as usize;
-
-        let mut set_refcounts = Vec::new();
 
         if !self.l2_cache.contains_key(l1_index) {
             // Not in the cache.
@@ -1026,22 +1355,7 @@ impl QcowFile {
             })?;
         }
 
-        let cluster_addr = match self.l2_cache.get(l1_index).unwrap()[l2_index] {
-            0 => {
-                // Need to allocate a data cluster
-                let cluster_addr = self.append_data_cluster()?;
-                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
-                cluster_addr
-            }
-            a => a,
-        };
-
-        for (addr, count) in set_refcounts {
-            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
-            self.unref_clusters.append(&mut newly_unref);
-        }
-
-        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+        Ok(())
     }
 
     // Updates the l1 and l2 tables to point to the new `cluster_addr`.
@@ -1104,8 +1418,8 @@ impl QcowFile {
         Ok(new_addr)
     }
 
-    // Returns true if the cluster containing `address` is already allocated.
-    fn cluster_allocated(&mut self, address: u64) -> std::io::Result<bool> {
+    // Gets the L2 entry of the cluster containing `address`, 0 if its L2 table isn't allocated.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1120,7 +1434,7 @@ impl QcowFile {
         if l2_addr_disk == 0 {
             // The whole L2 table for this address is not allocated yet,
             // so the cluster must also be unallocated.
-            return Ok(false);
+            return Ok(0);
         }
 
         if !self.l2_cache.contains_key(l1_index) {
@@ -1138,13 +1452,28 @@ impl QcowFile {
             })?;
         }
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        // If cluster_addr != 0, the cluster is allocated.
-        Ok(cluster_addr != 0)
+        Ok(self.l2_cache.get(l1_index).unwrap()[l2_index])
+    }
+
+    // Returns true if the cluster containing `address` holds data, either allocated in this file or
+    // read through from the backing file. Clusters with the zero flag hold no data.
+    fn cluster_has_data(&mut self, address: u64) -> std::io::Result<bool> {
+        match self.l2_entry(address)? {
+            0 => {}
+            l2_entry => return Ok(l2_entry & ZERO_FLAG == 0),
+        }
+
+        match self.backing_file.as_mut() {
+            Some(backing_file) => {
+                let cluster_addr = address - self.raw_file.cluster_offset(address);
+                backing_file.has_data(cluster_addr, self.raw_file.cluster_size())
+            }
+            None => Ok(false),
+        }
     }
 
     // Find the first guest address greater than or equal to `address` whose allocation state
-    // matches `allocated`.
+    // matches `allocated`. Clusters holding data in the backing file count as allocated.
     fn find_allocated_cluster(
         &mut self,
         address: u64,
@@ -1156,7 +1485,7 @@ impl QcowFile {
         }
 
         // If offset is already within a hole, return it.
-        if self.cluster_allocated(address)? == allocated {
+        if self.cluster_has_data(address)? == allocated {
             return Ok(Some(address));
         }
 
@@ -1166,7 +1495,7 @@ impl QcowFile {
 
         // Search for clusters with the desired allocation state.
         while cluster_addr < size {
-            if self.cluster_allocated(cluster_addr)? == allocated {
+            if self.cluster_has_data(cluster_addr)? == allocated {
                 return Ok(Some(cluster_addr));
             }
             cluster_addr += cluster_size;
@@ -1210,7 +1539,7 @@ impl QcowFile {
             })?;
         }
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
+        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index] & !ZERO_FLAG;
         if cluster_addr == 0 {
             // This cluster is already unallocated; nothing to do.
             return Ok(());
@@ -1252,6 +1581,26 @@ impl QcowFile {
         Ok(())
     }
 
+    // Deallocate the storage for the cluster starting at `address`, and set its zero flag so that
+    // it doesn't read through to the backing file. Any future reads of this cluster will return
+    // all zeroes.
+    fn zero_cluster(&mut self, address: u64) -> std::io::Result<()> {
+        self.deallocate_cluster(address)?;
+
+        let l1_index = self.l1_table_index(address) as usize;
+        let l2_index = self.l2_table_index(address) as usize;
+
+        let mut set_refcounts = Vec::new();
+        self.cache_l2_table_for_write(l1_index, &mut set_refcounts)?;
+        self.update_cluster_addr(l1_index, l2_index, ZERO_FLAG, &mut set_refcounts)?;
+
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        Ok(())
+    }
+
     // Deallocate the storage for `length` bytes starting at `address`.
     // Any future reads of this range will return all zeroes.
     fn deallocate_bytes(&mut self, address: u64, length: usize) -> std::io::Result<()> {
@@ -1262,18 +1611,21 @@ impl QcowFile {
             let curr_addr = address + nwritten as u64;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            if count == self.raw_file.cluster_size() as usize {
+            if count == self.raw_file.cluster_size() as usize && self.backing_file.is_none() {
                 // Full cluster - deallocate the storage.
                 self.deallocate_cluster(curr_addr)?;
-            } else {
-                // Partial cluster - zero out the relevant bytes if it was allocated.
-                // Any space in unallocated clusters can be left alone, since
-                // unallocated clusters already read back as zeroes.
-                if let Some(offset) = self.file_offset_read(curr_addr)? {
-                    // Partial cluster - zero it out.
-                    self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
-                    self.raw_file.file_mut().write_zeroes(count)?;
-                }
+            } else if count == self.raw_file.cluster_size() as usize && self.header.version >= 3 {
+                // Full cluster in front of a backing file - deallocate the storage and keep the
+                // cluster from reading through to the backing file.
+                self.zero_cluster(curr_addr)?;
+            } else if self.cluster_has_data(curr_addr)? {
+                // Partial cluster, or full cluster of a v2 image which has no zero flag - zero out
+                // the relevant bytes, after copying the rest of the cluster from the backing file
+                // if it isn't allocated yet. Clusters without data can be left alone, since they
+                // already read back as zeroes.
+                let offset = self.file_offset_write(curr_addr)?;
+                self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
+                self.raw_file.file_mut().write_zeroes(count)?;
             }
 
             nwritten += count;
@@ -1282,7 +1634,7 @@ impl QcowFile {
     }
 
     // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // cluster is compressed. The zero flag is kept along with the cluster addresses.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
         if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
@@ -1290,7 +1642,7 @@ impl QcowFile {
         }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| *entry & (L2_TABLE_OFFSET_MASK | ZERO_FLAG))
             .collect())
     }
 
@@ -1409,12 +1761,21 @@ impl Read for QcowFile {
             let curr_addr = address + nread as u64;
             let file_offset = self.file_offset_read(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
+            // Clusters with the zero flag don't read through to the backing file.
+            let backing_file = if self.l2_entry(curr_addr)? & ZERO_FLAG == 0 {
+                self.backing_file.as_mut()
+            } else {
+                None
+            };
 
             if let Some(offset) = file_offset {
                 self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                 self.raw_file
                     .file_mut()
                     .read_exact(&mut buf[nread..(nread + count)])?;
+            } else if let Some(backing_file) = backing_file {
+                // Previously unwritten region, read through to the backing file
+                backing_file.read_at(curr_addr, &mut buf[nread..(nread + count)])?;
             } else {
                 // Previously unwritten region, return zeros
                 for b in &mut buf[nread..(nread + count)] {
@@ -1701,7 +2062,7 @@ pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
 mod tests {
     use super::*;
     use std::io::{Read, Seek, SeekFrom, Write};
-    use tempfile::tempfile;
+    use tempfile::{tempdir, tempfile};
 
     fn valid_header_v3() -> Vec<u8> {
         vec![
@@ -2461,6 +2822,195 @@ mod tests {
         });
     }
 
+    fn create_file(path: &Path) -> RawFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        RawFile::new(file, false)
+    }
+
+    fn all_equal(buf: &[u8], value: u8) -> bool {
+        buf.iter().all(|b| *b == value)
+    }
+
+    #[test]
+    fn backing_file_raw() {
+        let dir = tempdir().unwrap();
+        let mut base = create_file(&dir.path().join("base.raw"));
+        base.write_all(&[0x55u8; 0x20000]).unwrap();
+        base.set_len(0x30000).unwrap();
+
+        // The backing file name is relative to the directory of the overlay.
+        let overlay_file = create_file(&dir.path().join("overlay.qcow2"));
+        let mut overlay = QcowFile::new_from_backing(overlay_file, 3, "base.raw")
+            .expect("Failed to create overlay.");
+        assert_eq!(overlay.seek(SeekFrom::End(0)).unwrap(), 0x30000);
+
+        // Unallocated clusters read through to the backing file.
+        let mut readback = [0u8; 0x30];
+        overlay.seek(SeekFrom::Start(0x1_fff0)).unwrap();
+        overlay.read_exact(&mut readback[..0x20]).unwrap();
+        assert!(all_equal(&readback[..0x10], 0x55));
+        assert!(all_equal(&readback[0x10..0x20], 0));
+
+        // A partial write copies the rest of the cluster from the backing file.
+        overlay.seek(SeekFrom::Start(0x1_0010)).unwrap();
+        overlay.write_all(&[0xaau8; 0x10]).unwrap();
+        overlay.seek(SeekFrom::Start(0x1_0000)).unwrap();
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback[..0x10], 0x55));
+        assert!(all_equal(&readback[0x10..0x20], 0xaa));
+        assert!(all_equal(&readback[0x20..], 0x55));
+
+        // The backing file is left untouched.
+        base.seek(SeekFrom::Start(0x1_0010)).unwrap();
+        base.read_exact(&mut readback[..0x10]).unwrap();
+        assert!(all_equal(&readback[..0x10], 0x55));
+
+        // Punching a hole hides the backing file data, partial clusters being copied from the
+        // backing file first.
+        overlay.punch_hole(0x10, 0x10).unwrap();
+        overlay.seek(SeekFrom::Start(0)).unwrap();
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback[..0x10], 0x55));
+        assert!(all_equal(&readback[0x10..0x20], 0));
+        assert!(all_equal(&readback[0x20..], 0x55));
+
+        // Full clusters lose their storage and get the zero flag instead.
+        overlay.punch_hole(0, 0x2_0000).unwrap();
+        assert_eq!(
+            &overlay.l2_table(0).unwrap().unwrap()[..2],
+            &[ZERO_FLAG, ZERO_FLAG]
+        );
+        for address in &[0, 0x1_0000] {
+            overlay.seek(SeekFrom::Start(*address)).unwrap();
+            overlay.read_exact(&mut readback).unwrap();
+            assert!(all_equal(&readback, 0));
+        }
+        assert_eq!(overlay.seek_hole(0).unwrap(), Some(0));
+
+        // Writing to a cluster with the zero flag doesn't copy the backing file.
+        overlay.seek(SeekFrom::Start(0x10)).unwrap();
+        overlay.write_all(&[0xaau8; 0x10]).unwrap();
+        overlay.seek(SeekFrom::Start(0)).unwrap();
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback[..0x10], 0));
+        assert!(all_equal(&readback[0x10..0x20], 0xaa));
+        assert!(all_equal(&readback[0x20..], 0));
+
+        // The zero flag is kept in the image.
+        drop(overlay);
+        let overlay_file = create_file(&dir.path().join("overlay.qcow2"));
+        let mut overlay = QcowFile::from(overlay_file).expect("Failed to open overlay.");
+        assert_eq!(overlay.l2_table(0).unwrap().unwrap()[1], ZERO_FLAG);
+        overlay.seek(SeekFrom::Start(0x1_0000)).unwrap();
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback, 0));
+    }
+
+    #[test]
+    fn backing_file_format() {
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("base.raw");
+        let mut base = create_file(&base_path);
+        base.set_len(0x1_0000).unwrap();
+        let overlay_path = dir.path().join("overlay.qcow2");
+        QcowFile::new_from_backing(create_file(&overlay_path), 3, "base.raw").unwrap();
+
+        // The probed format is recorded in the overlay.
+        let mut overlay_file = create_file(&overlay_path);
+        let header = QcowHeader::new(&mut overlay_file).unwrap();
+        assert_eq!(
+            read_backing_file_format(&mut overlay_file, &header, 0x1_0000).unwrap(),
+            Some(ImageType::Raw)
+        );
+
+        // The recorded format is used rather than probing the backing file again, which would
+        // now find a qcow2 image.
+        base.write_u32::<BigEndian>(QCOW_MAGIC).unwrap();
+        let mut overlay = QcowFile::from(overlay_file).expect("Failed to open overlay.");
+        assert_eq!(overlay.read_u32::<BigEndian>().unwrap(), QCOW_MAGIC);
+
+        let mut overlay_file = create_file(&overlay_path);
+        overlay_file
+            .seek(SeekFrom::Start(u64::from(V3_BARE_HEADER_SIZE) + 4))
+            .unwrap();
+        overlay_file.write_u32::<BigEndian>(4).unwrap();
+        overlay_file.write_all(b"vmdk").unwrap();
+        match QcowFile::from(overlay_file) {
+            Err(Error::UnsupportedBackingFileFormat(format)) => assert_eq!(format, "vmdk"),
+            _ => panic!("Unsupported backing file format was accepted."),
+        }
+    }
+
+    #[test]
+    fn backing_file_qcow_chain() {
+        let dir = tempdir().unwrap();
+        {
+            let base_file = create_file(&dir.path().join("base.qcow2"));
+            let mut base = QcowFile::new(base_file, 3, 0x4_0000).unwrap();
+            base.seek(SeekFrom::Start(0x1_0000)).unwrap();
+            base.write_all(&[0x55u8; 0x1_0000]).unwrap();
+        }
+        {
+            let middle_file = create_file(&dir.path().join("middle.qcow2"));
+            let mut middle = QcowFile::new_from_backing(middle_file, 3, "base.qcow2").unwrap();
+            middle.seek(SeekFrom::Start(0x3_0000)).unwrap();
+            middle.write_all(&[0x66u8; 0x1_0000]).unwrap();
+        }
+        let overlay_file = create_file(&dir.path().join("overlay.qcow2"));
+        QcowFile::new_from_backing(overlay_file, 3, "middle.qcow2").unwrap();
+
+        // Reopen the overlay, the backing chain being found from its header.
+        let overlay_file = create_file(&dir.path().join("overlay.qcow2"));
+        let mut overlay = QcowFile::from(overlay_file).expect("Failed to open overlay.");
+
+        let mut readback = [0u8; 0x1_0000];
+        overlay.seek(SeekFrom::Start(0x1_0000)).unwrap();
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback, 0x55));
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback, 0));
+        overlay.read_exact(&mut readback).unwrap();
+        assert!(all_equal(&readback, 0x66));
+
+        // Clusters holding data anywhere in the backing chain aren't holes.
+        assert_eq!(overlay.seek_data(0).unwrap(), Some(0x1_0000));
+        assert_eq!(overlay.seek_hole(0x1_0000).unwrap(), Some(0x2_0000));
+        assert_eq!(overlay.seek_data(0x2_0000).unwrap(), Some(0x3_0000));
+        assert_eq!(overlay.seek_hole(0x3_0000).unwrap(), Some(0x4_0000));
+
+        overlay.seek(SeekFrom::Start(0x2_0000)).unwrap();
+        overlay.write_all(&[0x77u8; 0x1_0000]).unwrap();
+        assert_eq!(overlay.seek_hole(0).unwrap(), Some(0));
+        assert_eq!(overlay.seek_hole(0x1_0000).unwrap(), Some(0x4_0000));
+    }
+
+    #[test]
+    fn backing_file_loop() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("loop.qcow2");
+        QcowFile::new(create_file(&path), 3, 0x1_0000).unwrap();
+
+        // Make the image its own backing file, stored after the end of the header extensions
+        // which leaves its format to be probed.
+        let name_offset = u64::from(V3_BARE_HEADER_SIZE) + 8;
+        let mut file = create_file(&path);
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_u64::<BigEndian>(name_offset).unwrap();
+        file.write_u32::<BigEndian>(10).unwrap();
+        file.seek(SeekFrom::Start(name_offset)).unwrap();
+        file.write_all(b"loop.qcow2").unwrap();
+
+        match QcowFile::from(file) {
+            Err(Error::MaxNestingDepthExceeded) => {}
+            _ => panic!("Image backed by itself was accepted."),
+        }
+    }
+
     fn seek_cur(file: &mut QcowFile) -> u64 {
         file.seek(SeekFrom::Current(0)).unwrap()
     }
@@ -313,6 +313,12 @@ impl SeekHole for RawFile {
     }
 }
 
+impl AsRawFd for RawFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Clone for RawFile {
     fn clone(&self) -> Self {
         RawFile {
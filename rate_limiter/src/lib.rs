@@ -0,0 +1,484 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Copyright © 2020 Intel Corporation
+//
+
+//! Token bucket based rate limiter.
+//!
+//! A `RateLimiter` holds up to two token buckets, one limiting the bandwidth
+//! in bytes, the other one limiting the number of operations. Each bucket is
+//! refilled over time, and consuming tokens from an empty bucket fails.
+//!
+//! When consuming fails, the rate limiter arms a timer firing once the bucket
+//! should hold enough tokens again. The timer file descriptor is meant to be
+//! registered with the epoll loop of the device, which must then call
+//! `event_handler()`.
+//!
+//! A rate limiter can be shared by several epoll loops, all registering its
+//! timer while only the first one to handle the timer event gets to read it.
+//! Each of them gets its own event from `new_unblocked_evt()` instead, which
+//! is notified once the rate limiter is unblocked, so that it can resume the
+//! processing it deferred.
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use std::{cmp, io, result};
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+const NANOSEC_IN_ONE_MILLISEC: u64 = 1_000_000;
+
+// Time to wait before checking an empty bucket again.
+const REFILL_TIMER_INTERVAL_MS: u64 = 100;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the timer.
+    TimerFdCreate(errno::Error),
+    /// Failed to make the timer non blocking.
+    TimerFdSetNonBlocking(io::Error),
+    /// Failed to read the timer.
+    TimerFdRead(errno::Error),
+    /// Failed to create an unblocked event.
+    UnblockedEventFdCreate(io::Error),
+    /// Failed to notify an unblocked event.
+    UnblockedEventFdWrite(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configuration of a token bucket.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct TokenBucketConfig {
+    /// Number of tokens the bucket holds when full.
+    pub size: u64,
+    /// Initial burst of tokens, consumed before the bucket itself and never
+    /// refilled.
+    #[serde(default)]
+    pub one_time_burst: Option<u64>,
+    /// Time in milliseconds for the bucket to go from empty to full.
+    pub refill_time: u64,
+}
+
+/// Configuration of a rate limiter. A missing bucket means no limit.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RateLimiterConfig {
+    /// Limit on the number of bytes.
+    #[serde(default)]
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Limit on the number of operations.
+    #[serde(default)]
+    pub ops: Option<TokenBucketConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum BucketReduction {
+    /// Not enough tokens in the bucket.
+    Failure,
+    /// The tokens were consumed.
+    Success,
+    /// The tokens were consumed, but exceeded the size of the bucket by the
+    /// given ratio.
+    OverConsumption(f64),
+}
+
+struct TokenBucket {
+    size: u64,
+    one_time_burst: u64,
+    // Refill time in nanoseconds.
+    refill_time: u64,
+    budget: u64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &TokenBucketConfig) -> Option<Self> {
+        if config.size == 0 || config.refill_time == 0 {
+            return None;
+        }
+
+        Some(TokenBucket {
+            size: config.size,
+            one_time_burst: config.one_time_burst.unwrap_or(0),
+            refill_time: config.refill_time.saturating_mul(NANOSEC_IN_ONE_MILLISEC),
+            budget: config.size,
+            last_update: Instant::now(),
+        })
+    }
+
+    fn auto_replenish(&mut self) {
+        let time_delta = self.last_update.elapsed().as_nanos();
+        let size = u128::from(self.size);
+        let refill_time = u128::from(self.refill_time);
+
+        let tokens = time_delta * size / refill_time;
+        if tokens >= size {
+            self.budget = self.size;
+            self.last_update = Instant::now();
+        } else {
+            // Only account for the time matching the tokens added, so that
+            // fractions of tokens are not lost from one refill to the next.
+            self.last_update += Duration::from_nanos((tokens * refill_time / size) as u64);
+            self.budget = cmp::min(self.budget.saturating_add(tokens as u64), self.size);
+        }
+    }
+
+    fn reduce(&mut self, mut tokens: u64) -> BucketReduction {
+        // The one time burst is consumed first.
+        let one_time_burst = self.one_time_burst;
+        if one_time_burst > 0 {
+            if one_time_burst >= tokens {
+                self.one_time_burst -= tokens;
+                self.last_update = Instant::now();
+                return BucketReduction::Success;
+            }
+
+            tokens -= one_time_burst;
+            self.one_time_burst = 0;
+        }
+
+        if tokens > self.budget {
+            self.auto_replenish();
+
+            if tokens > self.size {
+                // The bucket can never hold that many tokens. Empty it and
+                // let the caller know how far beyond its size this goes.
+                tokens -= self.budget;
+                self.budget = 0;
+                return BucketReduction::OverConsumption(tokens as f64 / self.size as f64);
+            }
+
+            if tokens > self.budget {
+                // Nothing is consumed, the one time burst included.
+                self.one_time_burst = one_time_burst;
+                return BucketReduction::Failure;
+            }
+        }
+
+        self.budget -= tokens;
+        BucketReduction::Success
+    }
+
+    fn force_replenish(&mut self, tokens: u64) {
+        self.budget = cmp::min(self.budget.saturating_add(tokens), self.size);
+    }
+}
+
+/// Type of the tokens to consume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenType {
+    /// Bytes, consumed from the bandwidth bucket.
+    Bytes,
+    /// Operations, consumed from the ops bucket.
+    Ops,
+}
+
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    timer_fd: TimerFd,
+    // Set while the timer is armed, nothing can be consumed until it fires.
+    timer_active: bool,
+    // Notified once the timer fired, one for each user of the rate limiter.
+    unblocked_evts: Vec<EventFd>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter from the given configuration.
+    pub fn new(config: &RateLimiterConfig) -> Result<Self> {
+        let timer_fd = TimerFd::new().map_err(Error::TimerFdCreate)?;
+
+        // Reading the timer must not block, since the configuration can be
+        // updated between the timer firing and the event being handled.
+        // Safe because the file descriptor is valid and we check the result.
+        let ret = unsafe { libc::fcntl(timer_fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(Error::TimerFdSetNonBlocking(io::Error::last_os_error()));
+        }
+
+        Ok(RateLimiter {
+            bandwidth: config.bandwidth.as_ref().and_then(TokenBucket::new),
+            ops: config.ops.as_ref().and_then(TokenBucket::new),
+            timer_fd,
+            timer_active: false,
+            unblocked_evts: Vec::new(),
+        })
+    }
+
+    /// Returns a new event, notified each time the rate limiter is unblocked.
+    pub fn new_unblocked_evt(&mut self) -> Result<EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::UnblockedEventFdCreate)?;
+        self.unblocked_evts
+            .push(evt.try_clone().map_err(Error::UnblockedEventFdCreate)?);
+
+        Ok(evt)
+    }
+
+    fn activate_timer(&mut self, duration: Duration) {
+        // A zero duration would disarm the timer, leaving the rate limiter
+        // blocked forever.
+        let duration = cmp::max(duration, Duration::from_millis(1));
+        if let Err(e) = self.timer_fd.reset(duration, None) {
+            error!("Failed to arm the rate limiter timer: {:?}", e);
+            return;
+        }
+        self.timer_active = true;
+    }
+
+    /// Try to consume the given number of tokens. Returns false if there are
+    /// not enough tokens, in which case the timer is armed.
+    pub fn consume(&mut self, tokens: u64, token_type: TokenType) -> bool {
+        if self.timer_active {
+            return false;
+        }
+
+        let bucket = match token_type {
+            TokenType::Bytes => self.bandwidth.as_mut(),
+            TokenType::Ops => self.ops.as_mut(),
+        };
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return true,
+        };
+
+        let refill_time = bucket.refill_time;
+        match bucket.reduce(tokens) {
+            BucketReduction::Success => true,
+            BucketReduction::Failure => {
+                self.activate_timer(Duration::from_millis(REFILL_TIMER_INTERVAL_MS));
+                false
+            }
+            BucketReduction::OverConsumption(ratio) => {
+                // The tokens are consumed, but nothing else can be until the
+                // bucket had the time to absorb them.
+                self.activate_timer(Duration::from_nanos((ratio * refill_time as f64) as u64));
+                true
+            }
+        }
+    }
+
+    /// Give back tokens previously consumed, for instance when consuming
+    /// from the other bucket failed.
+    pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
+        let bucket = match token_type {
+            TokenType::Bytes => self.bandwidth.as_mut(),
+            TokenType::Ops => self.ops.as_mut(),
+        };
+        if let Some(bucket) = bucket {
+            bucket.force_replenish(tokens);
+        }
+    }
+
+    /// Returns true while consuming tokens is not possible.
+    pub fn is_blocked(&self) -> bool {
+        self.timer_active
+    }
+
+    /// Handle the timer event, unblocking the rate limiter and notifying its
+    /// unblocked events. Nothing happens if the timer did not fire, either
+    /// because another user of the rate limiter already handled the event, or
+    /// because the rate limiter was updated in the meantime.
+    pub fn event_handler(&mut self) -> Result<()> {
+        match self.timer_fd.wait() {
+            Ok(_) => {
+                self.timer_active = false;
+                for evt in self.unblocked_evts.iter() {
+                    evt.write(1).map_err(Error::UnblockedEventFdWrite)?;
+                }
+                Ok(())
+            }
+            Err(e) if e.errno() == libc::EAGAIN => Ok(()),
+            Err(e) => Err(Error::TimerFdRead(e)),
+        }
+    }
+
+    /// Replace the token buckets with new ones built from the configuration.
+    pub fn update(&mut self, config: &RateLimiterConfig) {
+        self.bandwidth = config.bandwidth.as_ref().and_then(TokenBucket::new);
+        self.ops = config.ops.as_ref().and_then(TokenBucket::new);
+
+        // Don't wait according to the previous limits, but still go through
+        // the timer so that the device resumes its processing.
+        if self.timer_active {
+            self.activate_timer(Duration::from_millis(1));
+        }
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn bucket_config(
+        size: u64,
+        one_time_burst: Option<u64>,
+        refill_time: u64,
+    ) -> TokenBucketConfig {
+        TokenBucketConfig {
+            size,
+            one_time_burst,
+            refill_time,
+        }
+    }
+
+    #[test]
+    fn test_token_bucket_create() {
+        assert!(TokenBucket::new(&bucket_config(0, None, 1000)).is_none());
+        assert!(TokenBucket::new(&bucket_config(1000, None, 0)).is_none());
+
+        let bucket = TokenBucket::new(&bucket_config(1000, Some(100), 1000)).unwrap();
+        assert_eq!(bucket.size, 1000);
+        assert_eq!(bucket.budget, 1000);
+        assert_eq!(bucket.one_time_burst, 100);
+        assert_eq!(bucket.refill_time, 1000 * NANOSEC_IN_ONE_MILLISEC);
+    }
+
+    #[test]
+    fn test_token_bucket_reduce() {
+        // A one hour refill time makes the refill negligible.
+        let mut bucket = TokenBucket::new(&bucket_config(1000, Some(100), 3_600_000)).unwrap();
+
+        // The one time burst is consumed first.
+        assert_eq!(bucket.reduce(50), BucketReduction::Success);
+        assert_eq!(bucket.one_time_burst, 50);
+        assert_eq!(bucket.budget, 1000);
+
+        // Then the bucket.
+        assert_eq!(bucket.reduce(150), BucketReduction::Success);
+        assert_eq!(bucket.one_time_burst, 0);
+        assert_eq!(bucket.budget, 900);
+
+        assert_eq!(bucket.reduce(900), BucketReduction::Success);
+        assert_eq!(bucket.reduce(1), BucketReduction::Failure);
+
+        bucket.force_replenish(500);
+        assert_eq!(bucket.budget, 500);
+        bucket.force_replenish(u64::MAX);
+        assert_eq!(bucket.budget, 1000);
+
+        assert_eq!(bucket.reduce(1500), BucketReduction::OverConsumption(0.5));
+        assert_eq!(bucket.budget, 0);
+    }
+
+    #[test]
+    fn test_token_bucket_reduce_failure() {
+        let mut bucket = TokenBucket::new(&bucket_config(1000, Some(100), 3_600_000)).unwrap();
+        assert_eq!(bucket.reduce(50), BucketReduction::Success);
+        bucket.budget = 100;
+
+        // Nothing is consumed when the burst and the bucket together don't
+        // hold enough tokens.
+        assert_eq!(bucket.reduce(200), BucketReduction::Failure);
+        assert_eq!(bucket.one_time_burst, 50);
+        assert_eq!(bucket.budget, 100);
+
+        assert_eq!(bucket.reduce(150), BucketReduction::Success);
+        assert_eq!(bucket.one_time_burst, 0);
+        assert_eq!(bucket.budget, 0);
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let mut bucket = TokenBucket::new(&bucket_config(1000, None, 100)).unwrap();
+
+        assert_eq!(bucket.reduce(1000), BucketReduction::Success);
+        assert_eq!(bucket.reduce(1000), BucketReduction::Failure);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(bucket.reduce(1000), BucketReduction::Success);
+    }
+
+    #[test]
+    fn test_rate_limiter_unlimited() {
+        let mut limiter = RateLimiter::new(&RateLimiterConfig::default()).unwrap();
+
+        assert!(limiter.consume(u64::MAX, TokenType::Bytes));
+        assert!(limiter.consume(u64::MAX, TokenType::Ops));
+        assert!(!limiter.is_blocked());
+    }
+
+    #[test]
+    fn test_rate_limiter_blocking() {
+        let mut limiter = RateLimiter::new(&RateLimiterConfig {
+            bandwidth: Some(bucket_config(1000, None, 3_600_000)),
+            ops: Some(bucket_config(10, None, 3_600_000)),
+        })
+        .unwrap();
+
+        assert!(limiter.consume(1000, TokenType::Bytes));
+        assert!(limiter.consume(10, TokenType::Ops));
+        assert!(!limiter.is_blocked());
+
+        // Nothing is left, the rate limiter blocks until the timer fires.
+        assert!(!limiter.consume(1, TokenType::Ops));
+        assert!(limiter.is_blocked());
+        assert!(!limiter.consume(1, TokenType::Bytes));
+
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        limiter.event_handler().unwrap();
+        assert!(!limiter.is_blocked());
+
+        // The buckets are still empty after the timer fired.
+        assert!(!limiter.consume(1, TokenType::Bytes));
+        assert!(limiter.is_blocked());
+
+        // Handling the event before the timer fired leaves it blocked.
+        limiter.event_handler().unwrap();
+        assert!(limiter.is_blocked());
+    }
+
+    #[test]
+    fn test_rate_limiter_unblocked_evts() {
+        let mut limiter = RateLimiter::new(&RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(bucket_config(1, None, 3_600_000)),
+        })
+        .unwrap();
+        let unblocked_evts = [
+            limiter.new_unblocked_evt().unwrap(),
+            limiter.new_unblocked_evt().unwrap(),
+        ];
+
+        assert!(limiter.consume(1, TokenType::Ops));
+        assert!(!limiter.consume(1, TokenType::Ops));
+        assert!(unblocked_evts.iter().all(|evt| evt.read().is_err()));
+
+        // Every user is notified, whichever handled the timer event.
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        limiter.event_handler().unwrap();
+        assert!(unblocked_evts.iter().all(|evt| evt.read().unwrap() == 1));
+    }
+
+    #[test]
+    fn test_rate_limiter_update() {
+        let mut limiter = RateLimiter::new(&RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(bucket_config(1, None, 3_600_000)),
+        })
+        .unwrap();
+
+        assert!(limiter.consume(1, TokenType::Ops));
+        assert!(!limiter.consume(1, TokenType::Ops));
+        assert!(limiter.is_blocked());
+
+        // Removing the limit unblocks the rate limiter shortly after.
+        limiter.update(&RateLimiterConfig::default());
+        thread::sleep(Duration::from_millis(10));
+        limiter.event_handler().unwrap();
+        assert!(limiter.consume(u64::MAX, TokenType::Ops));
+    }
+}
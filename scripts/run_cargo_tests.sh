@@ -14,6 +14,8 @@ time cargo clippy --all-targets --all-features -- -D warnings
 time cargo rustc --bin cloud-hypervisor -- -D warnings
 time cargo rustc -p vhost_user_net --bin vhost_user_net -- -D warnings
 time cargo test
+time cargo test -p hypervisor --features mock
+time cargo test -p vmm --features "mock pci_support"
 time cargo audit
 time cargo clippy --all-targets --no-default-features --features "pci,acpi,kvm" -- -D warnings
 time cargo rustc --bin cloud-hypervisor --no-default-features --features "pci,acpi,kvm"  -- -D warnings
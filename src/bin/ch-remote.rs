@@ -24,6 +24,7 @@ enum Error {
     InvalidCPUCount(std::num::ParseIntError),
     InvalidMemorySize(std::num::ParseIntError),
     InvalidBalloonSize(std::num::ParseIntError),
+    InvalidNumaNode(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
@@ -51,6 +52,7 @@ impl fmt::Display for Error {
             InvalidCPUCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {}", e),
+            InvalidNumaNode(e) => write!(f, "Error parsing NUMA node: {}", e),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
@@ -207,6 +209,7 @@ fn resize_api_command(
     cpus: Option<&str>,
     memory: Option<&str>,
     balloon: Option<&str>,
+    numa_node: Option<&str>,
 ) -> Result<(), Error> {
     let desired_vcpus: Option<u8> = if let Some(cpus) = cpus {
         Some(cpus.parse().map_err(Error::InvalidCPUCount)?)
@@ -226,10 +229,17 @@ fn resize_api_command(
         None
     };
 
+    let numa_node: Option<u32> = if let Some(numa_node) = numa_node {
+        Some(numa_node.parse().map_err(Error::InvalidNumaNode)?)
+    } else {
+        None
+    };
+
     let resize = vmm::api::VmResizeData {
         desired_vcpus,
         desired_ram,
         desired_ram_w_balloon,
+        numa_node,
     };
 
     simple_api_command(
@@ -317,9 +327,16 @@ fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Er
     )
 }
 
-fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn snapshot_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    parent_url: Option<&str>,
+    track_changes: bool,
+) -> Result<(), Error> {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
+        parent_url: parent_url.map(String::from),
+        track_changes,
     };
 
     simple_api_command(
@@ -341,6 +358,58 @@ fn restore_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Erro
     )
 }
 
+fn verify_snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let verify_snapshot_data = vmm::api::VmVerifySnapshotData {
+        source_url: url.to_owned(),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "verify-snapshot",
+        Some(&serde_json::to_string(&verify_snapshot_data).unwrap()),
+    )
+}
+
+fn coredump_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let coredump_data = vmm::api::VmCoredumpData {
+        destination_url: url.to_owned(),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "coredump",
+        Some(&serde_json::to_string(&coredump_data).unwrap()),
+    )
+}
+
+fn send_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let send_migration_data = vmm::api::VmSendMigrationData {
+        destination_url: url.to_owned(),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "send-migration",
+        Some(&serde_json::to_string(&send_migration_data).unwrap()),
+    )
+}
+
+fn receive_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let receive_migration_data = vmm::api::VmReceiveMigrationData {
+        receiver_url: url.to_owned(),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "receive-migration",
+        Some(&serde_json::to_string(&receive_migration_data).unwrap()),
+    )
+}
+
 fn do_command(matches: &ArgMatches) -> Result<(), Error> {
     let mut socket =
         UnixStream::connect(matches.value_of("api-socket").unwrap()).map_err(Error::Socket)?;
@@ -362,6 +431,10 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .subcommand_matches("resize")
                 .unwrap()
                 .value_of("balloon"),
+            matches
+                .subcommand_matches("resize")
+                .unwrap()
+                .value_of("numa_node"),
         ),
         Some("add-device") => add_device_api_command(
             &mut socket,
@@ -426,6 +499,14 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("snapshot_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("snapshot_parent"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .is_present("track_changes"),
         ),
         Some("restore") => restore_api_command(
             &mut socket,
@@ -435,6 +516,38 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("restore_config")
                 .unwrap(),
         ),
+        Some("verify-snapshot") => verify_snapshot_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("verify-snapshot")
+                .unwrap()
+                .value_of("verify_snapshot_config")
+                .unwrap(),
+        ),
+        Some("coredump") => coredump_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("coredump")
+                .unwrap()
+                .value_of("coredump_config")
+                .unwrap(),
+        ),
+        Some("send-migration") => send_migration_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("send-migration")
+                .unwrap()
+                .value_of("send_migration_config")
+                .unwrap(),
+        ),
+        Some("receive-migration") => receive_migration_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("receive-migration")
+                .unwrap()
+                .value_of("receive_migration_config")
+                .unwrap(),
+        ),
         Some(c) => simple_api_command(&mut socket, "PUT", c, None),
         None => unreachable!(),
     }
@@ -539,6 +652,13 @@ fn main() {
                         .help("New memory with balloon size")
                         .takes_value(true)
                         .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("numa_node")
+                        .long("numa-node")
+                        .help("NUMA node whose virtio-mem device is resized")
+                        .takes_value(true)
+                        .number_of_values(1),
                 ),
         )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
@@ -550,6 +670,16 @@ fn main() {
                     Arg::with_name("snapshot_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("snapshot_parent")
+                        .index(2)
+                        .help("<parent_url> Only save the memory changed since this snapshot"),
+                )
+                .arg(
+                    Arg::with_name("track_changes")
+                        .long("track-changes")
+                        .help("Allow the next snapshot to be incremental"),
                 ),
         )
         .subcommand(
@@ -560,6 +690,42 @@ fn main() {
                         .index(1)
                         .help(vmm::config::RestoreConfig::SYNTAX),
                 ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-snapshot")
+                .about("Check the integrity of a snapshot")
+                .arg(
+                    Arg::with_name("verify_snapshot_config")
+                        .index(1)
+                        .help("<source_url>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("coredump")
+                .about("Write a core dump of the VM memory and vCPUs state")
+                .arg(
+                    Arg::with_name("coredump_config")
+                        .index(1)
+                        .help("<destination_url>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("send-migration")
+                .about("Initiate a VM migration")
+                .arg(
+                    Arg::with_name("send_migration_config")
+                        .index(1)
+                        .help("<destination_url>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("receive-migration")
+                .about("Receive a VM migration")
+                .arg(
+                    Arg::with_name("receive_migration_config")
+                        .index(1)
+                        .help("<receiver_url>"),
+                ),
         );
 
     let matches = app.get_matches();
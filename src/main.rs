@@ -97,7 +97,9 @@ fn create_app<'a, 'b>(
                 .long("cpus")
                 .help(
                     "boot=<boot_vcpus>,max=<max_vcpus>,\
-                    topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>",
+                    topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
+                    model=<cpu_model>,features=[<+|-><feature>,...],\
+                    affinity=[<vcpu_id>@[<host_cpu_id>,...],...]",
                 )
                 .default_value(&default_vcpus)
                 .group("vm-config"),
@@ -108,12 +110,22 @@ fn create_app<'a, 'b>(
                 .help(
                     "Memory parameters \
                      \"size=<guest_memory_size>,mergeable=on|off,shared=on|off,hugepages=on|off,\
-                     hotplug_method=acpi|virtio-mem,\
-                     hotplug_size=<hotpluggable_memory_size>\"",
+                     hugepage_size=<hugepage_size>,hotplug_method=acpi|virtio-mem,\
+                     hotplug_size=<hotpluggable_memory_size>,balloon=on|off,\
+                     balloon_stats_interval=<seconds>,free_page_reporting=on|off,\
+                     deflate_on_oom=on|off\"",
                 )
                 .default_value(&default_memory)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("memory-zone")
+                .long("memory-zone")
+                .help(config::MemoryZoneConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("kernel")
                 .long("kernel")
@@ -208,6 +220,14 @@ fn create_app<'a, 'b>(
                 .number_of_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("numa")
+                .long("numa")
+                .help(config::NumaConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -276,6 +296,18 @@ fn create_app<'a, 'b>(
         );
     }
 
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    {
+        app = app.arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .help(config::GdbConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        );
+    }
+
     app
 }
 
@@ -511,6 +543,9 @@ mod unit_tests {
                     boot_vcpus: 1,
                     max_vcpus: 1,
                     topology: None,
+                    model: None,
+                    features: Vec::new(),
+                    affinity: None,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
@@ -520,8 +555,13 @@ mod unit_tests {
                     hotplug_size: None,
                     shared: false,
                     hugepages: false,
+                    hugepage_size: None,
                     balloon: false,
                     balloon_size: 0,
+                    balloon_stats_interval: None,
+                    free_page_reporting: false,
+                    deflate_on_oom: false,
+                    zones: None,
                 },
                 kernel: Some(KernelConfig {
                     path: PathBuf::from("/path/to/kernel"),
@@ -553,6 +593,9 @@ mod unit_tests {
                 iommu: false,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
+                #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+                gdb: None,
+                numa: None,
             };
 
             aver_eq!(tb, expected_vm_config, result_vm_config);
@@ -10,14 +10,18 @@
 
 extern crate block_util;
 extern crate log;
+extern crate rate_limiter;
 extern crate vhost_rs;
 extern crate vhost_user_backend;
 
-use block_util::{build_disk_image_id, Request, VirtioBlockConfig};
+use block_util::{
+    build_disk_image_id, Request, VirtioBlockConfig, MAX_DISCARD_WRITE_ZEROES_SECTORS,
+};
 use libc::EFD_NONBLOCK;
 use log::*;
 use option_parser::{OptionParser, OptionParserError, Toggle};
 use qcow::{self, ImageType, QcowFile};
+use rate_limiter::{RateLimiter, RateLimiterConfig, TokenBucketConfig};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
@@ -25,6 +29,7 @@ use std::io::{Seek, SeekFrom, Write};
 use std::num::Wrapping;
 use std::ops::DerefMut;
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::process;
 use std::result;
@@ -41,6 +46,7 @@ use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::ByteValued;
 use vm_memory::{Bytes, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::PunchHole;
 
 const SECTOR_SHIFT: u8 = 9;
 const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
@@ -50,8 +56,22 @@ const BLK_SIZE: u32 = 512;
 // and the overhead of the emulation layer.
 const POLL_QUEUE_US: u128 = 50;
 
-trait DiskFile: Read + Seek + Write + Send + Sync {}
-impl<D: Read + Seek + Write + Send + Sync> DiskFile for D {}
+// The queue event is event index 0 and the exit event is event index 1, the
+// rate limiter events come right after.
+const QUEUE_AVAIL_EVENT: u16 = 0;
+const RATE_LIMITER_EVENT: u16 = 2;
+const RATE_LIMITER_UNBLOCKED_EVENT: u16 = 3;
+
+trait DiskFile: Read + Seek + Write + PunchHole + Send + Sync {}
+impl<D: Read + Seek + Write + PunchHole + Send + Sync> DiskFile for D {}
+
+// Requests are executed on a reference to the disk image, which must be able
+// to punch holes as well.
+impl PunchHole for &mut dyn DiskFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        (**self).punch_hole(offset, length)
+    }
+}
 
 type Result<T> = std::result::Result<T, Error>;
 type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
@@ -60,6 +80,10 @@ type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
 enum Error {
     /// Failed to create kill eventfd
     CreateKillEventFd(io::Error),
+    /// Failed to create the rate limiter
+    CreateRateLimiter(rate_limiter::Error),
+    /// Failed to create the rate limiter unblocked event
+    CreateRateLimiterUnblockedEvent(rate_limiter::Error),
     /// Failed to parse configuration string
     FailedConfigParse(OptionParserError),
     /// Failed to handle event other than input event.
@@ -68,6 +92,12 @@ enum Error {
     HandleEventUnknownEvent,
     /// No path provided
     PathParameterMissing,
+    /// Failed to handle the rate limiter event.
+    RateLimiterEvent(rate_limiter::Error),
+    /// Failed to read the rate limiter unblocked event.
+    RateLimiterUnblockedEvent(io::Error),
+    /// Failed to register the rate limiter events.
+    RegisterRateLimiterEvent(io::Error),
     /// No socket provided
     SocketParameterMissing,
 }
@@ -75,7 +105,10 @@ enum Error {
 pub const SYNTAX: &str = "vhost-user-block backend parameters \
  \"path=<image_path>,socket=<socket_path>,num_queues=<number_of_queues>,\
  queue_size=<size_of_each_queue>,readonly=true|false,direct=true|false,\
- poll_queue=true|false\"";
+ poll_queue=true|false,rate_limit_bw_size=<bytes>,\
+ rate_limit_bw_one_time_burst=<bytes>,rate_limit_bw_refill_time=<ms>,\
+ rate_limit_ops_size=<ops>,rate_limit_ops_one_time_burst=<ops>,\
+ rate_limit_ops_refill_time=<ms>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -99,6 +132,8 @@ struct VhostUserBlkThread {
     event_idx: bool,
     kill_evt: EventFd,
     writeback: Arc<AtomicBool>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    rate_limiter_unblocked_evt: EventFd,
 }
 
 impl VhostUserBlkThread {
@@ -107,7 +142,14 @@ impl VhostUserBlkThread {
         disk_image_id: Vec<u8>,
         disk_nsectors: u64,
         writeback: Arc<AtomicBool>,
+        rate_limiter: Arc<Mutex<RateLimiter>>,
     ) -> Result<Self> {
+        let rate_limiter_unblocked_evt = rate_limiter
+            .lock()
+            .unwrap()
+            .new_unblocked_evt()
+            .map_err(Error::CreateRateLimiterUnblockedEvent)?;
+
         Ok(VhostUserBlkThread {
             mem: None,
             disk_image,
@@ -116,6 +158,8 @@ impl VhostUserBlkThread {
             event_idx: false,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             writeback,
+            rate_limiter,
+            rate_limiter_unblocked_evt,
         })
     }
 
@@ -132,6 +176,13 @@ impl VhostUserBlkThread {
             match Request::parse(&head, mem) {
                 Ok(mut request) => {
                     debug!("element is a valid request");
+                    if !request.consume_rate_limiter_tokens(&mut self.rate_limiter.lock().unwrap())
+                    {
+                        // The request will be processed once the rate
+                        // limiter is unblocked.
+                        vring.mut_queue().go_to_previous_position();
+                        break;
+                    }
                     request.set_writeback(self.writeback.load(Ordering::SeqCst));
                     let status = match request.execute(
                         &mut self.disk_image.lock().unwrap().deref_mut(),
@@ -198,6 +249,7 @@ impl VhostUserBlkBackend {
         direct: bool,
         poll_queue: bool,
         queue_size: usize,
+        rate_limiter_config: RateLimiterConfig,
     ) -> Result<Self> {
         let mut options = OpenOptions::new();
         options.read(true);
@@ -228,16 +280,30 @@ impl VhostUserBlkBackend {
         config.opt_io_size = 1;
         config.num_queues = num_queues as u16;
         config.writeback = 1;
+        if !rdonly {
+            config.max_discard_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = 1;
+            config.max_write_zeroes_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
 
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
         let writeback = Arc::new(AtomicBool::new(true));
+        // The queues share the rate limiter, so that the limits apply to the
+        // whole device.
+        let rate_limiter = Arc::new(Mutex::new(
+            RateLimiter::new(&rate_limiter_config).map_err(Error::CreateRateLimiter)?,
+        ));
         for i in 0..num_queues {
             let thread = Mutex::new(VhostUserBlkThread::new(
                 image.clone(),
                 image_id.clone(),
                 nsectors,
                 writeback.clone(),
+                rate_limiter.clone(),
             )?);
             threads.push(thread);
             queues_per_thread.push(0b1 << i);
@@ -296,6 +362,8 @@ impl VhostUserBackend for VhostUserBlkBackend {
 
         if self.rdonly {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
         }
         avail_features
     }
@@ -337,44 +405,61 @@ impl VhostUserBackend for VhostUserBlkBackend {
 
         let mut thread = self.threads[thread_id].lock().unwrap();
         match device_event {
-            0 => {
-                let mut vring = vrings[0].write().unwrap();
-
-                if self.poll_queue {
-                    // Actively poll the queue until POLL_QUEUE_US has passed
-                    // without seeing a new request.
-                    let mut now = Instant::now();
-                    loop {
-                        if thread.process_queue(&mut vring) {
-                            now = Instant::now();
-                        } else if now.elapsed().as_micros() > POLL_QUEUE_US {
-                            break;
-                        }
-                    }
-                }
+            QUEUE_AVAIL_EVENT => {}
+            // The queues are notified through their unblocked event once the
+            // rate limiter is unblocked.
+            RATE_LIMITER_EVENT => {
+                thread
+                    .rate_limiter
+                    .lock()
+                    .unwrap()
+                    .event_handler()
+                    .map_err(Error::RateLimiterEvent)?;
+                return Ok(false);
+            }
+            RATE_LIMITER_UNBLOCKED_EVENT => {
+                thread
+                    .rate_limiter_unblocked_evt
+                    .read()
+                    .map_err(Error::RateLimiterUnblockedEvent)?;
+            }
+            _ => return Err(Error::HandleEventUnknownEvent.into()),
+        }
 
-                if thread.event_idx {
-                    // vm-virtio's Queue implementation only checks avail_index
-                    // once, so to properly support EVENT_IDX we need to keep
-                    // calling process_queue() until it stops finding new
-                    // requests on the queue.
-                    loop {
-                        vring
-                            .mut_queue()
-                            .update_avail_event(thread.mem.as_ref().unwrap());
-                        if !thread.process_queue(&mut vring) {
-                            break;
-                        }
-                    }
-                } else {
-                    // Without EVENT_IDX, a single call is enough.
-                    thread.process_queue(&mut vring);
+        let mut vring = vrings[0].write().unwrap();
+
+        if self.poll_queue {
+            // Actively poll the queue until POLL_QUEUE_US has passed
+            // without seeing a new request.
+            let mut now = Instant::now();
+            loop {
+                if thread.process_queue(&mut vring) {
+                    now = Instant::now();
+                } else if now.elapsed().as_micros() > POLL_QUEUE_US {
+                    break;
                 }
+            }
+        }
 
-                Ok(false)
+        if thread.event_idx {
+            // vm-virtio's Queue implementation only checks avail_index
+            // once, so to properly support EVENT_IDX we need to keep
+            // calling process_queue() until it stops finding new
+            // requests on the queue.
+            loop {
+                vring
+                    .mut_queue()
+                    .update_avail_event(thread.mem.as_ref().unwrap());
+                if !thread.process_queue(&mut vring) {
+                    break;
+                }
             }
-            _ => Err(Error::HandleEventUnknownEvent.into()),
+        } else {
+            // Without EVENT_IDX, a single call is enough.
+            thread.process_queue(&mut vring);
         }
+
+        Ok(false)
     }
 
     fn get_config(&self, _offset: u32, _size: u32) -> Vec<u8> {
@@ -421,6 +506,33 @@ struct VhostUserBlkBackendConfig {
     readonly: bool,
     direct: bool,
     poll_queue: bool,
+    rate_limiter_config: RateLimiterConfig,
+}
+
+fn parse_token_bucket_config(
+    parser: &OptionParser,
+    prefix: &str,
+) -> Result<Option<TokenBucketConfig>> {
+    let size = parser
+        .convert(&format!("{}_size", prefix))
+        .map_err(Error::FailedConfigParse)?;
+    let one_time_burst = parser
+        .convert(&format!("{}_one_time_burst", prefix))
+        .map_err(Error::FailedConfigParse)?;
+    let refill_time = parser
+        .convert(&format!("{}_refill_time", prefix))
+        .map_err(Error::FailedConfigParse)?;
+
+    if size.is_none() && one_time_burst.is_none() && refill_time.is_none() {
+        return Ok(None);
+    }
+
+    // A bucket with a null size or refill time does not limit anything.
+    Ok(Some(TokenBucketConfig {
+        size: size.unwrap_or(0),
+        one_time_burst,
+        refill_time: refill_time.unwrap_or(0),
+    }))
 }
 
 impl VhostUserBlkBackendConfig {
@@ -433,7 +545,13 @@ impl VhostUserBlkBackendConfig {
             .add("num_queues")
             .add("queue_size")
             .add("socket")
-            .add("poll_queue");
+            .add("poll_queue")
+            .add("rate_limit_bw_size")
+            .add("rate_limit_bw_one_time_burst")
+            .add("rate_limit_bw_refill_time")
+            .add("rate_limit_ops_size")
+            .add("rate_limit_ops_one_time_burst")
+            .add("rate_limit_ops_refill_time");
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
         let path = parser.get("path").ok_or(Error::PathParameterMissing)?;
@@ -461,6 +579,10 @@ impl VhostUserBlkBackendConfig {
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(1024);
+        let rate_limiter_config = RateLimiterConfig {
+            bandwidth: parse_token_bucket_config(&parser, "rate_limit_bw")?,
+            ops: parse_token_bucket_config(&parser, "rate_limit_ops")?,
+        };
 
         Ok(VhostUserBlkBackendConfig {
             path,
@@ -470,6 +592,7 @@ impl VhostUserBlkBackendConfig {
             direct,
             poll_queue,
             queue_size,
+            rate_limiter_config,
         })
     }
 }
@@ -491,6 +614,7 @@ pub fn start_block_backend(backend_command: &str) {
             backend_config.direct,
             backend_config.poll_queue,
             backend_config.queue_size,
+            backend_config.rate_limiter_config,
         )
         .unwrap(),
     ));
@@ -504,6 +628,41 @@ pub fn start_block_backend(backend_command: &str) {
 
     debug!("blk_daemon is created!\n");
 
+    let vring_workers = blk_daemon.get_vring_workers();
+
+    if vring_workers.len() != blk_backend.read().unwrap().threads.len() {
+        error!("Number of vring workers must be identical to the number of backend threads");
+        process::exit(1);
+    }
+
+    for (thread, vring_worker) in blk_backend
+        .read()
+        .unwrap()
+        .threads
+        .iter()
+        .zip(vring_workers.iter())
+    {
+        let thread = thread.lock().unwrap();
+        let rate_limiter_fd = thread.rate_limiter.lock().unwrap().as_raw_fd();
+        for (fd, event) in [
+            (rate_limiter_fd, RATE_LIMITER_EVENT),
+            (
+                thread.rate_limiter_unblocked_evt.as_raw_fd(),
+                RATE_LIMITER_UNBLOCKED_EVENT,
+            ),
+        ]
+        .iter()
+        {
+            if let Err(e) = vring_worker
+                .register_listener(*fd, epoll::Events::EPOLLIN, u64::from(*event))
+                .map_err(Error::RegisterRateLimiterEvent)
+            {
+                error!("Failed to register the rate limiter events: {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
     if let Err(e) = blk_daemon.start(listener) {
         error!(
             "Failed to start daemon for vhost-user-block with error: {:?}\n",
@@ -8,6 +8,7 @@
 
 extern crate log;
 extern crate net_util;
+extern crate rate_limiter;
 extern crate vhost_rs;
 extern crate vhost_user_backend;
 
@@ -17,6 +18,7 @@ use net_util::{
     open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
 };
 use option_parser::{OptionParser, OptionParserError};
+use rate_limiter::{RateLimiter, RateLimiterConfig, TokenBucketConfig};
 use std::fmt;
 use std::io::{self};
 use std::net::Ipv4Addr;
@@ -32,6 +34,13 @@ use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
+// The queues are event indexes 0 and 1, the tap event index 2, and the exit
+// event index 3. The rate limiter events come right after.
+const RX_RATE_LIMITER_EVENT: u16 = 4;
+const TX_RATE_LIMITER_EVENT: u16 = 5;
+const RX_RATE_LIMITER_UNBLOCKED_EVENT: u16 = 6;
+const TX_RATE_LIMITER_UNBLOCKED_EVENT: u16 = 7;
+
 pub type VhostUserResult<T> = std::result::Result<T, VhostUserError>;
 pub type Result<T> = std::result::Result<T, Error>;
 pub type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
@@ -42,6 +51,10 @@ pub enum Error {
     BadActivate,
     /// Failed to create kill eventfd
     CreateKillEventFd(io::Error),
+    /// Failed to create the rate limiter
+    CreateRateLimiter(rate_limiter::Error),
+    /// Failed to create the rate limiter unblocked event
+    CreateRateLimiterUnblockedEvent(rate_limiter::Error),
     /// Failed to add event.
     EpollCtl(io::Error),
     /// Fail to wait event.
@@ -66,6 +79,12 @@ pub enum Error {
     NoMemoryConfigured,
     /// Open tap device failed.
     OpenTap(OpenTapError),
+    /// Failed to handle the rate limiter event.
+    RateLimiterEvent(rate_limiter::Error),
+    /// Failed to read the rate limiter unblocked event.
+    RateLimiterUnblockedEvent(io::Error),
+    /// Failed to register the rate limiter events.
+    RegisterRateLimiterEvent(io::Error),
     /// No socket provided
     SocketParameterMissing,
     /// Underlying QueuePair error
@@ -74,7 +93,13 @@ pub enum Error {
 
 pub const SYNTAX: &str = "vhost-user-net backend parameters \
 \"ip=<ip_addr>,mask=<net_mask>,socket=<socket_path>,\
-num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"";
+num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+rx_rate_limit_bw_size=<bytes>,rx_rate_limit_bw_one_time_burst=<bytes>,\
+rx_rate_limit_bw_refill_time=<ms>,rx_rate_limit_ops_size=<frames>,\
+rx_rate_limit_ops_one_time_burst=<frames>,rx_rate_limit_ops_refill_time=<ms>,\
+tx_rate_limit_bw_size=<bytes>,tx_rate_limit_bw_one_time_burst=<bytes>,\
+tx_rate_limit_bw_refill_time=<ms>,tx_rate_limit_ops_size=<frames>,\
+tx_rate_limit_ops_one_time_burst=<frames>,tx_rate_limit_ops_refill_time=<ms>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -98,7 +123,22 @@ struct VhostUserNetThread {
 
 impl VhostUserNetThread {
     /// Create a new virtio network device with the given TAP interface.
-    fn new(tap: Tap) -> Result<Self> {
+    fn new(
+        tap: Tap,
+        rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+        tx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    ) -> Result<Self> {
+        let rx_rate_limiter_unblocked_evt = rx_rate_limiter
+            .lock()
+            .unwrap()
+            .new_unblocked_evt()
+            .map_err(Error::CreateRateLimiterUnblockedEvent)?;
+        let tx_rate_limiter_unblocked_evt = tx_rate_limiter
+            .lock()
+            .unwrap()
+            .new_unblocked_evt()
+            .map_err(Error::CreateRateLimiterUnblockedEvent)?;
+
         Ok(VhostUserNetThread {
             vring_worker: None,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
@@ -111,6 +151,10 @@ impl VhostUserNetThread {
                 epoll_fd: None,
                 counters: NetCounters::default(),
                 tap_event_id: 2,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                rx_rate_limiter_unblocked_evt,
+                tx_rate_limiter_unblocked_evt,
             },
         })
     }
@@ -136,6 +180,8 @@ impl VhostUserNetBackend {
         num_queues: usize,
         queue_size: u16,
         ifname: Option<&str>,
+        rx_rate_limiter_config: RateLimiterConfig,
+        tx_rate_limiter_config: RateLimiterConfig,
     ) -> Result<Self> {
         let mut taps = open_tap(
             ifname,
@@ -148,8 +194,20 @@ impl VhostUserNetBackend {
 
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
+        // The queue pairs share the rate limiters, so that the limits apply
+        // to the whole device.
+        let rx_rate_limiter = Arc::new(Mutex::new(
+            RateLimiter::new(&rx_rate_limiter_config).map_err(Error::CreateRateLimiter)?,
+        ));
+        let tx_rate_limiter = Arc::new(Mutex::new(
+            RateLimiter::new(&tx_rate_limiter_config).map_err(Error::CreateRateLimiter)?,
+        ));
         for (i, tap) in taps.drain(..).enumerate() {
-            let thread = Mutex::new(VhostUserNetThread::new(tap)?);
+            let thread = Mutex::new(VhostUserNetThread::new(
+                tap,
+                rx_rate_limiter.clone(),
+                tx_rate_limiter.clone(),
+            )?);
             threads.push(thread);
             queues_per_thread.push(0b11 << (i * 2));
         }
@@ -210,7 +268,41 @@ impl VhostUserBackend for VhostUserNetBackend {
 
         let mut thread = self.threads[thread_id].lock().unwrap();
         match device_event {
-            0 => {
+            // The queue pairs are notified through their unblocked events
+            // once the rate limiter is unblocked.
+            RX_RATE_LIMITER_EVENT | TX_RATE_LIMITER_EVENT => {
+                let rate_limiter = if device_event == RX_RATE_LIMITER_EVENT {
+                    &thread.net.rx_rate_limiter
+                } else {
+                    &thread.net.tx_rate_limiter
+                };
+                rate_limiter
+                    .lock()
+                    .unwrap()
+                    .event_handler()
+                    .map_err(Error::RateLimiterEvent)?;
+                return Ok(false);
+            }
+            RX_RATE_LIMITER_UNBLOCKED_EVENT => {
+                thread
+                    .net
+                    .rx_rate_limiter_unblocked_evt
+                    .read()
+                    .map_err(Error::RateLimiterUnblockedEvent)?;
+            }
+            TX_RATE_LIMITER_UNBLOCKED_EVENT => {
+                thread
+                    .net
+                    .tx_rate_limiter_unblocked_evt
+                    .read()
+                    .map_err(Error::RateLimiterUnblockedEvent)?;
+            }
+            _ => {}
+        }
+
+        match device_event {
+            // Resuming RX receives the frame deferred by the rate limiter.
+            0 | RX_RATE_LIMITER_UNBLOCKED_EVENT => {
                 let mut vring = vrings[0].write().unwrap();
                 if thread
                     .net
@@ -222,7 +314,7 @@ impl VhostUserBackend for VhostUserNetBackend {
                         .map_err(Error::FailedSignalingUsedQueue)?
                 }
             }
-            1 => {
+            1 | TX_RATE_LIMITER_UNBLOCKED_EVENT => {
                 let mut vring = vrings[1].write().unwrap();
                 if thread
                     .net
@@ -279,6 +371,41 @@ pub struct VhostUserNetBackendConfig {
     pub num_queues: usize,
     pub queue_size: u16,
     pub tap: Option<String>,
+    pub rx_rate_limiter_config: RateLimiterConfig,
+    pub tx_rate_limiter_config: RateLimiterConfig,
+}
+
+fn parse_token_bucket_config(
+    parser: &OptionParser,
+    prefix: &str,
+) -> Result<Option<TokenBucketConfig>> {
+    let size = parser
+        .convert(&format!("{}_size", prefix))
+        .map_err(Error::FailedConfigParse)?;
+    let one_time_burst = parser
+        .convert(&format!("{}_one_time_burst", prefix))
+        .map_err(Error::FailedConfigParse)?;
+    let refill_time = parser
+        .convert(&format!("{}_refill_time", prefix))
+        .map_err(Error::FailedConfigParse)?;
+
+    if size.is_none() && one_time_burst.is_none() && refill_time.is_none() {
+        return Ok(None);
+    }
+
+    // A bucket with a null size or refill time does not limit anything.
+    Ok(Some(TokenBucketConfig {
+        size: size.unwrap_or(0),
+        one_time_burst,
+        refill_time: refill_time.unwrap_or(0),
+    }))
+}
+
+fn parse_rate_limiter_config(parser: &OptionParser, prefix: &str) -> Result<RateLimiterConfig> {
+    Ok(RateLimiterConfig {
+        bandwidth: parse_token_bucket_config(parser, &format!("{}_bw", prefix))?,
+        ops: parse_token_bucket_config(parser, &format!("{}_ops", prefix))?,
+    })
 }
 
 impl VhostUserNetBackendConfig {
@@ -293,6 +420,18 @@ impl VhostUserNetBackendConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket");
+        for direction in &["rx", "tx"] {
+            for option in &[
+                "bw_size",
+                "bw_one_time_burst",
+                "bw_refill_time",
+                "ops_size",
+                "ops_one_time_burst",
+                "ops_refill_time",
+            ] {
+                parser.add(&format!("{}_rate_limit_{}", direction, option));
+            }
+        }
 
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
@@ -318,6 +457,8 @@ impl VhostUserNetBackendConfig {
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(2);
         let socket = parser.get("socket").ok_or(Error::SocketParameterMissing)?;
+        let rx_rate_limiter_config = parse_rate_limiter_config(&parser, "rx_rate_limit")?;
+        let tx_rate_limiter_config = parse_rate_limiter_config(&parser, "tx_rate_limit")?;
 
         Ok(VhostUserNetBackendConfig {
             ip,
@@ -327,6 +468,8 @@ impl VhostUserNetBackendConfig {
             num_queues,
             queue_size,
             tap,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
         })
     }
 }
@@ -354,6 +497,8 @@ pub fn start_net_backend(backend_command: &str) {
             backend_config.num_queues,
             backend_config.queue_size,
             tap,
+            backend_config.rx_rate_limiter_config,
+            backend_config.tx_rate_limiter_config,
         )
         .unwrap(),
     ));
@@ -371,10 +516,35 @@ pub fn start_net_backend(backend_command: &str) {
     }
 
     for thread in net_backend.read().unwrap().threads.iter() {
-        thread
-            .lock()
-            .unwrap()
-            .set_vring_worker(Some(vring_workers.remove(0)));
+        let mut thread = thread.lock().unwrap();
+        let vring_worker = vring_workers.remove(0);
+
+        let rx_rate_limiter_fd = thread.net.rx_rate_limiter.lock().unwrap().as_raw_fd();
+        let tx_rate_limiter_fd = thread.net.tx_rate_limiter.lock().unwrap().as_raw_fd();
+        for (fd, event) in [
+            (rx_rate_limiter_fd, RX_RATE_LIMITER_EVENT),
+            (tx_rate_limiter_fd, TX_RATE_LIMITER_EVENT),
+            (
+                thread.net.rx_rate_limiter_unblocked_evt.as_raw_fd(),
+                RX_RATE_LIMITER_UNBLOCKED_EVENT,
+            ),
+            (
+                thread.net.tx_rate_limiter_unblocked_evt.as_raw_fd(),
+                TX_RATE_LIMITER_UNBLOCKED_EVENT,
+            ),
+        ]
+        .iter()
+        {
+            if let Err(e) = vring_worker
+                .register_listener(*fd, epoll::Events::EPOLLIN, u64::from(*event))
+                .map_err(Error::RegisterRateLimiterEvent)
+            {
+                error!("Failed to register the rate limiter events: {:?}", e);
+                process::exit(1);
+            }
+        }
+
+        thread.set_vring_worker(Some(vring_worker));
     }
 
     if let Err(e) = net_daemon.start(listener) {
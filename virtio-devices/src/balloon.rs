@@ -19,26 +19,38 @@ use super::{
 };
 use crate::vm_memory::GuestMemory;
 use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::mem::size_of;
+use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
-    GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
 };
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 const QUEUE_SIZE: u16 = 128;
-const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+const MIN_NUM_QUEUES: usize = 2;
+
+// Inflate and deflate queues are always present, the statistics queue
+// follows when VIRTIO_BALLOON_F_STATS_VQ is negotiated, and the reporting
+// queue comes last when VIRTIO_BALLOON_F_REPORTING is negotiated. The driver
+// only sets up the queues of the features it acked, one after the other.
+const INFLATE_QUEUE_INDEX: usize = 0;
+const DEFLATE_QUEUE_INDEX: usize = 1;
+const STATS_QUEUE_INDEX: usize = 2;
 
 // Get resize event.
 const RESIZE_EVENT: DeviceEventT = 0;
@@ -50,6 +62,12 @@ const DEFLATE_QUEUE_EVENT: DeviceEventT = 2;
 const KILL_EVENT: DeviceEventT = 3;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 4;
+// New descriptors are pending on the statistics queue.
+const STATS_QUEUE_EVENT: DeviceEventT = 5;
+// New statistics should be requested from the guest.
+const STATS_TIMER_EVENT: DeviceEventT = 6;
+// New descriptors are pending on the reporting queue.
+const REPORTING_QUEUE_EVENT: DeviceEventT = 7;
 
 // Page shift in the host.
 const PAGE_SHIFT: u32 = 12;
@@ -57,6 +75,23 @@ const PAGE_SHIFT: u32 = 12;
 // Size of a PFN in the balloon interface.
 const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
 
+// Feature bits, from include/uapi/linux/virtio_balloon.h
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
+
+// Statistics tags, from include/uapi/linux/virtio_balloon.h
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
 #[derive(Debug)]
 pub enum Error {
     // Guest gave us bad memory addresses.
@@ -67,6 +102,8 @@ pub enum Error {
     InvalidRequest,
     // Madvise fail.
     MadviseFail(std::io::Error),
+    // Fallocate fail.
+    FallocateFail(std::io::Error),
     // Failed to EventFd write.
     EventFdWriteFail(std::io::Error),
     // Failed to EventFd try_clone.
@@ -94,6 +131,36 @@ struct VirtioBalloonConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBalloonConfig {}
 
+// Offset of the "actual" field, the only one written by the guest.
+const CONFIG_ACTUAL_OFFSET: u64 = 4;
+
+// Got from include/uapi/linux/virtio_balloon.h
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioBalloonStat {
+    tag: u16,
+    val: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioBalloonStat {}
+
+fn stat_name(tag: u16) -> Option<&'static str> {
+    match tag {
+        VIRTIO_BALLOON_S_SWAP_IN => Some("swap_in"),
+        VIRTIO_BALLOON_S_SWAP_OUT => Some("swap_out"),
+        VIRTIO_BALLOON_S_MAJFLT => Some("major_faults"),
+        VIRTIO_BALLOON_S_MINFLT => Some("minor_faults"),
+        VIRTIO_BALLOON_S_MEMFREE => Some("free_memory"),
+        VIRTIO_BALLOON_S_MEMTOT => Some("total_memory"),
+        VIRTIO_BALLOON_S_AVAIL => Some("available_memory"),
+        VIRTIO_BALLOON_S_CACHES => Some("disk_caches"),
+        VIRTIO_BALLOON_S_HTLB_PGALLOC => Some("hugetlb_allocations"),
+        VIRTIO_BALLOON_S_HTLB_PGFAIL => Some("hugetlb_failures"),
+        _ => None,
+    }
+}
+
 struct VirtioBalloonResizeReceiver {
     size: Arc<AtomicU64>,
     tx: mpsc::Sender<Result<(), Error>>,
@@ -152,6 +219,14 @@ struct BalloonEpollHandler {
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     inflate_queue_evt: EventFd,
     deflate_queue_evt: EventFd,
+    stats_queue_evt: Option<EventFd>,
+    stats_timer: Option<TimerFd>,
+    // Descriptor holding the latest statistics, returned to the guest
+    // when new statistics are requested.
+    stats_desc_index: Option<u16>,
+    stats: Arc<Mutex<HashMap<&'static str, Wrapping<u64>>>>,
+    reporting_queue_evt: Option<EventFd>,
+    reporting_queue_index: usize,
     kill_evt: EventFd,
     pause_evt: EventFd,
 }
@@ -170,8 +245,8 @@ impl BalloonEpollHandler {
 
     fn process_queue(&mut self, ev_type: u16) -> result::Result<(), Error> {
         let queue_index = match ev_type {
-            INFLATE_QUEUE_EVENT => 0,
-            DEFLATE_QUEUE_EVENT => 1,
+            INFLATE_QUEUE_EVENT => INFLATE_QUEUE_INDEX,
+            DEFLATE_QUEUE_EVENT => DEFLATE_QUEUE_INDEX,
             _ => return Err(Error::ProcessQueueWrongEvType(ev_type)),
         };
 
@@ -235,6 +310,167 @@ impl BalloonEpollHandler {
         Ok(())
     }
 
+    fn process_stats_queue(&mut self) -> result::Result<(), Error> {
+        let mem = self.mem.memory();
+        let mut stale_desc_index = None;
+        for avail_desc in self.queues[STATS_QUEUE_INDEX].iter(&mem) {
+            if avail_desc.is_write_only() {
+                error!("The statistics buffer is write only");
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
+            let stat_size = size_of::<VirtioBalloonStat>();
+            if avail_desc.len as usize % stat_size != 0 {
+                error!("the statistics size {} is not right", avail_desc.len);
+                return Err(Error::InvalidRequest);
+            }
+
+            let mut stats = self.stats.lock().unwrap();
+            let mut offset = 0u64;
+            while offset < avail_desc.len as u64 {
+                let addr = avail_desc.addr.checked_add(offset).unwrap();
+                let stat: VirtioBalloonStat = mem.read_obj(addr).map_err(Error::GuestMemory)?;
+                offset += stat_size as u64;
+
+                // Unknown tags come from newer guests and are ignored.
+                if let Some(name) = stat_name(stat.tag) {
+                    stats.insert(name, Wrapping(stat.val));
+                }
+            }
+
+            // The guest should only ever hand over a single buffer, but
+            // never hold on to more than one.
+            if let Some(index) = self.stats_desc_index.replace(avail_desc.index) {
+                stale_desc_index = Some(index);
+            }
+        }
+
+        if let Some(index) = stale_desc_index {
+            self.queues[STATS_QUEUE_INDEX].add_used(&mem, index, 0);
+            self.signal(
+                &VirtioInterruptType::Queue,
+                Some(&self.queues[STATS_QUEUE_INDEX]),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn request_stats(&mut self) -> result::Result<(), Error> {
+        // Returning the buffer to the guest asks it for new statistics,
+        // which come back through the statistics queue.
+        if let Some(index) = self.stats_desc_index.take() {
+            let mem = self.mem.memory();
+            self.queues[STATS_QUEUE_INDEX].add_used(&mem, index, 0);
+            self.signal(
+                &VirtioInterruptType::Queue,
+                Some(&self.queues[STATS_QUEUE_INDEX]),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Give the backing memory of a free guest range back to the host. The
+    // range can span several memory regions. Failing to release the memory
+    // is logged and doesn't prevent releasing the rest of the range.
+    fn release_memory_range(
+        mem: &GuestMemoryMmap,
+        mut addr: GuestAddress,
+        mut len: u64,
+    ) -> result::Result<(), Error> {
+        while len > 0 {
+            let region = mem.find_region(addr).ok_or_else(|| {
+                error!("Address 0x{:x} is not available", addr.0);
+                Error::InvalidRequest
+            })?;
+            let offset = addr.0 - region.start_addr().0;
+            let chunk_len = std::cmp::min(len, region.len() - offset);
+
+            // Dropping the pages from a shared mapping leaves them in the
+            // backing file, hence the hole punching. A private mapping only
+            // needs its own pages to be dropped, and its backing file, such
+            // as a snapshot, must be left untouched.
+            if region.flags() & libc::MAP_SHARED != 0 {
+                if let Some(file_offset) = region.file_offset() {
+                    let res = unsafe {
+                        libc::fallocate64(
+                            file_offset.file().as_raw_fd(),
+                            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                            (file_offset.start() + offset) as libc::off64_t,
+                            chunk_len as libc::off64_t,
+                        )
+                    };
+                    if res != 0 {
+                        error!(
+                            "Failed punching hole at 0x{:x}: {:?}",
+                            addr.0,
+                            Error::FallocateFail(io::Error::last_os_error())
+                        );
+                    }
+                }
+            }
+
+            let hva = mem.get_host_address(addr).map_err(Error::GuestMemory)?;
+            let res = unsafe {
+                libc::madvise(
+                    hva as *mut libc::c_void,
+                    chunk_len as libc::size_t,
+                    libc::MADV_DONTNEED,
+                )
+            };
+            if res != 0 {
+                error!(
+                    "Failed dropping the pages at 0x{:x}: {:?}",
+                    addr.0,
+                    Error::MadviseFail(io::Error::last_os_error())
+                );
+            }
+
+            addr = addr.unchecked_add(chunk_len);
+            len -= chunk_len;
+        }
+
+        Ok(())
+    }
+
+    fn process_reporting_queue(&mut self) -> result::Result<(), Error> {
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in self.queues[self.reporting_queue_index].iter(&mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+
+            // Each descriptor of the chain is a free range reported by the
+            // guest. Failing to release a range only means the host keeps
+            // the memory, so the descriptor is given back to the guest anyway.
+            let mut desc = Some(avail_desc);
+            while let Some(d) = desc {
+                if let Err(e) = Self::release_memory_range(&mem, d.addr, u64::from(d.len)) {
+                    error!(
+                        "Failed releasing free range 0x{:x}-0x{:x}: {:?}",
+                        d.addr.0,
+                        d.addr.0 + u64::from(d.len),
+                        e
+                    );
+                }
+                desc = d.next_descriptor();
+            }
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            self.queues[self.reporting_queue_index].add_used(&mem, desc_index, 0);
+        }
+        if used_count > 0 {
+            self.signal(
+                &VirtioInterruptType::Queue,
+                Some(&self.queues[self.reporting_queue_index]),
+            )?;
+        }
+
+        Ok(())
+    }
+
     fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
@@ -282,6 +518,36 @@ impl BalloonEpollHandler {
         )
         .map_err(DeviceError::EpollCtl)?;
 
+        if let Some(stats_queue_evt) = &self.stats_queue_evt {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                stats_queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(STATS_QUEUE_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        if let Some(stats_timer) = &self.stats_timer {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                stats_timer.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(STATS_TIMER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        if let Some(reporting_queue_evt) = &self.reporting_queue_evt {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                reporting_queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(REPORTING_QUEUE_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
@@ -366,6 +632,45 @@ impl BalloonEpollHandler {
                             )));
                         }
                     }
+                    STATS_QUEUE_EVENT => {
+                        if let Err(e) = self.stats_queue_evt.as_ref().unwrap().read() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to get statistics queue event: {:?}",
+                                e
+                            )));
+                        } else if let Err(e) = self.process_stats_queue() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to process statistics queue: {:?}",
+                                e
+                            )));
+                        }
+                    }
+                    STATS_TIMER_EVENT => {
+                        if let Err(e) = self.stats_timer.as_mut().unwrap().wait() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to get statistics timer event: {:?}",
+                                e
+                            )));
+                        } else if let Err(e) = self.request_stats() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to request statistics: {:?}",
+                                e
+                            )));
+                        }
+                    }
+                    REPORTING_QUEUE_EVENT => {
+                        if let Err(e) = self.reporting_queue_evt.as_ref().unwrap().read() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to get reporting queue event: {:?}",
+                                e
+                            )));
+                        } else if let Err(e) = self.process_reporting_queue() {
+                            return Err(DeviceError::EpollHander(format!(
+                                "Failed to process reporting queue: {:?}",
+                                e
+                            )));
+                        }
+                    }
                     KILL_EVENT => {
                         debug!("kill_evt received, stopping epoll loop");
                         break 'epoll;
@@ -401,6 +706,10 @@ pub struct Balloon {
     avail_features: u64,
     pub acked_features: u64,
     config: Arc<Mutex<VirtioBalloonConfig>>,
+    queue_sizes: Vec<u16>,
+    stats_interval: Option<u64>,
+    deflate_on_oom: bool,
+    stats: Arc<Mutex<HashMap<&'static str, Wrapping<u64>>>>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
@@ -408,9 +717,30 @@ pub struct Balloon {
 }
 
 impl Balloon {
-    // Create a new virtio-balloon.
-    pub fn new(id: String, size: u64) -> io::Result<Self> {
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1;
+    // Create a new virtio-balloon, polling the guest statistics every
+    // stats_interval seconds if set, letting the guest report its free
+    // pages if free_page_reporting is set, and letting the guest deflate
+    // the balloon when running out of memory if deflate_on_oom is set.
+    pub fn new(
+        id: String,
+        size: u64,
+        stats_interval: Option<u64>,
+        free_page_reporting: bool,
+        deflate_on_oom: bool,
+    ) -> io::Result<Self> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        if deflate_on_oom {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        let mut queue_sizes = vec![QUEUE_SIZE; MIN_NUM_QUEUES];
+        if stats_interval.is_some() {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+            queue_sizes.push(QUEUE_SIZE);
+        }
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+            queue_sizes.push(QUEUE_SIZE);
+        }
 
         let mut config = VirtioBalloonConfig::default();
         config.num_pages = (size >> PAGE_SHIFT) as u32;
@@ -423,6 +753,10 @@ impl Balloon {
             avail_features,
             acked_features: 0u64,
             config: Arc::new(Mutex::new(config)),
+            queue_sizes,
+            stats_interval,
+            deflate_on_oom,
+            stats: Arc::new(Mutex::new(HashMap::new())),
             queue_evts: None,
             interrupt_cb: None,
             epoll_threads: None,
@@ -433,6 +767,12 @@ impl Balloon {
     pub fn resize(&self, size: u64) -> Result<(), Error> {
         self.resize.work(size)
     }
+
+    // Get the requested size of the balloon, lowered whenever the guest
+    // deflates it on its own.
+    pub fn get_size(&self) -> u64 {
+        (self.config.lock().unwrap().num_pages as u64) << PAGE_SHIFT
+    }
 }
 
 impl Drop for Balloon {
@@ -450,7 +790,7 @@ impl VirtioDevice for Balloon {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self) -> u64 {
@@ -474,6 +814,32 @@ impl VirtioDevice for Balloon {
         self.read_config_from_slice(self.config.lock().unwrap().as_slice(), offset, data);
     }
 
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The "actual" field is the only mutable field
+        if offset != CONFIG_ACTUAL_OFFSET || data.len() != size_of::<u32>() {
+            error!(
+                "Attempt to write to read-only field: offset {:x} length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let mut actual = [0u8; 4];
+        actual.copy_from_slice(data);
+        let actual = u32::from_le_bytes(actual);
+
+        let mut config = self.config.lock().unwrap();
+        // The guest shrinking the balloon below the requested size means it
+        // deflated it on its own to recover from running out of memory. The
+        // requested size follows, so that it reflects the current balloon
+        // and the host does not inflate it again.
+        if self.deflate_on_oom && actual < config.actual && actual < config.num_pages {
+            config.num_pages = actual;
+        }
+        config.actual = actual;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -481,10 +847,10 @@ impl VirtioDevice for Balloon {
         queues: Vec<Queue>,
         mut queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                self.queue_sizes.len(),
                 queues.len()
             );
             return Err(ActivateError::BadActivate);
@@ -519,6 +885,24 @@ impl VirtioDevice for Balloon {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
+        let stats_vq = self.acked_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0;
+        let reporting = self.acked_features & (1u64 << VIRTIO_BALLOON_F_REPORTING) != 0;
+        let stats_timer = match self.stats_interval {
+            Some(stats_interval) if stats_vq => {
+                let mut timer = TimerFd::new().map_err(|e| {
+                    error!("failed to create statistics timer: {}", e);
+                    ActivateError::BadActivate
+                })?;
+                let interval = Duration::from_secs(stats_interval);
+                timer.reset(interval, Some(interval)).map_err(|e| {
+                    error!("failed to arm statistics timer: {}", e);
+                    ActivateError::BadActivate
+                })?;
+                Some(timer)
+            }
+            _ => None,
+        };
+
         let mut handler = BalloonEpollHandler {
             config: self.config.clone(),
             resize_receiver: self.resize.get_receiver().map_err(|e| {
@@ -530,6 +914,20 @@ impl VirtioDevice for Balloon {
             interrupt_cb,
             inflate_queue_evt: queue_evts.remove(0),
             deflate_queue_evt: queue_evts.remove(0),
+            stats_queue_evt: if stats_vq {
+                Some(queue_evts.remove(0))
+            } else {
+                None
+            },
+            stats_timer,
+            stats_desc_index: None,
+            stats: self.stats.clone(),
+            reporting_queue_evt: if reporting {
+                Some(queue_evts.remove(0))
+            } else {
+                None
+            },
+            reporting_queue_index: STATS_QUEUE_INDEX + stats_vq as usize,
             kill_evt,
             pause_evt,
         };
@@ -566,6 +964,14 @@ impl VirtioDevice for Balloon {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        if self.stats_interval.is_none() {
+            return None;
+        }
+
+        Some(self.stats.lock().unwrap().clone())
+    }
 }
 
 virtio_pausable!(Balloon);
@@ -573,6 +979,259 @@ impl Snapshottable for Balloon {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "virtio-balloon devices cannot be snapshotted"
+        )))
+    }
 }
 impl Transportable for Balloon {}
 impl Migratable for Balloon {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    const MEM_SIZE: usize = 0x100_0000;
+    const GUEST_QUEUE_SIZE: u16 = 16;
+    const STATS_ADDR: u64 = 0x80_0000;
+    const REPORTED_ADDR: u64 = 0x90_0000;
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    fn create_guest_queues(mem: &GuestMemoryMmap, count: usize) -> Vec<GuestQ> {
+        (0..count)
+            .map(|i| {
+                GuestQ::new(
+                    GuestAddress(0x10_0000 * (i as u64 + 1)),
+                    mem,
+                    GUEST_QUEUE_SIZE,
+                )
+            })
+            .collect()
+    }
+
+    // Activates the balloon once the driver acked the given features, with
+    // the queues it set up, returning their EventFds.
+    fn activate_balloon(
+        balloon: &mut Balloon,
+        mem: &GuestMemoryMmap,
+        guest_queues: &[GuestQ],
+        acked_features: u64,
+    ) -> Vec<EventFd> {
+        balloon.ack_features(acked_features);
+        let queue_evts: Vec<EventFd> = guest_queues
+            .iter()
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect();
+        balloon
+            .activate(
+                GuestMemoryAtomic::new(mem.clone()),
+                Arc::new(NoopVirtioInterrupt {}),
+                guest_queues.iter().map(|q| q.create_queue()).collect(),
+                queue_evts.iter().map(|e| e.try_clone().unwrap()).collect(),
+            )
+            .unwrap();
+        queue_evts
+    }
+
+    // Hands the guest statistics over to the device through the given queue.
+    fn send_stats(mem: &GuestMemoryMmap, guest_queue: &GuestQ, queue_evt: &EventFd) {
+        let stat = VirtioBalloonStat {
+            tag: VIRTIO_BALLOON_S_MEMFREE,
+            val: 0x1000,
+        };
+        mem.write_obj(stat, GuestAddress(STATS_ADDR)).unwrap();
+        guest_queue.dtable[0].set(STATS_ADDR, size_of::<VirtioBalloonStat>() as u32, 0, 0);
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.idx.set(1);
+        queue_evt.write(1).unwrap();
+    }
+
+    // Reports a free page, filled with data until the device drops it, to
+    // the device through the given queue.
+    fn report_free_page(mem: &GuestMemoryMmap, guest_queue: &GuestQ, queue_evt: &EventFd) {
+        mem.write_slice(&[0xaa; 0x1000], GuestAddress(REPORTED_ADDR))
+            .unwrap();
+        guest_queue.dtable[0].set(REPORTED_ADDR, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.idx.set(1);
+        queue_evt.write(1).unwrap();
+    }
+
+    // Gives the device thread some time to process its queues.
+    fn wait_until<F: Fn() -> bool>(condition: F) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_stats_queue() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+
+        // The statistics queue follows the inflate and deflate queues,
+        // whether free page reporting is acked or not.
+        for reporting in [0, 1u64 << VIRTIO_BALLOON_F_REPORTING].iter() {
+            let mut balloon =
+                Balloon::new(String::from("balloon"), 0, Some(3600), true, false).unwrap();
+            let guest_queues = create_guest_queues(&mem, balloon.queue_max_sizes().len());
+            let queue_evts = activate_balloon(
+                &mut balloon,
+                &mem,
+                &guest_queues,
+                1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_BALLOON_F_STATS_VQ | reporting,
+            );
+
+            send_stats(
+                &mem,
+                &guest_queues[STATS_QUEUE_INDEX],
+                &queue_evts[STATS_QUEUE_INDEX],
+            );
+            let free_memory = || balloon.counters().unwrap().get("free_memory").cloned();
+            assert!(wait_until(|| free_memory() == Some(Wrapping(0x1000))));
+        }
+    }
+
+    #[test]
+    fn test_stats_queue_not_acked() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+
+        // The statistics queue is offered, but the driver did not set it up.
+        let mut balloon =
+            Balloon::new(String::from("balloon"), 0, Some(3600), false, false).unwrap();
+        let guest_queues = create_guest_queues(&mem, balloon.queue_max_sizes().len());
+        let queue_evts = activate_balloon(
+            &mut balloon,
+            &mem,
+            &guest_queues,
+            1u64 << VIRTIO_F_VERSION_1,
+        );
+
+        send_stats(
+            &mem,
+            &guest_queues[STATS_QUEUE_INDEX],
+            &queue_evts[STATS_QUEUE_INDEX],
+        );
+        // Nothing processes the queue.
+        thread::sleep(Duration::from_millis(100));
+        assert!(balloon.counters().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reporting_queue() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+
+        // The reporting queue comes right after the statistics queue if the
+        // driver acked it, or takes its place otherwise.
+        for (stats_vq, reporting_queue_index) in [
+            (0, STATS_QUEUE_INDEX),
+            (1u64 << VIRTIO_BALLOON_F_STATS_VQ, STATS_QUEUE_INDEX + 1),
+        ]
+        .iter()
+        {
+            let mut balloon =
+                Balloon::new(String::from("balloon"), 0, Some(3600), true, false).unwrap();
+            let guest_queues = create_guest_queues(&mem, balloon.queue_max_sizes().len());
+            let queue_evts = activate_balloon(
+                &mut balloon,
+                &mem,
+                &guest_queues,
+                1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_BALLOON_F_REPORTING | stats_vq,
+            );
+
+            let guest_queue = &guest_queues[*reporting_queue_index];
+            report_free_page(&mem, guest_queue, &queue_evts[*reporting_queue_index]);
+            assert!(wait_until(|| guest_queue.used.idx.get() == 1));
+            let page: u8 = mem.read_obj(GuestAddress(REPORTED_ADDR)).unwrap();
+            assert_eq!(page, 0);
+        }
+    }
+
+    #[test]
+    fn test_reporting_queue_not_acked() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+
+        // Free page reporting is offered, but the driver did not set it up.
+        let mut balloon = Balloon::new(String::from("balloon"), 0, None, true, false).unwrap();
+        let guest_queues = create_guest_queues(&mem, balloon.queue_max_sizes().len());
+        let queue_evts = activate_balloon(
+            &mut balloon,
+            &mem,
+            &guest_queues,
+            1u64 << VIRTIO_F_VERSION_1,
+        );
+
+        report_free_page(&mem, &guest_queues[2], &queue_evts[2]);
+        // Nothing processes the queue.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(guest_queues[2].used.idx.get(), 0);
+        let page: u8 = mem.read_obj(GuestAddress(REPORTED_ADDR)).unwrap();
+        assert_eq!(page, 0xaa);
+    }
+
+    #[test]
+    fn test_deflate_on_oom() {
+        let write_actual = |balloon: &mut Balloon, pages: u32| {
+            balloon.write_config(CONFIG_ACTUAL_OFFSET, &pages.to_le_bytes());
+        };
+
+        for deflate_on_oom in [false, true].iter() {
+            let mut balloon = Balloon::new(
+                String::from("balloon"),
+                256 << PAGE_SHIFT,
+                None,
+                false,
+                *deflate_on_oom,
+            )
+            .unwrap();
+
+            // Inflating up to the requested size.
+            write_actual(&mut balloon, 128);
+            write_actual(&mut balloon, 256);
+            assert_eq!(balloon.get_size(), 256 << PAGE_SHIFT);
+
+            // Deflating on its own lowers the requested size, only if the
+            // driver is allowed to.
+            write_actual(&mut balloon, 200);
+            let expected_pages = if *deflate_on_oom { 200 } else { 256 };
+            assert_eq!(balloon.get_size(), expected_pages << PAGE_SHIFT);
+
+            // The requested size is only written by the VMM.
+            balloon.write_config(0, &16u32.to_le_bytes());
+            assert_eq!(balloon.get_size(), expected_pages << PAGE_SHIFT);
+        }
+
+        // Deflating after the VMM lowered the requested size is not the
+        // guest running out of memory.
+        let mut balloon = Balloon::new(
+            String::from("balloon"),
+            256 << PAGE_SHIFT,
+            None,
+            false,
+            true,
+        )
+        .unwrap();
+        write_actual(&mut balloon, 256);
+        balloon.config.lock().unwrap().num_pages = 100;
+        write_actual(&mut balloon, 150);
+        assert_eq!(balloon.get_size(), 100 << PAGE_SHIFT);
+    }
+}
@@ -10,13 +10,17 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    create_rate_limiter, ActivateError, ActivateResult, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Queue, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
+    EPOLL_HELPER_EVENT_LAST,
 };
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
-use block_util::{build_disk_image_id, Request, RequestType, VirtioBlockConfig};
+use block_util::{
+    build_disk_image_id, Request, RequestType, VirtioBlockConfig, MAX_DISCARD_WRITE_ZEROES_SECTORS,
+};
 use libc::EFD_NONBLOCK;
+use rate_limiter::{RateLimiter, RateLimiterConfig};
 use std::collections::HashMap;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::num::Wrapping;
@@ -38,12 +42,17 @@ use vm_migration::{
     Transportable,
 };
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::PunchHole;
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The rate limiter timer fired.
+const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// The rate limiter can accept new requests.
+const RATE_LIMITER_UNBLOCKED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 
 #[derive(Debug)]
 pub enum Error {
@@ -65,15 +74,15 @@ pub enum Error {
     InvalidOffset,
 }
 
-pub trait DiskFile: Read + Seek + Write + Clone {}
-impl<D: Read + Seek + Write + Clone> DiskFile for D {}
+pub trait DiskFile: Read + Seek + Write + PunchHole + Clone {}
+impl<D: Read + Seek + Write + PunchHole + Clone> DiskFile for D {}
 
 #[derive(Default, Clone)]
 pub struct BlockCounters {
-    read_bytes: Arc<AtomicU64>,
-    read_ops: Arc<AtomicU64>,
-    write_bytes: Arc<AtomicU64>,
-    write_ops: Arc<AtomicU64>,
+    pub(crate) read_bytes: Arc<AtomicU64>,
+    pub(crate) read_ops: Arc<AtomicU64>,
+    pub(crate) write_bytes: Arc<AtomicU64>,
+    pub(crate) write_ops: Arc<AtomicU64>,
 }
 
 struct BlockEpollHandler<T: DiskFile> {
@@ -89,6 +98,8 @@ struct BlockEpollHandler<T: DiskFile> {
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     queue_evt: EventFd,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    rate_limiter_unblocked_evt: EventFd,
 }
 
 impl<T: DiskFile> BlockEpollHandler<T> {
@@ -102,11 +113,20 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         let mut write_bytes = Wrapping(0);
         let mut read_ops = Wrapping(0);
         let mut write_ops = Wrapping(0);
+        let mut rate_limited = false;
 
         for avail_desc in queue.iter(&mem) {
             let len;
             match Request::parse(&avail_desc, &mem) {
                 Ok(mut request) => {
+                    if !request.consume_rate_limiter_tokens(&mut self.rate_limiter.lock().unwrap())
+                    {
+                        // The request is processed again once the rate
+                        // limiter is unblocked.
+                        rate_limited = true;
+                        break;
+                    }
+
                     request.set_writeback(self.writeback.load(Ordering::SeqCst));
 
                     let mut disk_image_locked = self.disk_image.lock().unwrap();
@@ -151,6 +171,10 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             used_count += 1;
         }
 
+        if rate_limited {
+            queue.go_to_previous_position();
+        }
+
         for &(desc_index, len) in used_desc_heads.iter() {
             queue.add_used(&mem, desc_index, len);
         }
@@ -181,6 +205,29 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             })
     }
 
+    fn process_queue_and_signal(&mut self) -> result::Result<(), DeviceError> {
+        if self.event_idx {
+            // vm-virtio's Queue implementation only checks avail_index
+            // once, so to properly support EVENT_IDX we need to keep
+            // calling process_queue() until it stops finding new
+            // requests on the queue.
+            while self.process_queue() {
+                self.queue.update_avail_event(&self.mem.memory());
+
+                if self
+                    .queue
+                    .needs_notification(&self.mem.memory(), self.queue.next_used)
+                {
+                    self.signal_used_queue()?;
+                }
+            }
+        } else if self.process_queue() {
+            self.signal_used_queue()?;
+        }
+
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn update_disk_image(
         &mut self,
@@ -199,6 +246,12 @@ impl<T: DiskFile> BlockEpollHandler<T> {
     fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        let rate_limiter_fd = self.rate_limiter.lock().unwrap().as_raw_fd();
+        helper.add_event(rate_limiter_fd, RATE_LIMITER_EVENT)?;
+        helper.add_event(
+            self.rate_limiter_unblocked_evt.as_raw_fd(),
+            RATE_LIMITER_UNBLOCKED_EVENT,
+        )?;
         helper.run(paused, self)?;
 
         Ok(())
@@ -212,33 +265,27 @@ impl<T: DiskFile> EpollHelperHandler for BlockEpollHandler<T> {
                 if let Err(e) = self.queue_evt.read() {
                     error!("Failed to get queue event: {:?}", e);
                     return true;
-                } else if self.event_idx {
-                    // vm-virtio's Queue implementation only checks avail_index
-                    // once, so to properly support EVENT_IDX we need to keep
-                    // calling process_queue() until it stops finding new
-                    // requests on the queue.
-                    loop {
-                        if self.process_queue() {
-                            self.queue.update_avail_event(&self.mem.memory());
-
-                            if self
-                                .queue
-                                .needs_notification(&self.mem.memory(), self.queue.next_used)
-                            {
-                                if let Err(e) = self.signal_used_queue() {
-                                    error!("Failed to signal used queue: {:?}", e);
-                                    return true;
-                                }
-                            }
-                        } else {
-                            break;
-                        }
-                    }
-                } else if self.process_queue() {
-                    if let Err(e) = self.signal_used_queue() {
-                        error!("Failed to signal used queue: {:?}", e);
-                        return true;
-                    }
+                } else if let Err(e) = self.process_queue_and_signal() {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
+            }
+            RATE_LIMITER_EVENT => {
+                if let Err(e) = self.rate_limiter.lock().unwrap().event_handler() {
+                    error!("Failed to get rate limiter event: {:?}", e);
+                    return true;
+                }
+            }
+            RATE_LIMITER_UNBLOCKED_EVENT => {
+                if let Err(e) = self.rate_limiter_unblocked_evt.read() {
+                    error!("Failed to get rate limiter unblocked event: {:?}", e);
+                    return true;
+                }
+
+                // Process the requests deferred by the rate limiter.
+                if let Err(e) = self.process_queue_and_signal() {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
                 }
             }
             _ => {
@@ -250,24 +297,26 @@ impl<T: DiskFile> EpollHelperHandler for BlockEpollHandler<T> {
     }
 }
 
-/// Virtio device for exposing block level read/write operations on a host file.
-pub struct Block<T: DiskFile> {
-    id: String,
+/// Device state, configuration and lifecycle shared by the virtio-blk
+/// devices, whichever way they process the requests.
+pub(crate) struct BlockCommon {
+    pub(crate) id: String,
     kill_evt: Option<EventFd>,
-    disk_image: Arc<Mutex<T>>,
-    disk_path: PathBuf,
-    disk_nsectors: u64,
+    pub(crate) disk_path: PathBuf,
+    pub(crate) disk_nsectors: u64,
     avail_features: u64,
     acked_features: u64,
     config: VirtioBlockConfig,
     queue_evts: Option<Vec<EventFd>>,
-    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
-    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>>,
+    pub(crate) interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    pub(crate) epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), EpollHelperError>>>>,
     pause_evt: Option<EventFd>,
-    paused: Arc<AtomicBool>,
-    queue_size: Vec<u16>,
-    writeback: Arc<AtomicBool>,
-    counters: BlockCounters,
+    pub(crate) paused: Arc<AtomicBool>,
+    pub(crate) queue_size: Vec<u16>,
+    pub(crate) writeback: Arc<AtomicBool>,
+    pub(crate) counters: BlockCounters,
+    pub(crate) rate_limiter: Arc<Mutex<RateLimiter>>,
+    pub(crate) rate_limiter_unblocked_evts: Vec<EventFd>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -279,20 +328,18 @@ pub struct BlockState {
     pub config: VirtioBlockConfig,
 }
 
-impl<T: DiskFile> Block<T> {
-    /// Create a new virtio block device that operates on the given file.
-    ///
-    /// The given file must be seekable and sizable.
-    pub fn new(
+impl BlockCommon {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
         id: String,
-        mut disk_image: T,
+        disk_size: u64,
         disk_path: PathBuf,
         is_disk_read_only: bool,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
-    ) -> io::Result<Block<T>> {
-        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> io::Result<Self> {
         if disk_size % SECTOR_SIZE != 0 {
             warn!(
                 "Disk size {} is not a multiple of sector size {}; \
@@ -310,10 +357,6 @@ impl<T: DiskFile> Block<T> {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
-        if is_disk_read_only {
-            avail_features |= 1u64 << VIRTIO_BLK_F_RO;
-        }
-
         let disk_nsectors = disk_size / SECTOR_SIZE;
         let mut config = VirtioBlockConfig {
             capacity: disk_nsectors,
@@ -321,15 +364,29 @@ impl<T: DiskFile> Block<T> {
             ..Default::default()
         };
 
+        if is_disk_read_only {
+            avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+            config.max_discard_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = 1;
+            config.max_write_zeroes_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
+
         if num_queues > 1 {
             avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
             config.num_queues = num_queues as u16;
         }
 
-        Ok(Block {
+        let (rate_limiter, rate_limiter_unblocked_evts) =
+            create_rate_limiter(rate_limiter_config, num_queues)?;
+
+        Ok(BlockCommon {
             id,
             kill_evt: None,
-            disk_image: Arc::new(Mutex::new(disk_image)),
             disk_path,
             disk_nsectors,
             avail_features,
@@ -343,9 +400,15 @@ impl<T: DiskFile> Block<T> {
             queue_size: vec![queue_size; num_queues],
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
+            rate_limiter,
+            rate_limiter_unblocked_evts,
         })
     }
 
+    pub(crate) fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_BLOCK as u32
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.clone(),
@@ -386,31 +449,12 @@ impl<T: DiskFile> Block<T> {
         );
         self.writeback.store(writeback, Ordering::SeqCst);
     }
-}
-
-impl<T: DiskFile> Drop for Block<T> {
-    fn drop(&mut self) {
-        if let Some(kill_evt) = self.kill_evt.take() {
-            // Ignore the result because there is nothing we can do about it.
-            let _ = kill_evt.write(1);
-        }
-    }
-}
 
-impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
-    fn device_type(&self) -> u32 {
-        VirtioDeviceType::TYPE_BLOCK as u32
-    }
-
-    fn queue_max_sizes(&self) -> &[u16] {
-        self.queue_size.as_slice()
-    }
-
-    fn features(&self) -> u64 {
+    pub(crate) fn features(&self) -> u64 {
         self.avail_features
     }
 
-    fn ack_features(&mut self, value: u64) {
+    pub(crate) fn ack_features(&mut self, value: u64) {
         let mut v = value;
         // Check if the guest is ACK'ing a feature that we didn't claim to have.
         let unrequested_features = v & !self.avail_features;
@@ -423,11 +467,15 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         self.acked_features |= v;
     }
 
-    fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    pub(crate) fn event_idx(&self) -> bool {
+        self.acked_features & 1u64 << VIRTIO_RING_F_EVENT_IDX == 1u64 << VIRTIO_RING_F_EVENT_IDX
     }
 
-    fn write_config(&mut self, offset: u64, data: &[u8]) {
+    pub(crate) fn config(&self) -> &[u8] {
+        self.config.as_slice()
+    }
+
+    pub(crate) fn write_config(&mut self, offset: u64, data: &[u8]) {
         // The "writeback" field is the only mutable field
         let writeback_offset =
             (&self.config.writeback as *const _ as u64) - (&self.config as *const _ as u64);
@@ -445,13 +493,13 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         self.update_writeback();
     }
 
-    fn activate(
+    /// Checks the queues handed over to the device, and creates the kill
+    /// and pause events of its epoll threads.
+    pub(crate) fn activate(
         &mut self,
-        mem: GuestMemoryAtomic<GuestMemoryMmap>,
-        interrupt_cb: Arc<dyn VirtioInterrupt>,
-        mut queues: Vec<Queue>,
-        mut queue_evts: Vec<EventFd>,
-    ) -> ActivateResult {
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+    ) -> result::Result<(EventFd, EventFd), ActivateError> {
         if queues.len() != self.queue_size.len() || queue_evts.len() != self.queue_size.len() {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
@@ -478,19 +526,6 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             })?;
         self.pause_evt = Some(self_pause_evt);
 
-        let disk_image_id = build_disk_image_id(&self.disk_path);
-
-        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
-        for queue_evt in queue_evts.iter() {
-            // Save the queue EventFD as we need to return it on reset
-            // but clone it to pass into the thread.
-            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
-                error!("failed to clone queue EventFd: {}", e);
-                ActivateError::BadActivate
-            })?);
-        }
-        self.queue_evts = Some(tmp_queue_evts);
-
         let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
         for queue_evt in queue_evts.iter() {
             // Save the queue EventFD as we need to return it on reset
@@ -502,54 +537,15 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
-        let event_idx = self.acked_features & 1u64 << VIRTIO_RING_F_EVENT_IDX
-            == 1u64 << VIRTIO_RING_F_EVENT_IDX;
         self.update_writeback();
 
-        let mut epoll_threads = Vec::new();
-        for _ in 0..self.queue_size.len() {
-            let queue_evt = queue_evts.remove(0);
-            let mut handler = BlockEpollHandler {
-                queue: queues.remove(0),
-                mem: mem.clone(),
-                disk_image: self.disk_image.clone(),
-                disk_nsectors: self.disk_nsectors,
-                interrupt_cb: interrupt_cb.clone(),
-                disk_image_id: disk_image_id.clone(),
-                kill_evt: kill_evt.try_clone().unwrap(),
-                pause_evt: pause_evt.try_clone().unwrap(),
-                event_idx,
-                writeback: self.writeback.clone(),
-                counters: self.counters.clone(),
-                queue_evt,
-            };
-
-            handler.queue.set_event_idx(event_idx);
-
-            let paused = self.paused.clone();
-            thread::Builder::new()
-                .name("virtio_blk".to_string())
-                .spawn(move || handler.run(paused))
-                .map(|thread| epoll_threads.push(thread))
-                .map_err(|e| {
-                    error!("failed to clone the virtio-blk epoll thread: {}", e);
-                    ActivateError::BadActivate
-                })?;
-        }
-
-        // Save the interrupt EventFD as we need to return it on reset
-        // but clone it to pass into the thread.
-        self.interrupt_cb = Some(interrupt_cb);
-
-        self.epoll_threads = Some(epoll_threads);
-
-        Ok(())
+        Ok((kill_evt, pause_evt))
     }
 
-    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+    pub(crate) fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
         // We first must resume the virtio thread if it was paused.
         if self.pause_evt.take().is_some() {
-            self.resume().ok()?;
+            self.virtio_resume().ok()?;
         }
 
         if let Some(kill_evt) = self.kill_evt.take() {
@@ -564,7 +560,7 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         ))
     }
 
-    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+    pub(crate) fn counters(&self) -> HashMap<&'static str, Wrapping<u64>> {
         let mut counters = HashMap::new();
 
         counters.insert(
@@ -584,31 +580,40 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
         );
 
-        Some(counters)
+        counters
     }
-}
 
-virtio_pausable!(Block, T: 'static + DiskFile + Send);
-impl<T: 'static + DiskFile + Send> Snapshottable for Block<T> {
-    fn id(&self) -> String {
-        self.id.clone()
+    virtio_pausable_trait_inner!();
+
+    pub(crate) fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_pause()
     }
 
-    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+    pub(crate) fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_resume()
+    }
+
+    pub(crate) fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
         let snapshot =
             serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
 
         let mut block_snapshot = Snapshot::new(self.id.as_str());
         block_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: BLOCK_SNAPSHOT_VERSION,
             snapshot,
         });
 
         Ok(block_snapshot)
     }
 
-    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+    pub(crate) fn restore(
+        &mut self,
+        snapshot: Snapshot,
+    ) -> std::result::Result<(), MigratableError> {
         if let Some(block_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            block_section.check_version(BLOCK_SNAPSHOT_VERSION)?;
+
             let block_state = match serde_json::from_slice(&block_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
@@ -629,5 +634,174 @@ impl<T: 'static + DiskFile + Send> Snapshottable for Block<T> {
         )))
     }
 }
+
+impl Drop for BlockCommon {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+/// Virtio device for exposing block level read/write operations on a host file.
+pub struct Block<T: DiskFile> {
+    common: BlockCommon,
+    disk_image: Arc<Mutex<T>>,
+}
+
+impl<T: DiskFile> Block<T> {
+    /// Create a new virtio block device that operates on the given file.
+    ///
+    /// The given file must be seekable and sizable.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: String,
+        mut disk_image: T,
+        disk_path: PathBuf,
+        is_disk_read_only: bool,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> io::Result<Block<T>> {
+        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+
+        Ok(Block {
+            common: BlockCommon::new(
+                id,
+                disk_size,
+                disk_path,
+                is_disk_read_only,
+                iommu,
+                num_queues,
+                queue_size,
+                rate_limiter_config,
+            )?,
+            disk_image: Arc::new(Mutex::new(disk_image)),
+        })
+    }
+
+    /// The rate limiter shared by the queues, which can be updated while
+    /// the device is running.
+    pub fn rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.common.rate_limiter.clone()
+    }
+}
+
+impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
+    fn device_type(&self) -> u32 {
+        self.common.device_type()
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.common.queue_size.as_slice()
+    }
+
+    fn features(&self) -> u64 {
+        self.common.features()
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.common.config(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.common.write_config(offset, data)
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let (kill_evt, pause_evt) = self.common.activate(&queues, &queue_evts)?;
+        let disk_image_id = build_disk_image_id(&self.common.disk_path);
+        let event_idx = self.common.event_idx();
+
+        let mut epoll_threads = Vec::new();
+        for i in 0..self.common.queue_size.len() {
+            let queue_evt = queue_evts.remove(0);
+            let mut handler = BlockEpollHandler {
+                queue: queues.remove(0),
+                mem: mem.clone(),
+                disk_image: self.disk_image.clone(),
+                disk_nsectors: self.common.disk_nsectors,
+                interrupt_cb: interrupt_cb.clone(),
+                disk_image_id: disk_image_id.clone(),
+                kill_evt: kill_evt.try_clone().unwrap(),
+                pause_evt: pause_evt.try_clone().unwrap(),
+                event_idx,
+                writeback: self.common.writeback.clone(),
+                counters: self.common.counters.clone(),
+                queue_evt,
+                rate_limiter: self.common.rate_limiter.clone(),
+                rate_limiter_unblocked_evt: self.common.rate_limiter_unblocked_evts[i]
+                    .try_clone()
+                    .unwrap(),
+            };
+
+            handler.queue.set_event_idx(event_idx);
+
+            let paused = self.common.paused.clone();
+            thread::Builder::new()
+                .name("virtio_blk".to_string())
+                .spawn(move || handler.run(paused))
+                .map(|thread| epoll_threads.push(thread))
+                .map_err(|e| {
+                    error!("failed to clone the virtio-blk epoll thread: {}", e);
+                    ActivateError::BadActivate
+                })?;
+        }
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.common.interrupt_cb = Some(interrupt_cb);
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        self.common.reset()
+    }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        Some(self.common.counters())
+    }
+}
+
+impl<T: 'static + DiskFile + Send> Pausable for Block<T> {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+const BLOCK_SNAPSHOT_VERSION: u16 = 1;
+
+impl<T: 'static + DiskFile + Send> Snapshottable for Block<T> {
+    fn id(&self) -> String {
+        self.common.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        self.common.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        self.common.restore(snapshot)
+    }
+}
 impl<T: 'static + DiskFile + Send> Transportable for Block<T> {}
 impl<T: 'static + DiskFile + Send> Migratable for Block<T> {}
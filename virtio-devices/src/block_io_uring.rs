@@ -0,0 +1,794 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, BlockCounters, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Queue, VirtioDevice, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+};
+use crate::block::BlockCommon;
+use crate::VirtioInterrupt;
+use block_util::{
+    async_io::AsyncIo, async_io::DiskFile, build_disk_image_id, Request, RequestType,
+};
+use rate_limiter::{RateLimiter, RateLimiterConfig};
+use std::collections::HashMap;
+use std::io;
+use std::num::Wrapping;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use virtio_bindings::bindings::virtio_blk::*;
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New completed requests are pending on the io_uring completion queue.
+const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// The rate limiter timer fired.
+const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The rate limiter can accept new requests.
+const RATE_LIMITER_UNBLOCKED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+
+struct BlockIoUringEpollHandler {
+    queue: Queue,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    disk_image: Box<dyn AsyncIo>,
+    disk_nsectors: u64,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    disk_image_id: Vec<u8>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    event_idx: bool,
+    writeback: Arc<AtomicBool>,
+    counters: BlockCounters,
+    queue_evt: EventFd,
+    // Requests submitted to the disk image, indexed by their descriptor.
+    request_list: HashMap<u16, Request>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    rate_limiter_unblocked_evt: EventFd,
+    // Some requests are waiting for room in the submission queue.
+    submission_queue_full: bool,
+    // Tells the device that no request is pending anymore once paused.
+    pause_ack: mpsc::Sender<()>,
+}
+
+impl BlockIoUringEpollHandler {
+    fn process_queue_submit(&mut self) -> bool {
+        let queue = &mut self.queue;
+        let mem = self.mem.memory();
+
+        let mut used_desc_heads = Vec::new();
+        let mut used_count = 0;
+        let mut deferred = false;
+
+        for avail_desc in queue.iter(&mem) {
+            let len;
+            match Request::parse(&avail_desc, &mem) {
+                Ok(mut request) => {
+                    if !request.consume_rate_limiter_tokens(&mut self.rate_limiter.lock().unwrap())
+                    {
+                        // The request is submitted again once the rate
+                        // limiter is unblocked.
+                        deferred = true;
+                        break;
+                    }
+
+                    request.set_writeback(self.writeback.load(Ordering::SeqCst));
+
+                    match request.execute_async(
+                        &mem,
+                        self.disk_nsectors,
+                        self.disk_image.as_mut(),
+                        &self.disk_image_id,
+                        avail_desc.index as u64,
+                    ) {
+                        Ok(true) => {
+                            self.request_list.insert(avail_desc.index, request);
+                            continue;
+                        }
+                        Ok(false) => {
+                            len = request.data_len;
+                            // We use unwrap because the request parsing process
+                            // already checked that the status_addr was valid.
+                            mem.write_obj(VIRTIO_BLK_S_OK, request.status_addr).unwrap();
+                        }
+                        // The request is submitted again once some of the
+                        // pending requests have completed. With none of them
+                        // pending, it could never be submitted.
+                        Err(e) if e.submission_queue_full() && !self.request_list.is_empty() => {
+                            request.replenish_rate_limiter_tokens(
+                                &mut self.rate_limiter.lock().unwrap(),
+                            );
+                            self.submission_queue_full = true;
+                            deferred = true;
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Failed to execute request: {:?}", e);
+                            len = 1; // We need at least 1 byte for the status.
+                            mem.write_obj(e.status(), request.status_addr).unwrap();
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to parse available descriptor chain: {:?}", e);
+                    len = 0;
+                }
+            }
+            used_desc_heads.push((avail_desc.index, len));
+            used_count += 1;
+        }
+
+        if deferred {
+            queue.go_to_previous_position();
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, len);
+        }
+
+        used_count > 0
+    }
+
+    fn process_queue_complete(&mut self) -> bool {
+        let queue = &mut self.queue;
+        let mem = self.mem.memory();
+
+        let mut used_desc_heads = Vec::new();
+        let mut used_count = 0;
+        let mut read_bytes = Wrapping(0);
+        let mut write_bytes = Wrapping(0);
+        let mut read_ops = Wrapping(0);
+        let mut write_ops = Wrapping(0);
+
+        let completion_list = self.disk_image.complete();
+        for (user_data, result) in completion_list {
+            let desc_index = user_data as u16;
+            let request = match self.request_list.remove(&desc_index) {
+                Some(request) => request,
+                None => {
+                    error!("Unknown request completed: {}", desc_index);
+                    continue;
+                }
+            };
+
+            let status = if result < 0 {
+                error!(
+                    "Request failed: {:?}",
+                    io::Error::from_raw_os_error(-result)
+                );
+                VIRTIO_BLK_S_IOERR
+            } else {
+                VIRTIO_BLK_S_OK
+            };
+
+            match request.request_type {
+                RequestType::In => {
+                    read_bytes += Wrapping(request.data_len as u64);
+                    read_ops += Wrapping(1);
+                }
+                RequestType::Out => {
+                    write_bytes += Wrapping(request.data_len as u64);
+                    write_ops += Wrapping(1);
+                }
+                _ => {}
+            }
+
+            let len = if status == VIRTIO_BLK_S_OK {
+                request.data_len
+            } else {
+                1 // We need at least 1 byte for the status.
+            };
+
+            // We use unwrap because the request parsing process already
+            // checked that the status_addr was valid.
+            mem.write_obj(status, request.status_addr).unwrap();
+
+            used_desc_heads.push((desc_index, len));
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, len);
+        }
+
+        self.counters
+            .write_bytes
+            .fetch_add(write_bytes.0, Ordering::AcqRel);
+        self.counters
+            .write_ops
+            .fetch_add(write_ops.0, Ordering::AcqRel);
+
+        self.counters
+            .read_bytes
+            .fetch_add(read_bytes.0, Ordering::AcqRel);
+        self.counters
+            .read_ops
+            .fetch_add(read_ops.0, Ordering::AcqRel);
+
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn notify_used_queue(&mut self) -> bool {
+        if self.event_idx {
+            self.queue.update_avail_event(&self.mem.memory());
+            if !self
+                .queue
+                .needs_notification(&self.mem.memory(), self.queue.next_used)
+            {
+                return false;
+            }
+        }
+
+        if let Err(e) = self.signal_used_queue() {
+            error!("Failed to signal used queue: {:?}", e);
+            return true;
+        }
+
+        false
+    }
+
+    fn process_queue_submit_and_notify(&mut self) -> bool {
+        if self.event_idx {
+            // vm-virtio's Queue implementation only checks avail_index
+            // once, so to properly support EVENT_IDX we need to keep
+            // calling process_queue_submit() until it stops finding
+            // new requests on the queue.
+            while self.process_queue_submit() {
+                if self.notify_used_queue() {
+                    return true;
+                }
+            }
+        } else if self.process_queue_submit() && self.notify_used_queue() {
+            return true;
+        }
+
+        false
+    }
+
+    // Wait for all the requests submitted to the disk image to complete, and
+    // publish them in the used ring.
+    fn complete_pending_requests(&mut self) {
+        let mut completed = false;
+        while !self.request_list.is_empty() {
+            let mut pollfd = libc::pollfd {
+                fd: self.disk_image.notifier().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Safe because the pollfd is valid for the duration of the call.
+            let ret = unsafe { libc::poll(&mut pollfd, 1, -1) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Failed to wait for pending requests: {:?}", e);
+                break;
+            }
+
+            if let Err(e) = self.disk_image.notifier().read() {
+                error!("Failed to get completion event: {:?}", e);
+                break;
+            }
+            completed |= self.process_queue_complete();
+        }
+
+        if completed {
+            self.notify_used_queue();
+        }
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        let rate_limiter_fd = self.rate_limiter.lock().unwrap().as_raw_fd();
+        helper.add_event(rate_limiter_fd, RATE_LIMITER_EVENT)?;
+        helper.add_event(
+            self.rate_limiter_unblocked_evt.as_raw_fd(),
+            RATE_LIMITER_UNBLOCKED_EVENT,
+        )?;
+        helper.run(paused, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for BlockIoUringEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: u16) -> bool {
+        match event {
+            QUEUE_AVAIL_EVENT => {
+                if let Err(e) = self.queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else if self.process_queue_submit_and_notify() {
+                    return true;
+                }
+            }
+            COMPLETION_EVENT => {
+                if let Err(e) = self.disk_image.notifier().read() {
+                    error!("Failed to get completion event: {:?}", e);
+                    return true;
+                }
+
+                if self.process_queue_complete() && self.notify_used_queue() {
+                    return true;
+                }
+
+                // Submit the requests deferred until some room was made in
+                // the submission queue.
+                if self.submission_queue_full {
+                    self.submission_queue_full = false;
+                    if self.process_queue_submit_and_notify() {
+                        return true;
+                    }
+                }
+            }
+            RATE_LIMITER_EVENT => {
+                if let Err(e) = self.rate_limiter.lock().unwrap().event_handler() {
+                    error!("Failed to get rate limiter event: {:?}", e);
+                    return true;
+                }
+            }
+            RATE_LIMITER_UNBLOCKED_EVENT => {
+                if let Err(e) = self.rate_limiter_unblocked_evt.read() {
+                    error!("Failed to get rate limiter unblocked event: {:?}", e);
+                    return true;
+                }
+
+                // Submit the requests deferred by the rate limiter.
+                if self.process_queue_submit_and_notify() {
+                    return true;
+                }
+            }
+            _ => {
+                error!("Unexpected event: {}", event);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn handle_pause(&mut self) {
+        // The requests still in flight would neither be found in the used
+        // ring of a snapshot, nor be submitted again once restored.
+        self.complete_pending_requests();
+        // Nobody waits for the acknowledgement if pausing the device failed.
+        let _ = self.pause_ack.send(());
+    }
+}
+
+/// Virtio device for exposing block level read/write operations on a host
+/// file, relying on io_uring to process the requests asynchronously.
+pub struct BlockIoUring {
+    common: BlockCommon,
+    disk_image: Box<dyn DiskFile>,
+    // One per epoll thread, acknowledging the thread has been paused.
+    pause_acks: Vec<mpsc::Receiver<()>>,
+}
+
+impl BlockIoUring {
+    /// Create a new virtio block device that operates on the given file.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: String,
+        mut disk_image: Box<dyn DiskFile>,
+        disk_path: PathBuf,
+        is_disk_read_only: bool,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> io::Result<Self> {
+        let disk_size = disk_image
+            .size()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+
+        Ok(BlockIoUring {
+            common: BlockCommon::new(
+                id,
+                disk_size,
+                disk_path,
+                is_disk_read_only,
+                iommu,
+                num_queues,
+                queue_size,
+                rate_limiter_config,
+            )?,
+            disk_image,
+            pause_acks: Vec::new(),
+        })
+    }
+
+    /// The rate limiter shared by the queues, which can be updated while
+    /// the device is running.
+    pub fn rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.common.rate_limiter.clone()
+    }
+}
+
+impl VirtioDevice for BlockIoUring {
+    fn device_type(&self) -> u32 {
+        self.common.device_type()
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.common.queue_size.as_slice()
+    }
+
+    fn features(&self) -> u64 {
+        self.common.features()
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.common.config(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.common.write_config(offset, data)
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let (kill_evt, pause_evt) = self.common.activate(&queues, &queue_evts)?;
+        let disk_image_id = build_disk_image_id(&self.common.disk_path);
+        let event_idx = self.common.event_idx();
+
+        let mut epoll_threads = Vec::new();
+        let mut pause_acks = Vec::new();
+        for i in 0..self.common.queue_size.len() {
+            let queue_evt = queue_evts.remove(0);
+            let queue_size = self.common.queue_size[i];
+            // Each queue gets its own io_uring instance, deep enough for
+            // all the descriptors of the queue.
+            let disk_image = self
+                .disk_image
+                .new_async_io(u32::from(queue_size))
+                .map_err(|e| {
+                    error!("failed to create new AsyncIo: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+
+            let (pause_ack, pause_ack_rx) = mpsc::channel();
+            pause_acks.push(pause_ack_rx);
+
+            let mut handler = BlockIoUringEpollHandler {
+                queue: queues.remove(0),
+                mem: mem.clone(),
+                disk_image,
+                disk_nsectors: self.common.disk_nsectors,
+                interrupt_cb: interrupt_cb.clone(),
+                disk_image_id: disk_image_id.clone(),
+                kill_evt: kill_evt.try_clone().unwrap(),
+                pause_evt: pause_evt.try_clone().unwrap(),
+                event_idx,
+                writeback: self.common.writeback.clone(),
+                counters: self.common.counters.clone(),
+                queue_evt,
+                request_list: HashMap::with_capacity(queue_size.into()),
+                rate_limiter: self.common.rate_limiter.clone(),
+                rate_limiter_unblocked_evt: self.common.rate_limiter_unblocked_evts[i]
+                    .try_clone()
+                    .unwrap(),
+                submission_queue_full: false,
+                pause_ack,
+            };
+
+            handler.queue.set_event_idx(event_idx);
+
+            let paused = self.common.paused.clone();
+            thread::Builder::new()
+                .name("virtio_blk_io_uring".to_string())
+                .spawn(move || handler.run(paused))
+                .map(|thread| epoll_threads.push(thread))
+                .map_err(|e| {
+                    error!("failed to clone the virtio-blk epoll thread: {}", e);
+                    ActivateError::BadActivate
+                })?;
+        }
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.common.interrupt_cb = Some(interrupt_cb);
+
+        self.common.epoll_threads = Some(epoll_threads);
+        self.pause_acks = pause_acks;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        self.common.reset()
+    }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        Some(self.common.counters())
+    }
+}
+
+impl Pausable for BlockIoUring {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // The epoll threads don't run while the device is paused.
+        if self.common.paused.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        self.common.pause()?;
+
+        // Each epoll thread publishes its pending requests in the used ring
+        // before being paused, so that they are part of the snapshot. A
+        // thread which already exited doesn't have any pending request.
+        for pause_ack in self.pause_acks.iter() {
+            let _ = pause_ack.recv();
+        }
+
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+// The snapshot is the one of the Block device, so that they can be used
+// interchangeably to restore a VM.
+impl Snapshottable for BlockIoUring {
+    fn id(&self) -> String {
+        self.common.id.clone()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        self.common.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        self.common.restore(snapshot)
+    }
+}
+
+impl Transportable for BlockIoUring {}
+impl Migratable for BlockIoUring {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_util::async_io::{AsyncIoError, AsyncIoResult};
+    use libc::EFD_NONBLOCK;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const MEM_SIZE: usize = 0x100_0000;
+    const GUEST_QUEUE_SIZE: u16 = 16;
+    const REQUESTS_ADDR: u64 = 0x80_0000;
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    // Disk image taking a single request at a time, which completes as
+    // soon as its completion is looked for.
+    struct SingleRequestAsyncIo {
+        notifier: EventFd,
+        pending: Option<u64>,
+    }
+
+    impl SingleRequestAsyncIo {
+        fn submit(&mut self, user_data: u64) -> AsyncIoResult<()> {
+            if self.pending.is_some() {
+                return Err(AsyncIoError::SubmissionQueueFull);
+            }
+
+            self.pending = Some(user_data);
+            self.notifier.write(1).unwrap();
+            Ok(())
+        }
+    }
+
+    impl AsyncIo for SingleRequestAsyncIo {
+        fn notifier(&self) -> &EventFd {
+            &self.notifier
+        }
+
+        fn read_vectored(
+            &mut self,
+            _offset: libc::off_t,
+            _iovecs: Vec<libc::iovec>,
+            user_data: u64,
+        ) -> AsyncIoResult<()> {
+            self.submit(user_data)
+        }
+
+        fn write_vectored(
+            &mut self,
+            _offset: libc::off_t,
+            _iovecs: Vec<libc::iovec>,
+            user_data: u64,
+            _sync: bool,
+        ) -> AsyncIoResult<()> {
+            self.submit(user_data)
+        }
+
+        fn fsync(&mut self, user_data: u64) -> AsyncIoResult<()> {
+            self.submit(user_data)
+        }
+
+        fn punch_hole(&mut self, _offset: u64, _length: u64) -> AsyncIoResult<()> {
+            Ok(())
+        }
+
+        fn write_zeroes(&mut self, _offset: u64, _length: u64, _unmap: bool) -> AsyncIoResult<()> {
+            Ok(())
+        }
+
+        fn complete(&mut self) -> Vec<(u64, i32)> {
+            self.pending
+                .take()
+                .map(|user_data| (user_data, 0))
+                .into_iter()
+                .collect()
+        }
+    }
+
+    // Queues a flush request, made of a header and a status descriptor.
+    fn queue_flush(mem: &GuestMemoryMmap, guest_queue: &GuestQ, index: u16) {
+        let desc_index = 2 * index;
+        let header_addr = REQUESTS_ADDR + 0x1000 * u64::from(index);
+        let status_addr = header_addr + 0x100;
+        mem.write_obj(VIRTIO_BLK_T_FLUSH, GuestAddress(header_addr))
+            .unwrap();
+        mem.write_obj(0xffu8, GuestAddress(status_addr)).unwrap();
+        guest_queue.dtable[desc_index as usize].set(
+            header_addr,
+            16,
+            VIRTQ_DESC_F_NEXT,
+            desc_index + 1,
+        );
+        guest_queue.dtable[desc_index as usize + 1].set(status_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.avail.ring[index as usize].set(desc_index);
+        guest_queue.avail.idx.set(index + 1);
+    }
+
+    fn create_handler(
+        mem: &GuestMemoryMmap,
+        guest_queue: &GuestQ,
+        kill_evt: &EventFd,
+        pause_evt: &EventFd,
+        pause_ack: mpsc::Sender<()>,
+    ) -> BlockIoUringEpollHandler {
+        BlockIoUringEpollHandler {
+            queue: guest_queue.create_queue(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            disk_image: Box::new(SingleRequestAsyncIo {
+                notifier: EventFd::new(EFD_NONBLOCK).unwrap(),
+                pending: None,
+            }),
+            disk_nsectors: 0,
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            disk_image_id: Vec::new(),
+            kill_evt: kill_evt.try_clone().unwrap(),
+            pause_evt: pause_evt.try_clone().unwrap(),
+            event_idx: false,
+            writeback: Arc::new(AtomicBool::new(true)),
+            counters: BlockCounters::default(),
+            queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            request_list: HashMap::new(),
+            rate_limiter: Arc::new(Mutex::new(
+                RateLimiter::new(&RateLimiterConfig::default()).unwrap(),
+            )),
+            rate_limiter_unblocked_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            submission_queue_full: false,
+            pause_ack,
+        }
+    }
+
+    #[test]
+    fn test_submission_queue_full() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0x10_0000), &mem, GUEST_QUEUE_SIZE);
+        let kill_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let pause_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut helper = EpollHelper::new(&kill_evt, &pause_evt).unwrap();
+        let mut handler =
+            create_handler(&mem, &guest_queue, &kill_evt, &pause_evt, mpsc::channel().0);
+
+        queue_flush(&mem, &guest_queue, 0);
+        queue_flush(&mem, &guest_queue, 1);
+
+        // The second request stays on the virtio queue until the first one
+        // completes.
+        assert!(!handler.process_queue_submit());
+        assert!(handler.submission_queue_full);
+        assert_eq!(handler.queue.next_avail, Wrapping(1));
+        assert_eq!(handler.request_list.keys().collect::<Vec<_>>(), vec![&0]);
+
+        assert!(!handler.handle_event(&mut helper, COMPLETION_EVENT));
+        assert!(!handler.submission_queue_full);
+        assert_eq!(handler.queue.next_avail, Wrapping(2));
+        assert_eq!(handler.request_list.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(guest_queue.used.idx.get(), 1);
+        assert_eq!(guest_queue.used.ring[0].get().id, 0);
+
+        assert!(!handler.handle_event(&mut helper, COMPLETION_EVENT));
+        assert!(handler.request_list.is_empty());
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        assert_eq!(guest_queue.used.ring[1].get().id, 2);
+
+        for index in 0..2 {
+            let status: u8 = mem
+                .read_obj(GuestAddress(REQUESTS_ADDR + 0x1000 * index + 0x100))
+                .unwrap();
+            assert_eq!(u32::from(status), VIRTIO_BLK_S_OK);
+        }
+    }
+
+    #[test]
+    fn test_pause_completes_pending_requests() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0x10_0000), &mem, GUEST_QUEUE_SIZE);
+        let kill_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let pause_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (pause_ack, pause_ack_rx) = mpsc::channel();
+        let mut handler = create_handler(&mem, &guest_queue, &kill_evt, &pause_evt, pause_ack);
+
+        queue_flush(&mem, &guest_queue, 0);
+        assert!(!handler.process_queue_submit());
+        assert_eq!(handler.request_list.len(), 1);
+        assert_eq!(guest_queue.used.idx.get(), 0);
+
+        // The request is in the used ring by the time the pause is
+        // acknowledged.
+        handler.handle_pause();
+        assert!(pause_ack_rx.try_recv().is_ok());
+        assert!(handler.request_list.is_empty());
+        assert_eq!(guest_queue.used.idx.get(), 1);
+        assert_eq!(guest_queue.used.ring[0].get().id, 0);
+        let status: u8 = mem.read_obj(GuestAddress(REQUESTS_ADDR + 0x100)).unwrap();
+        assert_eq!(u32::from(status), VIRTIO_BLK_S_OK);
+
+        // Nothing is pending anymore when pausing again.
+        handler.handle_pause();
+        assert!(pause_ack_rx.try_recv().is_ok());
+        assert_eq!(guest_queue.used.idx.get(), 1);
+    }
+}
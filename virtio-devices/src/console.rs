@@ -617,6 +617,9 @@ impl VirtioDevice for Console {
 }
 
 virtio_pausable!(Console);
+
+const CONSOLE_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Console {
     fn id(&self) -> String {
         self.id.clone()
@@ -629,6 +632,7 @@ impl Snapshottable for Console {
         let mut console_snapshot = Snapshot::new(self.id.as_str());
         console_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: CONSOLE_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -637,6 +641,8 @@ impl Snapshottable for Console {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(console_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            console_section.check_version(CONSOLE_SNAPSHOT_VERSION)?;
+
             let console_state = match serde_json::from_slice(&console_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
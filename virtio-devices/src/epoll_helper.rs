@@ -34,6 +34,9 @@ pub const EPOLL_HELPER_EVENT_LAST: u16 = 15;
 pub trait EpollHelperHandler {
     // Return true if execution of the loop should be stopped
     fn handle_event(&mut self, helper: &mut EpollHelper, event: u16) -> bool;
+    // Called before the loop gets paused, letting the handler complete the
+    // work which can't be left pending while the device is paused
+    fn handle_pause(&mut self) {}
 }
 
 impl EpollHelper {
@@ -110,6 +113,8 @@ impl EpollHelper {
                     }
                     EPOLL_HELPER_EVENT_PAUSE => {
                         debug!("PAUSE_EVENT received, pausing epoll loop");
+                        handler.handle_pause();
+
                         // We loop here to handle spurious park() returns.
                         // Until we have not resumed, the paused boolean will
                         // be true.
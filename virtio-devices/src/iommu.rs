@@ -1072,6 +1072,9 @@ impl VirtioDevice for Iommu {
 }
 
 virtio_pausable!(Iommu);
+
+const IOMMU_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Iommu {
     fn id(&self) -> String {
         self.id.clone()
@@ -1084,6 +1087,7 @@ impl Snapshottable for Iommu {
         let mut iommu_snapshot = Snapshot::new(self.id.as_str());
         iommu_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: IOMMU_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -1092,6 +1096,8 @@ impl Snapshottable for Iommu {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(iommu_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            iommu_section.check_version(IOMMU_SNAPSHOT_VERSION)?;
+
             let iommu_state = match serde_json::from_slice(&iommu_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
@@ -25,12 +25,17 @@ extern crate virtio_bindings;
 extern crate vm_device;
 extern crate vm_memory;
 
+use rate_limiter::{RateLimiter, RateLimiterConfig};
 use std::io;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
 
 #[macro_use]
 mod device;
 pub mod balloon;
 pub mod block;
+#[cfg(feature = "io_uring")]
+mod block_io_uring;
 mod console;
 pub mod epoll_helper;
 mod iommu;
@@ -45,6 +50,8 @@ pub mod vsock;
 
 pub use self::balloon::*;
 pub use self::block::*;
+#[cfg(feature = "io_uring")]
+pub use self::block_io_uring::*;
 pub use self::console::*;
 pub use self::device::*;
 pub use self::epoll_helper::*;
@@ -123,3 +130,19 @@ pub enum Error {
     NoMemoryConfigured,
     NetQueuePair(::net_util::NetQueuePairError),
 }
+
+// The queues of a device share a single rate limiter, each of them being
+// notified through its own event once the rate limiter is unblocked.
+pub(crate) fn create_rate_limiter(
+    config: Option<RateLimiterConfig>,
+    num_queues: usize,
+) -> io::Result<(Arc<Mutex<RateLimiter>>, Vec<EventFd>)> {
+    let mut rate_limiter = RateLimiter::new(&config.unwrap_or_default())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    let unblocked_evts = (0..num_queues)
+        .map(|_| rate_limiter.new_unblocked_evt())
+        .collect::<rate_limiter::Result<Vec<EventFd>>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+
+    Ok((Arc::new(Mutex::new(rate_limiter)), unblocked_evts))
+}
@@ -18,6 +18,7 @@ use super::{
     VirtioDeviceType, VIRTIO_F_VERSION_1,
 };
 use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use std::cmp;
 use std::fs::File;
@@ -33,7 +34,7 @@ use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
 };
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 128;
@@ -44,6 +45,9 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 pub const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 512 * 4096;
 const VIRTIO_MEM_USABLE_EXTENT: u64 = 256 * 1024 * 1024;
 
+// The device has a guest NUMA node, exposed through node_id.
+const VIRTIO_MEM_F_ACPI_PXM: u8 = 0;
+
 // Request processed successfully, applicable for
 // - VIRTIO_MEM_REQ_PLUG
 // - VIRTIO_MEM_REQ_UNPLUG
@@ -785,7 +789,12 @@ pub struct Mem {
 
 impl Mem {
     // Create a new virtio-mem device.
-    pub fn new(id: String, region: &Arc<GuestRegionMmap>, resize: Resize) -> io::Result<Mem> {
+    pub fn new(
+        id: String,
+        region: &Arc<GuestRegionMmap>,
+        resize: Resize,
+        numa_node_id: Option<u16>,
+    ) -> io::Result<Mem> {
         let region_len = region.len();
 
         if region_len != region_len / VIRTIO_MEM_DEFAULT_BLOCK_SIZE * VIRTIO_MEM_DEFAULT_BLOCK_SIZE
@@ -799,10 +808,15 @@ impl Mem {
             ));
         }
 
-        // Fixme: Not support VIRTIO_MEM_F_ACPI_PXM
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
         let mut config = VirtioMemConfig::default();
+
+        if let Some(node_id) = numa_node_id {
+            avail_features |= 1u64 << VIRTIO_MEM_F_ACPI_PXM;
+            config.node_id = node_id;
+        }
+
         config.block_size = VIRTIO_MEM_DEFAULT_BLOCK_SIZE;
         config.addr = region.start_addr().raw_value();
         config.region_size = region.len();
@@ -976,6 +990,12 @@ impl Snapshottable for Mem {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "virtio-mem devices cannot be snapshotted"
+        )))
+    }
 }
 impl Transportable for Mem {}
 impl Migratable for Mem {}
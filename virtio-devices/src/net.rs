@@ -11,8 +11,9 @@ use super::net_util::{
 };
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    create_rate_limiter, ActivateError, ActivateResult, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Queue, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
+    EPOLL_HELPER_EVENT_LAST,
 };
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
@@ -20,13 +21,15 @@ use libc::EFD_NONBLOCK;
 use net_util::{
     open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
 };
+use rate_limiter::{RateLimiter, RateLimiterConfig};
 use std::collections::HashMap;
+use std::io;
 use std::net::Ipv4Addr;
 use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
@@ -44,11 +47,21 @@ pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 pub const TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // A frame is available for reading from the tap device to receive in the guest.
 pub const RX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The RX rate limiter timer fired.
+pub const RX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// The TX rate limiter timer fired.
+pub const TX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+// The RX rate limiter can accept new frames.
+pub const RX_RATE_LIMITER_UNBLOCKED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// The TX rate limiter can accept new frames.
+pub const TX_RATE_LIMITER_UNBLOCKED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to open taps.
     OpenTap(OpenTapError),
+    /// Failed to create the rate limiters.
+    CreateRateLimiter(io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -83,6 +96,10 @@ impl NetEpollHandler {
             error!("Failed to get rx queue event: {:?}", e);
         }
 
+        self.resume_rx()
+    }
+
+    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
         if self
             .net
             .resume_rx(&mut self.queue_pair[0])
@@ -103,6 +120,11 @@ impl NetEpollHandler {
         if let Err(e) = queue_evt.read() {
             error!("Failed to get tx queue event: {:?}", e);
         }
+
+        self.process_tx()
+    }
+
+    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
         if self
             .net
             .process_tx(&mut self.queue_pair[1])
@@ -136,6 +158,18 @@ impl NetEpollHandler {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt_pair[0].as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evt_pair[1].as_raw_fd(), TX_QUEUE_EVENT)?;
+        let rx_rate_limiter_fd = self.net.rx_rate_limiter.lock().unwrap().as_raw_fd();
+        helper.add_event(rx_rate_limiter_fd, RX_RATE_LIMITER_EVENT)?;
+        let tx_rate_limiter_fd = self.net.tx_rate_limiter.lock().unwrap().as_raw_fd();
+        helper.add_event(tx_rate_limiter_fd, TX_RATE_LIMITER_EVENT)?;
+        helper.add_event(
+            self.net.rx_rate_limiter_unblocked_evt.as_raw_fd(),
+            RX_RATE_LIMITER_UNBLOCKED_EVENT,
+        )?;
+        helper.add_event(
+            self.net.tx_rate_limiter_unblocked_evt.as_raw_fd(),
+            TX_RATE_LIMITER_UNBLOCKED_EVENT,
+        )?;
 
         // If there are some already available descriptors on the RX queue,
         // then we can start the thread while listening onto the TAP.
@@ -180,6 +214,44 @@ impl EpollHelperHandler for NetEpollHandler {
                     return true;
                 }
             }
+            // The queue pairs are notified through their unblocked events
+            // once the rate limiter is unblocked.
+            RX_RATE_LIMITER_EVENT | TX_RATE_LIMITER_EVENT => {
+                let rate_limiter = if event == RX_RATE_LIMITER_EVENT {
+                    &self.net.rx_rate_limiter
+                } else {
+                    &self.net.tx_rate_limiter
+                };
+                if let Err(e) = rate_limiter.lock().unwrap().event_handler() {
+                    error!("Failed to get rate limiter event: {:?}", e);
+                    return true;
+                }
+            }
+            RX_RATE_LIMITER_UNBLOCKED_EVENT => {
+                if let Err(e) = self.net.rx_rate_limiter_unblocked_evt.read() {
+                    error!("Failed to get RX rate limiter unblocked event: {:?}", e);
+                    return true;
+                }
+
+                // Receive the deferred frame and start reading from the tap
+                // again.
+                if let Err(e) = self.resume_rx() {
+                    error!("Error processing RX queue: {:?}", e);
+                    return true;
+                }
+            }
+            TX_RATE_LIMITER_UNBLOCKED_EVENT => {
+                if let Err(e) = self.net.tx_rate_limiter_unblocked_evt.read() {
+                    error!("Failed to get TX rate limiter unblocked event: {:?}", e);
+                    return true;
+                }
+
+                // Send the frames deferred by the rate limiter.
+                if let Err(e) = self.process_tx() {
+                    error!("Error processing TX queue: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unknown event: {}", event);
                 return true;
@@ -204,6 +276,10 @@ pub struct Net {
     paused: Arc<AtomicBool>,
     queue_size: Vec<u16>,
     counters: NetCounters,
+    rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    rx_rate_limiter_unblocked_evts: Vec<EventFd>,
+    tx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    tx_rate_limiter_unblocked_evts: Vec<EventFd>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -216,6 +292,7 @@ pub struct NetState {
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_tap(
         id: String,
         taps: Vec<Tap>,
@@ -223,6 +300,8 @@ impl Net {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        rx_rate_limiter_config: Option<RateLimiterConfig>,
+        tx_rate_limiter_config: Option<RateLimiterConfig>,
     ) -> Result<Self> {
         let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
@@ -247,6 +326,13 @@ impl Net {
             build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
         }
 
+        let (rx_rate_limiter, rx_rate_limiter_unblocked_evts) =
+            create_rate_limiter(rx_rate_limiter_config, taps.len())
+                .map_err(Error::CreateRateLimiter)?;
+        let (tx_rate_limiter, tx_rate_limiter_unblocked_evts) =
+            create_rate_limiter(tx_rate_limiter_config, taps.len())
+                .map_err(Error::CreateRateLimiter)?;
+
         Ok(Net {
             id,
             kill_evt: None,
@@ -262,6 +348,10 @@ impl Net {
             paused: Arc::new(AtomicBool::new(false)),
             queue_size: vec![queue_size; queue_num],
             counters: NetCounters::default(),
+            rx_rate_limiter,
+            rx_rate_limiter_unblocked_evts,
+            tx_rate_limiter,
+            tx_rate_limiter_unblocked_evts,
         })
     }
 
@@ -278,11 +368,34 @@ impl Net {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        rx_rate_limiter_config: Option<RateLimiterConfig>,
+        tx_rate_limiter_config: Option<RateLimiterConfig>,
     ) -> Result<Self> {
         let taps = open_tap(if_name, ip_addr, netmask, host_mac, num_queues / 2)
             .map_err(Error::OpenTap)?;
 
-        Self::new_with_tap(id, taps, guest_mac, iommu, num_queues, queue_size)
+        Self::new_with_tap(
+            id,
+            taps,
+            guest_mac,
+            iommu,
+            num_queues,
+            queue_size,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
+        )
+    }
+
+    /// The RX rate limiter shared by the queue pairs, which can be updated
+    /// while the device is running.
+    pub fn rx_rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.rx_rate_limiter.clone()
+    }
+
+    /// The TX rate limiter shared by the queue pairs, which can be updated
+    /// while the device is running.
+    pub fn tx_rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.tx_rate_limiter.clone()
     }
 
     fn state(&self) -> NetState {
@@ -417,7 +530,7 @@ impl VirtioDevice for Net {
             let event_idx = self.acked_features & 1 << VIRTIO_RING_F_EVENT_IDX != 0;
 
             let mut epoll_threads = Vec::new();
-            for _ in 0..taps.len() {
+            for i in 0..taps.len() {
                 let rx = RxVirtio::new();
                 let tx = TxVirtio::new();
                 let rx_tap_listening = false;
@@ -442,6 +555,14 @@ impl VirtioDevice for Net {
                         rx_tap_listening,
                         counters: self.counters.clone(),
                         tap_event_id: RX_TAP_EVENT,
+                        rx_rate_limiter: self.rx_rate_limiter.clone(),
+                        tx_rate_limiter: self.tx_rate_limiter.clone(),
+                        rx_rate_limiter_unblocked_evt: self.rx_rate_limiter_unblocked_evts[i]
+                            .try_clone()
+                            .unwrap(),
+                        tx_rate_limiter_unblocked_evt: self.tx_rate_limiter_unblocked_evts[i]
+                            .try_clone()
+                            .unwrap(),
                     },
                     queue_pair,
                     queue_evt_pair,
@@ -512,6 +633,9 @@ impl VirtioDevice for Net {
 }
 
 virtio_ctrl_q_pausable!(Net);
+
+const NET_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Net {
     fn id(&self) -> String {
         self.id.clone()
@@ -524,6 +648,7 @@ impl Snapshottable for Net {
         let mut net_snapshot = Snapshot::new(self.id.as_str());
         net_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: NET_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -532,6 +657,8 @@ impl Snapshottable for Net {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(net_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            net_section.check_version(NET_SNAPSHOT_VERSION)?;
+
             let net_state = match serde_json::from_slice(&net_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
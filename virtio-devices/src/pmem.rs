@@ -570,6 +570,9 @@ impl VirtioDevice for Pmem {
 }
 
 virtio_pausable!(Pmem);
+
+const PMEM_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Pmem {
     fn id(&self) -> String {
         self.id.clone()
@@ -582,6 +585,7 @@ impl Snapshottable for Pmem {
         let mut pmem_snapshot = Snapshot::new(self.id.as_str());
         pmem_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: PMEM_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -590,6 +594,8 @@ impl Snapshottable for Pmem {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(pmem_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            pmem_section.check_version(PMEM_SNAPSHOT_VERSION)?;
+
             let pmem_state = match serde_json::from_slice(&pmem_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
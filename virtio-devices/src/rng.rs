@@ -389,6 +389,9 @@ impl VirtioDevice for Rng {
 }
 
 virtio_pausable!(Rng);
+
+const RNG_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Rng {
     fn id(&self) -> String {
         self.id.clone()
@@ -401,6 +404,7 @@ impl Snapshottable for Rng {
         let mut rng_snapshot = Snapshot::new(self.id.as_str());
         rng_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: RNG_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -409,6 +413,8 @@ impl Snapshottable for Rng {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(rng_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            rng_section.check_version(RNG_SNAPSHOT_VERSION)?;
+
             let rng_state = match serde_json::from_slice(&rng_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
@@ -457,6 +457,8 @@ impl Pausable for MmioDevice {
     }
 }
 
+const VIRTIO_MMIO_DEVICE_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for MmioDevice {
     fn id(&self) -> String {
         self.id.clone()
@@ -469,6 +471,7 @@ impl Snapshottable for MmioDevice {
         let mut virtio_mmio_dev_snapshot = Snapshot::new(self.id.as_str());
         virtio_mmio_dev_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: VIRTIO_MMIO_DEVICE_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -479,6 +482,8 @@ impl Snapshottable for MmioDevice {
         if let Some(virtio_mmio_dev_section) =
             snapshot.snapshot_data.get(&format!("{}-section", self.id))
         {
+            virtio_mmio_dev_section.check_version(VIRTIO_MMIO_DEVICE_SNAPSHOT_VERSION)?;
+
             let virtio_mmio_dev_state =
                 match serde_json::from_slice(&virtio_mmio_dev_section.snapshot) {
                     Ok(state) => state,
@@ -285,6 +285,8 @@ impl VirtioPciCommonConfig {
 
 impl Pausable for VirtioPciCommonConfig {}
 
+const VIRTIO_PCI_COMMON_CONFIG_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for VirtioPciCommonConfig {
     fn id(&self) -> String {
         String::from("virtio_pci_common_config")
@@ -297,6 +299,7 @@ impl Snapshottable for VirtioPciCommonConfig {
         let mut config_snapshot = Snapshot::new(self.id().as_str());
         config_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id()),
+            version: VIRTIO_PCI_COMMON_CONFIG_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -308,6 +311,8 @@ impl Snapshottable for VirtioPciCommonConfig {
             .snapshot_data
             .get(&format!("{}-section", self.id()))
         {
+            config_section.check_version(VIRTIO_PCI_COMMON_CONFIG_SNAPSHOT_VERSION)?;
+
             let config_state = match serde_json::from_slice(&config_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
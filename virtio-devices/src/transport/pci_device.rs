@@ -1035,6 +1035,8 @@ impl Pausable for VirtioPciDevice {
     }
 }
 
+const VIRTIO_PCI_DEVICE_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for VirtioPciDevice {
     fn id(&self) -> String {
         self.id.clone()
@@ -1047,6 +1049,7 @@ impl Snapshottable for VirtioPciDevice {
         let mut virtio_pci_dev_snapshot = Snapshot::new(self.id.as_str());
         virtio_pci_dev_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: VIRTIO_PCI_DEVICE_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -1068,6 +1071,8 @@ impl Snapshottable for VirtioPciDevice {
         if let Some(virtio_pci_dev_section) =
             snapshot.snapshot_data.get(&format!("{}-section", self.id))
         {
+            virtio_pci_dev_section.check_version(VIRTIO_PCI_DEVICE_SNAPSHOT_VERSION)?;
+
             // Restore MSI-X
             if let Some(msix_config) = &self.msix_config {
                 let id = msix_config.lock().unwrap().id();
@@ -7,6 +7,7 @@ use super::vu_common_ctrl::*;
 use super::Error as DeviceError;
 use super::{Error, Result};
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use block_util::VirtioBlockConfig;
 use libc::EFD_NONBLOCK;
 use std::mem;
@@ -24,7 +25,7 @@ use vhost_rs::VhostBackend;
 use virtio_bindings::bindings::virtio_blk::*;
 use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 struct SlaveReqHandler {}
@@ -59,6 +60,8 @@ impl Blk {
             | 1 << VIRTIO_BLK_F_TOPOLOGY
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | 1 << VIRTIO_BLK_F_CONFIG_WCE
+            | 1 << VIRTIO_BLK_F_DISCARD
+            | 1 << VIRTIO_BLK_F_WRITE_ZEROES
             | 1 << VIRTIO_F_VERSION_1
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
@@ -323,6 +326,12 @@ impl Snapshottable for Blk {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "vhost-user-blk devices cannot be snapshotted"
+        )))
+    }
 }
 impl Transportable for Blk {}
 impl Migratable for Blk {}
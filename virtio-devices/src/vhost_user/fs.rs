@@ -9,6 +9,7 @@ use crate::{
     ActivateError, ActivateResult, Queue, UserspaceMapping, VirtioDevice, VirtioDeviceType,
     VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
 };
+use anyhow::anyhow;
 use libc::{self, c_void, off64_t, pread64, pwrite64, EFD_NONBLOCK};
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -28,7 +29,7 @@ use vm_memory::{
     Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryMmap, MmapRegion,
 };
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 const NUM_QUEUE_OFFSET: usize = 1;
@@ -588,6 +589,12 @@ impl Snapshottable for Fs {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "vhost-user-fs devices cannot be snapshotted"
+        )))
+    }
 }
 impl Transportable for Fs {}
 impl Migratable for Fs {}
@@ -11,6 +11,7 @@ use super::vu_common_ctrl::*;
 use super::Error as DeviceError;
 use super::{Error, Result};
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use net_util::MacAddr;
 use std::os::unix::io::AsRawFd;
@@ -25,7 +26,7 @@ use vhost_rs::VhostBackend;
 use virtio_bindings::bindings::virtio_net;
 use virtio_bindings::bindings::virtio_ring;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 const DEFAULT_QUEUE_NUMBER: usize = 2;
@@ -346,6 +347,12 @@ impl Snapshottable for Net {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "vhost-user-net devices cannot be snapshotted"
+        )))
+    }
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
@@ -8,7 +8,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use super::{VsockBackend, VsockPacket};
+use super::defs::uapi;
+use super::{VsockBackend, VsockBackendState, VsockPacket};
 use crate::Error as DeviceError;
 use crate::VirtioInterrupt;
 use crate::{
@@ -45,7 +46,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
@@ -96,6 +97,7 @@ pub struct VsockEpollHandler<B: VsockBackend> {
     pub pause_evt: EventFd,
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub backend: Arc<RwLock<B>>,
+    pub transport_reset: bool,
 }
 
 impl<B> VsockEpollHandler<B>
@@ -198,6 +200,38 @@ where
         }
     }
 
+    /// Notify the guest driver that all its connections must be reset, if
+    /// such a notification is pending. This happens after the device has
+    /// been restored, since the host side of the connections is gone.
+    ///
+    fn process_evt(&mut self) -> result::Result<(), DeviceError> {
+        if !self.transport_reset {
+            return Ok(());
+        }
+
+        let mem = self.mem.memory();
+        let (desc_index, used_len) = match self.queues[2].iter(&mem).next() {
+            Some(avail_desc) => {
+                let event_id = uapi::VSOCK_EVENT_TRANSPORT_RESET;
+                if !avail_desc.is_write_only() || (avail_desc.len as usize) < 4 {
+                    warn!("vsock: invalid event queue descriptor");
+                    (avail_desc.index, 0)
+                } else if let Err(e) = mem.write_obj(event_id, avail_desc.addr) {
+                    warn!("vsock: failed to write transport reset event: {:?}", e);
+                    (avail_desc.index, 0)
+                } else {
+                    self.transport_reset = false;
+                    (avail_desc.index, 4)
+                }
+            }
+            // Wait for the driver to provide an event buffer.
+            None => return Ok(()),
+        };
+
+        self.queues[2].add_used(&mem, desc_index, used_len);
+        self.signal_used_queue(&self.queues[2])
+    }
+
     fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
@@ -261,6 +295,8 @@ where
             thread::park();
         }
 
+        self.process_evt()?;
+
         'epoll: loop {
             let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
                 Ok(res) => res,
@@ -345,6 +381,8 @@ where
                         event_type: "evt queue event",
                         underlying: e,
                     });
+                } else {
+                    self.process_evt()?;
                 }
             }
             BACKEND_EVENT => {
@@ -405,12 +443,15 @@ pub struct Vsock<B: VsockBackend> {
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
     path: PathBuf,
+    transport_reset: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct VsockState {
     pub avail_features: u64,
     pub acked_features: u64,
+    #[serde(default)]
+    pub backend: VsockBackendState,
 }
 
 impl<B> Vsock<B>
@@ -445,6 +486,7 @@ where
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
             path,
+            transport_reset: false,
         })
     }
 
@@ -452,12 +494,17 @@ where
         VsockState {
             avail_features: self.avail_features,
             acked_features: self.acked_features,
+            backend: self.backend.read().unwrap().state(),
         }
     }
 
     fn set_state(&mut self, state: &VsockState) -> io::Result<()> {
         self.avail_features = state.avail_features;
         self.acked_features = state.acked_features;
+        self.backend.write().unwrap().set_state(&state.backend);
+        // The guest may have open connections which can't be restored, it
+        // must be told to reset them once the device is activated.
+        self.transport_reset = true;
 
         Ok(())
     }
@@ -574,7 +621,9 @@ where
             pause_evt,
             interrupt_cb,
             backend: self.backend.clone(),
+            transport_reset: self.transport_reset,
         };
+        self.transport_reset = false;
 
         let paused = self.paused.clone();
         let mut epoll_threads = Vec::new();
@@ -617,6 +666,8 @@ where
 
 virtio_pausable!(Vsock, T: 'static + VsockBackend + Sync);
 
+const VSOCK_SNAPSHOT_VERSION: u16 = 1;
+
 impl<B> Snapshottable for Vsock<B>
 where
     B: VsockBackend + Sync + 'static,
@@ -632,6 +683,7 @@ where
         let mut vsock_snapshot = Snapshot::new(self.id.as_str());
         vsock_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", self.id),
+            version: VSOCK_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -640,6 +692,8 @@ where
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(vsock_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            vsock_section.check_version(VSOCK_SNAPSHOT_VERSION)?;
+
             let vsock_state = match serde_json::from_slice(&vsock_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
@@ -1007,4 +1061,12 @@ mod tests {
             other => panic!("{:?}", other),
         }
     }
+
+    #[test]
+    fn test_restore_without_state() {
+        let mut ctx = TestContext::new();
+
+        assert!(ctx.device.restore(Snapshot::new("vsock")).is_err());
+        assert!(!ctx.device.transport_reset);
+    }
 }
@@ -60,6 +60,12 @@ mod defs {
         pub const VSOCK_TYPE_STREAM: u16 = 1;
 
         pub const VSOCK_HOST_CID: u64 = 2;
+
+        /// Vsock event IDs.
+        /// Defined in `/include/uapi/linux/virtio_vsock.h`.
+        ///
+        /// The communication has been interrupted, all connections must be reset.
+        pub const VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
     }
 }
 
@@ -153,7 +159,27 @@ pub trait VsockChannel {
 /// sendable through a mpsc channel (the latter due to how `vmm::EpollContext` works).
 /// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
 /// translates guest-side vsock connections to host-side Unix domain socket connections.
-pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
+pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {
+    /// Get the backend state to be saved in a snapshot.
+    fn state(&self) -> VsockBackendState {
+        VsockBackendState::default()
+    }
+
+    /// Restore the backend from a saved state.
+    fn set_state(&mut self, _state: &VsockBackendState) {}
+}
+
+/// The saved state of a `VsockBackend`.
+///
+/// The host side of a connection can't be saved, which is why connections are only described
+/// by their ports. They are reset on restore, through a transport reset event sent to the guest.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct VsockBackendState {
+    /// The (local port, peer port) pairs of the connections alive at snapshot time.
+    pub connections: Vec<(u32, u32)>,
+    /// The last host-side port assigned to a host-initiated connection.
+    pub local_port_last: u32,
+}
 
 #[cfg(test)]
 mod tests {
@@ -324,6 +350,7 @@ mod tests {
                     pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
                     interrupt_cb,
                     backend: Arc::new(RwLock::new(TestBackend::new())),
+                    transport_reset: false,
                 },
             }
         }
@@ -41,7 +41,8 @@ use super::super::csm::ConnState;
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
 use super::super::{
-    Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
+    Result as VsockResult, VsockBackend, VsockBackendState, VsockChannel, VsockEpollListener,
+    VsockError,
 };
 use super::defs;
 use super::muxer_killq::MuxerKillQ;
@@ -325,7 +326,32 @@ impl VsockEpollListener for VsockMuxer {
     }
 }
 
-impl VsockBackend for VsockMuxer {}
+impl VsockBackend for VsockMuxer {
+    fn state(&self) -> VsockBackendState {
+        VsockBackendState {
+            connections: self
+                .conn_map
+                .keys()
+                .map(|key| (key.local_port, key.peer_port))
+                .collect(),
+            local_port_last: self.local_port_last,
+        }
+    }
+
+    fn set_state(&mut self, state: &VsockBackendState) {
+        // The connections themselves are not recreated, since the guest is
+        // asked to reset all of them. Host-side ports allocation resumes from
+        // where it was, so that a restored guest doesn't see a new host
+        // connection reusing the port of a connection it just dropped.
+        if !state.connections.is_empty() {
+            info!(
+                "vsock: resetting {} connection(s) from snapshot",
+                state.connections.len()
+            );
+        }
+        self.local_port_last = state.local_port_last;
+    }
+}
 
 impl VsockMuxer {
     /// Muxer constructor.
@@ -1415,4 +1441,23 @@ mod tests {
         // not be any pending RX in the muxer.
         assert!(!ctx.muxer.has_pending_rx());
     }
+
+    #[test]
+    fn test_muxer_state() {
+        let mut ctx = MuxerTestContext::new("muxer_state");
+        let peer_port = 1025;
+        let (_stream, local_port) = ctx.local_connect(peer_port);
+
+        let state = ctx.muxer.state();
+        assert_eq!(state.connections, vec![(local_port, peer_port)]);
+        assert_eq!(state.local_port_last, local_port);
+
+        // Restoring the state doesn't recreate the connections, but the next
+        // host-initiated connection gets a port which hasn't been used yet.
+        let mut other_ctx = MuxerTestContext::new("muxer_state_restore");
+        other_ctx.muxer.set_state(&state);
+        assert!(other_ctx.muxer.conn_map.is_empty());
+        let (_other_stream, other_local_port) = other_ctx.local_connect(peer_port);
+        assert_ne!(other_local_port, local_port);
+    }
 }
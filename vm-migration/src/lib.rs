@@ -8,8 +8,11 @@ extern crate thiserror;
 #[macro_use]
 extern crate serde_derive;
 
+use crate::protocol::MemoryRangeTable;
 use thiserror::Error;
 
+pub mod protocol;
+
 #[derive(Error, Debug)]
 pub enum MigratableError {
     #[error("Failed to pause migratable component: {0}")]
@@ -29,8 +32,38 @@ pub enum MigratableError {
 
     #[error("Failed to receive migratable component snapshot: {0}")]
     MigrateReceive(#[source] anyhow::Error),
+
+    #[error("Socket error: {0}")]
+    MigrateSocket(#[source] std::io::Error),
+
+    #[error("Failed to start dirty log for migratable component: {0}")]
+    StartDirtyLog(#[source] anyhow::Error),
+
+    #[error("Failed to stop dirty log for migratable component: {0}")]
+    StopDirtyLog(#[source] anyhow::Error),
+
+    #[error("Failed to retrieve dirty ranges for migratable component: {0}")]
+    DirtyLog(#[source] anyhow::Error),
+
+    #[error("Unsupported snapshot version {version} for {id}, expecting at most {max_version}")]
+    UnsupportedVersion {
+        id: String,
+        version: u16,
+        max_version: u16,
+    },
+
+    #[error("Snapshot is not compatible with this host: {0}")]
+    IncompatibleHost(#[source] anyhow::Error),
+
+    #[error("Snapshot integrity check failed: {0}")]
+    Integrity(#[source] anyhow::Error),
 }
 
+/// The version of the snapshot format, i.e. of the Snapshot tree itself.
+/// The content of each SnapshotDataSection is versioned separately, by the
+/// component which produced it.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
 /// A Pausable component can be paused and resumed.
 pub trait Pausable {
     /// Pause the component.
@@ -54,10 +87,34 @@ pub struct SnapshotDataSection {
     /// The section id.
     pub id: String,
 
+    /// The version of the section serialized snapshot. Sections taken
+    /// before versioning was introduced are deserialized as version 0.
+    #[serde(default)]
+    pub version: u16,
+
     /// The section serialized snapshot.
     pub snapshot: Vec<u8>,
 }
 
+impl SnapshotDataSection {
+    /// Check the section can be restored by a component which supports
+    /// versions up to `max_version`, and return the version to restore.
+    /// Unversioned sections share the layout of version 1, which is why
+    /// they are upgraded to it.
+    pub fn check_version(&self, max_version: u16) -> Result<u16, MigratableError> {
+        let version = std::cmp::max(self.version, 1);
+        if version > max_version {
+            return Err(MigratableError::UnsupportedVersion {
+                id: self.id.clone(),
+                version,
+                max_version,
+            });
+        }
+
+        Ok(version)
+    }
+}
+
 /// A Snapshottable component's snapshot is a tree of snapshots, where leafs
 /// contain the snapshot data. Nodes of this tree track all their children
 /// through the snapshots field, which is basically their sub-components.
@@ -74,6 +131,11 @@ pub struct Snapshot {
     /// The Snapshottable component id.
     pub id: String,
 
+    /// The snapshot format version. Snapshots taken before versioning was
+    /// introduced are deserialized as version 0.
+    #[serde(default)]
+    pub version: u16,
+
     /// The Snapshottable component snapshots.
     pub snapshots: std::collections::HashMap<String, Box<Snapshot>>,
 
@@ -87,10 +149,25 @@ impl Snapshot {
     pub fn new(id: &str) -> Self {
         Snapshot {
             id: id.to_string(),
+            version: SNAPSHOT_VERSION,
             ..Default::default()
         }
     }
 
+    /// Check the snapshot format is supported, which is only relevant for
+    /// the root of the snapshot tree.
+    pub fn check_version(&self) -> Result<(), MigratableError> {
+        if self.version > SNAPSHOT_VERSION {
+            return Err(MigratableError::UnsupportedVersion {
+                id: self.id.clone(),
+                version: self.version,
+                max_version: SNAPSHOT_VERSION,
+            });
+        }
+
+        Ok(())
+    }
+
     /// Add a sub-component's Snapshot to the Snapshot.
     pub fn add_snapshot(&mut self, snapshot: Snapshot) {
         self.snapshots
@@ -159,4 +236,63 @@ pub trait Transportable: Pausable + Snapshottable {
 /// and Snapshottable.
 /// Moreover a migratable component can be transported to a remote or local
 /// destination and thus must be Transportable.
-pub trait Migratable: Send + Pausable + Snapshottable + Transportable {}
+pub trait Migratable: Send + Pausable + Snapshottable + Transportable {
+    /// Start tracking the guest memory pages modified by the component.
+    fn start_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    /// Stop tracking the guest memory pages modified by the component.
+    fn stop_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    /// Return the guest memory ranges modified by the component since
+    /// either the dirty log was started or the last call to this method.
+    fn dirty_log(&mut self) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        Ok(MemoryRangeTable::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_version() {
+        let mut section = SnapshotDataSection {
+            id: "device-section".to_string(),
+            version: 0,
+            snapshot: Vec::new(),
+        };
+        // Unversioned sections are restored as version 1.
+        assert_eq!(section.check_version(1).unwrap(), 1);
+
+        section.version = 2;
+        assert_eq!(section.check_version(3).unwrap(), 2);
+        assert!(matches!(
+            section.check_version(1),
+            Err(MigratableError::UnsupportedVersion {
+                version: 2,
+                max_version: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_version() {
+        let mut snapshot = Snapshot::new("vm");
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        assert!(snapshot.check_version().is_ok());
+
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        assert!(snapshot.check_version().is_err());
+
+        // Snapshots taken before versioning still deserialize.
+        let snapshot: Snapshot =
+            serde_json::from_str(r#"{"id":"vm","snapshots":{},"snapshot_data":{}}"#).unwrap();
+        assert_eq!(snapshot.version, 0);
+        assert!(snapshot.check_version().is_ok());
+    }
+}
@@ -0,0 +1,366 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The live migration wire protocol.
+//!
+//! Migrating a VM over a socket is a sequence of requests sent by the
+//! source VMM, each of them acknowledged by a response from the destination
+//! VMM:
+//!
+//! 1. `Start`: Begin a new migration.
+//! 2. `Config`: The payload is the JSON serialized VM configuration, which the
+//!    destination uses to create the VM memory.
+//! 3. `Memory`: The payload is a `MemoryRangeTable`, immediately followed by
+//!    the content of each guest memory range from the table. This request is
+//!    sent once for each pre-copy round, and once more after the VM has been
+//!    paused.
+//! 4. `State`: The payload is the JSON serialized VM `Snapshot`.
+//! 5. `Complete`: The destination VM can be resumed.
+//!
+//! At any point, the source can send `Abandon` to cancel the migration.
+
+use crate::MigratableError;
+use anyhow::anyhow;
+use std::io::{Read, Write};
+
+const HEADER_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u16)]
+pub enum Command {
+    Invalid,
+    Start,
+    Config,
+    State,
+    Memory,
+    Complete,
+    Abandon,
+}
+
+impl From<u16> for Command {
+    fn from(command: u16) -> Self {
+        match command {
+            1 => Command::Start,
+            2 => Command::Config,
+            3 => Command::State,
+            4 => Command::Memory,
+            5 => Command::Complete,
+            6 => Command::Abandon,
+            _ => Command::Invalid,
+        }
+    }
+}
+
+/// A migration request, sent from the source to the destination.
+///
+/// The request header is followed by `length` bytes of command specific
+/// payload.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Request {
+    command: Command,
+    length: u64,
+}
+
+impl Request {
+    pub fn new(command: Command, length: u64) -> Self {
+        Self { command, length }
+    }
+
+    pub fn start() -> Self {
+        Self::new(Command::Start, 0)
+    }
+
+    pub fn config(length: u64) -> Self {
+        Self::new(Command::Config, length)
+    }
+
+    pub fn state(length: u64) -> Self {
+        Self::new(Command::State, length)
+    }
+
+    pub fn memory(length: u64) -> Self {
+        Self::new(Command::Memory, length)
+    }
+
+    pub fn complete() -> Self {
+        Self::new(Command::Complete, 0)
+    }
+
+    pub fn abandon() -> Self {
+        Self::new(Command::Abandon, 0)
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn read_from(fd: &mut dyn Read) -> Result<Request, MigratableError> {
+        let (command, length) = read_header(fd)?;
+
+        Ok(Request {
+            command: Command::from(command),
+            length,
+        })
+    }
+
+    pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
+        write_header(fd, self.command as u16, self.length)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u16)]
+pub enum Status {
+    Invalid,
+    Ok,
+    Error,
+}
+
+impl From<u16> for Status {
+    fn from(status: u16) -> Self {
+        match status {
+            1 => Status::Ok,
+            2 => Status::Error,
+            _ => Status::Invalid,
+        }
+    }
+}
+
+/// A migration response, sent back from the destination to the source for
+/// every request.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Response {
+    status: Status,
+    length: u64,
+}
+
+impl Response {
+    pub fn new(status: Status, length: u64) -> Self {
+        Self { status, length }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(Status::Ok, 0)
+    }
+
+    pub fn error() -> Self {
+        Self::new(Status::Error, 0)
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn read_from(fd: &mut dyn Read) -> Result<Response, MigratableError> {
+        let (status, length) = read_header(fd)?;
+
+        Ok(Response {
+            status: Status::from(status),
+            length,
+        })
+    }
+
+    pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
+        write_header(fd, self.status as u16, self.length)
+    }
+}
+
+// Both requests and responses share the same 16 bytes header layout: a
+// little endian u16 command or status, 6 bytes of padding and a little
+// endian u64 payload length.
+fn read_header(fd: &mut dyn Read) -> Result<(u16, u64), MigratableError> {
+    let mut header = [0u8; HEADER_SIZE];
+    fd.read_exact(&mut header)
+        .map_err(MigratableError::MigrateSocket)?;
+
+    let mut kind = [0u8; 2];
+    kind.copy_from_slice(&header[0..2]);
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&header[8..16]);
+
+    Ok((u16::from_le_bytes(kind), u64::from_le_bytes(length)))
+}
+
+fn write_header(fd: &mut dyn Write, kind: u16, length: u64) -> Result<(), MigratableError> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[0..2].copy_from_slice(&kind.to_le_bytes());
+    header[8..16].copy_from_slice(&length.to_le_bytes());
+
+    fd.write_all(&header)
+        .map_err(MigratableError::MigrateSocket)
+}
+
+/// A guest physical memory range.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryRange {
+    pub gpa: u64,
+    pub length: u64,
+}
+
+impl MemoryRange {
+    const SIZE: usize = 16;
+}
+
+/// A table of guest physical memory ranges.
+#[derive(Clone, Default, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryRangeTable {
+    data: Vec<MemoryRange>,
+}
+
+impl MemoryRangeTable {
+    /// Build a table out of a dirty pages bitmap, where each bit represents
+    /// one page of `page_size` bytes, starting at guest physical address
+    /// `start_addr`. Contiguous dirty pages are merged into a single range.
+    pub fn from_bitmap(bitmap: Vec<u64>, start_addr: u64, page_size: u64) -> Self {
+        let mut table = MemoryRangeTable::default();
+        let mut entry: Option<MemoryRange> = None;
+        for (i, block) in bitmap.iter().enumerate() {
+            for j in 0..64 {
+                let is_page_dirty = ((block >> j) & 1u64) != 0u64;
+                let page_offset = ((i * 64) + j) as u64 * page_size;
+                if is_page_dirty {
+                    if let Some(entry) = &mut entry {
+                        entry.length += page_size;
+                    } else {
+                        entry = Some(MemoryRange {
+                            gpa: start_addr + page_offset,
+                            length: page_size,
+                        });
+                    }
+                } else if let Some(entry) = entry.take() {
+                    table.push(entry);
+                }
+            }
+        }
+        if let Some(entry) = entry.take() {
+            table.push(entry);
+        }
+
+        table
+    }
+
+    pub fn regions(&self) -> &[MemoryRange] {
+        &self.data
+    }
+
+    pub fn push(&mut self, range: MemoryRange) {
+        self.data.push(range)
+    }
+
+    pub fn extend(&mut self, table: Self) {
+        self.data.extend(table.data)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The size of the serialized table, in bytes.
+    pub fn length(&self) -> u64 {
+        (MemoryRange::SIZE * self.data.len()) as u64
+    }
+
+    /// The amount of guest memory covered by the table, in bytes.
+    pub fn effective_size(&self) -> u64 {
+        self.data.iter().map(|r| r.length).sum()
+    }
+
+    pub fn read_from(fd: &mut dyn Read, length: u64) -> Result<MemoryRangeTable, MigratableError> {
+        if length as usize % MemoryRange::SIZE != 0 {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Invalid memory range table length: {}",
+                length
+            )));
+        }
+
+        let mut data = vec![0u8; length as usize];
+        fd.read_exact(&mut data)
+            .map_err(MigratableError::MigrateSocket)?;
+
+        let mut table = MemoryRangeTable::default();
+        for range in data.chunks_exact(MemoryRange::SIZE) {
+            let mut gpa = [0u8; 8];
+            gpa.copy_from_slice(&range[0..8]);
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&range[8..16]);
+
+            table.push(MemoryRange {
+                gpa: u64::from_le_bytes(gpa),
+                length: u64::from_le_bytes(length),
+            });
+        }
+
+        Ok(table)
+    }
+
+    pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
+        let mut data = Vec::with_capacity(self.length() as usize);
+        for range in self.data.iter() {
+            data.extend_from_slice(&range.gpa.to_le_bytes());
+            data.extend_from_slice(&range.length.to_le_bytes());
+        }
+
+        fd.write_all(&data).map_err(MigratableError::MigrateSocket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let mut buf = Vec::new();
+        Request::memory(0x20).write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE);
+
+        let request = Request::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(request.command(), Command::Memory);
+        assert_eq!(request.length(), 0x20);
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let mut buf = Vec::new();
+        Response::error().write_to(&mut buf).unwrap();
+
+        let response = Response::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(response.status(), Status::Error);
+    }
+
+    #[test]
+    fn test_memory_range_table_from_bitmap() {
+        // Pages 0-1, 4 and 63-64 are dirty.
+        let table = MemoryRangeTable::from_bitmap(vec![0x8000_0000_0000_0013, 0x1], 0x1000, 0x1000);
+        assert_eq!(
+            table.regions(),
+            &[
+                MemoryRange {
+                    gpa: 0x1000,
+                    length: 0x2000
+                },
+                MemoryRange {
+                    gpa: 0x5000,
+                    length: 0x1000
+                },
+                MemoryRange {
+                    gpa: 0x40000,
+                    length: 0x2000
+                },
+            ]
+        );
+        assert_eq!(table.effective_size(), 0x5000);
+
+        let mut buf = Vec::new();
+        table.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len() as u64, table.length());
+        let read_table = MemoryRangeTable::read_from(&mut buf.as_slice(), table.length()).unwrap();
+        assert_eq!(read_table, table);
+    }
+}
@@ -7,7 +7,7 @@ use acpi_tables::{
     rsdp::RSDP,
     sdt::{GenericAddress, SDT},
 };
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{GuestAddress, GuestMemoryMmap, GuestMemoryRegion};
 
 use vm_memory::{Address, ByteValued, Bytes};
 
@@ -16,6 +16,7 @@ use std::sync::{Arc, Mutex};
 use crate::cpu::CpuManager;
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
+use crate::vm::NumaNodes;
 use arch::layout;
 
 #[repr(packed)]
@@ -28,6 +29,62 @@ struct PCIRangeEntry {
     _reserved: u32,
 }
 
+#[repr(packed)]
+#[derive(Default)]
+struct MemoryAffinity {
+    pub r#type: u8,
+    pub length: u8,
+    pub proximity_domain: u32,
+    _reserved1: u16,
+    pub base_addr_lo: u32,
+    pub base_addr_hi: u32,
+    pub length_lo: u32,
+    pub length_hi: u32,
+    _reserved2: u32,
+    pub flags: u32,
+    _reserved3: u64,
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct ProcessorLocalX2ApicAffinity {
+    pub r#type: u8,
+    pub length: u8,
+    _reserved1: u16,
+    pub proximity_domain: u32,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub clock_domain: u32,
+    _reserved2: u32,
+}
+
+// Memory affinity structure flags
+const MEMORY_AFFINITY_ENABLED: u32 = 1 << 0;
+const MEMORY_AFFINITY_HOTPLUGGABLE: u32 = 1 << 1;
+
+// Processor local x2APIC affinity structure flags
+const PROCESSOR_AFFINITY_ENABLED: u32 = 1 << 0;
+
+// Distance of a NUMA node to itself, and default distance to any other node
+const LOCAL_DISTANCE: u8 = 10;
+const REMOTE_DISTANCE: u8 = 20;
+
+impl MemoryAffinity {
+    fn from_range(base_addr: u64, size: u64, proximity_domain: u32) -> Self {
+        MemoryAffinity {
+            r#type: 1,
+            length: 40,
+            proximity_domain,
+            base_addr_lo: base_addr as u32,
+            base_addr_hi: (base_addr >> 32) as u32,
+            length_lo: size as u32,
+            length_hi: (size >> 32) as u32,
+            flags: MEMORY_AFFINITY_ENABLED,
+            ..Default::default()
+        }
+    }
+}
+
 pub fn create_dsdt_table(
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
@@ -43,11 +100,77 @@ pub fn create_dsdt_table(
     dsdt
 }
 
+fn create_srat_table(numa_nodes: &NumaNodes) -> SDT {
+    let mut srat = SDT::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
+    // SRAT reserved 12 bytes, the first 4 being 1 for backward compatibility
+    srat.append(1u32);
+    srat.append(0u64);
+
+    for (node_id, node) in numa_nodes.iter() {
+        for region in node.memory_regions() {
+            srat.append(MemoryAffinity::from_range(
+                region.start_addr().raw_value(),
+                region.len(),
+                *node_id,
+            ));
+        }
+
+        for region in node.hotplug_regions() {
+            srat.append(MemoryAffinity {
+                flags: MEMORY_AFFINITY_ENABLED | MEMORY_AFFINITY_HOTPLUGGABLE,
+                ..MemoryAffinity::from_range(
+                    region.start_addr().raw_value(),
+                    region.len(),
+                    *node_id,
+                )
+            });
+        }
+
+        for cpu in node.cpus() {
+            srat.append(ProcessorLocalX2ApicAffinity {
+                r#type: 2,
+                length: 24,
+                proximity_domain: *node_id,
+                x2apic_id: u32::from(*cpu),
+                flags: PROCESSOR_AFFINITY_ENABLED,
+                ..Default::default()
+            });
+        }
+    }
+
+    srat
+}
+
+fn create_slit_table(numa_nodes: &NumaNodes) -> SDT {
+    let mut slit = SDT::new(*b"SLIT", 36, 1, *b"CLOUDH", *b"CHSLIT  ", 1);
+    // Number of System Localities on 8 bytes.
+    slit.append(numa_nodes.len() as u64);
+
+    // The node ids are contiguous from 0, which lets them be used as the
+    // indexes of the distances matrix.
+    for (node_id, node) in numa_nodes.iter() {
+        for destination in numa_nodes.keys() {
+            let distance = if destination == node_id {
+                LOCAL_DISTANCE
+            } else {
+                *node
+                    .distances()
+                    .get(destination)
+                    .unwrap_or(&REMOTE_DISTANCE)
+            };
+            slit.append(distance);
+        }
+    }
+
+    slit
+}
+
 pub fn create_acpi_tables(
     guest_mem: &GuestMemoryMmap,
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
     memory_manager: &Arc<Mutex<MemoryManager>>,
+    numa_nodes: &NumaNodes,
 ) -> GuestAddress {
     // RSDP is at the EBDA
     let rsdp_offset = layout::RSDP_POINTER;
@@ -125,6 +248,32 @@ pub fn create_acpi_tables(
         .expect("Error writing MCFG table");
     tables.push(mcfg_offset.0);
 
+    let mut prev_tbl_len = mcfg.len() as u64;
+    let mut prev_tbl_off = mcfg_offset;
+
+    // SRAT and SLIT
+    // Only created if the NUMA nodes list is not empty.
+    if !numa_nodes.is_empty() {
+        // SRAT
+        let srat = create_srat_table(numa_nodes);
+        let srat_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(srat.as_slice(), srat_offset)
+            .expect("Error writing SRAT table");
+        tables.push(srat_offset.0);
+
+        // SLIT
+        let slit = create_slit_table(numa_nodes);
+        let slit_offset = srat_offset.checked_add(srat.len() as u64).unwrap();
+        guest_mem
+            .write_slice(slit.as_slice(), slit_offset)
+            .expect("Error writing SLIT table");
+        tables.push(slit_offset.0);
+
+        prev_tbl_len = slit.len() as u64;
+        prev_tbl_off = slit_offset;
+    }
+
     // XSDT
     let mut xsdt = SDT::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
@@ -132,7 +281,7 @@ pub fn create_acpi_tables(
     }
     xsdt.update_checksum();
 
-    let xsdt_offset = mcfg_offset.checked_add(mcfg.len() as u64).unwrap();
+    let xsdt_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
     guest_mem
         .write_slice(xsdt.as_slice(), xsdt_offset)
         .expect("Error writing XSDT table");
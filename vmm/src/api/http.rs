@@ -62,6 +62,18 @@ pub enum HttpError {
     /// Could not restore a VM
     VmRestore(ApiError),
 
+    /// Could not verify a VM snapshot
+    VmVerifySnapshot(ApiError),
+
+    /// Could not write a VM core dump
+    VmCoredump(ApiError),
+
+    /// Could not send a VM for live migration
+    VmSendMigration(ApiError),
+
+    /// Could not receive a VM from live migration
+    VmReceiveMigration(ApiError),
+
     /// Could not act on a VM
     VmAction(ApiError),
 
@@ -97,6 +109,12 @@ pub enum HttpError {
 
     /// Could not get counters from VM
     VmCounters(ApiError),
+
+    /// Could not update the rate limiter of a disk
+    VmUpdateDiskRateLimiter(ApiError),
+
+    /// Could not update the rate limiters of a network device
+    VmUpdateNetRateLimiter(ApiError),
 }
 
 impl From<serde_json::Error> for HttpError {
@@ -196,18 +214,24 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.add-pmem"), Box::new(VmActionHandler::new(VmAction::AddPmem(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmActionHandler::new(VmAction::AddVsock(Arc::default()))));
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
+        r.routes.insert(endpoint!("/vm.coredump"), Box::new(VmActionHandler::new(VmAction::Coredump(Arc::default()))));
         r.routes.insert(endpoint!("/vm.counters"), Box::new(VmActionHandler::new(VmAction::Counters)));
         r.routes.insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
+        r.routes.insert(endpoint!("/vm.receive-migration"), Box::new(VmActionHandler::new(VmAction::ReceiveMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resize"), Box::new(VmActionHandler::new(VmAction::Resize(Arc::default()))));
         r.routes.insert(endpoint!("/vm.restore"), Box::new(VmActionHandler::new(VmAction::Restore(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
+        r.routes.insert(endpoint!("/vm.send-migration"), Box::new(VmActionHandler::new(VmAction::SendMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.update-disk-rate-limiter"), Box::new(VmActionHandler::new(VmAction::UpdateDiskRateLimiter(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.update-net-rate-limiter"), Box::new(VmActionHandler::new(VmAction::UpdateNetRateLimiter(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.verify-snapshot"), Box::new(VmActionHandler::new(VmAction::VerifySnapshot(Arc::default()))));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
 
@@ -6,9 +6,10 @@
 use crate::api::http::{error_response, EndpointHandler, HttpError};
 use crate::api::{
     vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
-    vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_remove_device, vm_resize,
-    vm_restore, vm_resume, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction,
-    VmConfig,
+    vm_coredump, vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_reboot,
+    vm_receive_migration, vm_remove_device, vm_resize, vm_restore, vm_resume, vm_send_migration,
+    vm_shutdown, vm_snapshot, vm_update_disk_rate_limiter, vm_update_net_rate_limiter,
+    vm_verify_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction, VmConfig,
 };
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::sync::mpsc::Sender;
@@ -146,6 +147,48 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmSnapshot),
 
+                VerifySnapshot(_) => vm_verify_snapshot(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmVerifySnapshot),
+
+                Coredump(_) => vm_coredump(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmCoredump),
+
+                SendMigration(_) => vm_send_migration(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSendMigration),
+
+                ReceiveMigration(_) => vm_receive_migration(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmReceiveMigration),
+
+                UpdateDiskRateLimiter(_) => vm_update_disk_rate_limiter(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmUpdateDiskRateLimiter),
+
+                UpdateNetRateLimiter(_) => vm_update_net_rate_limiter(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmUpdateNetRateLimiter),
+
                 _ => Err(HttpError::BadRequest),
             }
         } else {
@@ -41,6 +41,7 @@ use crate::config::{
 };
 use crate::vm::{Error as VmError, VmState};
 use micro_http::Body;
+use rate_limiter::RateLimiterConfig;
 use std::io;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
@@ -103,6 +104,12 @@ pub enum ApiError {
     /// The VM could not restored.
     VmRestore(VmError),
 
+    /// The VM snapshot could not be verified.
+    VmVerifySnapshot(VmError),
+
+    /// The VM core dump could not be written.
+    VmCoredump(VmError),
+
     /// The VMM could not shutdown.
     VmmShutdown(VmError),
 
@@ -135,6 +142,18 @@ pub enum ApiError {
 
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
+
+    /// The VM could not be sent to the migration destination.
+    VmSendMigration(VmError),
+
+    /// The VM could not be received from the migration source.
+    VmReceiveMigration(VmError),
+
+    /// The disk rate limiter could not be updated.
+    VmUpdateDiskRateLimiter(VmError),
+
+    /// The network device rate limiters could not be updated.
+    VmUpdateNetRateLimiter(VmError),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -154,6 +173,9 @@ pub struct VmResizeData {
     pub desired_vcpus: Option<u8>,
     pub desired_ram: Option<u64>,
     pub desired_ram_w_balloon: Option<u64>,
+    /// The guest NUMA node whose virtio-mem device is resized
+    #[serde(default)]
+    pub numa_node: Option<u32>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default)]
@@ -161,10 +183,63 @@ pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmUpdateDiskRateLimiterData {
+    /// The identifier of the disk to update
+    pub id: String,
+    /// The new limits, or no limit at all if not provided
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmUpdateNetRateLimiterData {
+    /// The identifier of the network device to update
+    pub id: String,
+    /// The new RX limits, or no limit at all if not provided
+    #[serde(default)]
+    pub rx_rate_limiter_config: Option<RateLimiterConfig>,
+    /// The new TX limits, or no limit at all if not provided
+    #[serde(default)]
+    pub tx_rate_limiter_config: Option<RateLimiterConfig>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    /// The URL of the previous snapshot, when only the memory changed since
+    /// then must be saved
+    #[serde(default)]
+    pub parent_url: Option<String>,
+    /// Keep track of the memory changes after this snapshot, so that the
+    /// next one can be incremental
+    #[serde(default)]
+    pub track_changes: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmVerifySnapshotData {
+    /// The URL of the snapshot to verify
+    pub source_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmCoredumpData {
+    /// The URL of the core dump file to write
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmSendMigrationData {
+    /// The migration destination URL
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmReceiveMigrationData {
+    /// The URL to wait for the migration source on
+    pub receiver_url: String,
 }
 
 pub enum ApiResponsePayload {
@@ -262,6 +337,24 @@ pub enum ApiRequest {
 
     /// Restore from a VM snapshot
     VmRestore(Arc<RestoreConfig>, Sender<ApiResponse>),
+
+    /// Check the integrity of a VM snapshot
+    VmVerifySnapshot(Arc<VmVerifySnapshotData>, Sender<ApiResponse>),
+
+    /// Write a core dump of the VM
+    VmCoredump(Arc<VmCoredumpData>, Sender<ApiResponse>),
+
+    /// Live migrate the VM to a remote VMM
+    VmSendMigration(Arc<VmSendMigrationData>, Sender<ApiResponse>),
+
+    /// Receive a live migrated VM from a remote VMM
+    VmReceiveMigration(Arc<VmReceiveMigrationData>, Sender<ApiResponse>),
+
+    /// Update the rate limiter of a disk
+    VmUpdateDiskRateLimiter(Arc<VmUpdateDiskRateLimiterData>, Sender<ApiResponse>),
+
+    /// Update the rate limiters of a network device
+    VmUpdateNetRateLimiter(Arc<VmUpdateNetRateLimiterData>, Sender<ApiResponse>),
 }
 
 pub fn vm_create(
@@ -336,6 +429,24 @@ pub enum VmAction {
 
     /// Snapshot VM
     Snapshot(Arc<VmSnapshotConfig>),
+
+    /// Verify VM snapshot
+    VerifySnapshot(Arc<VmVerifySnapshotData>),
+
+    /// Write VM core dump
+    Coredump(Arc<VmCoredumpData>),
+
+    /// Send VM for live migration
+    SendMigration(Arc<VmSendMigrationData>),
+
+    /// Receive VM from live migration
+    ReceiveMigration(Arc<VmReceiveMigrationData>),
+
+    /// Update disk rate limiter
+    UpdateDiskRateLimiter(Arc<VmUpdateDiskRateLimiterData>),
+
+    /// Update network device rate limiters
+    UpdateNetRateLimiter(Arc<VmUpdateNetRateLimiterData>),
 }
 
 fn vm_action(
@@ -364,6 +475,12 @@ fn vm_action(
         Resize(v) => ApiRequest::VmResize(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
+        VerifySnapshot(v) => ApiRequest::VmVerifySnapshot(v, response_sender),
+        Coredump(v) => ApiRequest::VmCoredump(v, response_sender),
+        SendMigration(v) => ApiRequest::VmSendMigration(v, response_sender),
+        ReceiveMigration(v) => ApiRequest::VmReceiveMigration(v, response_sender),
+        UpdateDiskRateLimiter(v) => ApiRequest::VmUpdateDiskRateLimiter(v, response_sender),
+        UpdateNetRateLimiter(v) => ApiRequest::VmUpdateNetRateLimiter(v, response_sender),
     };
 
     // Send the VM request.
@@ -423,6 +540,38 @@ pub fn vm_restore(
     vm_action(api_evt, api_sender, VmAction::Restore(data))
 }
 
+pub fn vm_verify_snapshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmVerifySnapshotData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::VerifySnapshot(data))
+}
+
+pub fn vm_coredump(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmCoredumpData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Coredump(data))
+}
+
+pub fn vm_send_migration(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSendMigrationData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SendMigration(data))
+}
+
+pub fn vm_receive_migration(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmReceiveMigrationData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ReceiveMigration(data))
+}
+
 pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmInfo> {
     let (response_sender, response_receiver) = channel();
 
@@ -533,3 +682,19 @@ pub fn vm_add_vsock(
 ) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::AddVsock(data))
 }
+
+pub fn vm_update_disk_rate_limiter(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmUpdateDiskRateLimiterData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::UpdateDiskRateLimiter(data))
+}
+
+pub fn vm_update_net_rate_limiter(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmUpdateNetRateLimiterData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::UpdateNetRateLimiter(data))
+}
@@ -5,8 +5,11 @@
 
 use clap::ArgMatches;
 use net_util::MacAddr;
-use option_parser::{ByteSized, OptionParser, OptionParserError, Toggle};
-use std::convert::From;
+use option_parser::{
+    ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, Tuple,
+};
+use rate_limiter::{RateLimiterConfig, TokenBucketConfig};
+use std::convert::{From, TryFrom};
 use std::fmt;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
@@ -15,6 +18,7 @@ use std::str::FromStr;
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
+pub const DEFAULT_HUGEPAGE_SIZE: u64 = 2 << 20;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
 pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
@@ -42,6 +46,10 @@ pub enum Error {
     ParseCpus(OptionParserError),
     /// Error parsing memory options
     ParseMemory(OptionParserError),
+    /// Error parsing memory zone options
+    ParseMemoryZone(OptionParserError),
+    /// Missing 'id' from memory zone
+    ParseMemoryZoneIdMissing,
     /// Error parsing disk options
     ParseDisk(OptionParserError),
     /// Error parsing network options
@@ -67,6 +75,14 @@ pub enum Error {
     /// Failed to parse SGX EPC parameters
     #[cfg(target_arch = "x86_64")]
     ParseSgxEpc(OptionParserError),
+    /// Failed to parse GDB stub parameters
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    ParseGdb(OptionParserError),
+    /// Missing socket from GDB stub
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    ParseGdbSockMissing,
+    /// Failed to parse NUMA parameters
+    ParseNuma(OptionParserError),
     /// Failed to validate configuration
     Validation(ValidationError),
 }
@@ -93,6 +109,47 @@ pub enum ValidationError {
     CpuTopologyCount,
     /// One part of the CPU topology was zero
     CpuTopologyZeroPart,
+    /// Unknown CPU model
+    UnknownCpuModel(String),
+    /// Unknown CPU feature
+    UnknownCpuFeature(String),
+    /// vCPU from the CPU affinity is greater than the maximum vCPUs
+    CpuAffinityVcpuOutOfRange(u8),
+    /// vCPU listed several times in the CPU affinity
+    CpuAffinityDuplicateVcpu(u8),
+    /// Invalid host CPUs in the CPU affinity of a vCPU
+    CpuAffinityInvalidHostCpus(u8),
+    /// NUMA node ids must be unique and contiguous, starting from 0
+    InvalidNumaNodeId(u32),
+    /// vCPU out of range or assigned to several NUMA nodes
+    InvalidNumaNodeCpu(u8),
+    /// Invalid distance between two NUMA nodes
+    InvalidNumaNodeDistance(u32, u32),
+    /// The memory of the NUMA nodes doesn't add up to the guest RAM size
+    NumaNodesMemoryMismatch,
+    /// The hotpluggable memory of the NUMA nodes doesn't add up to the
+    /// hotplug size
+    NumaNodesHotplugMemoryMismatch,
+    /// Hotpluggable memory per NUMA node requires virtio-mem
+    NumaNodesHotplugRequiresVirtioMem,
+    /// NUMA nodes are only supported on x86_64 with ACPI
+    NumaUnsupported,
+    /// The memory zones don't add up to the guest RAM size
+    MemoryZonesSizeMismatch,
+    /// Memory zone id used several times
+    DuplicateMemoryZoneId(String),
+    /// Hugepage size set without hugepages, or not a power of two
+    InvalidHugePageSize(u64),
+    /// Memory size not a multiple of the hugepage size
+    MemorySizeNotHugePageAligned(u64),
+    /// Balloon option set without the balloon device
+    BalloonRequired,
+    /// Balloon statistics interval is zero
+    InvalidBalloonStatsInterval,
+    /// Rate limiter token bucket with a zero size or refill time
+    InvalidRateLimiterConfig,
+    /// Rate limiter set on a vhost-user disk with an external backend
+    VhostUserDiskSocketRateLimiter,
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -116,6 +173,73 @@ impl fmt::Display for ValidationError {
                 f,
                 "Product of CPU topology parts does not match maximum vCPUs"
             ),
+            UnknownCpuModel(m) => write!(f, "Unknown CPU model: {}", m),
+            UnknownCpuFeature(n) => write!(f, "Unknown CPU feature: {}", n),
+            CpuAffinityVcpuOutOfRange(v) => write!(
+                f,
+                "vCPU {} from the CPU affinity is greater than the maximum vCPUs",
+                v
+            ),
+            CpuAffinityDuplicateVcpu(v) => {
+                write!(f, "vCPU {} is listed several times in the CPU affinity", v)
+            }
+            CpuAffinityInvalidHostCpus(v) => {
+                write!(f, "Invalid host CPUs in the CPU affinity of vCPU {}", v)
+            }
+            InvalidNumaNodeId(n) => write!(
+                f,
+                "Invalid NUMA node id {}: ids must be unique and contiguous from 0",
+                n
+            ),
+            InvalidNumaNodeCpu(c) => write!(
+                f,
+                "vCPU {} is out of range or assigned to several NUMA nodes",
+                c
+            ),
+            InvalidNumaNodeDistance(s, d) => write!(
+                f,
+                "Invalid distance from NUMA node {} to NUMA node {}",
+                s, d
+            ),
+            NumaNodesMemoryMismatch => write!(
+                f,
+                "The memory of the NUMA nodes doesn't add up to the guest RAM size"
+            ),
+            NumaNodesHotplugMemoryMismatch => write!(
+                f,
+                "The hotpluggable memory of the NUMA nodes doesn't add up to the hotplug size"
+            ),
+            NumaNodesHotplugRequiresVirtioMem => write!(
+                f,
+                "Hotpluggable memory per NUMA node requires the virtio-mem hotplug method"
+            ),
+            NumaUnsupported => write!(f, "NUMA nodes are only supported on x86_64 with ACPI"),
+            MemoryZonesSizeMismatch => {
+                write!(f, "The memory zones don't add up to the guest RAM size")
+            }
+            DuplicateMemoryZoneId(id) => write!(f, "Memory zone id {} used several times", id),
+            InvalidHugePageSize(s) => write!(
+                f,
+                "Invalid hugepage size {}: hugepages must be enabled and the size a power of two",
+                s
+            ),
+            MemorySizeNotHugePageAligned(s) => write!(
+                f,
+                "Memory size is not a multiple of the hugepage size {}",
+                s
+            ),
+            BalloonRequired => write!(f, "Balloon options require the balloon device"),
+            InvalidBalloonStatsInterval => {
+                write!(f, "Balloon statistics interval must be greater than zero")
+            }
+            InvalidRateLimiterConfig => write!(
+                f,
+                "Rate limiter token buckets require a non-zero size and refill time"
+            ),
+            VhostUserDiskSocketRateLimiter => write!(
+                f,
+                "Rate limiting a vhost-user disk requires the backend to be spawned by the VMM"
+            ),
         }
     }
 }
@@ -144,15 +268,22 @@ impl fmt::Display for Error {
             ParseVsockCidMissing => write!(f, "Error parsing --vsock: cid missing"),
             ParseVsockSockMissing => write!(f, "Error parsing --vsock: socket missing"),
             ParseMemory(o) => write!(f, "Error parsing --memory: {}", o),
+            ParseMemoryZone(o) => write!(f, "Error parsing --memory-zone: {}", o),
+            ParseMemoryZoneIdMissing => write!(f, "Error parsing --memory-zone: id missing"),
             ParseNetwork(o) => write!(f, "Error parsing --net: {}", o),
             ParseDisk(o) => write!(f, "Error parsing --disk: {}", o),
             ParseRNG(o) => write!(f, "Error parsing --rng: {}", o),
             ParseRestore(o) => write!(f, "Error parsing --restore: {}", o),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpc(o) => write!(f, "Error parsing --sgx-epc: {}", o),
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            ParseGdb(o) => write!(f, "Error parsing --gdb: {}", o),
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            ParseGdbSockMissing => write!(f, "Error parsing --gdb: socket missing"),
             ParseRestoreSourceUrlMissing => {
                 write!(f, "Error parsing --restore: source_url missing")
             }
+            ParseNuma(o) => write!(f, "Error parsing --numa: {}", o),
             Validation(v) => write!(f, "Error validating configuration: {}", v),
         }
     }
@@ -163,6 +294,7 @@ pub type Result<T> = result::Result<T, Error>;
 pub struct VmParams<'a> {
     pub cpus: &'a str,
     pub memory: &'a str,
+    pub memory_zones: Option<Vec<&'a str>>,
     pub kernel: Option<&'a str>,
     pub initramfs: Option<&'a str>,
     pub cmdline: Option<&'a str>,
@@ -177,6 +309,9 @@ pub struct VmParams<'a> {
     pub vsock: Option<&'a str>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub gdb: Option<&'a str>,
+    pub numa: Option<Vec<&'a str>>,
 }
 
 impl<'a> VmParams<'a> {
@@ -187,6 +322,7 @@ impl<'a> VmParams<'a> {
         let rng = args.value_of("rng").unwrap();
         let serial = args.value_of("serial").unwrap();
 
+        let memory_zones: Option<Vec<&str>> = args.values_of("memory-zone").map(|x| x.collect());
         let kernel = args.value_of("kernel");
         let initramfs = args.value_of("initramfs");
         let cmdline = args.value_of("cmdline");
@@ -200,10 +336,14 @@ impl<'a> VmParams<'a> {
         let vsock: Option<&str> = args.value_of("vsock");
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        let gdb: Option<&str> = args.value_of("gdb");
+        let numa: Option<Vec<&str>> = args.values_of("numa").map(|x| x.collect());
 
         VmParams {
             cpus,
             memory,
+            memory_zones,
             kernel,
             initramfs,
             cmdline,
@@ -218,6 +358,9 @@ impl<'a> VmParams<'a> {
             vsock,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            gdb,
+            numa,
         }
     }
 }
@@ -292,18 +435,69 @@ impl FromStr for CpuTopology {
     }
 }
 
+pub enum CpuFeatureParseError {
+    InvalidValue(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuFeatureConfig {
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl FromStr for CpuFeatureConfig {
+    type Err = CpuFeatureParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, enabled) = if let Some(name) = s.strip_prefix('+') {
+            (name, true)
+        } else if let Some(name) = s.strip_prefix('-') {
+            (name, false)
+        } else {
+            (s, true)
+        };
+
+        if name.is_empty() {
+            return Err(Self::Err::InvalidValue(s.to_owned()));
+        }
+
+        Ok(CpuFeatureConfig {
+            name: name.to_owned(),
+            enabled,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuAffinity {
+    pub vcpu: u8,
+    pub host_cpus: Vec<usize>,
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
     pub max_vcpus: u8,
     #[serde(default)]
     pub topology: Option<CpuTopology>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub features: Vec<CpuFeatureConfig>,
+    #[serde(default)]
+    pub affinity: Option<Vec<CpuAffinity>>,
 }
 
 impl CpusConfig {
     pub fn parse(cpus: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("boot").add("max").add("topology");
+        parser
+            .add("boot")
+            .add("max")
+            .add("topology")
+            .add("model")
+            .add("features")
+            .add("affinity");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
         let boot_vcpus: u8 = parser
@@ -315,11 +509,41 @@ impl CpusConfig {
             .map_err(Error::ParseCpus)?
             .unwrap_or(boot_vcpus);
         let topology = parser.convert("topology").map_err(Error::ParseCpus)?;
+        let model = parser.get("model");
+        let features = parser
+            .convert::<StringList>("features")
+            .map_err(Error::ParseCpus)?
+            .map(|l| l.0)
+            .unwrap_or_default()
+            .iter()
+            .map(|f| {
+                f.parse().map_err(|_| {
+                    Error::ParseCpus(OptionParserError::Conversion(
+                        "features".to_owned(),
+                        f.to_owned(),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<CpuFeatureConfig>>>()?;
+        let affinity = parser
+            .convert::<Tuple<u8, IntegerList>>("affinity")
+            .map_err(Error::ParseCpus)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|(vcpu, host_cpus)| CpuAffinity {
+                        vcpu: *vcpu,
+                        host_cpus: host_cpus.0.iter().map(|h| *h as usize).collect(),
+                    })
+                    .collect()
+            });
 
         Ok(CpusConfig {
             boot_vcpus,
             max_vcpus,
             topology,
+            model,
+            features,
+            affinity,
         })
     }
 }
@@ -330,6 +554,9 @@ impl Default for CpusConfig {
             boot_vcpus: DEFAULT_VCPUS,
             max_vcpus: DEFAULT_VCPUS,
             topology: None,
+            model: None,
+            features: Vec::new(),
+            affinity: None,
         }
     }
 }
@@ -350,9 +577,19 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub hugepages: bool,
     #[serde(default)]
+    pub hugepage_size: Option<u64>,
+    #[serde(default)]
     pub balloon: bool,
     #[serde(default)]
     pub balloon_size: u64,
+    #[serde(default)]
+    pub balloon_stats_interval: Option<u64>,
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    #[serde(default)]
+    pub deflate_on_oom: bool,
+    #[serde(default)]
+    pub zones: Option<Vec<MemoryZoneConfig>>,
 }
 
 impl MemoryConfig {
@@ -366,7 +603,11 @@ impl MemoryConfig {
             .add("hotplug_size")
             .add("shared")
             .add("hugepages")
-            .add("balloon");
+            .add("hugepage_size")
+            .add("balloon")
+            .add("balloon_stats_interval")
+            .add("free_page_reporting")
+            .add("deflate_on_oom");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -398,11 +639,28 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let hugepage_size = parser
+            .convert::<ByteSized>("hugepage_size")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
         let balloon = parser
             .convert::<Toggle>("balloon")
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let balloon_stats_interval = parser
+            .convert("balloon_stats_interval")
+            .map_err(Error::ParseMemory)?;
+        let free_page_reporting = parser
+            .convert::<Toggle>("free_page_reporting")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let deflate_on_oom = parser
+            .convert::<Toggle>("deflate_on_oom")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         Ok(MemoryConfig {
             size,
@@ -412,8 +670,13 @@ impl MemoryConfig {
             hotplug_size,
             shared,
             hugepages,
+            hugepage_size,
             balloon,
             balloon_size: 0,
+            balloon_stats_interval,
+            free_page_reporting,
+            deflate_on_oom,
+            zones: None,
         })
     }
 }
@@ -428,12 +691,88 @@ impl Default for MemoryConfig {
             hotplug_size: None,
             shared: false,
             hugepages: false,
+            hugepage_size: None,
             balloon: false,
             balloon_size: 0,
+            balloon_stats_interval: None,
+            free_page_reporting: false,
+            deflate_on_oom: false,
+            zones: None,
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryZoneConfig {
+    pub id: String,
+    pub size: u64,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub shared: bool,
+    #[serde(default)]
+    pub hugepages: bool,
+    #[serde(default)]
+    pub hugepage_size: Option<u64>,
+    #[serde(default)]
+    pub mergeable: bool,
+}
+
+impl MemoryZoneConfig {
+    pub const SYNTAX: &'static str = "User defined memory zone parameters \
+        \"id=<zone_identifier>,size=<guest_memory_region_size>,file=<backing_file>,\
+        shared=on|off,hugepages=on|off,hugepage_size=<hugepage_size>,mergeable=on|off\"";
+    pub fn parse(memory_zone: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("id")
+            .add("size")
+            .add("file")
+            .add("shared")
+            .add("hugepages")
+            .add("hugepage_size")
+            .add("mergeable");
+        parser.parse(memory_zone).map_err(Error::ParseMemoryZone)?;
+
+        let id = parser.get("id").ok_or(Error::ParseMemoryZoneIdMissing)?;
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(ByteSized(DEFAULT_MEMORY_MB << 20))
+            .0;
+        let file = parser.get("file").map(PathBuf::from);
+        let shared = parser
+            .convert::<Toggle>("shared")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hugepages = parser
+            .convert::<Toggle>("hugepages")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hugepage_size = parser
+            .convert::<ByteSized>("hugepage_size")
+            .map_err(Error::ParseMemoryZone)?
+            .map(|v| v.0);
+        let mergeable = parser
+            .convert::<Toggle>("mergeable")
+            .map_err(Error::ParseMemoryZone)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(MemoryZoneConfig {
+            id,
+            size,
+            file,
+            shared,
+            hugepages,
+            hugepage_size,
+            mergeable,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct KernelConfig {
     pub path: PathBuf,
@@ -459,6 +798,66 @@ impl CmdlineConfig {
     }
 }
 
+// Options of a rate limiter, each one prefixed with the name of the limiter.
+const RATE_LIMITER_OPTIONS: [&str; 6] = [
+    "bw_size",
+    "bw_one_time_burst",
+    "bw_refill_time",
+    "ops_size",
+    "ops_one_time_burst",
+    "ops_refill_time",
+];
+
+fn add_rate_limiter_options(parser: &mut OptionParser, prefix: &str) {
+    for option in RATE_LIMITER_OPTIONS.iter() {
+        parser.add(&format!("{}_{}", prefix, option));
+    }
+}
+
+fn parse_token_bucket_config(
+    parser: &OptionParser,
+    prefix: &str,
+) -> result::Result<Option<TokenBucketConfig>, OptionParserError> {
+    let size = parser.convert(&format!("{}_size", prefix))?;
+    let one_time_burst = parser.convert(&format!("{}_one_time_burst", prefix))?;
+    let refill_time = parser.convert(&format!("{}_refill_time", prefix))?;
+
+    if size.is_none() && one_time_burst.is_none() && refill_time.is_none() {
+        return Ok(None);
+    }
+
+    // Missing values are caught by the validation.
+    Ok(Some(TokenBucketConfig {
+        size: size.unwrap_or(0),
+        one_time_burst,
+        refill_time: refill_time.unwrap_or(0),
+    }))
+}
+
+fn parse_rate_limiter_config(
+    parser: &OptionParser,
+    prefix: &str,
+) -> result::Result<Option<RateLimiterConfig>, OptionParserError> {
+    let bandwidth = parse_token_bucket_config(parser, &format!("{}_bw", prefix))?;
+    let ops = parse_token_bucket_config(parser, &format!("{}_ops", prefix))?;
+
+    if bandwidth.is_none() && ops.is_none() {
+        Ok(None)
+    } else {
+        Ok(Some(RateLimiterConfig { bandwidth, ops }))
+    }
+}
+
+pub fn validate_rate_limiter_config(config: &RateLimiterConfig) -> ValidationResult<()> {
+    for bucket in config.bandwidth.iter().chain(config.ops.iter()) {
+        if bucket.size == 0 || bucket.refill_time == 0 {
+            return Err(ValidationError::InvalidRateLimiterConfig);
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
@@ -479,6 +878,8 @@ pub struct DiskConfig {
     pub poll_queue: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -506,6 +907,7 @@ impl Default for DiskConfig {
             vhost_socket: None,
             poll_queue: default_diskconfig_poll_queue(),
             id: None,
+            rate_limiter_config: None,
         }
     }
 }
@@ -514,7 +916,10 @@ impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
          \"path=<disk_image_path>,readonly=on|off,iommu=on|off,num_queues=<number_of_queues>,\
          queue_size=<size_of_each_queue>,vhost_user=<vhost_user_enable>,\
-         socket=<vhost_user_socket_path>, default true>,id=<device_id>\"";
+         socket=<vhost_user_socket_path>, default true>,id=<device_id>,\
+         rate_limit_bw_size=<bytes>,rate_limit_bw_one_time_burst=<bytes>,\
+         rate_limit_bw_refill_time=<ms>,rate_limit_ops_size=<ops>,\
+         rate_limit_ops_one_time_burst=<ops>,rate_limit_ops_refill_time=<ms>\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -529,6 +934,7 @@ impl DiskConfig {
             .add("socket")
             .add("poll_queue")
             .add("id");
+        add_rate_limiter_options(&mut parser, "rate_limit");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -567,6 +973,8 @@ impl DiskConfig {
             .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
             .0;
         let id = parser.get("id");
+        let rate_limiter_config =
+            parse_rate_limiter_config(&parser, "rate_limit").map_err(Error::ParseDisk)?;
 
         if parser.is_set("poll_queue") && !vhost_user {
             warn!("poll_queue parameter currently only has effect when used vhost_user=true");
@@ -583,6 +991,7 @@ impl DiskConfig {
             vhost_user,
             poll_queue,
             id,
+            rate_limiter_config,
         })
     }
 }
@@ -610,6 +1019,10 @@ pub struct NetConfig {
     pub vhost_socket: Option<String>,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub rx_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub tx_rate_limiter_config: Option<RateLimiterConfig>,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -650,6 +1063,8 @@ impl Default for NetConfig {
             vhost_user: false,
             vhost_socket: None,
             id: None,
+            rx_rate_limiter_config: None,
+            tx_rate_limiter_config: None,
         }
     }
 }
@@ -658,7 +1073,13 @@ impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
     \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,iommu=on|off,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>\"";
+    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>,\
+    rx_rate_limit_bw_size=<bytes>,rx_rate_limit_bw_one_time_burst=<bytes>,\
+    rx_rate_limit_bw_refill_time=<ms>,rx_rate_limit_ops_size=<frames>,\
+    rx_rate_limit_ops_one_time_burst=<frames>,rx_rate_limit_ops_refill_time=<ms>,\
+    tx_rate_limit_bw_size=<bytes>,tx_rate_limit_bw_one_time_burst=<bytes>,\
+    tx_rate_limit_bw_refill_time=<ms>,tx_rate_limit_ops_size=<frames>,\
+    tx_rate_limit_ops_one_time_burst=<frames>,tx_rate_limit_ops_refill_time=<ms>\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -675,6 +1096,8 @@ impl NetConfig {
             .add("vhost_user")
             .add("socket")
             .add("id");
+        add_rate_limiter_options(&mut parser, "rx_rate_limit");
+        add_rate_limiter_options(&mut parser, "tx_rate_limit");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -711,6 +1134,10 @@ impl NetConfig {
             .0;
         let vhost_socket = parser.get("socket");
         let id = parser.get("id");
+        let rx_rate_limiter_config =
+            parse_rate_limiter_config(&parser, "rx_rate_limit").map_err(Error::ParseNetwork)?;
+        let tx_rate_limiter_config =
+            parse_rate_limiter_config(&parser, "tx_rate_limit").map_err(Error::ParseNetwork)?;
 
         Ok(NetConfig {
             tap,
@@ -724,6 +1151,8 @@ impl NetConfig {
             vhost_user,
             vhost_socket,
             id,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
         })
     }
 }
@@ -1124,21 +1553,154 @@ impl SgxEpcConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NumaDistance {
+    #[serde(default)]
+    pub destination: u32,
+    #[serde(default)]
+    pub distance: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NumaConfig {
+    #[serde(default)]
+    pub guest_numa_id: u32,
+    #[serde(default)]
+    pub cpus: Option<Vec<u8>>,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub distances: Option<Vec<NumaDistance>>,
+    #[serde(default)]
+    pub host_numa_node: Option<u32>,
+    #[serde(default)]
+    pub hotplug_size: Option<u64>,
+}
+
+impl NumaConfig {
+    pub const SYNTAX: &'static str = "Settings related to a given NUMA node \
+        \"guest_numa_id=<node_id>,cpus=<cpus_id>,size=<node_memory_size>,\
+        distances=<list_of_distances_to_destination_nodes>,host_numa_node=<host_node_id>,\
+        hotplug_size=<node_hotpluggable_memory_size>\"";
+    pub fn parse(numa: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("guest_numa_id")
+            .add("cpus")
+            .add("size")
+            .add("distances")
+            .add("host_numa_node")
+            .add("hotplug_size");
+        parser.parse(numa).map_err(Error::ParseNuma)?;
+
+        let guest_numa_id = parser
+            .convert::<u32>("guest_numa_id")
+            .map_err(Error::ParseNuma)?
+            .unwrap_or(0);
+        let cpus = parser
+            .convert::<IntegerList>("cpus")
+            .map_err(Error::ParseNuma)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|e| u8::try_from(*e))
+                    .collect::<result::Result<Vec<u8>, _>>()
+            })
+            .transpose()
+            .map_err(|_| {
+                Error::ParseNuma(OptionParserError::Conversion(
+                    "cpus".to_owned(),
+                    numa.to_owned(),
+                ))
+            })?;
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseNuma)?
+            .unwrap_or(ByteSized(0))
+            .0;
+        let distances = parser
+            .convert::<Tuple<u32, u8>>("distances")
+            .map_err(Error::ParseNuma)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|(destination, distance)| NumaDistance {
+                        destination: *destination,
+                        distance: *distance,
+                    })
+                    .collect()
+            });
+        let host_numa_node = parser
+            .convert::<u32>("host_numa_node")
+            .map_err(Error::ParseNuma)?;
+        let hotplug_size = parser
+            .convert::<ByteSized>("hotplug_size")
+            .map_err(Error::ParseNuma)?
+            .map(|v| v.0);
+
+        Ok(NumaConfig {
+            guest_numa_id,
+            cpus,
+            size,
+            distances,
+            host_numa_node,
+            hotplug_size,
+        })
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GdbConfig {
+    pub socket: PathBuf,
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+impl GdbConfig {
+    pub const SYNTAX: &'static str = "GDB stub parameters \
+        \"socket=<socket_path>\"";
+    pub fn parse(gdb: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("socket");
+        parser.parse(gdb).map_err(Error::ParseGdb)?;
+
+        let socket = parser
+            .get("socket")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseGdbSockMissing)?;
+
+        Ok(GdbConfig { socket })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct RestoreConfig {
     pub source_url: PathBuf,
     #[serde(default)]
     pub prefault: bool,
+    #[serde(default)]
+    pub lazy: bool,
+    #[serde(default)]
+    pub verify_files: bool,
+    #[serde(default)]
+    pub allow_unverified: bool,
 }
 
 impl RestoreConfig {
     pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
-        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
+        \nRestore parameters \"source_url=<source_url>,prefault=on|off,lazy=on|off,\
+        verify_files=on|off,allow_unverified=on|off\" \
         \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
-        \n`prefault` brings memory pages in when enabled (disabled by default)";
+        \n`prefault` brings memory pages in when enabled (disabled by default) \
+        \n`lazy` loads memory pages on demand, after the VM is resumed, when enabled (disabled by default) \
+        \n`verify_files` checks the digest of every snapshot file, reading the whole guest memory, when enabled (disabled by default) \
+        \n`allow_unverified` restores a snapshot without manifest, skipping the integrity checks, when enabled (disabled by default)";
     pub fn parse(restore: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault");
+        parser
+            .add("source_url")
+            .add("prefault")
+            .add("lazy")
+            .add("verify_files")
+            .add("allow_unverified");
         parser.parse(restore).map_err(Error::ParseRestore)?;
 
         let source_url = parser
@@ -1150,10 +1712,28 @@ impl RestoreConfig {
             .map_err(Error::ParseRestore)?
             .unwrap_or(Toggle(false))
             .0;
+        let lazy = parser
+            .convert::<Toggle>("lazy")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let verify_files = parser
+            .convert::<Toggle>("verify_files")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let allow_unverified = parser
+            .convert::<Toggle>("allow_unverified")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         Ok(RestoreConfig {
             source_url,
             prefault,
+            lazy,
+            verify_files,
+            allow_unverified,
         })
     }
 }
@@ -1185,6 +1765,9 @@ pub struct VmConfig {
     pub iommu: bool,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub gdb: Option<GdbConfig>,
+    pub numa: Option<Vec<NumaConfig>>,
 }
 
 impl VmConfig {
@@ -1212,27 +1795,105 @@ impl VmConfig {
             error!("Use of backing file ('--memory file=') is deprecated. Use the 'shared' and 'hugepages' controls.");
         }
 
+        if self.memory.hugepages {
+            let hugepage_size = self.memory.hugepage_size.unwrap_or(DEFAULT_HUGEPAGE_SIZE);
+            if !hugepage_size.is_power_of_two() {
+                return Err(ValidationError::InvalidHugePageSize(hugepage_size));
+            }
+            // Zones are checked on their own.
+            if (self.memory.zones.is_none() && self.memory.size % hugepage_size != 0)
+                || self.memory.hotplug_size.unwrap_or(0) % hugepage_size != 0
+            {
+                return Err(ValidationError::MemorySizeNotHugePageAligned(hugepage_size));
+            }
+        } else if let Some(hugepage_size) = self.memory.hugepage_size {
+            return Err(ValidationError::InvalidHugePageSize(hugepage_size));
+        }
+
+        if let Some(balloon_stats_interval) = self.memory.balloon_stats_interval {
+            if !self.memory.balloon {
+                return Err(ValidationError::BalloonRequired);
+            }
+            if balloon_stats_interval == 0 {
+                return Err(ValidationError::InvalidBalloonStatsInterval);
+            }
+        }
+
+        if (self.memory.free_page_reporting || self.memory.deflate_on_oom) && !self.memory.balloon {
+            return Err(ValidationError::BalloonRequired);
+        }
+
+        // vhost-user backends need all the guest RAM to be shared with them.
+        // With memory zones, the boot RAM is made of the zones, while the
+        // hotpluggable memory still follows the --memory parameters.
+        let mut shared_memory = self.memory.shared;
+        if let Some(zones) = &self.memory.zones {
+            shared_memory = self.memory.shared || self.memory.hotplug_size.is_none();
+            let mut zone_ids = Vec::new();
+            let mut zones_size = 0;
+            for zone in zones.iter() {
+                if zone_ids.contains(&zone.id) {
+                    return Err(ValidationError::DuplicateMemoryZoneId(zone.id.clone()));
+                }
+                zone_ids.push(zone.id.clone());
+
+                if zone.hugepages {
+                    let hugepage_size = zone.hugepage_size.unwrap_or(DEFAULT_HUGEPAGE_SIZE);
+                    if !hugepage_size.is_power_of_two() {
+                        return Err(ValidationError::InvalidHugePageSize(hugepage_size));
+                    }
+                    if zone.size % hugepage_size != 0 {
+                        return Err(ValidationError::MemorySizeNotHugePageAligned(hugepage_size));
+                    }
+                } else if let Some(hugepage_size) = zone.hugepage_size {
+                    return Err(ValidationError::InvalidHugePageSize(hugepage_size));
+                }
+
+                // File backed zones are always mapped shared.
+                shared_memory &= zone.shared || zone.file.is_some();
+                zones_size += zone.size;
+            }
+
+            if zones_size != self.memory.size {
+                return Err(ValidationError::MemoryZonesSizeMismatch);
+            }
+        }
+
         if let Some(disks) = &self.disks {
             for disk in disks {
                 if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
                     return Err(ValidationError::DiskSocketAndPath);
                 }
-                if disk.vhost_user && !self.memory.shared {
+                if disk.vhost_user && !shared_memory {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
+                if let Some(rate_limiter_config) = &disk.rate_limiter_config {
+                    // Only the backends spawned by the VMM are given the limits.
+                    if disk.vhost_user && disk.vhost_socket.is_some() {
+                        return Err(ValidationError::VhostUserDiskSocketRateLimiter);
+                    }
+                    validate_rate_limiter_config(rate_limiter_config)?;
+                }
             }
         }
 
         if let Some(nets) = &self.net {
             for net in nets {
-                if net.vhost_user && !self.memory.shared {
+                if net.vhost_user && !shared_memory {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
+                for rate_limiter_config in net
+                    .rx_rate_limiter_config
+                    .iter()
+                    .chain(net.tx_rate_limiter_config.iter())
+                {
+                    validate_rate_limiter_config(rate_limiter_config)?;
+                }
             }
         }
 
         if let Some(fses) = &self.fs {
-            if !fses.is_empty() && !self.memory.shared {
+            if !fses.is_empty() && !shared_memory {
                 return Err(ValidationError::VhostUserRequiresSharedMemory);
             }
         }
@@ -1261,6 +1922,110 @@ impl VmConfig {
             }
         }
 
+        // CPU models and features are only supported on x86_64.
+        if let Some(model) = &self.cpus.model {
+            #[cfg(target_arch = "x86_64")]
+            let known = arch::x86_64::cpuid::cpu_model(model).is_some();
+            #[cfg(not(target_arch = "x86_64"))]
+            let known = false;
+            if !known {
+                return Err(ValidationError::UnknownCpuModel(model.clone()));
+            }
+        }
+
+        if let Some(affinity) = &self.cpus.affinity {
+            let mut vcpus = Vec::new();
+            for a in affinity.iter() {
+                if a.vcpu >= self.cpus.max_vcpus {
+                    return Err(ValidationError::CpuAffinityVcpuOutOfRange(a.vcpu));
+                }
+                if vcpus.contains(&a.vcpu) {
+                    return Err(ValidationError::CpuAffinityDuplicateVcpu(a.vcpu));
+                }
+                if a.host_cpus.is_empty()
+                    || a.host_cpus.iter().any(|h| *h >= libc::CPU_SETSIZE as usize)
+                {
+                    return Err(ValidationError::CpuAffinityInvalidHostCpus(a.vcpu));
+                }
+                vcpus.push(a.vcpu);
+            }
+        }
+
+        if let Some(numa) = &self.numa {
+            // The NUMA nodes are described to the guest through the SRAT and
+            // SLIT ACPI tables.
+            if cfg!(not(all(target_arch = "x86_64", feature = "acpi"))) {
+                return Err(ValidationError::NumaUnsupported);
+            }
+
+            let node_count = numa.len() as u32;
+            let mut node_ids = Vec::new();
+            let mut node_cpus = Vec::new();
+            let mut nodes_size = 0;
+            let mut nodes_hotplug_size = None;
+            for node in numa.iter() {
+                if node.guest_numa_id >= node_count || node_ids.contains(&node.guest_numa_id) {
+                    return Err(ValidationError::InvalidNumaNodeId(node.guest_numa_id));
+                }
+                node_ids.push(node.guest_numa_id);
+
+                if let Some(cpus) = &node.cpus {
+                    for cpu in cpus.iter() {
+                        if *cpu >= self.cpus.max_vcpus || node_cpus.contains(cpu) {
+                            return Err(ValidationError::InvalidNumaNodeCpu(*cpu));
+                        }
+                        node_cpus.push(*cpu);
+                    }
+                }
+
+                // The distance of a node to itself is always 10, which means
+                // the distance to any other node must be greater.
+                if let Some(distances) = &node.distances {
+                    for distance in distances.iter() {
+                        if distance.destination >= node_count
+                            || distance.destination == node.guest_numa_id
+                            || distance.distance <= 10
+                        {
+                            return Err(ValidationError::InvalidNumaNodeDistance(
+                                node.guest_numa_id,
+                                distance.destination,
+                            ));
+                        }
+                    }
+                }
+
+                nodes_size += node.size;
+                if let Some(hotplug_size) = node.hotplug_size {
+                    *nodes_hotplug_size.get_or_insert(0) += hotplug_size;
+                }
+            }
+
+            if nodes_size != self.memory.size {
+                return Err(ValidationError::NumaNodesMemoryMismatch);
+            }
+
+            // Without any hotpluggable memory per node, it all belongs to
+            // the first node.
+            if let Some(nodes_hotplug_size) = nodes_hotplug_size {
+                if self.memory.hotplug_method != HotplugMethod::VirtioMem {
+                    return Err(ValidationError::NumaNodesHotplugRequiresVirtioMem);
+                }
+                if Some(nodes_hotplug_size) != self.memory.hotplug_size {
+                    return Err(ValidationError::NumaNodesHotplugMemoryMismatch);
+                }
+            }
+        }
+
+        for feature in self.cpus.features.iter() {
+            #[cfg(target_arch = "x86_64")]
+            let known = arch::x86_64::cpuid::cpuid_feature(&feature.name).is_some();
+            #[cfg(not(target_arch = "x86_64"))]
+            let known = false;
+            if !known {
+                return Err(ValidationError::UnknownCpuFeature(feature.name.clone()));
+            }
+        }
+
         Ok(())
     }
 
@@ -1362,6 +2127,25 @@ impl VmConfig {
             }
         }
 
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        let mut gdb: Option<GdbConfig> = None;
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        {
+            if let Some(gdb_params) = vm_params.gdb {
+                gdb = Some(GdbConfig::parse(gdb_params)?);
+            }
+        }
+
+        let mut numa: Option<Vec<NumaConfig>> = None;
+        if let Some(numa_list) = &vm_params.numa {
+            let mut numa_config_list = Vec::new();
+            for item in numa_list.iter() {
+                let numa_config = NumaConfig::parse(item)?;
+                numa_config_list.push(numa_config);
+            }
+            numa = Some(numa_config_list);
+        }
+
         let mut kernel: Option<KernelConfig> = None;
         if let Some(k) = vm_params.kernel {
             kernel = Some(KernelConfig {
@@ -1376,9 +2160,19 @@ impl VmConfig {
             });
         }
 
+        let mut memory = MemoryConfig::parse(vm_params.memory)?;
+        if let Some(memory_zones) = &vm_params.memory_zones {
+            let mut memory_zone_config_list = Vec::new();
+            for item in memory_zones.iter() {
+                let memory_zone_config = MemoryZoneConfig::parse(item)?;
+                memory_zone_config_list.push(memory_zone_config);
+            }
+            memory.zones = Some(memory_zone_config_list);
+        }
+
         let config = VmConfig {
             cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory)?,
+            memory,
             kernel,
             initramfs,
             cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
@@ -1394,6 +2188,9 @@ impl VmConfig {
             iommu,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            gdb,
+            numa,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -1438,7 +2235,10 @@ mod tests {
             CpusConfig {
                 boot_vcpus: 1,
                 max_vcpus: 1,
-                topology: None
+                topology: None,
+                model: None,
+                features: Vec::new(),
+                affinity: None,
             }
         );
         assert_eq!(
@@ -1446,7 +2246,10 @@ mod tests {
             CpusConfig {
                 boot_vcpus: 1,
                 max_vcpus: 2,
-                topology: None
+                topology: None,
+                model: None,
+                features: Vec::new(),
+                affinity: None,
             }
         );
         assert_eq!(
@@ -1459,12 +2262,108 @@ mod tests {
                     cores_per_die: 2,
                     dies_per_package: 1,
                     packages: 2
-                })
+                }),
+                model: None,
+                features: Vec::new(),
+                affinity: None,
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=2,model=x86-64-v3,features=[-avx512f,+x2apic,pdpe1gb]")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                topology: None,
+                model: Some("x86-64-v3".to_owned()),
+                features: vec![
+                    CpuFeatureConfig {
+                        name: "avx512f".to_owned(),
+                        enabled: false,
+                    },
+                    CpuFeatureConfig {
+                        name: "x2apic".to_owned(),
+                        enabled: true,
+                    },
+                    CpuFeatureConfig {
+                        name: "pdpe1gb".to_owned(),
+                        enabled: true,
+                    },
+                ],
+                affinity: None,
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=2,affinity=[0@[2,3],1@[4-6]]")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                topology: None,
+                model: None,
+                features: Vec::new(),
+                affinity: Some(vec![
+                    CpuAffinity {
+                        vcpu: 0,
+                        host_cpus: vec![2, 3],
+                    },
+                    CpuAffinity {
+                        vcpu: 1,
+                        host_cpus: vec![4, 5, 6],
+                    },
+                ]),
             }
         );
 
         assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
         assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+        assert!(CpusConfig::parse("features=-avx512f").is_err());
+        assert!(CpusConfig::parse("features=[-avx512f,+]").is_err());
+        assert!(CpusConfig::parse("features=[-avx512f").is_err());
+        assert!(CpusConfig::parse("affinity=[0@[2,3],1]").is_err());
+        assert!(CpusConfig::parse("affinity=[0@[3-2]]").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_numa_parsing() -> Result<()> {
+        assert_eq!(
+            NumaConfig::parse("guest_numa_id=1,size=1G")?,
+            NumaConfig {
+                guest_numa_id: 1,
+                cpus: None,
+                size: 1 << 30,
+                distances: None,
+                host_numa_node: None,
+                hotplug_size: None,
+            }
+        );
+        assert_eq!(
+            NumaConfig::parse(
+                "guest_numa_id=0,cpus=[0-2,5],size=512M,distances=[1@20,2@25],host_numa_node=1,\
+                 hotplug_size=1G"
+            )?,
+            NumaConfig {
+                guest_numa_id: 0,
+                cpus: Some(vec![0, 1, 2, 5]),
+                size: 512 << 20,
+                distances: Some(vec![
+                    NumaDistance {
+                        destination: 1,
+                        distance: 20,
+                    },
+                    NumaDistance {
+                        destination: 2,
+                        distance: 25,
+                    },
+                ]),
+                host_numa_node: Some(1),
+                hotplug_size: Some(1 << 30),
+            }
+        );
+
+        assert!(NumaConfig::parse("cpus=[0,300]").is_err());
+        assert!(NumaConfig::parse("distances=[1@300]").is_err());
+        assert!(NumaConfig::parse("distances=1@20").is_err());
 
         Ok(())
     }
@@ -1526,6 +2425,76 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("size=2G,hugepages=on,hugepage_size=1G")?,
+            MemoryConfig {
+                size: 2 << 30,
+                hugepages: true,
+                hugepage_size: Some(1 << 30),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,balloon=on,balloon_stats_interval=5")?,
+            MemoryConfig {
+                size: 1 << 30,
+                balloon: true,
+                balloon_stats_interval: Some(5),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,balloon=on,free_page_reporting=on")?,
+            MemoryConfig {
+                size: 1 << 30,
+                balloon: true,
+                free_page_reporting: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,balloon=on,deflate_on_oom=on")?,
+            MemoryConfig {
+                size: 1 << 30,
+                balloon: true,
+                deflate_on_oom: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_zone_parsing() -> Result<()> {
+        assert_eq!(
+            MemoryZoneConfig::parse("id=mem0,size=1G")?,
+            MemoryZoneConfig {
+                id: "mem0".to_owned(),
+                size: 1 << 30,
+                file: None,
+                shared: false,
+                hugepages: false,
+                hugepage_size: None,
+                mergeable: false,
+            }
+        );
+        assert_eq!(
+            MemoryZoneConfig::parse(
+                "id=mem1,size=2G,file=/dev/shm,shared=on,hugepages=on,hugepage_size=1G,mergeable=on"
+            )?,
+            MemoryZoneConfig {
+                id: "mem1".to_owned(),
+                size: 2 << 30,
+                file: Some(PathBuf::from("/dev/shm")),
+                shared: true,
+                hugepages: true,
+                hugepage_size: Some(1 << 30),
+                mergeable: true,
+            }
+        );
+
+        assert!(MemoryZoneConfig::parse("size=1G").is_err());
+
         Ok(())
     }
 
@@ -1619,6 +2588,42 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,rate_limit_bw_size=1000,rate_limit_bw_refill_time=100"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1000,
+                        one_time_burst: None,
+                        refill_time: 100,
+                    }),
+                    ops: None,
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,rate_limit_ops_size=10,rate_limit_ops_one_time_burst=50,\
+                 rate_limit_ops_refill_time=1000"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: None,
+                    ops: Some(TokenBucketConfig {
+                        size: 10,
+                        one_time_burst: Some(50),
+                        refill_time: 1000,
+                    }),
+                }),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,rate_limit_bw_size=foo").is_err());
 
         Ok(())
     }
@@ -1684,6 +2689,34 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,rx_rate_limit_bw_size=1000,rx_rate_limit_bw_refill_time=100,\
+                 tx_rate_limit_ops_size=10,tx_rate_limit_ops_refill_time=1000"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                rx_rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1000,
+                        one_time_burst: None,
+                        refill_time: 100,
+                    }),
+                    ops: None,
+                }),
+                tx_rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: None,
+                    ops: Some(TokenBucketConfig {
+                        size: 10,
+                        one_time_burst: None,
+                        refill_time: 1000,
+                    }),
+                }),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("rx_rate_limit_ops_size=-1").is_err());
+
         Ok(())
     }
 
@@ -1923,6 +2956,54 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    #[test]
+    fn test_gdb_parsing() -> Result<()> {
+        // socket is required
+        assert!(GdbConfig::parse("").is_err());
+        assert_eq!(
+            GdbConfig::parse("socket=/tmp/gdb.sock")?,
+            GdbConfig {
+                socket: PathBuf::from("/tmp/gdb.sock"),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_parsing() -> Result<()> {
+        // source_url is required
+        assert!(RestoreConfig::parse("prefault=on").is_err());
+        assert_eq!(
+            RestoreConfig::parse("source_url=/tmp/snapshot")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/tmp/snapshot"),
+                prefault: false,
+                lazy: false,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/tmp/snapshot,lazy=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/tmp/snapshot"),
+                prefault: false,
+                lazy: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/tmp/snapshot,verify_files=on,allow_unverified=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/tmp/snapshot"),
+                verify_files: true,
+                allow_unverified: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_config_validation() -> Result<()> {
         let valid_config = VmConfig {
@@ -1930,6 +3011,9 @@ mod tests {
                 boot_vcpus: 1,
                 max_vcpus: 1,
                 topology: None,
+                model: None,
+                features: Vec::new(),
+                affinity: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -1939,8 +3023,13 @@ mod tests {
                 hotplug_size: None,
                 shared: false,
                 hugepages: false,
+                hugepage_size: None,
                 balloon: false,
                 balloon_size: 0,
+                balloon_stats_interval: None,
+                free_page_reporting: false,
+                deflate_on_oom: false,
+                zones: None,
             },
             kernel: Some(KernelConfig {
                 path: PathBuf::from("/path/to/kernel"),
@@ -1972,6 +3061,9 @@ mod tests {
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            gdb: None,
+            numa: None,
         };
 
         assert!(valid_config.validate().is_ok());
@@ -2006,6 +3098,119 @@ mod tests {
         });
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 1,
+            host_cpus: vec![2],
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![
+            CpuAffinity {
+                vcpu: 0,
+                host_cpus: vec![2],
+            },
+            CpuAffinity {
+                vcpu: 0,
+                host_cpus: vec![3],
+            },
+        ]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 0,
+            host_cpus: Vec::new(),
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 0,
+            host_cpus: vec![0, 1],
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let numa_node = |guest_numa_id, cpus, size| NumaConfig {
+            guest_numa_id,
+            cpus: Some(cpus),
+            size,
+            distances: None,
+            host_numa_node: None,
+            hotplug_size: None,
+        };
+
+        let mut numa_config = valid_config.clone();
+        numa_config.numa = Some(vec![
+            numa_node(0, vec![0], 256 << 20),
+            numa_node(1, Vec::new(), 256 << 20),
+        ]);
+        #[cfg(not(all(target_arch = "x86_64", feature = "acpi")))]
+        assert!(numa_config.validate().is_err());
+
+        #[cfg(all(target_arch = "x86_64", feature = "acpi"))]
+        {
+            let still_valid_config = numa_config;
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.numa.as_mut().unwrap()[1].guest_numa_id = 2;
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.numa.as_mut().unwrap()[1].cpus = Some(vec![0]);
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.numa.as_mut().unwrap()[1].size = 512 << 20;
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.numa.as_mut().unwrap()[0].distances = Some(vec![NumaDistance {
+                destination: 1,
+                distance: 10,
+            }]);
+            assert!(invalid_config.validate().is_err());
+
+            let mut still_valid_config = still_valid_config.clone();
+            still_valid_config.memory.hotplug_method = HotplugMethod::VirtioMem;
+            still_valid_config.memory.hotplug_size = Some(1 << 30);
+            still_valid_config.numa.as_mut().unwrap()[0].hotplug_size = Some(512 << 20);
+            still_valid_config.numa.as_mut().unwrap()[1].hotplug_size = Some(512 << 20);
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.memory.hotplug_size = Some(2 << 30);
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.memory.hotplug_method = HotplugMethod::Acpi;
+            assert!(invalid_config.validate().is_err());
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.model = Some("pentium".to_owned());
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.features = vec![CpuFeatureConfig {
+            name: "avx1024".to_owned(),
+            enabled: false,
+        }];
+        assert!(invalid_config.validate().is_err());
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.model = Some("x86-64-v2".to_owned());
+            still_valid_config.cpus.features = vec![CpuFeatureConfig {
+                name: "avx".to_owned(),
+                enabled: true,
+            }];
+            assert!(still_valid_config.validate().is_ok());
+        }
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
             vhost_socket: Some("/path/to/sock".to_owned()),
@@ -2029,6 +3234,162 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hugepages = true;
+        still_valid_config.memory.hugepage_size = Some(1 << 30);
+        still_valid_config.memory.size = 2 << 30;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.size = 3 << 29;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.hugepage_size = Some(3 << 20);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.hugepages = false;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.balloon = true;
+        still_valid_config.memory.balloon_stats_interval = Some(1);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.balloon = false;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.balloon_stats_interval = Some(0);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/disk")),
+            rate_limiter_config: Some(RateLimiterConfig {
+                bandwidth: Some(TokenBucketConfig {
+                    size: 1000,
+                    one_time_burst: None,
+                    refill_time: 100,
+                }),
+                ops: None,
+            }),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0]
+            .rate_limiter_config
+            .as_mut()
+            .unwrap()
+            .bandwidth
+            .as_mut()
+            .unwrap()
+            .refill_time = 0;
+        assert!(invalid_config.validate().is_err());
+
+        // Only a vhost-user backend spawned by the VMM can be rate limited.
+        let mut still_valid_config = still_valid_config.clone();
+        still_valid_config.memory.shared = true;
+        still_valid_config.disks.as_mut().unwrap()[0].vhost_user = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].path = None;
+        invalid_config.disks.as_mut().unwrap()[0].vhost_socket =
+            Some(String::from("/tmp/vhost-user-blk.sock"));
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserDiskSocketRateLimiter)
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            tx_rate_limiter_config: Some(RateLimiterConfig {
+                bandwidth: None,
+                ops: Some(TokenBucketConfig {
+                    size: 0,
+                    one_time_burst: None,
+                    refill_time: 100,
+                }),
+            }),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.balloon = true;
+        still_valid_config.memory.free_page_reporting = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.balloon = false;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.balloon = true;
+        still_valid_config.memory.deflate_on_oom = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.balloon = false;
+        assert!(invalid_config.validate().is_err());
+
+        let memory_zone = |id: &str, size, shared| MemoryZoneConfig {
+            id: id.to_owned(),
+            size,
+            file: None,
+            shared,
+            hugepages: false,
+            hugepage_size: None,
+            mergeable: false,
+        };
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        still_valid_config.memory.zones = Some(vec![
+            memory_zone("mem0", 256 << 20, true),
+            memory_zone("mem1", 256 << 20, true),
+        ]);
+        assert!(still_valid_config.validate().is_ok());
+
+        // Every zone must be shared with the vhost-user backends.
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.zones.as_mut().unwrap()[1].shared = false;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config_file = invalid_config.clone();
+        still_valid_config_file.memory.zones.as_mut().unwrap()[1].file =
+            Some(PathBuf::from("/dev/shm/mem1"));
+        assert!(still_valid_config_file.validate().is_ok());
+
+        // And so must the hotpluggable memory.
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.hotplug_size = Some(1 << 30);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config_hotplug = invalid_config.clone();
+        still_valid_config_hotplug.memory.shared = true;
+        assert!(still_valid_config_hotplug.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.zones.as_mut().unwrap()[1].id = "mem0".to_owned();
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.zones.as_mut().unwrap()[1].size = 512 << 20;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.zones.as_mut().unwrap()[1].hugepage_size = Some(2 << 20);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
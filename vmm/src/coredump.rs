@@ -0,0 +1,444 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Guest core dump, in the ELF format.
+//!
+//! Each guest RAM region is written as a PT_LOAD segment, its physical
+//! address being the guest physical address of the region. The vCPUs state
+//! is written to a PT_NOTE segment, as one NT_PRSTATUS note per vCPU carrying
+//! the general purpose registers, followed by a "QEMU" note carrying the
+//! system registers. This is the layout expected by crash analysis tools such
+//! as `crash` or `gdb`.
+
+use hypervisor::CpuState;
+use std::io::{self, Write};
+use std::mem::size_of;
+use vm_memory::{Address, ByteValued, Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+// See include/uapi/linux/elf.h in the kernel code.
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM_MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_MACHINE: u16 = 183;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+#[cfg(target_arch = "x86_64")]
+const NT_PRSTATUS: u32 = 1;
+
+// The memory content starts on a page boundary.
+const DATA_ALIGNMENT: u64 = 0x1000;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+// The notes only describe the vCPUs on x86_64.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+// Safe because these structures only contain plain data, without padding.
+unsafe impl ByteValued for Elf64Ehdr {}
+unsafe impl ByteValued for Elf64Phdr {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl ByteValued for Elf64Nhdr {}
+
+// See struct elf_prstatus in include/linux/elfcore.h in the kernel code.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct ElfPrStatus {
+    si_signo: i32,
+    si_code: i32,
+    si_errno: i32,
+    pr_cursig: i16,
+    pad0: i16,
+    pr_sigpend: u64,
+    pr_sighold: u64,
+    pr_pid: i32,
+    pr_ppid: i32,
+    pr_pgrp: i32,
+    pr_sid: i32,
+    pr_utime: [u64; 2],
+    pr_stime: [u64; 2],
+    pr_cutime: [u64; 2],
+    pr_cstime: [u64; 2],
+    // Laid out as struct user_regs_struct.
+    pr_reg: [u64; 27],
+    pr_fpvalid: i32,
+    pad1: i32,
+}
+
+// See QEMUCPUSegment and QEMUCPUState in target/i386/arch_dump.c in the QEMU
+// code.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct QemuCpuSegment {
+    selector: u32,
+    limit: u32,
+    flags: u32,
+    pad: u32,
+    base: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct QemuCpuState {
+    version: u32,
+    size: u32,
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rsp: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rflags: u64,
+    cs: QemuCpuSegment,
+    ds: QemuCpuSegment,
+    es: QemuCpuSegment,
+    fs: QemuCpuSegment,
+    gs: QemuCpuSegment,
+    ss: QemuCpuSegment,
+    ldt: QemuCpuSegment,
+    tr: QemuCpuSegment,
+    gdt: QemuCpuSegment,
+    idt: QemuCpuSegment,
+    cr: [u64; 5],
+    kernel_gs_base: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe impl ByteValued for ElfPrStatus {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl ByteValued for QemuCpuSegment {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl ByteValued for QemuCpuState {}
+
+#[cfg(target_arch = "x86_64")]
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+#[cfg(target_arch = "x86_64")]
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+// Append a note made of its header, its NUL terminated name and its
+// descriptor, both padded to 4 bytes.
+#[cfg(target_arch = "x86_64")]
+fn append_note(notes: &mut Vec<u8>, name: &str, n_type: u32, desc: &[u8]) {
+    let header = Elf64Nhdr {
+        n_namesz: name.len() as u32 + 1,
+        n_descsz: desc.len() as u32,
+        n_type,
+    };
+    notes.extend_from_slice(header.as_slice());
+    notes.extend_from_slice(name.as_bytes());
+    notes.resize(align_up(notes.len() as u64 + 1, 4) as usize, 0);
+    notes.extend_from_slice(desc);
+    notes.resize(align_up(notes.len() as u64, 4) as usize, 0);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn qemu_segment(segment: &hypervisor::x86_64::SegmentRegister) -> QemuCpuSegment {
+    QemuCpuSegment {
+        selector: u32::from(segment.selector),
+        limit: segment.limit,
+        // Same layout as the upper half of a segment descriptor.
+        flags: u32::from(segment.type_) << 8
+            | u32::from(segment.s) << 12
+            | u32::from(segment.dpl) << 13
+            | u32::from(segment.present) << 15
+            | u32::from(segment.avl) << 20
+            | u32::from(segment.l) << 21
+            | u32::from(segment.db) << 22
+            | u32::from(segment.g) << 23,
+        pad: 0,
+        base: segment.base,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn vcpu_notes(notes: &mut Vec<u8>, vcpus: &[(u8, CpuState)]) {
+    for (id, state) in vcpus {
+        let regs = &state.regs;
+        let sregs = &state.sregs;
+
+        let prstatus = ElfPrStatus {
+            // Tools identify each vCPU as a thread.
+            pr_pid: i32::from(*id) + 1,
+            pr_reg: [
+                regs.r15,
+                regs.r14,
+                regs.r13,
+                regs.r12,
+                regs.rbp,
+                regs.rbx,
+                regs.r11,
+                regs.r10,
+                regs.r9,
+                regs.r8,
+                regs.rax,
+                regs.rcx,
+                regs.rdx,
+                regs.rsi,
+                regs.rdi,
+                // No system call being interrupted.
+                u64::MAX,
+                regs.rip,
+                u64::from(sregs.cs.selector),
+                regs.rflags,
+                regs.rsp,
+                u64::from(sregs.ss.selector),
+                sregs.fs.base,
+                sregs.gs.base,
+                u64::from(sregs.ds.selector),
+                u64::from(sregs.es.selector),
+                u64::from(sregs.fs.selector),
+                u64::from(sregs.gs.selector),
+            ],
+            ..Default::default()
+        };
+        append_note(notes, "CORE", NT_PRSTATUS, prstatus.as_slice());
+
+        let kernel_gs_base = state
+            .msrs
+            .as_slice()
+            .iter()
+            .find(|entry| entry.index == MSR_KERNEL_GS_BASE)
+            .map_or(0, |entry| entry.data);
+        let qemu_state = QemuCpuState {
+            version: QEMU_CPU_STATE_VERSION,
+            size: size_of::<QemuCpuState>() as u32,
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.rflags,
+            cs: qemu_segment(&sregs.cs),
+            ds: qemu_segment(&sregs.ds),
+            es: qemu_segment(&sregs.es),
+            fs: qemu_segment(&sregs.fs),
+            gs: qemu_segment(&sregs.gs),
+            ss: qemu_segment(&sregs.ss),
+            ldt: qemu_segment(&sregs.ldt),
+            tr: qemu_segment(&sregs.tr),
+            gdt: QemuCpuSegment {
+                limit: u32::from(sregs.gdt.limit),
+                base: sregs.gdt.base,
+                ..Default::default()
+            },
+            idt: QemuCpuSegment {
+                limit: u32::from(sregs.idt.limit),
+                base: sregs.idt.base,
+                ..Default::default()
+            },
+            cr: [sregs.cr0, 0, sregs.cr2, sregs.cr3, sregs.cr4],
+            kernel_gs_base,
+        };
+        append_note(notes, "QEMU", 0, qemu_state.as_slice());
+    }
+}
+
+// The vCPU state doesn't expose any register on this architecture.
+#[cfg(target_arch = "aarch64")]
+fn vcpu_notes(_notes: &mut Vec<u8>, _vcpus: &[(u8, CpuState)]) {}
+
+/// Write an ELF core dump made of the guest memory and of the state of each
+/// vCPU, identified by its id. The vCPUs must not be running.
+pub fn write_coredump<F: Write>(
+    dst: &mut F,
+    guest_memory: &GuestMemoryMmap,
+    vcpus: &[(u8, CpuState)],
+) -> io::Result<()> {
+    let mut regions = Vec::new();
+    guest_memory.with_regions_mut(|_, region| {
+        regions.push((region.start_addr(), region.len()));
+        Ok::<(), io::Error>(())
+    })?;
+
+    let mut notes = Vec::new();
+    vcpu_notes(&mut notes, vcpus);
+
+    let phnum = regions.len() + 1;
+    let notes_offset = (size_of::<Elf64Ehdr>() + phnum * size_of::<Elf64Phdr>()) as u64;
+    let data_offset = align_up(notes_offset + notes.len() as u64, DATA_ALIGNMENT);
+
+    let mut e_ident = [0u8; 16];
+    e_ident[..4].copy_from_slice(b"\x7fELF");
+    e_ident[4] = ELFCLASS64;
+    e_ident[5] = ELFDATA2LSB;
+    e_ident[6] = EV_CURRENT;
+    let header = Elf64Ehdr {
+        e_ident,
+        e_type: ET_CORE,
+        e_machine: EM_MACHINE,
+        e_version: u32::from(EV_CURRENT),
+        e_phoff: size_of::<Elf64Ehdr>() as u64,
+        e_ehsize: size_of::<Elf64Ehdr>() as u16,
+        e_phentsize: size_of::<Elf64Phdr>() as u16,
+        e_phnum: phnum as u16,
+        ..Default::default()
+    };
+    dst.write_all(header.as_slice())?;
+
+    let note_header = Elf64Phdr {
+        p_type: PT_NOTE,
+        p_offset: notes_offset,
+        p_filesz: notes.len() as u64,
+        p_memsz: notes.len() as u64,
+        ..Default::default()
+    };
+    dst.write_all(note_header.as_slice())?;
+
+    let mut offset = data_offset;
+    for (start, len) in regions.iter() {
+        let load_header = Elf64Phdr {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_W,
+            p_offset: offset,
+            p_paddr: start.raw_value(),
+            p_filesz: *len,
+            p_memsz: *len,
+            ..Default::default()
+        };
+        dst.write_all(load_header.as_slice())?;
+        offset += len;
+    }
+
+    dst.write_all(&notes)?;
+    let padding = data_offset - notes_offset - notes.len() as u64;
+    dst.write_all(&vec![0u8; padding as usize])?;
+
+    for (start, len) in regions.iter() {
+        guest_memory
+            .write_all_to(*start, dst, *len as usize)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_coredump_layout() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x10_0000), 0x1000),
+        ])
+        .unwrap();
+        guest_memory
+            .write_slice(&[0xaa; 4], GuestAddress(0x10_0000))
+            .unwrap();
+
+        let mut core = Vec::new();
+        write_coredump(&mut core, &guest_memory, &[]).unwrap();
+
+        let header = Elf64Ehdr::from_slice(&core[..size_of::<Elf64Ehdr>()]).unwrap();
+        assert_eq!(&header.e_ident[..4], b"\x7fELF");
+        assert_eq!(header.e_type, ET_CORE);
+        assert_eq!(header.e_phnum, 3);
+
+        let phdr = |index: usize| {
+            let start = header.e_phoff as usize + index * size_of::<Elf64Phdr>();
+            *Elf64Phdr::from_slice(&core[start..start + size_of::<Elf64Phdr>()]).unwrap()
+        };
+        assert_eq!(phdr(0).p_type, PT_NOTE);
+        assert_eq!(phdr(0).p_filesz, 0);
+
+        let load = phdr(2);
+        assert_eq!(load.p_type, PT_LOAD);
+        assert_eq!(load.p_paddr, 0x10_0000);
+        assert_eq!(load.p_filesz, 0x1000);
+        assert_eq!(load.p_offset, phdr(1).p_offset + 0x2000);
+        assert_eq!(load.p_offset % DATA_ALIGNMENT, 0);
+        assert_eq!(core.len() as u64, load.p_offset + load.p_filesz);
+        let data = load.p_offset as usize;
+        assert_eq!(&core[data..data + 4], &[0xaa; 4]);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_note_layout() {
+        assert_eq!(size_of::<ElfPrStatus>(), 336);
+        assert_eq!(size_of::<QemuCpuState>(), 440);
+
+        let mut notes = Vec::new();
+        append_note(&mut notes, "CORE", NT_PRSTATUS, &[1, 2, 3]);
+        // Header, name padded to 8 bytes and descriptor padded to 4 bytes.
+        assert_eq!(notes.len(), 12 + 8 + 4);
+        assert_eq!(&notes[12..17], b"CORE\0");
+        assert_eq!(&notes[20..24], &[1, 2, 3, 0]);
+    }
+}
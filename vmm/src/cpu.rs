@@ -11,8 +11,6 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-#[cfg(target_arch = "x86_64")]
-use crate::config::CpuTopology;
 use crate::config::CpusConfig;
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
@@ -23,7 +21,7 @@ use anyhow::anyhow;
 #[cfg(feature = "acpi")]
 use arch::layout;
 #[cfg(target_arch = "x86_64")]
-use arch::x86_64::SgxEpcSection;
+use arch::x86_64::{cpuid, SgxEpcSection};
 use arch::EntryPoint;
 #[cfg(target_arch = "x86_64")]
 use arch::{CpuidPatch, CpuidReg};
@@ -38,7 +36,7 @@ use libc::{c_void, siginfo_t};
 use std::fmt;
 use std::os::unix::thread::JoinHandleExt;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use std::{cmp, io, result, thread};
 #[cfg(target_arch = "x86_64")]
 use vm_memory::GuestAddress;
@@ -48,6 +46,8 @@ use vm_migration::{
     Transportable,
 };
 use vmm_sys_util::eventfd::EventFd;
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use vmm_sys_util::eventfd::EFD_NONBLOCK;
 use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
 
 // CPUID feature bits
@@ -112,9 +112,19 @@ pub enum Error {
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
 
+    /// Cannot set the affinity of a vCPU thread.
+    VcpuAffinity(io::Error),
+
+    /// Cannot get the affinity status of a vCPU thread.
+    VcpuAffinityStatus(mpsc::RecvError),
+
     /// Cannot patch the CPU ID
     PatchCpuId(anyhow::Error),
 
+    #[cfg(target_arch = "x86_64")]
+    /// The host does not provide a CPU feature requested for the vCPUs.
+    CpuFeatureUnsupported(String),
+
     /// The call to KVM_SET_CPUID2 failed.
     SetSupportedCpusFailed(anyhow::Error),
 
@@ -193,6 +203,14 @@ pub enum Error {
 
     /// Error resuming vCPU on shutdown
     ResumeOnShutdown(MigratableError),
+
+    /// Cannot create the vCPUs debug EventFd.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    DebugEventFd(io::Error),
+
+    /// Failed to set the vCPU guest debug state.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    SetGuestDebug(anyhow::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -228,6 +246,18 @@ struct InterruptSourceOverride {
     pub flags: u16,
 }
 
+/// What the vCPU thread should do after `Vcpu::run()` returned.
+#[derive(Debug, PartialEq)]
+pub enum VcpuRunResult {
+    /// Keep running the vCPU.
+    Continue,
+    /// The guest triple-faulted or shut down, the VM must be reset.
+    Reset,
+    /// The vCPU hit a breakpoint or completed a single-step.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    Debug,
+}
+
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     // The hypervisor abstracted CPU.
@@ -316,13 +346,13 @@ impl Vcpu {
     ///
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
     /// anything useful.
-    pub fn run(&self) -> Result<bool> {
+    pub fn run(&self) -> Result<VcpuRunResult> {
         match self.vcpu.run() {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VmExit::IoIn(addr, data) => {
                     self.io_bus.read(u64::from(addr), data);
-                    Ok(true)
+                    Ok(VcpuRunResult::Continue)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VmExit::IoOut(addr, data) => {
@@ -330,15 +360,15 @@ impl Vcpu {
                         self.log_debug_ioport(data[0]);
                     }
                     self.io_bus.write(u64::from(addr), data);
-                    Ok(true)
+                    Ok(VcpuRunResult::Continue)
                 }
                 VmExit::MmioRead(addr, data) => {
                     self.mmio_bus.read(addr as u64, data);
-                    Ok(true)
+                    Ok(VcpuRunResult::Continue)
                 }
                 VmExit::MmioWrite(addr, data) => {
                     self.mmio_bus.write(addr as u64, data);
-                    Ok(true)
+                    Ok(VcpuRunResult::Continue)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VmExit::IoapicEoi(vector) => {
@@ -348,11 +378,16 @@ impl Vcpu {
                             .unwrap()
                             .end_of_interrupt(vector);
                     }
-                    Ok(true)
+                    Ok(VcpuRunResult::Continue)
                 }
-
-                VmExit::Ignore => Ok(true),
-                VmExit::Reset => Ok(false),
+                #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+                VmExit::Debug => Ok(VcpuRunResult::Debug),
+                // Guest debugging is only ever enabled by the GDB stub.
+                #[cfg(all(target_arch = "x86_64", not(feature = "gdb")))]
+                VmExit::Debug => Ok(VcpuRunResult::Continue),
+
+                VmExit::Ignore => Ok(VcpuRunResult::Continue),
+                VmExit::Reset => Ok(VcpuRunResult::Reset),
             },
 
             Err(e) => Err(Error::VcpuRun(e.into())),
@@ -395,6 +430,9 @@ impl Pausable for Vcpu {
         Ok(())
     }
 }
+
+const VCPU_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Vcpu {
     fn id(&self) -> String {
         VCPU_SNAPSHOT_ID.to_string()
@@ -407,6 +445,7 @@ impl Snapshottable for Vcpu {
         let mut vcpu_snapshot = Snapshot::new(&format!("{}", self.id));
         vcpu_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", VCPU_SNAPSHOT_ID),
+            version: VCPU_SNAPSHOT_VERSION,
             snapshot,
         });
 
@@ -418,6 +457,8 @@ impl Snapshottable for Vcpu {
             .snapshot_data
             .get(&format!("{}-section", VCPU_SNAPSHOT_ID))
         {
+            vcpu_section.check_version(VCPU_SNAPSHOT_VERSION)?;
+
             let vcpu_state = match serde_json::from_slice(&vcpu_section.snapshot) {
                 Ok(state) => state,
                 Err(error) => {
@@ -460,6 +501,8 @@ pub struct CpuManager {
     vcpu_states: Vec<VcpuState>,
     selected_cpu: u8,
     vcpus: Vec<Arc<Mutex<Vcpu>>>,
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    debug_evt: EventFd,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -538,6 +581,8 @@ struct VcpuState {
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    debug_exit: Arc<AtomicBool>,
 }
 
 impl VcpuState {
@@ -599,7 +644,7 @@ impl CpuManager {
                 None
             };
         #[cfg(target_arch = "x86_64")]
-        let cpuid = CpuManager::patch_cpuid(hypervisor, &config.topology, sgx_epc_sections)?;
+        let cpuid = CpuManager::patch_cpuid(hypervisor, config, sgx_epc_sections)?;
 
         let device_manager = device_manager.lock().unwrap();
         let cpu_manager = Arc::new(Mutex::new(CpuManager {
@@ -618,6 +663,8 @@ impl CpuManager {
             reset_evt,
             selected_cpu: 0,
             vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            debug_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::DebugEventFd)?,
         }));
 
         #[cfg(target_arch = "x86_64")]
@@ -642,7 +689,7 @@ impl CpuManager {
     #[cfg(target_arch = "x86_64")]
     fn patch_cpuid(
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
-        topology: &Option<CpuTopology>,
+        config: &CpusConfig,
         sgx_epc_sections: Option<Vec<SgxEpcSection>>,
     ) -> Result<CpuId> {
         let mut cpuid_patches = Vec::new();
@@ -676,7 +723,9 @@ impl CpuManager {
 
         CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
 
-        if let Some(t) = topology {
+        CpuManager::apply_cpu_features(&mut cpuid, config)?;
+
+        if let Some(t) = &config.topology {
             arch::x86_64::update_cpuid_topology(
                 &mut cpuid,
                 t.threads_per_core,
@@ -692,6 +741,41 @@ impl CpuManager {
         Ok(cpuid)
     }
 
+    // Restrict the CPUID to the features of the CPU model, and then add or
+    // remove the features explicitly listed. All the features ending up
+    // exposed to the guest must be provided by the host.
+    #[cfg(target_arch = "x86_64")]
+    fn apply_cpu_features(cpuid: &mut CpuId, config: &CpusConfig) -> Result<()> {
+        let host_cpuid = cpuid.clone();
+        let feature = |name: &str| {
+            cpuid::cpuid_feature(name)
+                .ok_or_else(|| Error::PatchCpuId(anyhow!("Unknown CPU feature {}", name)))
+        };
+
+        if let Some(name) = &config.model {
+            let model = cpuid::cpu_model(name)
+                .ok_or_else(|| Error::PatchCpuId(anyhow!("Unknown CPU model {}", name)))?;
+            for name in model.features() {
+                if !cpuid::is_cpuid_feature_enabled(&host_cpuid, feature(name)?) {
+                    return Err(Error::CpuFeatureUnsupported(name.to_owned()));
+                }
+            }
+            cpuid::apply_cpu_model(cpuid, model);
+        }
+
+        for feature_config in config.features.iter() {
+            let cpuid_feature = feature(&feature_config.name)?;
+            if feature_config.enabled
+                && !cpuid::is_cpuid_feature_enabled(&host_cpuid, cpuid_feature)
+            {
+                return Err(Error::CpuFeatureUnsupported(feature_config.name.clone()));
+            }
+            cpuid::set_cpuid_feature(cpuid, cpuid_feature, feature_config.enabled);
+        }
+
+        Ok(())
+    }
+
     fn create_vcpu(
         &mut self,
         cpu_id: u8,
@@ -779,12 +863,15 @@ impl CpuManager {
         Ok(())
     }
 
+    // Spawns the vCPU thread, which waits on `vcpu_thread_barrier` before
+    // running the vCPU. The returned channel tells whether the thread could
+    // be pinned to its host CPUs.
     fn start_vcpu(
         &mut self,
         vcpu: Arc<Mutex<Vcpu>>,
         vcpu_thread_barrier: Arc<Barrier>,
         inserting: bool,
-    ) -> Result<()> {
+    ) -> Result<mpsc::Receiver<io::Result<()>>> {
         let cpu_id = vcpu.lock().unwrap().id;
         let reset_evt = self.reset_evt.try_clone().unwrap();
         let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
@@ -794,9 +881,27 @@ impl CpuManager {
         let vcpu_run_interrupted = self.vcpu_states[usize::from(cpu_id)]
             .vcpu_run_interrupted
             .clone();
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        let vcpu_debug_exit = self.vcpu_states[usize::from(cpu_id)].debug_exit.clone();
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        let debug_evt = self.debug_evt.try_clone().unwrap();
+
+        // Prepare the CPU set the vCPU thread is pinned to, if any.
+        let cpuset = self.config.affinity.as_ref().and_then(|affinity| {
+            affinity.iter().find(|a| a.vcpu == cpu_id).map(|a| {
+                // The host CPUs have been checked against CPU_SETSIZE
+                // when validating the configuration.
+                let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+                for host_cpu in a.host_cpus.iter() {
+                    unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
+                }
+                cpuset
+            })
+        });
 
         info!("Starting vCPU: cpu_id = {}", cpu_id);
 
+        let (affinity_tx, affinity_rx) = mpsc::channel();
         let handle = Some(
             thread::Builder::new()
                 .name(format!("vcpu{}", cpu_id))
@@ -806,9 +911,36 @@ impl CpuManager {
                     register_signal_handler(SIGRTMIN(), handle_signal)
                         .expect("Failed to register vcpu signal handler");
 
+                    let affinity = match cpuset.as_ref() {
+                        Some(cpuset) => {
+                            let ret = unsafe {
+                                libc::sched_setaffinity(
+                                    0,
+                                    std::mem::size_of::<libc::cpu_set_t>(),
+                                    cpuset as *const libc::cpu_set_t,
+                                )
+                            };
+                            if ret != 0 {
+                                Err(io::Error::last_os_error())
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        None => Ok(()),
+                    };
+                    // Nobody waits for the result if starting another vCPU
+                    // already failed.
+                    let _ = affinity_tx.send(affinity);
+
                     // Block until all CPUs are ready.
                     vcpu_thread_barrier.wait();
 
+                    // Starting one of the vCPUs failed.
+                    if vcpu_kill.load(Ordering::SeqCst) {
+                        vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                        return;
+                    }
+
                     loop {
                         // If we are being told to pause, we park the thread
                         // until the pause boolean is toggled.
@@ -833,18 +965,35 @@ impl CpuManager {
                             break;
                         }
 
-                        // vcpu.run() returns false on a triple-fault so trigger a reset
+                        // vcpu.run() returns Reset on a triple-fault so trigger a reset
                         match vcpu.lock().unwrap().run() {
                             Err(e) => {
                                 error!("VCPU generated error: {:?}", e);
                                 break;
                             }
-                            Ok(true) => {}
-                            Ok(false) => {
+                            Ok(VcpuRunResult::Continue) => {}
+                            Ok(VcpuRunResult::Reset) => {
                                 vcpu_run_interrupted.store(true, Ordering::SeqCst);
                                 reset_evt.write(1).unwrap();
                                 break;
                             }
+                            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+                            Ok(VcpuRunResult::Debug) => {
+                                // Only this vCPU waits here, until the GDB stub
+                                // has paused all the vCPUs and released it.
+                                vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                vcpu_debug_exit.store(true, Ordering::SeqCst);
+                                debug_evt.write(1).unwrap();
+                                while vcpu_debug_exit.load(Ordering::SeqCst)
+                                    && !vcpu_kill_signalled.load(Ordering::SeqCst)
+                                    && !vcpu_kill.load(Ordering::SeqCst)
+                                {
+                                    thread::park();
+                                }
+                                if !vcpu_pause_signalled.load(Ordering::SeqCst) {
+                                    vcpu_run_interrupted.store(false, Ordering::SeqCst);
+                                }
+                            }
                         }
 
                         // We've been told to terminate
@@ -864,7 +1013,7 @@ impl CpuManager {
         self.vcpu_states[usize::from(cpu_id)].handle = handle;
         self.vcpu_states[usize::from(cpu_id)].inserting = inserting;
 
-        Ok(())
+        Ok(affinity_rx)
     }
 
     /// Start up as many vCPUs threads as needed to reach `desired_vcpus`
@@ -885,14 +1034,51 @@ impl CpuManager {
         );
 
         // This reuses any inactive vCPUs as well as any that were newly created
-        for cpu_id in self.present_vcpus()..desired_vcpus {
+        let first_vcpu = self.present_vcpus();
+        let mut affinity_rxs = Vec::new();
+        for cpu_id in first_vcpu..desired_vcpus {
             let vcpu = Arc::clone(&self.vcpus[cpu_id as usize]);
-            self.start_vcpu(vcpu, vcpu_thread_barrier.clone(), inserting)?;
+            affinity_rxs.push(self.start_vcpu(vcpu, vcpu_thread_barrier.clone(), inserting)?);
+        }
+
+        // None of the vCPUs is run unless all of them could be pinned.
+        let mut result = Ok(());
+        for (cpu_id, affinity_rx) in (first_vcpu..desired_vcpus).zip(affinity_rxs) {
+            match affinity_rx.recv() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("Failed setting the affinity of vCPU {}: {}", cpu_id, e);
+                    result = Err(Error::VcpuAffinity(e));
+                    break;
+                }
+                Err(e) => {
+                    error!("vCPU {} thread exited before starting: {}", cpu_id, e);
+                    result = Err(Error::VcpuAffinityStatus(e));
+                    break;
+                }
+            }
+        }
+        if result.is_err() {
+            for cpu_id in first_vcpu..desired_vcpus {
+                self.vcpu_states[usize::from(cpu_id)]
+                    .kill
+                    .store(true, Ordering::SeqCst);
+            }
         }
 
         // Unblock all CPU threads.
         vcpu_thread_barrier.wait();
-        Ok(())
+
+        if result.is_err() {
+            for cpu_id in first_vcpu..desired_vcpus {
+                let state = &mut self.vcpu_states[usize::from(cpu_id)];
+                state.join_thread()?;
+                state.kill.store(false, Ordering::SeqCst);
+                state.inserting = false;
+            }
+        }
+
+        result
     }
 
     fn mark_vcpus_for_removal(&mut self, desired_vcpus: u8) -> Result<()> {
@@ -921,8 +1107,10 @@ impl CpuManager {
         self.create_vcpus(self.boot_vcpus(), Some(entry_point))
     }
 
-    // Starts all the vCPUs that the VM is booting with. Blocks until all vCPUs are running.
-    pub fn start_boot_vcpus(&mut self) -> Result<()> {
+    // Starts all the vCPUs that the VM is booting with. Blocks until all vCPUs are running,
+    // or parked if `paused` is set, in which case they wait for resume() to be called.
+    pub fn start_boot_vcpus(&mut self, paused: bool) -> Result<()> {
+        self.vcpus_pause_signalled.store(paused, Ordering::SeqCst);
         self.activate_vcpus(self.boot_vcpus(), false)
     }
 
@@ -973,7 +1161,95 @@ impl CpuManager {
         self.config.max_vcpus
     }
 
-    fn present_vcpus(&self) -> u8 {
+    /// EventFd written by the vCPU threads whenever one of them exits on a
+    /// breakpoint or a single-step.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub fn debug_evt(&self) -> &EventFd {
+        &self.debug_evt
+    }
+
+    /// Returns the id of a vCPU which exited on a breakpoint or a single-step
+    /// since the last call, if any. Every vCPU waiting for its debug exit to
+    /// be handled is released, which means the vCPUs must have been paused
+    /// beforehand.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub fn take_debug_exit_vcpu(&self) -> Option<u8> {
+        let mut debug_exit_vcpu = None;
+        for (cpu_id, state) in self.vcpu_states.iter().enumerate() {
+            if state.debug_exit.swap(false, Ordering::SeqCst) {
+                state.unpark_thread();
+                debug_exit_vcpu.get_or_insert(cpu_id as u8);
+            }
+        }
+        debug_exit_vcpu
+    }
+
+    /// Enables or disables guest debugging on all the vCPUs. Hardware
+    /// breakpoints apply to all of them, while single-stepping is only
+    /// enabled on `singlestep_vcpu`.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub fn set_guest_debug(
+        &self,
+        enable: bool,
+        hw_breakpoints: &[GuestAddress],
+        singlestep_vcpu: Option<u8>,
+    ) -> Result<()> {
+        for vcpu in self.vcpus.iter() {
+            let vcpu = vcpu.lock().unwrap();
+            vcpu.vcpu
+                .set_guest_debug(enable, hw_breakpoints, singlestep_vcpu == Some(vcpu.id))
+                .map_err(|e| Error::SetGuestDebug(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the state of a vCPU, as saved when the vCPUs were paused.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub fn saved_vcpu_state(&self, cpu_id: u8) -> Option<CpuState> {
+        self.vcpus
+            .get(usize::from(cpu_id))
+            .and_then(|vcpu| vcpu.lock().unwrap().saved_state.clone())
+    }
+
+    /// Updates the state of a paused vCPU, which will be applied to the vCPU
+    /// when it is resumed.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    pub fn set_saved_vcpu_state(&self, cpu_id: u8, state: CpuState) {
+        if let Some(vcpu) = self.vcpus.get(usize::from(cpu_id)) {
+            vcpu.lock().unwrap().saved_state = Some(state);
+        }
+    }
+
+    /// Returns the CPU features exposed to the guest, along with the MSRs
+    /// saved when the vCPUs were paused.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpu_features(&self) -> CpuFeatures {
+        let mut msrs = Vec::new();
+        for (_, state) in self.saved_vcpu_states() {
+            for entry in state.msrs.as_slice() {
+                if !msrs.contains(&entry.index) {
+                    msrs.push(entry.index);
+                }
+            }
+        }
+
+        CpuFeatures::new(&self.cpuid, msrs)
+    }
+
+    /// Returns the state of each vCPU along with its id, as saved when the
+    /// vCPUs were paused.
+    pub fn saved_vcpu_states(&self) -> Vec<(u8, CpuState)> {
+        self.vcpus
+            .iter()
+            .filter_map(|vcpu| {
+                let vcpu = vcpu.lock().unwrap();
+                vcpu.saved_state.clone().map(|state| (vcpu.id, state))
+            })
+            .collect()
+    }
+
+    pub fn present_vcpus(&self) -> u8 {
         self.vcpu_states
             .iter()
             .fold(0, |acc, state| acc + state.active() as u8)
@@ -1405,16 +1681,141 @@ impl Snapshottable for CpuManager {
 impl Transportable for CpuManager {}
 impl Migratable for CpuManager {}
 
+// CPUID leaves enumerating the CPU features, as (function, index, masks),
+// with masks selecting the feature flags from EAX, EBX, ECX and EDX. The
+// flags the VMM sets itself whatever the host are left out.
+#[cfg(target_arch = "x86_64")]
+const CPUID_FEATURE_LEAVES: [(u32, u32, [u32; 4]); 4] = [
+    (
+        0x1,
+        0,
+        [
+            0,
+            0,
+            !(1 << TSC_DEADLINE_TIMER_ECX_BIT | 1 << HYPERVISOR_ECX_BIT),
+            0xffff_ffff,
+        ],
+    ),
+    (0x7, 0, [0, 0xffff_ffff, 0xffff_ffff, 0xffff_ffff]),
+    (0xd, 1, [0xffff_ffff, 0, 0, 0]),
+    (0x8000_0001, 0, [0, 0, 0xffff_ffff, 0xffff_ffff]),
+];
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Serialize, Deserialize)]
+struct CpuidFeatures {
+    function: u32,
+    index: u32,
+    registers: [u32; 4],
+}
+
+/// The CPU features and the MSRs of a guest, or the ones the hypervisor
+/// supports on a given host. The guest ones are recorded when snapshotting a
+/// VM, as the vCPUs can only be restored on a host providing at least the
+/// same ones.
+#[cfg(target_arch = "x86_64")]
+#[derive(Serialize, Deserialize)]
+pub struct CpuFeatures {
+    cpuid: Vec<CpuidFeatures>,
+    msrs: Vec<u32>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl CpuFeatures {
+    fn new(cpuid: &CpuId, msrs: Vec<u32>) -> Self {
+        let mut features = Vec::new();
+        for (function, index, masks) in CPUID_FEATURE_LEAVES.iter() {
+            if let Some(entry) = cpuid
+                .as_slice()
+                .iter()
+                .find(|entry| entry.function == *function && entry.index == *index)
+            {
+                let registers = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+                features.push(CpuidFeatures {
+                    function: *function,
+                    index: *index,
+                    registers: [
+                        registers[0] & masks[0],
+                        registers[1] & masks[1],
+                        registers[2] & masks[2],
+                        registers[3] & masks[3],
+                    ],
+                });
+            }
+        }
+
+        CpuFeatures {
+            cpuid: features,
+            msrs,
+        }
+    }
+
+    /// The CPU features and the MSRs the hypervisor supports on this host.
+    pub fn host(
+        hypervisor: &Arc<dyn hypervisor::Hypervisor>,
+    ) -> result::Result<Self, hypervisor::HypervisorError> {
+        Ok(CpuFeatures::new(
+            &hypervisor.get_cpuid()?,
+            hypervisor.get_msr_list()?.as_slice().to_vec(),
+        ))
+    }
+
+    /// Check every CPU feature and MSR recorded here is supported by `host`.
+    pub fn check_compatibility(&self, host: &CpuFeatures) -> result::Result<(), MigratableError> {
+        for features in self.cpuid.iter() {
+            let host_registers = host
+                .cpuid
+                .iter()
+                .find(|f| f.function == features.function && f.index == features.index)
+                .map(|f| f.registers)
+                .unwrap_or_default();
+
+            for (reg, (bits, host_bits)) in features
+                .registers
+                .iter()
+                .zip(host_registers.iter())
+                .enumerate()
+            {
+                let missing = bits & !host_bits;
+                if missing != 0 {
+                    return Err(MigratableError::IncompatibleHost(anyhow!(
+                        "Missing CPUID features {:#x} from leaf {:#x} index {:#x} register {}",
+                        missing,
+                        features.function,
+                        features.index,
+                        ["EAX", "EBX", "ECX", "EDX"][reg]
+                    )));
+                }
+            }
+        }
+
+        if let Some(msr) = self.msrs.iter().find(|msr| !host.msrs.contains(msr)) {
+            return Err(MigratableError::IncompatibleHost(anyhow!(
+                "Missing support for MSR {:#x}",
+                msr
+            )));
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    #[cfg(feature = "kvm")]
     use arch::x86_64::interrupts::*;
+    #[cfg(feature = "kvm")]
     use arch::x86_64::regs::*;
+    #[cfg(feature = "kvm")]
     use arch::x86_64::BootProtocol;
-    use hypervisor::x86_64::{FpuState, LapicState, SpecialRegisters, StandardRegisters};
+    use hypervisor::x86_64::StandardRegisters;
+    #[cfg(feature = "kvm")]
+    use hypervisor::x86_64::{FpuState, LapicState, SpecialRegisters};
 
+    #[cfg(feature = "kvm")]
     #[test]
     fn test_setlint() {
         let hv = hypervisor::new().unwrap();
@@ -1441,6 +1842,88 @@ mod tests {
         assert_eq!(lint1_mode_expected, lint1_mode_actual);
     }
 
+    #[test]
+    fn test_apply_cpu_features() {
+        use crate::config::CpuFeatureConfig;
+        use hypervisor::CpuIdEntry;
+
+        let host_cpuid = CpuId::from_entries(&[CpuIdEntry {
+            function: 0x7,
+            index: 0,
+            ebx: 1 << 5 | 1 << 16,
+            ..Default::default()
+        }]);
+        let avx2 = cpuid::cpuid_feature("avx2").unwrap();
+        let avx512f = cpuid::cpuid_feature("avx512f").unwrap();
+
+        let mut config = CpusConfig {
+            features: vec![CpuFeatureConfig {
+                name: "avx512f".to_owned(),
+                enabled: false,
+            }],
+            ..Default::default()
+        };
+        let mut cpuid = host_cpuid.clone();
+        CpuManager::apply_cpu_features(&mut cpuid, &config).unwrap();
+        assert!(cpuid::is_cpuid_feature_enabled(&cpuid, avx2));
+        assert!(!cpuid::is_cpuid_feature_enabled(&cpuid, avx512f));
+
+        // The host must provide the features added.
+        config.features[0].enabled = true;
+        config.features.push(CpuFeatureConfig {
+            name: "sha_ni".to_owned(),
+            enabled: true,
+        });
+        let mut cpuid = host_cpuid.clone();
+        assert!(CpuManager::apply_cpu_features(&mut cpuid, &config).is_err());
+
+        // As well as the features of the CPU model.
+        let config = CpusConfig {
+            model: Some("x86-64-v1".to_owned()),
+            ..Default::default()
+        };
+        let mut cpuid = host_cpuid;
+        assert!(CpuManager::apply_cpu_features(&mut cpuid, &config).is_err());
+    }
+
+    #[test]
+    fn test_cpu_features_compatibility() {
+        use hypervisor::CpuIdEntry;
+
+        let leaf7 = |ebx| {
+            CpuId::from_entries(&[CpuIdEntry {
+                function: 0x7,
+                index: 0,
+                ebx,
+                ..Default::default()
+            }])
+        };
+
+        // The guest only saw AVX2, so a host without AVX512F will do.
+        let guest = CpuFeatures::new(&leaf7(1 << 5), vec![0x10]);
+        let host = CpuFeatures::new(&leaf7(1 << 5), vec![0x10, 0x1b]);
+        assert!(guest.check_compatibility(&host).is_ok());
+
+        let guest = CpuFeatures::new(&leaf7(1 << 5 | 1 << 16), vec![0x10]);
+        assert!(guest.check_compatibility(&host).is_err());
+
+        let guest = CpuFeatures::new(&leaf7(1 << 5), vec![0x10, 0x3a]);
+        assert!(guest.check_compatibility(&host).is_err());
+
+        // The flags set by the VMM are not expected from the host.
+        let mut cpuid = leaf7(0);
+        CpuidPatch::set_cpuid_reg(
+            &mut cpuid,
+            0x1,
+            Some(0),
+            CpuidReg::ECX,
+            1 << HYPERVISOR_ECX_BIT,
+        );
+        let guest = CpuFeatures::new(&cpuid, Vec::new());
+        assert!(guest.check_compatibility(&host).is_ok());
+    }
+
+    #[cfg(feature = "kvm")]
     #[test]
     fn test_setup_fpu() {
         let hv = hypervisor::new().unwrap();
@@ -1463,6 +1946,7 @@ mod tests {
         // assert!(expected_fpu.mxcsr == actual_fpu.mxcsr);
     }
 
+    #[cfg(feature = "kvm")]
     #[test]
     fn test_setup_msrs() {
         use hypervisor::arch::x86::msr_index;
@@ -1492,6 +1976,7 @@ mod tests {
         assert_eq!(entry_vec.as_slice()[9], msrs.as_slice()[0]);
     }
 
+    #[cfg(feature = "kvm")]
     #[test]
     fn test_setup_regs() {
         let hv = hypervisor::new().unwrap();
@@ -1520,6 +2005,7 @@ mod tests {
         assert_eq!(actual_regs, expected_regs);
     }
 
+    #[cfg(feature = "kvm")]
     #[test]
     fn test_setup_sregs() {
         let hv = hypervisor::new().unwrap();
@@ -1535,9 +2021,122 @@ mod tests {
         let actual_sregs: SpecialRegisters = vcpu.get_sregs().unwrap();
         assert_eq!(expected_sregs, actual_sregs);
     }
+
+    #[cfg(feature = "mock")]
+    #[derive(Default)]
+    struct TestBusDevice {
+        reads: Vec<(u64, u64, usize)>,
+        writes: Vec<(u64, u64, Vec<u8>)>,
+    }
+
+    #[cfg(feature = "mock")]
+    impl BusDevice for TestBusDevice {
+        fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+            self.reads.push((base, offset, data.len()));
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+        }
+
+        fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+            self.writes.push((base, offset, data.to_vec()));
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    fn create_mock_vcpu(
+        vm: &Arc<hypervisor::mock::MockVm>,
+        io_bus: Arc<devices::Bus>,
+        mmio_bus: Arc<devices::Bus>,
+    ) -> Arc<Mutex<Vcpu>> {
+        let vm: Arc<dyn hypervisor::Vm> = vm.clone();
+        Vcpu::new(0, &vm, io_bus, mmio_bus, None, std::time::Instant::now()).unwrap()
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_vcpu_run_exits() {
+        use hypervisor::mock::{MockVm, MockVmExit};
+
+        let vm = Arc::new(MockVm::new());
+        let io_bus = Arc::new(devices::Bus::new());
+        let mmio_bus = Arc::new(devices::Bus::new());
+        let pio_device = Arc::new(Mutex::new(TestBusDevice::default()));
+        let mmio_device = Arc::new(Mutex::new(TestBusDevice::default()));
+        io_bus.insert(pio_device.clone(), 0x3f8, 0x8).unwrap();
+        mmio_bus
+            .insert(mmio_device.clone(), 0xd000_0000, 0x1000)
+            .unwrap();
+        let vcpu = create_mock_vcpu(&vm, io_bus, mmio_bus);
+        let vcpu = vcpu.lock().unwrap();
+
+        vm.vcpu(0).unwrap().push_exits(&[
+            MockVmExit::IoOut(0x3f9, vec![0x41]),
+            MockVmExit::IoIn(0x3fd, 1),
+            MockVmExit::MmioWrite(0xd000_0010, vec![1, 2, 3, 4]),
+            MockVmExit::MmioRead(0xd000_0020, 4),
+            // Exits not matching any device are ignored.
+            MockVmExit::IoOut(0x80, vec![0x10]),
+            MockVmExit::MmioRead(0xe000_0000, 8),
+            // No interrupt controller to notify.
+            MockVmExit::IoapicEoi(0x20),
+            MockVmExit::Ignore,
+        ]);
+        for _ in 0..8 {
+            assert_eq!(vcpu.run().unwrap(), VcpuRunResult::Continue);
+        }
+        // The guest resets once the script is exhausted.
+        assert_eq!(vcpu.run().unwrap(), VcpuRunResult::Reset);
+
+        let pio_device = pio_device.lock().unwrap();
+        assert_eq!(pio_device.writes, vec![(0x3f8, 1, vec![0x41])]);
+        assert_eq!(pio_device.reads, vec![(0x3f8, 5, 1)]);
+        let mmio_device = mmio_device.lock().unwrap();
+        assert_eq!(
+            mmio_device.writes,
+            vec![(0xd000_0000, 0x10, vec![1, 2, 3, 4])]
+        );
+        assert_eq!(mmio_device.reads, vec![(0xd000_0000, 0x20, 4)]);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_vcpu_pause_resume_snapshot() {
+        use hypervisor::mock::MockVm;
+
+        let vm = Arc::new(MockVm::new());
+        let vcpu = create_mock_vcpu(
+            &vm,
+            Arc::new(devices::Bus::new()),
+            Arc::new(devices::Bus::new()),
+        );
+        let mut vcpu = vcpu.lock().unwrap();
+        let mock_vcpu: Arc<dyn hypervisor::Vcpu> = vm.vcpu(0).unwrap();
+
+        let mut regs: StandardRegisters = mock_vcpu.get_regs().unwrap();
+        regs.rip = 0x1000;
+        mock_vcpu.set_regs(&regs).unwrap();
+
+        // Pausing saves the vCPU state, which is snapshot.
+        vcpu.pause().unwrap();
+        let snapshot = vcpu.snapshot().unwrap();
+
+        regs.rip = 0x2000;
+        mock_vcpu.set_regs(&regs).unwrap();
+        // Resuming restores the state saved when pausing.
+        vcpu.resume().unwrap();
+        assert_eq!(mock_vcpu.get_regs().unwrap().rip, 0x1000);
+
+        regs.rip = 0x3000;
+        mock_vcpu.set_regs(&regs).unwrap();
+        vcpu.restore(snapshot).unwrap();
+        vcpu.resume().unwrap();
+        assert_eq!(mock_vcpu.get_regs().unwrap().rip, 0x1000);
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
+#[cfg(feature = "kvm")]
 #[cfg(test)]
 mod tests {
     use arch::aarch64::layout;
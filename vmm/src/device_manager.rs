@@ -52,6 +52,7 @@ use pci::{
     VfioPciDevice,
 };
 use qcow::{self, ImageType, QcowFile};
+use rate_limiter::{RateLimiter, RateLimiterConfig};
 #[cfg(feature = "pci_support")]
 use std::any::Any;
 use std::collections::HashMap;
@@ -102,6 +103,20 @@ const IOAPIC_DEVICE_NAME: &str = "_ioapic";
 #[cfg(target_arch = "aarch64")]
 const GIC_DEVICE_NAME: &str = "_gic";
 
+#[cfg(target_arch = "x86_64")]
+const I8042_DEVICE_NAME: &str = "_i8042";
+#[cfg(all(target_arch = "x86_64", feature = "cmos"))]
+const CMOS_DEVICE_NAME: &str = "_cmos";
+
+// Devices which snapshots taken by older versions don't describe. They are
+// left in their initial state when restoring such a snapshot.
+const OPTIONAL_SNAPSHOT_DEVICES: &[&str] = &[
+    #[cfg(target_arch = "x86_64")]
+    I8042_DEVICE_NAME,
+    #[cfg(all(target_arch = "x86_64", feature = "cmos"))]
+    CMOS_DEVICE_NAME,
+];
+
 const SERIAL_DEVICE_NAME_PREFIX: &str = "_serial";
 
 const CONSOLE_DEVICE_NAME: &str = "_console";
@@ -290,6 +305,10 @@ pub enum DeviceManagerError {
     /// Missing PCI device.
     MissingPciDevice,
 
+    /// Cannot hotplug a VFIO device while the balloon reports free pages.
+    #[cfg(feature = "pci_support")]
+    VfioWithFreePageReporting,
+
     /// Failed removing a PCI device from the PCI bus.
     #[cfg(feature = "pci_support")]
     RemoveDeviceFromPciBus(pci::PciRootError),
@@ -376,11 +395,19 @@ pub enum DeviceManagerError {
 
     /// No support for device passthrough
     NoDevicePassthroughSupport,
+
+    /// No virtio-blk device with rate limiters for the given identifier.
+    UnknownRateLimitedDisk(String),
+
+    /// No virtio-net device with rate limiters for the given identifier.
+    UnknownRateLimitedNet(String),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
 type VirtioDeviceArc = Arc<Mutex<dyn virtio_devices::VirtioDevice>>;
 
+type NetRateLimiters = (Arc<Mutex<RateLimiter>>, Arc<Mutex<RateLimiter>>);
+
 pub fn get_win_size() -> (u16, u16) {
     #[repr(C)]
     #[derive(Default)]
@@ -681,6 +708,31 @@ impl Drop for ActivatedBackend {
     }
 }
 
+// Build the options passing a rate limiter configuration to a self spawned
+// vhost-user backend, each one starting with the given prefix.
+fn backend_rate_limiter_options(prefix: &str, config: &Option<RateLimiterConfig>) -> String {
+    let mut options = String::new();
+    if let Some(config) = config {
+        let buckets = [("bw", &config.bandwidth), ("ops", &config.ops)];
+        for (name, bucket) in buckets.iter() {
+            if let Some(bucket) = bucket {
+                options.push_str(&format!(
+                    ",{}_{}_size={},{}_{}_refill_time={}",
+                    prefix, name, bucket.size, prefix, name, bucket.refill_time
+                ));
+                if let Some(one_time_burst) = bucket.one_time_burst {
+                    options.push_str(&format!(
+                        ",{}_{}_one_time_burst={}",
+                        prefix, name, one_time_burst
+                    ));
+                }
+            }
+        }
+    }
+
+    options
+}
+
 #[derive(Serialize, Deserialize)]
 struct DeviceManagerState {
     device_tree: DeviceTree,
@@ -750,6 +802,12 @@ pub struct DeviceManager {
     // Backends that have been spawned
     vhost_user_backends: Vec<ActivatedBackend>,
 
+    // Rate limiters of the virtio-blk devices, indexed by device id
+    disk_rate_limiters: HashMap<String, Arc<Mutex<RateLimiter>>>,
+
+    // RX and TX rate limiters of the virtio-net devices, indexed by device id
+    net_rate_limiters: HashMap<String, NetRateLimiters>,
+
     // Counter to keep track of the consumed device IDs.
     device_id_cnt: Wrapping<usize>,
 
@@ -769,6 +827,9 @@ pub struct DeviceManager {
     #[cfg(feature = "pci_support")]
     iommu_device: Option<Arc<Mutex<virtio_devices::Iommu>>>,
 
+    // Free page reporting is offered by the balloon
+    free_page_reporting: bool,
+
     // Bitmap of PCI devices to hotplug.
     #[cfg(feature = "pci_support")]
     pci_devices_up: u32,
@@ -847,6 +908,8 @@ impl DeviceManager {
             bus_devices: Vec::new(),
             vmm_path,
             vhost_user_backends: Vec::new(),
+            disk_rate_limiters: HashMap::new(),
+            net_rate_limiters: HashMap::new(),
             device_id_cnt: Wrapping(0),
             #[cfg(feature = "pci_support")]
             pci_bus: None,
@@ -855,6 +918,7 @@ impl DeviceManager {
             passthrough_device: None,
             #[cfg(feature = "pci_support")]
             iommu_device: None,
+            free_page_reporting: false,
             #[cfg(feature = "pci_support")]
             pci_devices_up: 0,
             #[cfg(feature = "pci_support")]
@@ -1264,15 +1328,25 @@ impl DeviceManager {
     #[cfg(target_arch = "x86_64")]
     fn add_legacy_devices(&mut self, reset_evt: EventFd) -> DeviceManagerResult<()> {
         // Add a shutdown device (i8042)
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(reset_evt)));
+        let id = String::from(I8042_DEVICE_NAME);
+        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
+            id.clone(),
+            reset_evt,
+        )));
 
         self.bus_devices
             .push(Arc::clone(&i8042) as Arc<Mutex<dyn BusDevice>>);
 
         self.address_manager
             .io_bus
-            .insert(i8042, 0x61, 0x4)
+            .insert(i8042.clone(), 0x61, 0x4)
             .map_err(DeviceManagerError::BusError)?;
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, i8042));
+
         #[cfg(feature = "cmos")]
         {
             // Add a CMOS emulated device
@@ -1289,7 +1363,9 @@ impl DeviceManager {
             let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
             let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
 
+            let id = String::from(CMOS_DEVICE_NAME);
             let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
+                id.clone(),
                 mem_below_4g,
                 mem_above_4g,
             )));
@@ -1299,8 +1375,13 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(cmos, 0x70, 0x2)
+                .insert(cmos.clone(), 0x70, 0x2)
                 .map_err(DeviceManagerError::BusError)?;
+
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, cmos));
         }
         #[cfg(feature = "fwdebug")]
         {
@@ -1575,22 +1656,22 @@ impl DeviceManager {
         let _socket_file = NamedTempFile::new().map_err(DeviceManagerError::CreateSocketFile)?;
         let socket = _socket_file.path().to_str().unwrap().to_owned();
 
+        let backend_command = format!(
+            "path={},socket={},num_queues={},queue_size={}{}",
+            disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?
+                .to_str()
+                .unwrap(),
+            &socket,
+            disk_cfg.num_queues,
+            disk_cfg.queue_size,
+            backend_rate_limiter_options("rate_limit", &disk_cfg.rate_limiter_config)
+        );
+
         let child = std::process::Command::new(&self.vmm_path)
-            .args(&[
-                "--block-backend",
-                &format!(
-                    "path={},socket={},num_queues={},queue_size={}",
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .to_str()
-                        .unwrap(),
-                    &socket,
-                    disk_cfg.num_queues,
-                    disk_cfg.queue_size
-                ),
-            ])
+            .args(&["--block-backend", &backend_command])
             .spawn()
             .map_err(DeviceManagerError::SpawnBlockBackend)?;
 
@@ -1662,12 +1743,56 @@ impl DeviceManager {
                 )
                 .map_err(DeviceManagerError::Disk)?;
 
+            // Keep a handle on the file for the io_uring backend, as the
+            // synchronous one takes ownership of the image.
+            #[cfg(feature = "io_uring")]
+            let async_image = image.try_clone().map_err(DeviceManagerError::Disk)?;
+
             let mut raw_img = qcow::RawFile::new(image, disk_cfg.direct);
 
             let image_type = qcow::detect_image_type(&mut raw_img)
                 .map_err(DeviceManagerError::DetectImageType)?;
             match image_type {
                 ImageType::Raw => {
+                    // Rely on io_uring to process the requests asynchronously
+                    // if the host kernel supports it. O_DIRECT images keep
+                    // using the synchronous backend, which takes care of the
+                    // alignment constraints.
+                    #[cfg(feature = "io_uring")]
+                    {
+                        if !disk_cfg.direct && block_util::block_io_uring_is_supported() {
+                            let dev = virtio_devices::BlockIoUring::new(
+                                id.clone(),
+                                Box::new(block_util::raw_async::RawFileDisk::new(async_image)),
+                                disk_cfg
+                                    .path
+                                    .as_ref()
+                                    .ok_or(DeviceManagerError::NoDiskPath)?
+                                    .clone(),
+                                disk_cfg.readonly,
+                                disk_cfg.iommu,
+                                disk_cfg.num_queues,
+                                disk_cfg.queue_size,
+                                disk_cfg.rate_limiter_config,
+                            )
+                            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+
+                            self.disk_rate_limiters
+                                .insert(id.clone(), dev.rate_limiter());
+                            let block = Arc::new(Mutex::new(dev));
+
+                            // Fill the device tree with a new node. In case of restore, we
+                            // know there is nothing to do, so we can simply override the
+                            // existing entry.
+                            self.device_tree
+                                .lock()
+                                .unwrap()
+                                .insert(id.clone(), device_node!(id, block));
+
+                            return Ok((Arc::clone(&block) as VirtioDeviceArc, disk_cfg.iommu, id));
+                        }
+                    }
+
                     let dev = virtio_devices::Block::new(
                         id.clone(),
                         raw_img,
@@ -1680,9 +1805,12 @@ impl DeviceManager {
                         disk_cfg.iommu,
                         disk_cfg.num_queues,
                         disk_cfg.queue_size,
+                        disk_cfg.rate_limiter_config,
                     )
                     .map_err(DeviceManagerError::CreateVirtioBlock)?;
 
+                    self.disk_rate_limiters
+                        .insert(id.clone(), dev.rate_limiter());
                     let block = Arc::new(Mutex::new(dev));
 
                     // Fill the device tree with a new node. In case of restore, we
@@ -1710,9 +1838,12 @@ impl DeviceManager {
                         disk_cfg.iommu,
                         disk_cfg.num_queues,
                         disk_cfg.queue_size,
+                        disk_cfg.rate_limiter_config,
                     )
                     .map_err(DeviceManagerError::CreateVirtioBlock)?;
 
+                    self.disk_rate_limiters
+                        .insert(id.clone(), dev.rate_limiter());
                     let block = Arc::new(Mutex::new(dev));
 
                     // Fill the device tree with a new node. In case of restore, we
@@ -1754,7 +1885,7 @@ impl DeviceManager {
             .args(&[
                 "--net-backend",
                 &format!(
-                    "ip={},mask={},socket={},num_queues={},queue_size={}{}",
+                    "ip={},mask={},socket={},num_queues={},queue_size={}{}{}{}",
                     net_cfg.ip,
                     net_cfg.mask,
                     &socket,
@@ -1764,7 +1895,9 @@ impl DeviceManager {
                         format!(",host_mac={:}", mac)
                     } else {
                         "".to_owned()
-                    }
+                    },
+                    backend_rate_limiter_options("rx_rate_limit", &net_cfg.rx_rate_limiter_config),
+                    backend_rate_limiter_options("tx_rate_limit", &net_cfg.tx_rate_limiter_config)
                 ),
             ])
             .spawn()
@@ -1821,37 +1954,40 @@ impl DeviceManager {
                 id,
             ))
         } else {
-            let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
-                Arc::new(Mutex::new(
-                    virtio_devices::Net::new(
-                        id.clone(),
-                        Some(tap_if_name),
-                        None,
-                        None,
-                        Some(net_cfg.mac),
-                        &mut net_cfg.host_mac,
-                        net_cfg.iommu,
-                        net_cfg.num_queues,
-                        net_cfg.queue_size,
-                    )
-                    .map_err(DeviceManagerError::CreateVirtioNet)?,
-                ))
+            let dev = if let Some(ref tap_if_name) = net_cfg.tap {
+                virtio_devices::Net::new(
+                    id.clone(),
+                    Some(tap_if_name),
+                    None,
+                    None,
+                    Some(net_cfg.mac),
+                    &mut net_cfg.host_mac,
+                    net_cfg.iommu,
+                    net_cfg.num_queues,
+                    net_cfg.queue_size,
+                    net_cfg.rx_rate_limiter_config,
+                    net_cfg.tx_rate_limiter_config,
+                )
+                .map_err(DeviceManagerError::CreateVirtioNet)?
             } else {
-                Arc::new(Mutex::new(
-                    virtio_devices::Net::new(
-                        id.clone(),
-                        None,
-                        Some(net_cfg.ip),
-                        Some(net_cfg.mask),
-                        Some(net_cfg.mac),
-                        &mut net_cfg.host_mac,
-                        net_cfg.iommu,
-                        net_cfg.num_queues,
-                        net_cfg.queue_size,
-                    )
-                    .map_err(DeviceManagerError::CreateVirtioNet)?,
-                ))
+                virtio_devices::Net::new(
+                    id.clone(),
+                    None,
+                    Some(net_cfg.ip),
+                    Some(net_cfg.mask),
+                    Some(net_cfg.mac),
+                    &mut net_cfg.host_mac,
+                    net_cfg.iommu,
+                    net_cfg.num_queues,
+                    net_cfg.queue_size,
+                    net_cfg.rx_rate_limiter_config,
+                    net_cfg.tx_rate_limiter_config,
+                )
+                .map_err(DeviceManagerError::CreateVirtioNet)?
             };
+            self.net_rate_limiters
+                .insert(id.clone(), (dev.rx_rate_limiter(), dev.tx_rate_limiter()));
+            let virtio_net_device = Arc::new(Mutex::new(dev));
 
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
@@ -2328,16 +2464,21 @@ impl DeviceManager {
 
         let mm = self.memory_manager.clone();
         let mm = mm.lock().unwrap();
-        if let (Some(region), Some(resize)) = (&mm.virtiomem_region, &mm.virtiomem_resize) {
-            let id = String::from(MEM_DEVICE_NAME);
+        for zone in mm.virtio_mem_zones().iter() {
+            // Each NUMA node gets its own virtio-mem device.
+            let id = match zone.numa_node() {
+                Some(numa_node) => format!("{}{}", MEM_DEVICE_NAME, numa_node),
+                None => String::from(MEM_DEVICE_NAME),
+            };
 
             let virtio_mem_device = Arc::new(Mutex::new(
                 virtio_devices::Mem::new(
                     id.clone(),
-                    &region,
-                    resize
+                    zone.region(),
+                    zone.resize_handler()
                         .try_clone()
                         .map_err(DeviceManagerError::TryCloneVirtioMemResize)?,
+                    zone.numa_node().map(|n| n as u16),
                 )
                 .map_err(DeviceManagerError::CreateVirtioMem)?,
             ));
@@ -2365,13 +2506,31 @@ impl DeviceManager {
     ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
 
-        if self.config.lock().unwrap().memory.balloon {
+        let memory_config = self.config.lock().unwrap().memory.clone();
+        if memory_config.balloon {
             let id = String::from(BALLOON_DEVICE_NAME);
 
+            // VFIO devices pin the whole guest RAM, which would be faulted
+            // back in as soon as it is released for the reported free pages.
+            let vfio_devices = self
+                .config
+                .lock()
+                .unwrap()
+                .devices
+                .as_ref()
+                .map_or(false, |devices| !devices.is_empty());
+            if memory_config.free_page_reporting && vfio_devices {
+                warn!("Free page reporting is disabled because of VFIO devices");
+            }
+            self.free_page_reporting = memory_config.free_page_reporting && !vfio_devices;
+
             let virtio_balloon_device = Arc::new(Mutex::new(
                 virtio_devices::Balloon::new(
                     id.clone(),
-                    self.config.lock().unwrap().memory.balloon_size,
+                    memory_config.balloon_size,
+                    memory_config.balloon_stats_interval,
+                    self.free_page_reporting,
+                    memory_config.deflate_on_oom,
                 )
                 .map_err(DeviceManagerError::CreateVirtioBalloon)?,
             ));
@@ -2962,6 +3121,38 @@ impl DeviceManager {
         Ok(())
     }
 
+    pub fn remove_memory(&self, _old_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
+        // The region is already gone from the guest memory, which the virtio
+        // devices are updated with, vhost-user ones sending the new memory
+        // table to their backend.
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        for (virtio_device, _, _) in self.virtio_devices.iter() {
+            virtio_device
+                .lock()
+                .unwrap()
+                .update_memory(&memory.memory())
+                .map_err(DeviceManagerError::UpdateMemoryForVirtioDevice)?;
+        }
+
+        // Take care of removing the DMA mapping of VFIO PCI devices.
+        #[cfg(feature = "pci_support")]
+        {
+            for (_, any_device) in self.pci_devices.iter() {
+                if let Ok(vfio_pci_device) =
+                    Arc::clone(any_device).downcast::<Mutex<VfioPciDevice>>()
+                {
+                    vfio_pci_device
+                        .lock()
+                        .unwrap()
+                        .remove_memory(_old_region)
+                        .map_err(DeviceManagerError::UpdateMemoryForVfioPciDevice)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn notify_hotplug(
         &self,
         _notification_type: HotPlugNotificationFlags,
@@ -2990,6 +3181,10 @@ impl DeviceManager {
             return Err(DeviceManagerError::NoPciBus);
         };
 
+        if self.free_page_reporting {
+            return Err(DeviceManagerError::VfioWithFreePageReporting);
+        }
+
         let interrupt_manager = Arc::clone(&self.msi_interrupt_manager);
 
         if self.passthrough_device.is_none() {
@@ -3055,6 +3250,9 @@ impl DeviceManager {
                 }
             }
 
+            self.disk_rate_limiters.remove(&id);
+            self.net_rate_limiters.remove(&id);
+
             Ok(())
         } else {
             Err(DeviceManagerError::UnknownDeviceId(id))
@@ -3254,6 +3452,44 @@ impl DeviceManager {
 
         counters
     }
+
+    pub fn update_disk_rate_limiter(
+        &mut self,
+        id: &str,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> DeviceManagerResult<()> {
+        self.disk_rate_limiters
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownRateLimitedDisk(id.to_owned()))?
+            .lock()
+            .unwrap()
+            .update(&rate_limiter_config.unwrap_or_default());
+
+        Ok(())
+    }
+
+    pub fn update_net_rate_limiter(
+        &mut self,
+        id: &str,
+        rx_rate_limiter_config: Option<RateLimiterConfig>,
+        tx_rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> DeviceManagerResult<()> {
+        let (rx_rate_limiter, tx_rate_limiter) = self
+            .net_rate_limiters
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownRateLimitedNet(id.to_owned()))?;
+
+        rx_rate_limiter
+            .lock()
+            .unwrap()
+            .update(&rx_rate_limiter_config.unwrap_or_default());
+        tx_rate_limiter
+            .lock()
+            .unwrap()
+            .update(&tx_rate_limiter_config.unwrap_or_default());
+
+        Ok(())
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -3531,12 +3767,30 @@ impl Pausable for DeviceManager {
     }
 }
 
+const DEVICE_MANAGER_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for DeviceManager {
     fn id(&self) -> String {
         DEVICE_MANAGER_SNAPSHOT_ID.to_string()
     }
 
     fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        // VFIO devices are not part of the device tree, and their state lives
+        // in the physical device, which means the VM can't be snapshotted.
+        #[cfg(feature = "pci_support")]
+        {
+            for (_, any_device) in self.pci_devices.iter() {
+                if Arc::clone(any_device)
+                    .downcast::<Mutex<VfioPciDevice>>()
+                    .is_ok()
+                {
+                    return Err(MigratableError::Snapshot(anyhow!(
+                        "VFIO devices cannot be snapshotted"
+                    )));
+                }
+            }
+        }
+
         let mut snapshot = Snapshot::new(DEVICE_MANAGER_SNAPSHOT_ID);
 
         // We aggregate all devices snapshots.
@@ -3550,6 +3804,7 @@ impl Snapshottable for DeviceManager {
         // Then we store the DeviceManager state.
         snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", DEVICE_MANAGER_SNAPSHOT_ID),
+            version: DEVICE_MANAGER_SNAPSHOT_VERSION,
             snapshot: serde_json::to_vec(&self.state())
                 .map_err(|e| MigratableError::Snapshot(e.into()))?,
         });
@@ -3563,6 +3818,8 @@ impl Snapshottable for DeviceManager {
             .snapshot_data
             .get(&format!("{}-section", DEVICE_MANAGER_SNAPSHOT_ID))
         {
+            device_manager_section.check_version(DEVICE_MANAGER_SNAPSHOT_VERSION)?;
+
             let device_manager_state = serde_json::from_slice(&device_manager_section.snapshot)
                 .map_err(|e| {
                     MigratableError::Restore(anyhow!("Could not deserialize DeviceManager {}", e))
@@ -3599,6 +3856,8 @@ impl Snapshottable for DeviceManager {
                 if let Some(snapshot) = snapshot.snapshots.get(&node.id) {
                     migratable.lock().unwrap().pause()?;
                     migratable.lock().unwrap().restore(*snapshot.clone())?;
+                } else if OPTIONAL_SNAPSHOT_DEVICES.contains(&node.id.as_str()) {
+                    debug!("No snapshot for {}, keeping its initial state", node.id);
                 } else {
                     return Err(MigratableError::Restore(anyhow!(
                         "Missing device {}",
@@ -3695,3 +3954,138 @@ impl Drop for DeviceManager {
         }
     }
 }
+
+#[cfg(all(target_arch = "x86_64", feature = "mock", feature = "pci_support"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CmdlineConfig, ConsoleConfig, CpusConfig, MemoryConfig, RngConfig};
+    use hypervisor::mock::MockVm;
+    use libc::EFD_NONBLOCK;
+
+    fn create_mock_device_manager(vm: &Arc<MockVm>) -> Arc<Mutex<DeviceManager>> {
+        let console_off = ConsoleConfig {
+            file: None,
+            mode: ConsoleOutputMode::Off,
+            iommu: false,
+        };
+        let config = VmConfig {
+            cpus: CpusConfig::default(),
+            memory: MemoryConfig {
+                size: 16 << 20,
+                ..Default::default()
+            },
+            kernel: None,
+            initramfs: None,
+            cmdline: CmdlineConfig::default(),
+            disks: None,
+            net: None,
+            rng: RngConfig::default(),
+            fs: None,
+            pmem: None,
+            serial: console_off.clone(),
+            console: console_off,
+            devices: None,
+            vsock: None,
+            iommu: false,
+            sgx_epc: None,
+            #[cfg(feature = "gdb")]
+            gdb: None,
+            numa: None,
+        };
+
+        let memory_manager =
+            MemoryManager::new(vm.clone(), &config.memory, &None, None, false).unwrap();
+        let exit_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let reset_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let device_manager = DeviceManager::new(
+            vm.clone(),
+            Arc::new(Mutex::new(config)),
+            memory_manager,
+            &exit_evt,
+            &reset_evt,
+            PathBuf::from("/nonexistent"),
+        )
+        .unwrap();
+        device_manager.lock().unwrap().create_devices().unwrap();
+
+        device_manager
+    }
+
+    #[test]
+    fn test_hotplug_disk() {
+        let vm = Arc::new(MockVm::new());
+        let device_manager = create_mock_device_manager(&vm);
+        let mut device_manager = device_manager.lock().unwrap();
+        let ioevents = vm.ioevents().len();
+
+        let disk = NamedTempFile::new().unwrap();
+        disk.as_file().set_len(1 << 20).unwrap();
+        let mut disk_cfg =
+            DiskConfig::parse(&format!("path={},id=disk0", disk.path().display())).unwrap();
+        let info = device_manager.add_disk(&mut disk_cfg).unwrap();
+        assert_eq!(info.id, "disk0");
+
+        // The queue notifications are handled through ioeventfds.
+        assert_eq!(vm.ioevents().len(), ioevents + 1);
+        assert!(device_manager
+            .device_tree
+            .lock()
+            .unwrap()
+            .get("disk0")
+            .is_some());
+        assert_eq!(device_manager.pci_devices_up, 1 << (info.bdf >> 3));
+
+        // The device is only removed from the device tree until the guest
+        // ejects it.
+        device_manager.remove_device("disk0".to_owned()).unwrap();
+        assert!(device_manager
+            .device_tree
+            .lock()
+            .unwrap()
+            .get("disk0")
+            .is_none());
+        assert_eq!(device_manager.pci_devices_down, 1 << (info.bdf >> 3));
+        assert!(device_manager.pci_devices.contains_key(&info.bdf));
+
+        device_manager.eject_device((info.bdf >> 3) as u8).unwrap();
+        assert!(!device_manager.pci_devices.contains_key(&info.bdf));
+        assert!(!device_manager.pci_id_list.contains_key("disk0"));
+        assert_eq!(vm.ioevents().len(), ioevents);
+
+        match device_manager.remove_device("disk0".to_owned()) {
+            Err(DeviceManagerError::UnknownDeviceId(id)) => assert_eq!(id, "disk0"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_hotplug_pmem() {
+        let vm = Arc::new(MockVm::new());
+        let device_manager = create_mock_device_manager(&vm);
+        let mut device_manager = device_manager.lock().unwrap();
+        let regions = vm.memory_regions().len();
+
+        let pmem = NamedTempFile::new().unwrap();
+        pmem.as_file().set_len(2 << 20).unwrap();
+        let mut pmem_cfg =
+            PmemConfig::parse(&format!("file={},id=pmem0", pmem.path().display())).unwrap();
+        let info = device_manager.add_pmem(&mut pmem_cfg).unwrap();
+        assert_eq!(info.id, "pmem0");
+
+        // The file is mapped in the guest address space.
+        assert_eq!(vm.memory_regions().len(), regions + 1);
+
+        device_manager.remove_device("pmem0".to_owned()).unwrap();
+        device_manager.eject_device((info.bdf >> 3) as u8).unwrap();
+        assert_eq!(vm.memory_regions().len(), regions);
+
+        // The PCI slot of the ejected device can be reused.
+        let mut pmem_cfg =
+            PmemConfig::parse(&format!("file={},id=pmem1", pmem.path().display())).unwrap();
+        assert_eq!(
+            device_manager.add_pmem(&mut pmem_cfg).unwrap().bdf,
+            info.bdf
+        );
+    }
+}
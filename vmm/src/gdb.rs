@@ -0,0 +1,992 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! GDB stub, implementing the GDB Remote Serial Protocol over a UNIX socket.
+//!
+//! Each vCPU is exposed to GDB as a thread. All the vCPUs are stopped as soon
+//! as GDB attaches, and whenever one of them hits a breakpoint or completes a
+//! single-step. Registers accesses are done on the vCPU state saved when the
+//! vCPUs were paused, and memory accesses go through the guest page tables of
+//! the selected vCPU before reaching the guest memory.
+//!
+//! Software breakpoints are implemented by patching guest memory with an
+//! int3 instruction, while hardware breakpoints rely on the debug registers.
+//! Both of them, as well as single-stepping, are trapped by the hypervisor
+//! through its guest debug support.
+
+use crate::cpu::{self, CpuManager};
+use hypervisor::x86_64::{SpecialRegisters, StandardRegisters};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{result, thread};
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_migration::{MigratableError, Pausable};
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind to the GDB stub socket.
+    Bind(io::Error),
+
+    /// Cannot clone the vCPUs debug EventFd.
+    DebugEventFd(io::Error),
+
+    /// Cannot spawn the GDB stub thread.
+    ThreadSpawn(io::Error),
+
+    /// Error related to epoll.
+    Epoll(io::Error),
+
+    /// Error reading from or writing to the GDB connection.
+    Connection(io::Error),
+
+    /// Cannot pause the vCPUs.
+    Pause(MigratableError),
+
+    /// Cannot resume the vCPUs.
+    Resume(MigratableError),
+
+    /// Cannot set the vCPUs guest debug state.
+    GuestDebug(cpu::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// Control-C, sent by GDB to interrupt the running target.
+const GDB_INTERRUPT: u8 = 0x03;
+// Signal reported to GDB when the target stops.
+const GDB_SIGTRAP: u8 = 5;
+// Error numbers reported to GDB.
+const GDB_EFAULT: u8 = 14;
+const GDB_EINVAL: u8 = 22;
+const GDB_ENOSPC: u8 = 28;
+
+// Largest packet exchanged with GDB, as advertised through qSupported.
+const GDB_PACKET_SIZE: usize = 0x4000;
+
+// Software breakpoint instruction.
+const INT3: u8 = 0xcc;
+// Number of debug registers usable as hardware breakpoints.
+const MAX_HW_BREAKPOINTS: usize = 4;
+
+// Number of 64 bits registers in the GDB x86_64 register layout: rax, rbx,
+// rcx, rdx, rsi, rdi, rbp, rsp, r8-r15 and rip. They are followed by eflags
+// and the cs, ss, ds, es, fs and gs selectors, all being 32 bits.
+const GDB_NUM_REGS64: usize = 17;
+const GDB_NUM_REGS32: usize = 7;
+const GDB_REGS_SIZE: usize = GDB_NUM_REGS64 * 8 + GDB_NUM_REGS32 * 4;
+
+// Control registers and EFER bits involved in the page tables walk.
+const X86_CR0_PG: u64 = 1 << 31;
+const X86_CR4_PSE: u64 = 1 << 4;
+const X86_CR4_PAE: u64 = 1 << 5;
+const X86_CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+
+// Page table entries bits.
+const PTE_PRESENT: u64 = 1;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const PAGE_SIZE: u64 = 0x1000;
+
+// Epoll events.
+const EPOLL_LISTENER_EVENT: u64 = 0;
+const EPOLL_CONNECTION_EVENT: u64 = 1;
+const EPOLL_DEBUG_EVENT: u64 = 2;
+const EPOLL_EXIT_EVENT: u64 = 3;
+
+/// Data received from GDB.
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Ack,
+    Nack,
+    Interrupt,
+    Packet(Vec<u8>),
+    InvalidPacket,
+}
+
+enum ParserState {
+    Idle,
+    Data,
+    Checksum,
+}
+
+/// Splits the byte stream received from GDB into packets, which are framed
+/// as `$<data>#<checksum>`, the checksum being two hexadecimal digits.
+/// Packets larger than `GDB_PACKET_SIZE` are rejected.
+struct PacketParser {
+    state: ParserState,
+    data: Vec<u8>,
+    checksum: Vec<u8>,
+    overflow: bool,
+}
+
+impl PacketParser {
+    fn new() -> Self {
+        PacketParser {
+            state: ParserState::Idle,
+            data: Vec::new(),
+            checksum: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<Incoming> {
+        match self.state {
+            ParserState::Idle => match byte {
+                b'+' => Some(Incoming::Ack),
+                b'-' => Some(Incoming::Nack),
+                GDB_INTERRUPT => Some(Incoming::Interrupt),
+                b'$' => {
+                    self.data.clear();
+                    self.overflow = false;
+                    self.state = ParserState::Data;
+                    None
+                }
+                _ => None,
+            },
+            ParserState::Data => {
+                if byte == b'#' {
+                    self.checksum.clear();
+                    self.state = ParserState::Checksum;
+                } else if self.data.len() < GDB_PACKET_SIZE {
+                    self.data.push(byte);
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+            ParserState::Checksum => {
+                self.checksum.push(byte);
+                if self.checksum.len() < 2 {
+                    return None;
+                }
+
+                self.state = ParserState::Idle;
+                if self.overflow {
+                    self.data.clear();
+                    return Some(Incoming::InvalidPacket);
+                }
+                match parse_hex(&self.checksum) {
+                    Some(checksum) if checksum == u64::from(packet_checksum(&self.data)) => {
+                        Some(Incoming::Packet(self.data.split_off(0)))
+                    }
+                    _ => Some(Incoming::InvalidPacket),
+                }
+            }
+        }
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(format!("#{:02x}", packet_checksum(data)).as_bytes());
+    packet
+}
+
+fn to_hex(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|b| format!("{:02x}", b).into_bytes())
+        .collect()
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        })
+        .collect()
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    std::str::from_utf8(hex)
+        .ok()
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+}
+
+fn error_reply(errno: u8) -> Vec<u8> {
+    format!("E{:02x}", errno).into_bytes()
+}
+
+/// Converts the vCPU registers into the GDB x86_64 'g' packet layout.
+fn regs_to_gdb(regs: &StandardRegisters, sregs: &SpecialRegisters) -> Vec<u8> {
+    let regs64 = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    let regs32 = [
+        regs.rflags as u32,
+        u32::from(sregs.cs.selector),
+        u32::from(sregs.ss.selector),
+        u32::from(sregs.ds.selector),
+        u32::from(sregs.es.selector),
+        u32::from(sregs.fs.selector),
+        u32::from(sregs.gs.selector),
+    ];
+
+    let mut data = Vec::with_capacity(GDB_REGS_SIZE);
+    for reg in regs64.iter() {
+        data.extend_from_slice(&reg.to_le_bytes());
+    }
+    for reg in regs32.iter() {
+        data.extend_from_slice(&reg.to_le_bytes());
+    }
+    data
+}
+
+/// Updates the vCPU general purpose registers, instruction pointer and flags
+/// from the GDB x86_64 'G' packet layout. Segment selectors are ignored.
+fn regs_from_gdb(data: &[u8], regs: &mut StandardRegisters) -> bool {
+    if data.len() < GDB_NUM_REGS64 * 8 + 4 {
+        return false;
+    }
+
+    let mut regs64 = [0u64; GDB_NUM_REGS64];
+    for (i, reg) in regs64.iter_mut().enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[i * 8..(i + 1) * 8]);
+        *reg = u64::from_le_bytes(bytes);
+    }
+    let mut eflags = [0u8; 4];
+    eflags.copy_from_slice(&data[GDB_NUM_REGS64 * 8..GDB_NUM_REGS64 * 8 + 4]);
+
+    regs.rax = regs64[0];
+    regs.rbx = regs64[1];
+    regs.rcx = regs64[2];
+    regs.rdx = regs64[3];
+    regs.rsi = regs64[4];
+    regs.rdi = regs64[5];
+    regs.rbp = regs64[6];
+    regs.rsp = regs64[7];
+    regs.r8 = regs64[8];
+    regs.r9 = regs64[9];
+    regs.r10 = regs64[10];
+    regs.r11 = regs64[11];
+    regs.r12 = regs64[12];
+    regs.r13 = regs64[13];
+    regs.r14 = regs64[14];
+    regs.r15 = regs64[15];
+    regs.rip = regs64[16];
+    regs.rflags = u64::from(u32::from_le_bytes(eflags));
+    true
+}
+
+// Walks page tables made of 64 bits entries, `levels` deep, starting at
+// `table`. Large pages are only allowed at the PDPT and PD levels.
+fn walk_page_tables(
+    guest_memory: &GuestMemoryMmap,
+    mut table: u64,
+    gva: u64,
+    levels: u32,
+) -> Option<GuestAddress> {
+    for level in (1..=levels).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let index = (gva >> shift) & 0x1ff;
+        let entry: u64 = guest_memory
+            .read_obj(GuestAddress((table & PTE_ADDR_MASK) + index * 8))
+            .ok()?;
+        if entry & PTE_PRESENT == 0 {
+            return None;
+        }
+
+        if level == 1 || (level <= 3 && entry & PTE_PAGE_SIZE != 0) {
+            let page_mask = (1u64 << shift) - 1;
+            return Some(GuestAddress(
+                (entry & PTE_ADDR_MASK & !page_mask) | (gva & page_mask),
+            ));
+        }
+        table = entry;
+    }
+
+    None
+}
+
+/// Translates a guest virtual address into a guest physical address, using
+/// the paging mode and page tables described by the special registers.
+fn translate_gva(
+    guest_memory: &GuestMemoryMmap,
+    sregs: &SpecialRegisters,
+    gva: u64,
+) -> Option<GuestAddress> {
+    if sregs.cr0 & X86_CR0_PG == 0 {
+        return Some(GuestAddress(gva));
+    }
+
+    if sregs.efer & EFER_LMA != 0 {
+        let levels = if sregs.cr4 & X86_CR4_LA57 != 0 { 5 } else { 4 };
+        return walk_page_tables(guest_memory, sregs.cr3, gva, levels);
+    }
+
+    let gva = gva & 0xffff_ffff;
+    if sregs.cr4 & X86_CR4_PAE != 0 {
+        // The 4 entries PDPT is 32 bytes aligned and indexed by bits 31:30.
+        let pdpte: u64 = guest_memory
+            .read_obj(GuestAddress((sregs.cr3 & 0xffff_ffe0) + (gva >> 30) * 8))
+            .ok()?;
+        if pdpte & PTE_PRESENT == 0 {
+            return None;
+        }
+        return walk_page_tables(guest_memory, pdpte, gva, 2);
+    }
+
+    // Legacy 32 bits paging, with 4 bytes entries.
+    let pde: u32 = guest_memory
+        .read_obj(GuestAddress((sregs.cr3 & 0xffff_f000) + (gva >> 22) * 4))
+        .ok()?;
+    let pde = u64::from(pde);
+    if pde & PTE_PRESENT == 0 {
+        return None;
+    }
+    if sregs.cr4 & X86_CR4_PSE != 0 && pde & PTE_PAGE_SIZE != 0 {
+        return Some(GuestAddress((pde & 0xffc0_0000) | (gva & 0x3f_ffff)));
+    }
+    let pte: u32 = guest_memory
+        .read_obj(GuestAddress(
+            (pde & 0xffff_f000) + ((gva >> 12) & 0x3ff) * 4,
+        ))
+        .ok()?;
+    let pte = u64::from(pte);
+    if pte & PTE_PRESENT == 0 {
+        return None;
+    }
+    Some(GuestAddress((pte & 0xffff_f000) | (gva & 0xfff)))
+}
+
+/// What to do once a packet has been handled.
+enum Action {
+    Reply(Vec<u8>),
+    Resume { step: bool },
+    Detach { reply: bool },
+}
+
+pub struct GdbStub {
+    socket_path: PathBuf,
+    listener: UnixListener,
+    cpu_manager: Arc<Mutex<CpuManager>>,
+    guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    debug_evt: EventFd,
+    exit_evt: EventFd,
+    // Software breakpoints, indexed by guest virtual address, along with the
+    // guest physical address and the original byte replaced by int3.
+    sw_breakpoints: HashMap<u64, (GuestAddress, u8)>,
+    hw_breakpoints: Vec<GuestAddress>,
+    // vCPU targeted by registers and memory accesses, as well as single-steps.
+    selected_vcpu: u8,
+    running: bool,
+}
+
+impl GdbStub {
+    pub fn new(
+        socket_path: &Path,
+        cpu_manager: Arc<Mutex<CpuManager>>,
+        guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        exit_evt: EventFd,
+    ) -> Result<Self> {
+        std::fs::remove_file(socket_path).unwrap_or_default();
+        let listener = UnixListener::bind(socket_path).map_err(Error::Bind)?;
+        let debug_evt = cpu_manager
+            .lock()
+            .unwrap()
+            .debug_evt()
+            .try_clone()
+            .map_err(Error::DebugEventFd)?;
+
+        Ok(GdbStub {
+            socket_path: socket_path.to_path_buf(),
+            listener,
+            cpu_manager,
+            guest_memory,
+            debug_evt,
+            exit_evt,
+            sw_breakpoints: HashMap::new(),
+            hw_breakpoints: Vec::new(),
+            selected_vcpu: 0,
+            // The vCPUs are started paused, waiting for GDB to resume them.
+            running: false,
+        })
+    }
+
+    pub fn start(mut self) -> Result<thread::JoinHandle<()>> {
+        thread::Builder::new()
+            .name("gdb".to_string())
+            .spawn(move || {
+                if let Err(e) = self.run() {
+                    error!("Error running the GDB stub: {:?}", e);
+                }
+                std::fs::remove_file(&self.socket_path).unwrap_or_default();
+            })
+            .map_err(Error::ThreadSpawn)
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let epoll_fd = epoll::create(true).map_err(Error::Epoll)?;
+        epoll_add(epoll_fd, self.listener.as_raw_fd(), EPOLL_LISTENER_EVENT)?;
+        epoll_add(epoll_fd, self.debug_evt.as_raw_fd(), EPOLL_DEBUG_EVENT)?;
+        epoll_add(epoll_fd, self.exit_evt.as_raw_fd(), EPOLL_EXIT_EVENT)?;
+
+        let mut connection: Option<UnixStream> = None;
+        let mut parser = PacketParser::new();
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 4];
+
+        loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Epoll(e)),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    EPOLL_LISTENER_EVENT => {
+                        let (stream, _) = self.listener.accept().map_err(Error::Connection)?;
+                        if connection.is_some() {
+                            warn!("A debugger is already attached to the GDB stub");
+                            continue;
+                        }
+
+                        info!("Debugger attached to the GDB stub");
+                        epoll_add(epoll_fd, stream.as_raw_fd(), EPOLL_CONNECTION_EVENT)?;
+                        parser = PacketParser::new();
+                        // This also saves the state of the vCPUs when they
+                        // were started paused, waiting for the debugger.
+                        self.stop_vcpus()?;
+                        connection = Some(stream);
+                    }
+                    EPOLL_CONNECTION_EVENT => {
+                        let keep = match connection.as_mut() {
+                            Some(stream) => self.handle_connection(stream, &mut parser)?,
+                            None => true,
+                        };
+                        if !keep {
+                            if let Some(stream) = connection.take() {
+                                epoll::ctl(
+                                    epoll_fd,
+                                    epoll::ControlOptions::EPOLL_CTL_DEL,
+                                    stream.as_raw_fd(),
+                                    epoll::Event::new(epoll::Events::empty(), 0),
+                                )
+                                .map_err(Error::Epoll)?;
+                            }
+                            self.detach()?;
+                            info!("Debugger detached from the GDB stub");
+                        }
+                    }
+                    EPOLL_DEBUG_EVENT => {
+                        self.debug_evt.read().map_err(Error::Epoll)?;
+                        if let Some(stream) = connection.as_mut() {
+                            if self.running {
+                                self.stop_vcpus()?;
+                                self.send_packet(stream, &self.stop_reply())?;
+                            }
+                        }
+                    }
+                    EPOLL_EXIT_EVENT => {
+                        self.exit_evt.read().map_err(Error::Epoll)?;
+                        return Ok(());
+                    }
+                    _ => {
+                        error!("Unknown event for the GDB stub");
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns false when the connection must be closed.
+    fn handle_connection(
+        &mut self,
+        stream: &mut UnixStream,
+        parser: &mut PacketParser,
+    ) -> Result<bool> {
+        let mut buf = [0u8; 4096];
+        let count = match stream.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => {
+                warn!("Error reading from the GDB connection: {}", e);
+                return Ok(false);
+            }
+        };
+
+        for byte in buf[..count].iter() {
+            match parser.feed(*byte) {
+                Some(Incoming::Packet(packet)) => {
+                    stream.write_all(b"+").map_err(Error::Connection)?;
+                    match self.handle_packet(&packet)? {
+                        Action::Reply(reply) => self.send_packet(stream, &reply)?,
+                        Action::Resume { step } => self.resume_vcpus(step)?,
+                        Action::Detach { reply } => {
+                            if reply {
+                                self.send_packet(stream, b"OK")?;
+                            }
+                            return Ok(false);
+                        }
+                    }
+                }
+                Some(Incoming::InvalidPacket) => {
+                    stream.write_all(b"-").map_err(Error::Connection)?;
+                }
+                Some(Incoming::Interrupt) => {
+                    if self.running {
+                        self.stop_vcpus()?;
+                        self.send_packet(stream, &self.stop_reply())?;
+                    }
+                }
+                Some(Incoming::Ack) | Some(Incoming::Nack) | None => {}
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn send_packet(&self, stream: &mut UnixStream, data: &[u8]) -> Result<()> {
+        stream
+            .write_all(&encode_packet(data))
+            .map_err(Error::Connection)
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> Result<Action> {
+        debug!("GDB packet: {}", String::from_utf8_lossy(packet));
+
+        let (command, args) = match packet.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(Action::Reply(Vec::new())),
+        };
+
+        let reply = match command {
+            b'?' => self.stop_reply(),
+            b'g' => match self.read_registers() {
+                Some(data) => to_hex(&data),
+                None => error_reply(GDB_EINVAL),
+            },
+            b'G' => match from_hex(args) {
+                Some(data) if self.write_registers(&data) => b"OK".to_vec(),
+                _ => error_reply(GDB_EINVAL),
+            },
+            b'm' => match parse_addr_len(args) {
+                // The memory content is hex encoded in the reply.
+                Some((_, len)) if len > (GDB_PACKET_SIZE / 2) as u64 => error_reply(GDB_EINVAL),
+                Some((addr, len)) => match self.read_memory(addr, len) {
+                    Some(data) => to_hex(&data),
+                    None => error_reply(GDB_EFAULT),
+                },
+                None => error_reply(GDB_EINVAL),
+            },
+            b'M' => {
+                let mut split = args.splitn(2, |b| *b == b':');
+                match (
+                    split.next().and_then(parse_addr_len),
+                    split.next().and_then(from_hex),
+                ) {
+                    (Some((addr, len)), Some(data)) if data.len() as u64 == len => {
+                        if self.write_memory(addr, &data) {
+                            b"OK".to_vec()
+                        } else {
+                            error_reply(GDB_EFAULT)
+                        }
+                    }
+                    _ => error_reply(GDB_EINVAL),
+                }
+            }
+            b'c' => return Ok(Action::Resume { step: false }),
+            b's' => return Ok(Action::Resume { step: true }),
+            b'Z' | b'z' => self.handle_breakpoint(command == b'Z', args),
+            b'H' => {
+                // 'Hg' and 'Hc' followed by the thread id, where 0 means any
+                // thread and -1 all threads.
+                if let Some(thread_id) = args.get(1..).and_then(parse_hex) {
+                    if let Some(cpu_id) = self.thread_to_vcpu(thread_id) {
+                        self.selected_vcpu = cpu_id;
+                    }
+                }
+                b"OK".to_vec()
+            }
+            b'T' => match parse_hex(args).and_then(|thread_id| self.thread_to_vcpu(thread_id)) {
+                Some(_) => b"OK".to_vec(),
+                None => error_reply(GDB_EINVAL),
+            },
+            b'q' => self.handle_query(args),
+            b'D' => return Ok(Action::Detach { reply: true }),
+            b'k' => return Ok(Action::Detach { reply: false }),
+            _ => Vec::new(),
+        };
+
+        Ok(Action::Reply(reply))
+    }
+
+    fn handle_query(&self, query: &[u8]) -> Vec<u8> {
+        if query.starts_with(b"Supported") {
+            format!("PacketSize={:x}", GDB_PACKET_SIZE).into_bytes()
+        } else if query == b"Attached" {
+            b"1".to_vec()
+        } else if query == b"C" {
+            format!("QC{:x}", u64::from(self.selected_vcpu) + 1).into_bytes()
+        } else if query == b"fThreadInfo" {
+            let present_vcpus = self.cpu_manager.lock().unwrap().present_vcpus();
+            let threads: Vec<String> = (1..=u64::from(present_vcpus))
+                .map(|thread_id| format!("{:x}", thread_id))
+                .collect();
+            format!("m{}", threads.join(",")).into_bytes()
+        } else if query == b"sThreadInfo" {
+            b"l".to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn handle_breakpoint(&mut self, insert: bool, args: &[u8]) -> Vec<u8> {
+        let mut split = args.split(|b| *b == b',');
+        let (kind, addr) = match (
+            split.next().and_then(parse_hex),
+            split.next().and_then(parse_hex),
+        ) {
+            (Some(kind), Some(addr)) => (kind, addr),
+            _ => return error_reply(GDB_EINVAL),
+        };
+
+        match (kind, insert) {
+            (0, true) => {
+                if self.sw_breakpoints.contains_key(&addr) {
+                    return b"OK".to_vec();
+                }
+                let gpa = match self.translate(addr) {
+                    Some(gpa) => gpa,
+                    None => return error_reply(GDB_EFAULT),
+                };
+                let guest_memory = self.guest_memory.memory();
+                let orig: u8 = match guest_memory.read_obj(gpa) {
+                    Ok(orig) => orig,
+                    Err(_) => return error_reply(GDB_EFAULT),
+                };
+                if guest_memory.write_obj(INT3, gpa).is_err() {
+                    return error_reply(GDB_EFAULT);
+                }
+                self.sw_breakpoints.insert(addr, (gpa, orig));
+            }
+            (0, false) => {
+                if let Some((gpa, orig)) = self.sw_breakpoints.remove(&addr) {
+                    if self.guest_memory.memory().write_obj(orig, gpa).is_err() {
+                        return error_reply(GDB_EFAULT);
+                    }
+                }
+            }
+            (1, true) => {
+                if !self.hw_breakpoints.contains(&GuestAddress(addr)) {
+                    if self.hw_breakpoints.len() >= MAX_HW_BREAKPOINTS {
+                        return error_reply(GDB_ENOSPC);
+                    }
+                    self.hw_breakpoints.push(GuestAddress(addr));
+                }
+            }
+            (1, false) => {
+                self.hw_breakpoints.retain(|a| *a != GuestAddress(addr));
+            }
+            // Watchpoints are not supported.
+            _ => return Vec::new(),
+        }
+
+        b"OK".to_vec()
+    }
+
+    fn stop_reply(&self) -> Vec<u8> {
+        format!(
+            "T{:02x}thread:{:x};",
+            GDB_SIGTRAP,
+            u64::from(self.selected_vcpu) + 1
+        )
+        .into_bytes()
+    }
+
+    // GDB thread ids start at 1, each of them mapping to a vCPU.
+    fn thread_to_vcpu(&self, thread_id: u64) -> Option<u8> {
+        let present_vcpus = self.cpu_manager.lock().unwrap().present_vcpus();
+        if thread_id >= 1 && thread_id <= u64::from(present_vcpus) {
+            Some((thread_id - 1) as u8)
+        } else {
+            None
+        }
+    }
+
+    fn stop_vcpus(&mut self) -> Result<()> {
+        let mut cpu_manager = self.cpu_manager.lock().unwrap();
+        cpu_manager.pause().map_err(Error::Pause)?;
+        if let Some(cpu_id) = cpu_manager.take_debug_exit_vcpu() {
+            self.selected_vcpu = cpu_id;
+        }
+        self.running = false;
+        Ok(())
+    }
+
+    fn resume_vcpus(&mut self, step: bool) -> Result<()> {
+        let mut cpu_manager = self.cpu_manager.lock().unwrap();
+        let singlestep_vcpu = if step { Some(self.selected_vcpu) } else { None };
+        cpu_manager
+            .set_guest_debug(true, &self.hw_breakpoints, singlestep_vcpu)
+            .map_err(Error::GuestDebug)?;
+        cpu_manager.resume().map_err(Error::Resume)?;
+        self.running = true;
+        Ok(())
+    }
+
+    // Removes all breakpoints, disables guest debugging and lets the guest
+    // run freely.
+    fn detach(&mut self) -> Result<()> {
+        if self.running {
+            self.stop_vcpus()?;
+        }
+
+        let guest_memory = self.guest_memory.memory();
+        for (_, (gpa, orig)) in self.sw_breakpoints.drain() {
+            if let Err(e) = guest_memory.write_obj(orig, gpa) {
+                warn!("Could not remove breakpoint at {:#x}: {}", gpa.0, e);
+            }
+        }
+        self.hw_breakpoints.clear();
+
+        let mut cpu_manager = self.cpu_manager.lock().unwrap();
+        cpu_manager
+            .set_guest_debug(false, &[], None)
+            .map_err(Error::GuestDebug)?;
+        cpu_manager.resume().map_err(Error::Resume)?;
+        self.running = true;
+        Ok(())
+    }
+
+    fn read_registers(&self) -> Option<Vec<u8>> {
+        let state = self
+            .cpu_manager
+            .lock()
+            .unwrap()
+            .saved_vcpu_state(self.selected_vcpu)?;
+        Some(regs_to_gdb(&state.regs, &state.sregs))
+    }
+
+    fn write_registers(&self, data: &[u8]) -> bool {
+        let cpu_manager = self.cpu_manager.lock().unwrap();
+        let mut state = match cpu_manager.saved_vcpu_state(self.selected_vcpu) {
+            Some(state) => state,
+            None => return false,
+        };
+        if !regs_from_gdb(data, &mut state.regs) {
+            return false;
+        }
+        cpu_manager.set_saved_vcpu_state(self.selected_vcpu, state);
+        true
+    }
+
+    fn translate(&self, gva: u64) -> Option<GuestAddress> {
+        let state = self
+            .cpu_manager
+            .lock()
+            .unwrap()
+            .saved_vcpu_state(self.selected_vcpu)?;
+        translate_gva(&self.guest_memory.memory(), &state.sregs, gva)
+    }
+
+    // Splits a guest virtual memory range into guest physical chunks, each of
+    // them being contained in a single page.
+    fn for_each_page<F>(&self, gva: u64, len: u64, mut f: F) -> bool
+    where
+        F: FnMut(GuestAddress, usize, usize) -> bool,
+    {
+        let mut offset = 0;
+        while offset < len {
+            let addr = gva.wrapping_add(offset);
+            let chunk = std::cmp::min(len - offset, PAGE_SIZE - (addr & (PAGE_SIZE - 1)));
+            let gpa = match self.translate(addr) {
+                Some(gpa) => gpa,
+                None => return false,
+            };
+            if !f(gpa, offset as usize, chunk as usize) {
+                return false;
+            }
+            offset += chunk;
+        }
+        true
+    }
+
+    fn read_memory(&self, gva: u64, len: u64) -> Option<Vec<u8>> {
+        let guest_memory = self.guest_memory.memory();
+        let mut data = vec![0u8; len as usize];
+        if self.for_each_page(gva, len, |gpa, offset, chunk| {
+            guest_memory
+                .read_slice(&mut data[offset..offset + chunk], gpa)
+                .is_ok()
+        }) {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    fn write_memory(&self, gva: u64, data: &[u8]) -> bool {
+        let guest_memory = self.guest_memory.memory();
+        self.for_each_page(gva, data.len() as u64, |gpa, offset, chunk| {
+            guest_memory
+                .write_slice(&data[offset..offset + chunk], gpa)
+                .is_ok()
+        })
+    }
+}
+
+fn parse_addr_len(args: &[u8]) -> Option<(u64, u64)> {
+    let mut split = args.split(|b| *b == b',');
+    let addr = split.next().and_then(parse_hex)?;
+    let len = split.next().and_then(parse_hex)?;
+    Some((addr, len))
+}
+
+fn epoll_add(epoll_fd: RawFd, fd: RawFd, event: u64) -> Result<()> {
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        fd,
+        epoll::Event::new(epoll::Events::EPOLLIN, event),
+    )
+    .map_err(Error::Epoll)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_parser() {
+        let mut parser = PacketParser::new();
+        let mut incoming = Vec::new();
+        for byte in b"+$g#67-\x03$m10,4#00".iter() {
+            if let Some(i) = parser.feed(*byte) {
+                incoming.push(i);
+            }
+        }
+
+        assert_eq!(
+            incoming,
+            vec![
+                Incoming::Ack,
+                Incoming::Packet(b"g".to_vec()),
+                Incoming::Nack,
+                Incoming::Interrupt,
+                Incoming::InvalidPacket,
+            ]
+        );
+        assert_eq!(encode_packet(b"OK"), b"$OK#9a".to_vec());
+        assert_eq!(encode_packet(b""), b"$#00".to_vec());
+    }
+
+    #[test]
+    fn test_packet_parser_overflow() {
+        let mut parser = PacketParser::new();
+        let mut packet = vec![b'$'];
+        packet.resize(GDB_PACKET_SIZE + 2, b'0');
+        packet.extend_from_slice(b"#00$g#67");
+
+        let incoming: Vec<Incoming> = packet.iter().filter_map(|b| parser.feed(*b)).collect();
+        assert_eq!(
+            incoming,
+            vec![Incoming::InvalidPacket, Incoming::Packet(b"g".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x01, 0xab, 0xff]), b"01abff".to_vec());
+        assert_eq!(from_hex(b"01abff"), Some(vec![0x01, 0xab, 0xff]));
+        assert_eq!(from_hex(b"01a"), None);
+        assert_eq!(from_hex(b"zz"), None);
+        assert_eq!(parse_addr_len(b"ffff8000,10"), Some((0xffff_8000, 0x10)));
+        assert_eq!(parse_addr_len(b"ffff8000"), None);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut regs = StandardRegisters::default();
+        regs.rax = 0x1122_3344_5566_7788;
+        regs.r15 = 0xf;
+        regs.rip = 0xffff_ffff_8100_0000;
+        regs.rflags = 0x246;
+        let mut sregs = SpecialRegisters::default();
+        sregs.cs.selector = 0x10;
+
+        let data = regs_to_gdb(&regs, &sregs);
+        assert_eq!(data.len(), GDB_REGS_SIZE);
+        assert_eq!(&data[0..8], &0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(&data[128..136], &0xffff_ffff_8100_0000u64.to_le_bytes());
+        assert_eq!(&data[136..140], &0x246u32.to_le_bytes());
+        assert_eq!(&data[140..144], &0x10u32.to_le_bytes());
+
+        let mut new_regs = StandardRegisters::default();
+        assert!(regs_from_gdb(&data, &mut new_regs));
+        assert_eq!(new_regs, regs);
+        assert!(!regs_from_gdb(&data[..64], &mut new_regs));
+    }
+
+    #[test]
+    fn test_translate_gva() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+
+        // Paging disabled
+        let mut sregs = SpecialRegisters::default();
+        assert_eq!(
+            translate_gva(&guest_memory, &sregs, 0x1234),
+            Some(GuestAddress(0x1234))
+        );
+
+        // 4 levels paging: PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000
+        // and PT at 0x4000.
+        sregs.cr0 = X86_CR0_PG;
+        sregs.cr4 = X86_CR4_PAE;
+        sregs.efer = EFER_LMA;
+        sregs.cr3 = 0x1000;
+        let gva: u64 = 0xffff_8000_0000_0000 | (1 << 30) | (2 << 21) | (3 << 12) | 0x456;
+        guest_memory
+            .write_obj(0x2000u64 | PTE_PRESENT, GuestAddress(0x1000 + 256 * 8))
+            .unwrap();
+        guest_memory
+            .write_obj(0x3000u64 | PTE_PRESENT, GuestAddress(0x2000 + 8))
+            .unwrap();
+        guest_memory
+            .write_obj(0x4000u64 | PTE_PRESENT, GuestAddress(0x3000 + 2 * 8))
+            .unwrap();
+        guest_memory
+            .write_obj(0x8000u64 | PTE_PRESENT, GuestAddress(0x4000 + 3 * 8))
+            .unwrap();
+        assert_eq!(
+            translate_gva(&guest_memory, &sregs, gva),
+            Some(GuestAddress(0x8456))
+        );
+
+        // 2MiB page
+        guest_memory
+            .write_obj(
+                0x20_0000u64 | PTE_PRESENT | PTE_PAGE_SIZE,
+                GuestAddress(0x3000 + 2 * 8),
+            )
+            .unwrap();
+        assert_eq!(
+            translate_gva(&guest_memory, &sregs, gva),
+            Some(GuestAddress(0x20_3456))
+        );
+
+        // Not present
+        assert_eq!(translate_gva(&guest_memory, &sregs, 0x1000), None);
+    }
+}
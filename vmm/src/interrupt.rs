@@ -392,11 +392,13 @@ pub mod kvm {
     }
 }
 
-#[cfg(target_arch = "aarch64")]
 #[cfg(test)]
 mod tests {
+    #[cfg(all(target_arch = "aarch64", feature = "kvm"))]
     use arch::aarch64::gic::kvm::create_gic;
 
+    #[cfg(target_arch = "aarch64")]
+    #[cfg(feature = "kvm")]
     #[test]
     fn test_create_gic() {
         let hv = hypervisor::new().unwrap();
@@ -404,4 +406,77 @@ mod tests {
 
         assert!(create_gic(&vm, 1, false).is_ok());
     }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_msi_interrupt_group() {
+        use super::kvm::KvmMsiInterruptManager;
+        use hypervisor::mock::MockVm;
+        use std::os::unix::io::AsRawFd;
+        use std::sync::{Arc, Mutex};
+        #[cfg(target_arch = "x86_64")]
+        use vm_allocator::GsiApic;
+        use vm_allocator::SystemAllocator;
+        use vm_device::interrupt::{
+            InterruptManager, InterruptSourceConfig, MsiIrqGroupConfig, MsiIrqSourceConfig,
+        };
+        use vm_memory::GuestAddress;
+
+        let allocator = Arc::new(Mutex::new(
+            SystemAllocator::new(
+                #[cfg(target_arch = "x86_64")]
+                GuestAddress(0),
+                #[cfg(target_arch = "x86_64")]
+                0x10000,
+                GuestAddress(0x1_0000_0000),
+                0x1000_0000,
+                GuestAddress(0xd000_0000),
+                0x1000_0000,
+                #[cfg(target_arch = "x86_64")]
+                vec![GsiApic::new(0, 24)],
+            )
+            .unwrap(),
+        ));
+        let vm = Arc::new(MockVm::new());
+        let manager = KvmMsiInterruptManager::new(allocator, vm.clone());
+
+        let group = manager
+            .create_group(MsiIrqGroupConfig { base: 0, count: 2 })
+            .unwrap();
+        let config = MsiIrqSourceConfig {
+            high_addr: 0,
+            low_addr: 0xfee0_0000,
+            data: 0x30,
+            devid: 0,
+        };
+        group
+            .update(0, InterruptSourceConfig::MsiIrq(config))
+            .unwrap();
+        group.enable().unwrap();
+        assert_eq!(vm.irqfds().len(), 2);
+
+        let routes = vm.gsi_routing();
+        assert_eq!(routes.len(), 1);
+        let gsi = routes[0].gsi;
+        // Safe because the route was created as an MSI route.
+        let msi = unsafe { routes[0].u.msi };
+        assert_eq!(msi.address_lo, 0xfee0_0000);
+        assert_eq!(msi.data, 0x30);
+        let irq_fd = group.notifier(0).unwrap().as_raw_fd();
+        assert!(vm.irqfds().contains(&(irq_fd, gsi)));
+
+        // Masking the vector removes both its route and its irqfd.
+        group.mask(0).unwrap();
+        assert!(vm.gsi_routing().is_empty());
+        assert!(!vm.irqfds().contains(&(irq_fd, gsi)));
+
+        group.unmask(0).unwrap();
+        assert_eq!(vm.gsi_routing().len(), 1);
+        assert_eq!(vm.irqfds().len(), 2);
+
+        // Unknown vectors are refused.
+        assert!(group
+            .update(2, InterruptSourceConfig::MsiIrq(config))
+            .is_err());
+    }
 }
@@ -17,40 +17,57 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate tempfile;
 extern crate url;
+#[macro_use]
 extern crate vmm_sys_util;
 #[cfg(test)]
 #[macro_use]
 extern crate credibility;
 
-use crate::api::{ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmmPingResponse};
+use crate::api::{
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmCoredumpData, VmInfo,
+    VmReceiveMigrationData, VmSendMigrationData, VmSnapshotConfig, VmVerifySnapshotData,
+    VmmPingResponse,
+};
 use crate::config::{
     DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
 };
-use crate::migration::{get_vm_snapshot, recv_vm_snapshot};
+use crate::memory_manager::MemoryManager;
+use crate::migration::{
+    accept_migration_socket, check_vm_snapshot, connect_migration_socket, get_vm_snapshot,
+    recv_vm_snapshot, verify_vm_snapshot, MigrationSocket, SnapshotChecks, VmmSnapshot,
+    MAX_MIGRATION_ROUNDS, MIGRATION_DOWNTIME_THRESHOLD,
+};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vm::{Error as VmError, Vm, VmState};
+use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
+use rate_limiter::RateLimiterConfig;
 use seccomp::{SeccompFilter, SeccompLevel};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::fs::File;
-use std::io;
+use std::io::{self, Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use std::{result, thread};
-use vm_migration::{Pausable, Snapshottable, Transportable};
+use vm_migration::protocol::{Command, MemoryRangeTable, Request, Response, Status};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 pub mod api;
 pub mod config;
+mod coredump;
 pub mod cpu;
 pub mod device_manager;
 pub mod device_tree;
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+mod gdb;
 pub mod interrupt;
 pub mod memory_manager;
 pub mod migration;
 pub mod seccomp_filters;
+mod userfaultfd;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -87,6 +104,9 @@ pub enum Error {
     /// Cannot handle the VM STDIN stream
     Stdin(VmError),
 
+    /// Cannot remove the memory ejected by the guest
+    MemoryEject(VmError),
+
     /// Cannot reboot the VM
     VmReboot(VmError),
 
@@ -116,6 +136,7 @@ pub enum EpollDispatch {
     Reset,
     Stdin,
     Api,
+    MemoryEject,
 }
 
 pub struct EpollContext {
@@ -252,6 +273,7 @@ pub struct Vmm {
     exit_evt: EventFd,
     reset_evt: EventFd,
     api_evt: EventFd,
+    memory_eject_evt: EventFd,
     version: String,
     vm: Option<Vm>,
     vm_config: Option<Arc<Mutex<VmConfig>>>,
@@ -269,6 +291,7 @@ impl Vmm {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let memory_eject_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
             epoll.add_stdin().map_err(Error::Epoll)?;
@@ -286,11 +309,16 @@ impl Vmm {
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&memory_eject_evt, EpollDispatch::MemoryEject)
+            .map_err(Error::Epoll)?;
+
         Ok(Vmm {
             epoll,
             exit_evt,
             reset_evt,
             api_evt,
+            memory_eject_evt,
             version: vmm_version,
             vm: None,
             vm_config: None,
@@ -304,12 +332,17 @@ impl Vmm {
         if self.vm.is_none() {
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let memory_eject_evt = self
+                .memory_eject_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
 
             if let Some(ref vm_config) = self.vm_config {
                 let vm = Vm::new(
                     Arc::clone(vm_config),
                     exit_evt,
                     reset_evt,
+                    memory_eject_evt,
                     self.vmm_path.clone(),
                     self.hypervisor.clone(),
                 )?;
@@ -341,14 +374,46 @@ impl Vmm {
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+    fn vm_snapshot(&mut self, snapshot_cfg: &VmSnapshotConfig) -> result::Result<(), VmError> {
+        let version = &self.version;
         if let Some(ref mut vm) = self.vm {
+            vm.set_snapshot_parent(snapshot_cfg.parent_url.as_deref())
+                .map_err(VmError::Snapshot)?;
             vm.snapshot()
+                .and_then(|mut snapshot| {
+                    VmmSnapshot::new(version, vm).add_to(&mut snapshot)?;
+                    Ok(snapshot)
+                })
                 .map_err(VmError::Snapshot)
                 .and_then(|snapshot| {
-                    vm.send(&snapshot, destination_url)
+                    vm.send(&snapshot, &snapshot_cfg.destination_url)
                         .map_err(VmError::SnapshotSend)
-                })
+                })?;
+
+            // Only keep track of the memory changes from now on when asked
+            // for, as the dirty pages logging slows the guest down.
+            if snapshot_cfg.track_changes {
+                vm.track_snapshot_changes(&snapshot_cfg.destination_url);
+            } else {
+                vm.untrack_snapshot_changes();
+            }
+
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_verify_snapshot(
+        &self,
+        verify_data: &VmVerifySnapshotData,
+    ) -> result::Result<(), VmError> {
+        verify_vm_snapshot(&verify_data.source_url).map_err(VmError::SnapshotVerify)
+    }
+
+    fn vm_coredump(&mut self, coredump_data: &VmCoredumpData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.coredump(&coredump_data.destination_url)
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -366,21 +431,33 @@ impl Vmm {
         // Safe to unwrap as we checked it was Some(&str).
         let source_url = source_url.unwrap();
 
-        let snapshot = recv_vm_snapshot(source_url).map_err(VmError::Restore)?;
+        let checks = SnapshotChecks {
+            files: restore_cfg.verify_files,
+            allow_unverified: restore_cfg.allow_unverified,
+        };
+        let snapshot = recv_vm_snapshot(source_url, checks).map_err(VmError::Restore)?;
+        check_vm_snapshot(&snapshot, &self.version, &self.hypervisor).map_err(VmError::Restore)?;
         let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::Restore)?;
 
         self.vm_config = Some(Arc::clone(&vm_snapshot.config));
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let memory_eject_evt = self
+            .memory_eject_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
 
         let vm = Vm::new_from_snapshot(
             &snapshot,
             exit_evt,
             reset_evt,
+            memory_eject_evt,
             self.vmm_path.clone(),
             source_url,
             restore_cfg.prefault,
+            restore_cfg.lazy,
+            checks,
             self.hypervisor.clone(),
         )?;
         self.vm = Some(vm);
@@ -418,6 +495,10 @@ impl Vmm {
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let memory_eject_evt = self
+                .memory_eject_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
 
             // The Linux kernel fires off an i8042 reset after doing the ACPI reset so there may be
             // an event sitting in the shared reset_evt. Without doing this we get very early reboots
@@ -429,6 +510,7 @@ impl Vmm {
                 config,
                 exit_evt,
                 reset_evt,
+                memory_eject_evt,
                 self.vmm_path.clone(),
                 self.hypervisor.clone(),
             )?);
@@ -452,8 +534,17 @@ impl Vmm {
                     None => VmState::Created,
                 };
 
+                // The guest can deflate the balloon on its own, hence the
+                // configuration is updated from the actual balloon size.
+                if let Some(vm) = &self.vm {
+                    let mut config = config.lock().unwrap();
+                    if config.memory.balloon {
+                        config.memory.balloon_size = vm.balloon_size();
+                    }
+                }
+
                 Ok(VmInfo {
-                    config: Arc::clone(config),
+                    config: config.clone(),
                     state,
                 })
             }
@@ -491,9 +582,11 @@ impl Vmm {
         desired_vcpus: Option<u8>,
         desired_ram: Option<u64>,
         desired_ram_w_balloon: Option<u64>,
+        numa_node: Option<u32>,
     ) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            if let Err(e) = vm.resize(desired_vcpus, desired_ram, desired_ram_w_balloon) {
+            if let Err(e) = vm.resize(desired_vcpus, desired_ram, desired_ram_w_balloon, numa_node)
+            {
                 error!("Error when resizing VM: {:?}", e);
                 Err(e)
             } else {
@@ -589,6 +682,43 @@ impl Vmm {
         }
     }
 
+    fn vm_update_disk_rate_limiter(
+        &mut self,
+        id: String,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.update_disk_rate_limiter(id, rate_limiter_config) {
+                error!("Error when updating the disk rate limiter: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_update_net_rate_limiter(
+        &mut self,
+        id: String,
+        rx_rate_limiter_config: Option<RateLimiterConfig>,
+        tx_rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) =
+                vm.update_net_rate_limiter(id, rx_rate_limiter_config, tx_rate_limiter_config)
+            {
+                error!("Error when updating the network rate limiters: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_counters(&mut self) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.counters().map_err(|e| {
@@ -601,6 +731,335 @@ impl Vmm {
         }
     }
 
+    fn vm_send_migration(
+        &mut self,
+        send_data_migration: VmSendMigrationData,
+    ) -> result::Result<(), VmError> {
+        info!(
+            "Sending migration: destination_url = {}",
+            send_data_migration.destination_url
+        );
+
+        if let Some(ref mut vm) = self.vm {
+            if vm.get_state()? != VmState::Running {
+                return Err(VmError::MigrateSend(MigratableError::MigrateSend(anyhow!(
+                    "Only a running VM can be migrated"
+                ))));
+            }
+
+            let mut socket = connect_migration_socket(&send_data_migration.destination_url)
+                .map_err(VmError::MigrateSend)?;
+
+            if let Err(e) = Vmm::send_migration(vm, &self.version, &mut socket) {
+                error!("Migration failed: {:?}", e);
+
+                // Let the destination know, and give the VM back to the
+                // guest as if nothing happened.
+                if let Err(e) = Request::abandon().write_to(&mut socket) {
+                    warn!("Could not abandon migration: {:?}", e);
+                }
+                if let Err(e) = vm.stop_dirty_log() {
+                    warn!("Could not stop dirty log: {:?}", e);
+                }
+                if vm.get_state()? == VmState::Paused {
+                    vm.resume().map_err(VmError::Resume)?;
+                }
+
+                return Err(VmError::MigrateSend(e));
+            }
+        } else {
+            return Err(VmError::VmNotRunning);
+        }
+
+        // The VM is now running on the destination, so the local one can
+        // be discarded.
+        info!("Migration complete");
+        self.vm_delete()
+    }
+
+    fn send_migration(
+        vm: &mut Vm,
+        version: &str,
+        socket: &mut MigrationSocket,
+    ) -> result::Result<(), MigratableError> {
+        // Start the migration
+        Request::start().write_to(socket)?;
+        Vmm::check_migration_response(socket, "starting")?;
+
+        // Send the VM configuration, used to create the guest RAM on the
+        // destination.
+        let config_data = serde_json::to_vec(&vm.get_config())
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        Request::config(config_data.len() as u64).write_to(socket)?;
+        socket
+            .write_all(&config_data)
+            .map_err(MigratableError::MigrateSocket)?;
+        Vmm::check_migration_response(socket, "sending config")?;
+
+        // Copy the whole guest RAM while the guest keeps running, and then
+        // iterate over the pages it dirtied in the meantime, until the
+        // amount of dirty memory is small enough or stops shrinking.
+        vm.start_dirty_log()?;
+        let table = vm.memory_range_table()?;
+        Vmm::send_memory_ranges(vm, &table, socket)?;
+
+        let mut last_size = table.effective_size();
+        let mut pending = MemoryRangeTable::default();
+        for round in 0..MAX_MIGRATION_ROUNDS {
+            let table = vm.dirty_log()?;
+            let size = table.effective_size();
+            if size <= MIGRATION_DOWNTIME_THRESHOLD || size >= last_size {
+                pending = table;
+                break;
+            }
+
+            debug!(
+                "Migration pre-copy round {}: sending {} bytes",
+                round + 1,
+                size
+            );
+            Vmm::send_memory_ranges(vm, &table, socket)?;
+            last_size = size;
+        }
+
+        // Pause the VM and send the last dirty pages.
+        vm.pause()?;
+        pending.extend(vm.dirty_log()?);
+        Vmm::send_memory_ranges(vm, &pending, socket)?;
+        vm.stop_dirty_log()?;
+
+        // Send the VM state
+        let mut snapshot = vm.snapshot()?;
+        VmmSnapshot::new(version, vm).add_to(&mut snapshot)?;
+        let snapshot_data =
+            serde_json::to_vec(&snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        Request::state(snapshot_data.len() as u64).write_to(socket)?;
+        socket
+            .write_all(&snapshot_data)
+            .map_err(MigratableError::MigrateSocket)?;
+        Vmm::check_migration_response(socket, "sending state")?;
+
+        // Complete the migration
+        Request::complete().write_to(socket)?;
+        Vmm::check_migration_response(socket, "completing")
+    }
+
+    fn send_memory_ranges(
+        vm: &Vm,
+        table: &MemoryRangeTable,
+        socket: &mut MigrationSocket,
+    ) -> result::Result<(), MigratableError> {
+        Request::memory(table.length()).write_to(socket)?;
+        table.write_to(socket)?;
+        vm.send_memory_regions(table, socket)?;
+        Vmm::check_migration_response(socket, "sending memory")
+    }
+
+    fn check_migration_response(
+        socket: &mut MigrationSocket,
+        step: &str,
+    ) -> result::Result<(), MigratableError> {
+        if Response::read_from(socket)?.status() != Status::Ok {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error {} migration",
+                step
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn vm_receive_migration(
+        &mut self,
+        receive_data_migration: VmReceiveMigrationData,
+    ) -> result::Result<(), VmError> {
+        info!(
+            "Receiving migration: receiver_url = {}",
+            receive_data_migration.receiver_url
+        );
+
+        if self.vm.is_some() || self.vm_config.is_some() {
+            return Err(VmError::VmAlreadyCreated);
+        }
+
+        let mut socket = accept_migration_socket(&receive_data_migration.receiver_url)
+            .map_err(VmError::MigrateReceive)?;
+
+        if let Err(e) = self.receive_migration(&mut socket) {
+            error!("Migration failed: {:?}", e);
+
+            if let Err(e) = Response::error().write_to(&mut socket) {
+                warn!("Could not report migration failure: {:?}", e);
+            }
+
+            // Drop anything partially received.
+            self.vm = None;
+            self.vm_config = None;
+
+            return Err(VmError::MigrateReceive(e));
+        }
+
+        info!("Migration complete");
+        Ok(())
+    }
+
+    fn receive_migration(
+        &mut self,
+        socket: &mut MigrationSocket,
+    ) -> result::Result<(), MigratableError> {
+        let mut started = false;
+        let mut vm_config: Option<Arc<Mutex<VmConfig>>> = None;
+        let mut hypervisor_vm: Option<Arc<dyn hypervisor::Vm>> = None;
+        let mut memory_manager: Option<Arc<Mutex<MemoryManager>>> = None;
+
+        loop {
+            let request = Request::read_from(socket)?;
+            if request.command() != Command::Start && !started {
+                return Err(MigratableError::MigrateReceive(anyhow!(
+                    "Migration not started"
+                )));
+            }
+
+            match request.command() {
+                Command::Invalid => {
+                    return Err(MigratableError::MigrateReceive(anyhow!(
+                        "Invalid migration command"
+                    )));
+                }
+                Command::Start => {
+                    started = true;
+                }
+                Command::Config => {
+                    let data = Vmm::read_migration_payload(socket, request.length())?;
+                    let config: VmConfig = serde_json::from_slice(&data)
+                        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+                    #[cfg(target_arch = "x86_64")]
+                    self.hypervisor
+                        .check_required_extensions()
+                        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                    let vm = self
+                        .hypervisor
+                        .create_vm()
+                        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                    #[cfg(target_arch = "x86_64")]
+                    vm.enable_split_irq()
+                        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+                    memory_manager = Some(
+                        MemoryManager::new(vm.clone(), &config.memory, &config.numa, None, false)
+                            .map_err(|e| {
+                            MigratableError::MigrateReceive(anyhow!(
+                                "Error creating memory manager: {:?}",
+                                e
+                            ))
+                        })?,
+                    );
+                    hypervisor_vm = Some(vm);
+                    vm_config = Some(Arc::new(Mutex::new(config)));
+                }
+                Command::Memory => {
+                    let memory_manager = memory_manager.as_ref().ok_or_else(|| {
+                        MigratableError::MigrateReceive(anyhow!(
+                            "Memory received before the VM config"
+                        ))
+                    })?;
+
+                    let table = MemoryRangeTable::read_from(socket, request.length())?;
+                    memory_manager
+                        .lock()
+                        .unwrap()
+                        .receive_memory_regions(&table, socket)?;
+                }
+                Command::State => {
+                    let data = Vmm::read_migration_payload(socket, request.length())?;
+                    let snapshot: Snapshot = serde_json::from_slice(&data)
+                        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                    check_vm_snapshot(&snapshot, &self.version, &self.hypervisor)?;
+
+                    let (config, memory_manager, vm) = match (
+                        vm_config.take(),
+                        memory_manager.take(),
+                        hypervisor_vm.take(),
+                    ) {
+                        (Some(config), Some(memory_manager), Some(vm)) => {
+                            (config, memory_manager, vm)
+                        }
+                        _ => {
+                            return Err(MigratableError::MigrateReceive(anyhow!(
+                                "State received before the VM config"
+                            )))
+                        }
+                    };
+
+                    let exit_evt = self
+                        .exit_evt
+                        .try_clone()
+                        .map_err(MigratableError::MigrateSocket)?;
+                    let reset_evt = self
+                        .reset_evt
+                        .try_clone()
+                        .map_err(MigratableError::MigrateSocket)?;
+                    let memory_eject_evt = self
+                        .memory_eject_evt
+                        .try_clone()
+                        .map_err(MigratableError::MigrateSocket)?;
+
+                    let mut vm = Vm::new_from_migration(
+                        config.clone(),
+                        memory_manager,
+                        vm,
+                        &snapshot,
+                        exit_evt,
+                        reset_evt,
+                        memory_eject_evt,
+                        self.vmm_path.clone(),
+                        self.hypervisor.clone(),
+                    )
+                    .map_err(|e| {
+                        MigratableError::MigrateReceive(anyhow!("Error creating VM: {:?}", e))
+                    })?;
+                    vm.restore(snapshot)?;
+
+                    self.vm = Some(vm);
+                    self.vm_config = Some(config);
+                }
+                Command::Complete => {
+                    if let Some(ref mut vm) = self.vm {
+                        vm.resume()?;
+                    } else {
+                        return Err(MigratableError::MigrateReceive(anyhow!(
+                            "Migration completed before the VM state was received"
+                        )));
+                    }
+
+                    Response::ok().write_to(socket)?;
+                    return Ok(());
+                }
+                Command::Abandon => {
+                    Response::ok().write_to(socket)?;
+                    return Err(MigratableError::MigrateReceive(anyhow!(
+                        "Migration abandoned by the source"
+                    )));
+                }
+            }
+
+            Response::ok().write_to(socket)?;
+        }
+    }
+
+    fn read_migration_payload(
+        socket: &mut MigrationSocket,
+        length: u64,
+    ) -> result::Result<Vec<u8>, MigratableError> {
+        let mut data = vec![0u8; length as usize];
+        socket
+            .read_exact(&mut data)
+            .map_err(MigratableError::MigrateSocket)?;
+
+        Ok(data)
+    }
+
     fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
@@ -647,6 +1106,13 @@ impl Vmm {
                                 vm.handle_stdin().map_err(Error::Stdin)?;
                             }
                         }
+                        EpollDispatch::MemoryEject => {
+                            // Consume the event.
+                            self.memory_eject_evt.read().map_err(Error::EventFdRead)?;
+                            if let Some(ref vm) = self.vm {
+                                vm.remove_ejected_memory().map_err(Error::MemoryEject)?;
+                            }
+                        }
                         EpollDispatch::Api => {
                             // Consume the event.
                             self.api_evt.read().map_err(Error::EventFdRead)?;
@@ -738,7 +1204,7 @@ impl Vmm {
                                 }
                                 ApiRequest::VmSnapshot(snapshot_data, sender) => {
                                     let response = self
-                                        .vm_snapshot(&snapshot_data.destination_url)
+                                        .vm_snapshot(&snapshot_data)
                                         .map_err(ApiError::VmSnapshot)
                                         .map(|_| ApiResponsePayload::Empty);
 
@@ -752,6 +1218,22 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmVerifySnapshot(verify_data, sender) => {
+                                    let response = self
+                                        .vm_verify_snapshot(&verify_data)
+                                        .map_err(ApiError::VmVerifySnapshot)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmCoredump(coredump_data, sender) => {
+                                    let response = self
+                                        .vm_coredump(&coredump_data)
+                                        .map_err(ApiError::VmCoredump)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmmShutdown(sender) => {
                                     let response = self
                                         .vmm_shutdown()
@@ -768,6 +1250,7 @@ impl Vmm {
                                             resize_data.desired_vcpus,
                                             resize_data.desired_ram,
                                             resize_data.desired_ram_w_balloon,
+                                            resize_data.numa_node,
                                         )
                                         .map_err(ApiError::VmResize)
                                         .map(|_| ApiResponsePayload::Empty);
@@ -822,6 +1305,43 @@ impl Vmm {
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmSendMigration(send_migration_data, sender) => {
+                                    let response = self
+                                        .vm_send_migration(send_migration_data.as_ref().clone())
+                                        .map_err(ApiError::VmSendMigration)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmReceiveMigration(receive_migration_data, sender) => {
+                                    let response = self
+                                        .vm_receive_migration(
+                                            receive_migration_data.as_ref().clone(),
+                                        )
+                                        .map_err(ApiError::VmReceiveMigration)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmUpdateDiskRateLimiter(update_data, sender) => {
+                                    let response = self
+                                        .vm_update_disk_rate_limiter(
+                                            update_data.id.clone(),
+                                            update_data.rate_limiter_config,
+                                        )
+                                        .map_err(ApiError::VmUpdateDiskRateLimiter)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmUpdateNetRateLimiter(update_data, sender) => {
+                                    let response = self
+                                        .vm_update_net_rate_limiter(
+                                            update_data.id.clone(),
+                                            update_data.rx_rate_limiter_config,
+                                            update_data.tx_rate_limiter_config,
+                                        )
+                                        .map_err(ApiError::VmUpdateNetRateLimiter)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmCounters(sender) => {
                                     let response = self
                                         .vm_counters()
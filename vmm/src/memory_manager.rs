@@ -5,7 +5,11 @@
 extern crate hypervisor;
 #[cfg(target_arch = "x86_64")]
 use crate::config::SgxEpcConfig;
-use crate::config::{HotplugMethod, MemoryConfig};
+use crate::config::{
+    HotplugMethod, MemoryConfig, MemoryZoneConfig, NumaConfig, DEFAULT_HUGEPAGE_SIZE,
+};
+use crate::migration::{recv_vm_snapshot, url_to_path, SnapshotChecks};
+use crate::userfaultfd::{LazyLoader, LazyRegion};
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
@@ -19,14 +23,17 @@ use devices::BusDevice;
 
 #[cfg(target_arch = "x86_64")]
 use libc::{MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi;
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
 #[cfg(target_arch = "x86_64")]
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::{FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Mutex};
 use url::Url;
@@ -40,15 +47,32 @@ use vm_memory::{
     GuestRegionMmap, GuestUsize, MemoryRegionAddress, MmapRegion,
 };
 use vm_migration::{
+    protocol::{MemoryRange, MemoryRangeTable},
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
+use vmm_sys_util::eventfd::EventFd;
 
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
 const HOTPLUG_COUNT: usize = 8;
 
+// Bit 55 of a /proc/self/pagemap entry is set when the page has been
+// written to since the soft-dirty bits were last cleared.
+const PAGEMAP_SOFT_DIRTY: u64 = 1 << 55;
+
+// Number of pagemap entries read at once.
+const PAGEMAP_CHUNK_PAGES: u64 = 4096;
+
+// Memory policy and flags from <linux/mempolicy.h>, as used by mbind().
+const MPOL_BIND: u64 = 2;
+const MPOL_MF_STRICT: u64 = 1;
+const MPOL_MF_MOVE: u64 = 1 << 1;
+
+// Directory holding one subdirectory per hugepages pool of the host.
+const HUGEPAGES_SYSFS_DIR: &str = "/sys/kernel/mm/hugepages";
+
 #[derive(Default)]
 struct HotPlugState {
     base: u64,
@@ -56,6 +80,49 @@ struct HotPlugState {
     active: bool,
     inserting: bool,
     removing: bool,
+    // Removal requested, waiting for the guest to eject the slot.
+    ejecting: bool,
+}
+
+// Hotpluggable memory handled by a virtio-mem device, along with the NUMA
+// node it belongs to, if any.
+pub struct VirtioMemZone {
+    region: Arc<GuestRegionMmap>,
+    resize: virtio_devices::Resize,
+    numa_node: Option<u32>,
+    hotplugged_size: u64,
+    mergeable: bool,
+}
+
+impl VirtioMemZone {
+    pub fn region(&self) -> &Arc<GuestRegionMmap> {
+        &self.region
+    }
+
+    pub fn resize_handler(&self) -> &virtio_devices::Resize {
+        &self.resize
+    }
+
+    pub fn numa_node(&self) -> Option<u32> {
+        self.numa_node
+    }
+}
+
+// A guest RAM region to create, along with the memory zone and the NUMA
+// node it belongs to.
+struct RamRegion {
+    start_addr: GuestAddress,
+    size: usize,
+    zone: usize,
+    numa_node: Option<u32>,
+}
+
+// A guest RAM region, as mapped into a hypervisor memory slot.
+struct GuestRamMapping {
+    slot: u32,
+    gpa: u64,
+    size: u64,
+    host_addr: u64,
 }
 
 pub struct MemoryManager {
@@ -72,15 +139,33 @@ pub struct MemoryManager {
     hotplug_method: HotplugMethod,
     boot_ram: u64,
     current_ram: u64,
-    next_hotplug_slot: usize,
-    pub virtiomem_region: Option<Arc<GuestRegionMmap>>,
-    pub virtiomem_resize: Option<virtio_devices::Resize>,
+    virtio_mem_zones: Vec<VirtioMemZone>,
     snapshot: Mutex<Option<GuestMemoryLoadGuard<GuestMemoryMmap>>>,
     shared: bool,
     hugepages: bool,
+    hugepage_size: Option<u64>,
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
     #[cfg(target_arch = "x86_64")]
     sgx_epc_region: Option<SgxEpcRegion>,
+    guest_ram_mappings: Vec<GuestRamMapping>,
+    dirty_log_active: bool,
+    // URL of the last snapshot, from which the memory changes are tracked.
+    last_snapshot_url: Option<String>,
+    // Parent URL of the snapshot being taken, along with the memory changed
+    // since then.
+    snapshot_parent: Option<(String, MemoryRangeTable)>,
+    // Only held so that the guest memory loading stops along with the
+    // memory manager.
+    #[allow(dead_code)]
+    lazy_loader: Option<LazyLoader>,
+    // Guest RAM regions of each NUMA node.
+    numa_regions: BTreeMap<u32, Vec<Arc<GuestRegionMmap>>>,
+    // Signaled whenever the guest ejects a hotplugged memory slot, for the
+    // devices to stop accessing it.
+    eject_evt: Option<EventFd>,
+    // Regions ejected by the guest, kept mapped until the devices have been
+    // updated.
+    ejected_regions: Vec<Arc<GuestRegionMmap>>,
 }
 
 #[derive(Debug)]
@@ -112,6 +197,12 @@ pub enum Error {
     /// The requested hotplug memory addition is not a valid size
     InvalidSize,
 
+    /// The requested memory removal doesn't match the hotplugged slots
+    InvalidHotUnplugSize,
+
+    /// The requested memory size is below the boot memory size
+    ResizeBelowBootRam,
+
     /// Failed to set the user memory region.
     SetUserMemoryRegion(hypervisor::HypervisorVmError),
 
@@ -124,6 +215,15 @@ pub enum Error {
     /// Failed to virtio-mem resize
     VirtioMemResizeFail(virtio_devices::mem::Error),
 
+    /// No virtio-mem device for the given NUMA node
+    UnknownVirtioMemNumaNode(u32),
+
+    /// A NUMA node must be selected among several virtio-mem devices
+    VirtioMemNumaNodeRequired,
+
+    /// Only the memory hotplugged through virtio-mem can target a NUMA node
+    NumaNodeResizeRequiresVirtioMem(u32),
+
     /// Cannot restore VM
     Restore(MigratableError),
 
@@ -156,6 +256,22 @@ pub enum Error {
     /// Failed creating a new MmapRegion instance.
     #[cfg(target_arch = "x86_64")]
     NewMmapRegion(vm_memory::mmap::MmapRegionError),
+
+    /// Lazy restore is not supported for this snapshot or memory configuration
+    LazyRestoreUnsupported,
+
+    /// Failed setting up the lazy loading of the guest memory
+    LazyRestore(io::Error),
+
+    /// Failed binding guest RAM to a host NUMA node
+    ApplyNumaPolicy(io::Error),
+
+    /// Hugepage size not supported by the host
+    HugePageSizeUnsupported(u64),
+
+    /// Not enough free hugepages on the host, for the given hugepage size,
+    /// number of required hugepages and number of free hugepages.
+    InsufficientHugePages(u64, u64, u64),
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -232,7 +348,9 @@ impl BusDevice for MemoryManager {
                 }
                 // Trigger removal of "DIMM"
                 if data[0] & (1 << EJECT_FLAG) == 1 << EJECT_FLAG {
-                    warn!("Ejection of memory not currently supported");
+                    if let Err(e) = self.remove_hotplug_slot(self.selected_slot) {
+                        error!("Error removing memory slot: {:?}", e);
+                    }
                 }
             }
             _ => {
@@ -249,6 +367,7 @@ impl MemoryManager {
     pub fn new(
         vm: Arc<dyn hypervisor::Vm>,
         config: &MemoryConfig,
+        numa_nodes: &Option<Vec<NumaConfig>>,
         ext_regions: Option<Vec<MemoryRegion>>,
         prefault: bool,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
@@ -261,37 +380,121 @@ impl MemoryManager {
             .map(|r| (r.0, r.1))
             .collect();
 
+        let zones = MemoryManager::memory_zones(config);
+        let ram_regions = MemoryManager::layout_ram_regions(&ram_regions, &zones, numa_nodes);
+        let virtio_mem_hotplug_zones =
+            MemoryManager::virtio_mem_hotplug_zones(config, &zones, &ram_regions, numa_nodes);
+
+        // On restore, only the shared or file backed zones are created from
+        // their parameters, the others being mapped from the snapshot files.
+        let mut hugepages_zones: Vec<MemoryZoneConfig> = zones
+            .iter()
+            .filter(|z| ext_regions.is_none() || z.shared)
+            .cloned()
+            .collect();
+        // The virtio-mem regions are created at boot, while the memory
+        // hotplugged through ACPI is only checked when it gets added.
+        hugepages_zones.extend(virtio_mem_hotplug_zones.iter().map(|(_, z)| z.clone()));
+        MemoryManager::check_hugepages(Path::new(HUGEPAGES_SYSFS_DIR), &hugepages_zones)?;
+
         let mut mem_regions = Vec::new();
+        let mut mergeable = Vec::new();
         if let Some(ext_regions) = &ext_regions {
             if ram_regions.len() > ext_regions.len() {
                 return Err(Error::InvalidAmountExternalBackingFiles);
             }
 
-            for region in ext_regions.iter() {
-                mem_regions.push(MemoryManager::create_ram_region(
-                    &Some(region.backing_file.clone()),
-                    region.start_addr,
-                    region.size as usize,
-                    true,
-                    prefault,
-                    false,
-                    false,
-                )?);
+            for (index, ext_region) in ext_regions.iter().enumerate() {
+                let zone = ram_regions.get(index).map(|r| &zones[r.zone]);
+                match zone {
+                    // Shared or file backed memory is created from the zone
+                    // parameters, and filled with the snapshot content.
+                    Some(zone) if zone.file.is_some() || zone.shared => {
+                        let region = MemoryManager::create_ram_region(
+                            &zone.file,
+                            ext_region.start_addr,
+                            ext_region.size as usize,
+                            false,
+                            prefault,
+                            zone.shared,
+                            zone.hugepages,
+                            zone.hugepage_size,
+                        )?;
+
+                        let mut memory_region_file =
+                            File::open(&ext_region.backing_file).map_err(|e| {
+                                Error::Restore(MigratableError::MigrateReceive(e.into()))
+                            })?;
+                        region
+                            .read_from(
+                                MemoryRegionAddress(0),
+                                &mut memory_region_file,
+                                region.len().try_into().unwrap(),
+                            )
+                            .map_err(|e| {
+                                Error::Restore(MigratableError::MigrateReceive(e.into()))
+                            })?;
+
+                        mem_regions.push(region);
+                    }
+                    // Any other memory can safely map the snapshot file
+                    // copy-on-write, which is faster and doesn't require
+                    // filling the memory content.
+                    _ => {
+                        mem_regions.push(MemoryManager::create_ram_region(
+                            &Some(ext_region.backing_file.clone()),
+                            ext_region.start_addr,
+                            ext_region.size as usize,
+                            true,
+                            prefault,
+                            false,
+                            false,
+                            None,
+                        )?);
+                    }
+                }
+                mergeable.push(zone.map_or(config.mergeable, |z| z.mergeable));
             }
         } else {
             for region in ram_regions.iter() {
+                let zone = &zones[region.zone];
                 mem_regions.push(MemoryManager::create_ram_region(
-                    &config.file,
-                    region.0,
-                    region.1,
+                    &zone.file,
+                    region.start_addr,
+                    region.size,
                     false,
                     prefault,
-                    config.shared,
-                    config.hugepages,
+                    zone.shared,
+                    zone.hugepages,
+                    zone.hugepage_size,
                 )?);
+                mergeable.push(zone.mergeable);
             }
         }
 
+        // Bind the RAM of each NUMA node to its host NUMA node, if any.
+        let mut numa_regions: BTreeMap<u32, Vec<Arc<GuestRegionMmap>>> = BTreeMap::new();
+        for (region, ram_region) in mem_regions.iter().zip(ram_regions.iter()) {
+            if let Some(numa_node) = &ram_region.numa_node {
+                if let Some(host_numa_node) = numa_nodes
+                    .iter()
+                    .flatten()
+                    .find(|n| n.guest_numa_id == *numa_node)
+                    .and_then(|n| n.host_numa_node)
+                {
+                    MemoryManager::mbind(region, host_numa_node)?;
+                }
+
+                numa_regions
+                    .entry(*numa_node)
+                    .or_default()
+                    .push(region.clone());
+            }
+        }
+
+        let ram_mappings: Vec<(Arc<GuestRegionMmap>, bool)> =
+            mem_regions.iter().cloned().zip(mergeable).collect();
+
         let guest_memory =
             GuestMemoryMmap::from_arc_regions(mem_regions).map_err(Error::GuestMemory)?;
 
@@ -299,34 +502,59 @@ impl MemoryManager {
 
         let mut start_of_device_area = MemoryManager::start_addr(guest_memory.last_addr(), false);
 
-        let mut virtiomem_region = None;
-        let mut virtiomem_resize = None;
+        let mut virtio_mem_zones = Vec::new();
         if let Some(size) = config.hotplug_size {
             if config.hotplug_method == HotplugMethod::VirtioMem {
-                // Alignment must be "natural" i.e. same as size of block
-                let start_addr = GuestAddress(
-                    (start_of_device_area.0 + virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE - 1)
-                        / virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE
-                        * virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
-                );
-                virtiomem_region = Some(MemoryManager::create_ram_region(
-                    &config.file,
-                    start_addr,
-                    size as usize,
-                    false,
-                    false,
-                    config.shared,
-                    config.hugepages,
-                )?);
+                // One virtio-mem device per NUMA node with hotpluggable memory.
+                for (numa_node, zone) in virtio_mem_hotplug_zones {
+                    let size = zone.size;
+                    // Alignment must be "natural" i.e. same as size of block
+                    let start_addr = GuestAddress(
+                        (start_of_device_area.0 + virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE
+                            - 1)
+                            / virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE
+                            * virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
+                    );
+                    let region = MemoryManager::create_ram_region(
+                        &zone.file,
+                        start_addr,
+                        size as usize,
+                        false,
+                        false,
+                        zone.shared,
+                        zone.hugepages,
+                        zone.hugepage_size,
+                    )?;
+
+                    if let Some(host_numa_node) = numa_nodes
+                        .iter()
+                        .flatten()
+                        .find(|n| Some(n.guest_numa_id) == numa_node)
+                        .and_then(|n| n.host_numa_node)
+                    {
+                        MemoryManager::mbind(&region, host_numa_node)?;
+                    }
 
-                virtiomem_resize = Some(virtio_devices::Resize::new().map_err(Error::EventFdFail)?);
+                    virtio_mem_zones.push(VirtioMemZone {
+                        region,
+                        resize: virtio_devices::Resize::new().map_err(Error::EventFdFail)?,
+                        numa_node,
+                        hotplugged_size: 0,
+                        mergeable: zone.mergeable,
+                    });
 
-                start_of_device_area = start_addr.unchecked_add(size);
+                    start_of_device_area = start_addr.unchecked_add(size);
+                }
             } else {
                 start_of_device_area = start_of_device_area.unchecked_add(size);
             }
         }
 
+        let virtio_mem_regions: Vec<(Arc<GuestRegionMmap>, bool)> = virtio_mem_zones
+            .iter()
+            .map(|z| (z.region.clone(), z.mergeable))
+            .collect();
+
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
 
         let mut hotplug_slots = Vec::with_capacity(HOTPLUG_COUNT);
@@ -366,36 +594,36 @@ impl MemoryManager {
             hotplug_method: config.hotplug_method.clone(),
             boot_ram: config.size,
             current_ram: config.size,
-            next_hotplug_slot: 0,
-            virtiomem_region: virtiomem_region.clone(),
-            virtiomem_resize,
+            virtio_mem_zones,
             snapshot: Mutex::new(None),
             shared: config.shared,
             hugepages: config.hugepages,
+            hugepage_size: config.hugepage_size,
             balloon: None,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_region: None,
+            guest_ram_mappings: Vec::new(),
+            dirty_log_active: false,
+            last_snapshot_url: None,
+            snapshot_parent: None,
+            lazy_loader: None,
+            numa_regions,
+            eject_evt: None,
+            ejected_regions: Vec::new(),
         }));
 
-        guest_memory.memory().with_regions(|_, region| {
-            let _ = memory_manager.lock().unwrap().create_userspace_mapping(
-                region.start_addr().raw_value(),
-                region.len() as u64,
-                region.as_ptr() as u64,
-                config.mergeable,
-                false,
-            )?;
-            Ok(())
-        })?;
+        for (region, mergeable) in ram_mappings.iter() {
+            memory_manager
+                .lock()
+                .unwrap()
+                .create_ram_mapping(region, *mergeable)?;
+        }
 
-        if let Some(region) = virtiomem_region {
-            memory_manager.lock().unwrap().create_userspace_mapping(
-                region.start_addr().raw_value(),
-                region.len() as u64,
-                region.as_ptr() as u64,
-                config.mergeable,
-                false,
-            )?;
+        for (region, mergeable) in virtio_mem_regions {
+            memory_manager
+                .lock()
+                .unwrap()
+                .create_ram_mapping(&region, mergeable)?;
             allocator
                 .lock()
                 .unwrap()
@@ -415,82 +643,180 @@ impl MemoryManager {
         Ok(memory_manager)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_snapshot(
         snapshot: &Snapshot,
         vm: Arc<dyn hypervisor::Vm>,
         config: &MemoryConfig,
+        numa_nodes: &Option<Vec<NumaConfig>>,
         source_url: &str,
         prefault: bool,
+        lazy: bool,
+        checks: SnapshotChecks,
+        exit_evt: &EventFd,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
-        let url = Url::parse(source_url).unwrap();
-        /* url must be valid dir which is verified in recv_vm_snapshot() */
-        let vm_snapshot_path = url.to_file_path().unwrap();
+        // An incremental snapshot only carries the memory changed since its
+        // parent snapshot, which means the guest memory must be rebuilt by
+        // layering the whole chain of snapshots, starting from the last full
+        // one.
+        let mut snapshots = vec![(
+            source_url.to_string(),
+            MemoryManager::snapshot_data(snapshot).map_err(Error::Restore)?,
+        )];
+        while let Some(parent_url) = snapshots.last().unwrap().1.parent_url.clone() {
+            let parent_snapshot = recv_vm_snapshot(&parent_url, checks).map_err(Error::Restore)?;
+            let mem_snapshot = parent_snapshot
+                .snapshots
+                .get(MEMORY_MANAGER_SNAPSHOT_ID)
+                .ok_or_else(|| {
+                    Error::Restore(MigratableError::Restore(anyhow!(
+                        "Missing memory manager snapshot from {}",
+                        parent_url
+                    )))
+                })?;
+            let mem_snapshot_data =
+                MemoryManager::snapshot_data(mem_snapshot).map_err(Error::Restore)?;
+
+            snapshots.push((parent_url, mem_snapshot_data));
+        }
 
-        if let Some(mem_section) = snapshot
-            .snapshot_data
-            .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
-        {
-            let mem_snapshot: MemoryManagerSnapshotData =
-                match serde_json::from_slice(&mem_section.snapshot) {
-                    Ok(snapshot) => snapshot,
-                    Err(error) => {
-                        return Err(Error::Restore(MigratableError::Restore(anyhow!(
-                            "Could not deserialize MemoryManager {}",
-                            error
-                        ))))
-                    }
-                };
+        // Safe to unwrap as the chain contains at least the snapshot being
+        // restored.
+        let (base_url, base_snapshot) = snapshots.pop().unwrap();
+        let base_path = MemoryManager::snapshot_path(&base_url).map_err(Error::Restore)?;
 
-            let mut ext_regions = mem_snapshot.memory_regions;
-            for region in ext_regions.iter_mut() {
-                let mut memory_region_path = vm_snapshot_path.clone();
-                memory_region_path.push(region.backing_file.clone());
-                region.backing_file = memory_region_path;
+        let mut ext_regions = base_snapshot.memory_regions;
+        for region in ext_regions.iter_mut() {
+            let mut memory_region_path = base_path.clone();
+            memory_region_path.push(region.backing_file.clone());
+            region.backing_file = memory_region_path;
+        }
+
+        // With lazy restore, the guest RAM is mapped empty and the pages are
+        // loaded from the snapshot files on demand, once the VM is resumed.
+        if lazy {
+            // Only anonymous memory can be registered with userfaultfd, each
+            // page must come from a single snapshot file, and the pages are
+            // loaded with a single page size.
+            let zones = MemoryManager::memory_zones(config);
+            let page_size = MemoryManager::zone_page_size(&zones[0]);
+            if zones
+                .iter()
+                .any(|z| z.file.is_some() || MemoryManager::zone_page_size(z) != page_size)
+                || !snapshots.is_empty()
+            {
+                return Err(Error::LazyRestoreUnsupported);
             }
 
-            // In case there was no backing file, we can safely use CoW by
-            // mapping the source files provided for restoring. This case
-            // allows for a faster VM restoration and does not require us to
-            // fill the memory content, hence we can return right away.
-            if config.file.is_none() {
-                return MemoryManager::new(vm, config, Some(ext_regions), prefault);
-            };
+            let memory_manager = MemoryManager::new(vm, config, numa_nodes, None, false)?;
+
+            let mut regions = Vec::new();
+            memory_manager
+                .lock()
+                .unwrap()
+                .guest_memory()
+                .memory()
+                .with_regions_mut(|index, region| {
+                    let file =
+                        File::open(&ext_regions[index].backing_file).map_err(Error::LazyRestore)?;
+                    regions.push(LazyRegion {
+                        host_addr: region.as_ptr() as u64,
+                        size: region.len(),
+                        file,
+                    });
+                    Ok(())
+                })?;
+
+            let exit_evt = exit_evt.try_clone().map_err(Error::EventFdFail)?;
+            let lazy_loader =
+                LazyLoader::new(regions, page_size, exit_evt).map_err(Error::LazyRestore)?;
+            memory_manager.lock().unwrap().lazy_loader = Some(lazy_loader);
+
+            return Ok(memory_manager);
+        }
 
-            let memory_manager = MemoryManager::new(vm, config, None, false)?;
-            let guest_memory = memory_manager.lock().unwrap().guest_memory();
+        // Each memory region is restored from its snapshot file, either
+        // mapping it copy-on-write or copying its content, depending on the
+        // memory zone it belongs to.
+        let memory_manager =
+            MemoryManager::new(vm, config, numa_nodes, Some(ext_regions), prefault)?;
 
-            // In case the previous config was using a backing file, this means
-            // it was MAP_SHARED, therefore we must copy the content into the
-            // new regions so that we can still use MAP_SHARED when restoring
-            // the VM.
-            guest_memory.memory().with_regions(|index, region| {
-                // Open (read only) the snapshot file for the given region.
+        // Apply the memory changes from each incremental snapshot, from the
+        // oldest one to the one being restored.
+        let guest_memory = memory_manager.lock().unwrap().guest_memory().memory();
+        for (url, mem_snapshot) in snapshots.iter().rev() {
+            let path = MemoryManager::snapshot_path(url).map_err(Error::Restore)?;
+
+            for region in mem_snapshot.memory_regions.iter() {
+                let mut memory_region_path = path.clone();
+                memory_region_path.push(region.backing_file.clone());
                 let mut memory_region_file = OpenOptions::new()
                     .read(true)
-                    .open(&ext_regions[index].backing_file)
+                    .open(memory_region_path)
                     .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
 
-                // Fill the region with the file content.
-                region
-                    .read_from(
-                        MemoryRegionAddress(0),
-                        &mut memory_region_file,
-                        region.len().try_into().unwrap(),
-                    )
-                    .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
+                let ranges = if let Some(ranges) = &region.ranges {
+                    ranges.regions().to_vec()
+                } else {
+                    vec![MemoryRange {
+                        gpa: region.start_addr.raw_value(),
+                        length: region.size,
+                    }]
+                };
+
+                for range in ranges.iter() {
+                    guest_memory
+                        .read_exact_from(
+                            GuestAddress(range.gpa),
+                            &mut memory_region_file,
+                            range.length as usize,
+                        )
+                        .map_err(|e| Error::Restore(MigratableError::MigrateReceive(e.into())))?;
+                }
+            }
+        }
+
+        Ok(memory_manager)
+    }
 
-                Ok(())
-            })?;
+    fn snapshot_data(
+        snapshot: &Snapshot,
+    ) -> result::Result<MemoryManagerSnapshotData, MigratableError> {
+        if let Some(mem_section) = snapshot
+            .snapshot_data
+            .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
+        {
+            mem_section.check_version(MEMORY_MANAGER_SNAPSHOT_VERSION)?;
 
-            Ok(memory_manager)
+            serde_json::from_slice(&mem_section.snapshot).map_err(|e| {
+                MigratableError::Restore(anyhow!("Could not deserialize MemoryManager {}", e))
+            })
         } else {
-            Err(Error::Restore(MigratableError::Restore(anyhow!(
+            Err(MigratableError::Restore(anyhow!(
                 "Could not find {}-section from snapshot",
                 MEMORY_MANAGER_SNAPSHOT_ID
-            ))))
+            )))
         }
     }
 
+    /// Return the files the memory manager writes to the snapshot directory,
+    /// relative to it.
+    pub fn snapshot_files(snapshot: &Snapshot) -> result::Result<Vec<PathBuf>, MigratableError> {
+        Ok(MemoryManager::snapshot_data(snapshot)?
+            .memory_regions
+            .into_iter()
+            .map(|region| region.backing_file)
+            .collect())
+    }
+
+    fn snapshot_path(url: &str) -> result::Result<PathBuf, MigratableError> {
+        let url = Url::parse(url).map_err(|e| {
+            MigratableError::Restore(anyhow!("Could not parse snapshot URL: {}", e))
+        })?;
+
+        url_to_path(&url)
+    }
+
     fn memfd_create(name: &ffi::CStr, flags: u32) -> Result<RawFd, io::Error> {
         let res = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
 
@@ -501,6 +827,216 @@ impl MemoryManager {
         }
     }
 
+    // Memory zones the guest RAM is made of, which is a single zone built
+    // from the memory parameters when no zone is defined.
+    fn memory_zones(config: &MemoryConfig) -> Vec<MemoryZoneConfig> {
+        match &config.zones {
+            Some(zones) if !zones.is_empty() => zones.clone(),
+            _ => vec![MemoryZoneConfig {
+                id: String::from("mem0"),
+                size: config.size,
+                file: config.file.clone(),
+                shared: config.shared,
+                hugepages: config.hugepages,
+                hugepage_size: config.hugepage_size,
+                mergeable: config.mergeable,
+            }],
+        }
+    }
+
+    // Memory zone standing for the memory hotplugged after boot, which
+    // follows the --memory parameters.
+    fn hotplug_zone(config: &MemoryConfig, size: u64) -> MemoryZoneConfig {
+        MemoryZoneConfig {
+            id: String::from("hotplug"),
+            size,
+            file: config.file.clone(),
+            shared: config.shared,
+            hugepages: config.hugepages,
+            hugepage_size: config.hugepage_size,
+            mergeable: config.mergeable,
+        }
+    }
+
+    // Memory zones standing for the memory hotplugged through virtio-mem, one
+    // per NUMA node with hotpluggable memory, the first node getting all of
+    // it if none is given. The memory of a node is backed like the zone
+    // holding the end of its boot RAM, while without NUMA it follows the
+    // --memory parameters.
+    fn virtio_mem_hotplug_zones(
+        config: &MemoryConfig,
+        zones: &[MemoryZoneConfig],
+        ram_regions: &[RamRegion],
+        numa_nodes: &Option<Vec<NumaConfig>>,
+    ) -> Vec<(Option<u32>, MemoryZoneConfig)> {
+        let size = match config.hotplug_size {
+            Some(size) if config.hotplug_method == HotplugMethod::VirtioMem => size,
+            _ => return Vec::new(),
+        };
+
+        let hotplug_sizes: Vec<(Option<u32>, u64)> = match numa_nodes {
+            Some(nodes) if nodes.iter().any(|n| n.hotplug_size.is_some()) => nodes
+                .iter()
+                .filter_map(|n| n.hotplug_size.map(|s| (Some(n.guest_numa_id), s)))
+                .filter(|(_, s)| *s > 0)
+                .collect(),
+            Some(_) => vec![(Some(0), size)],
+            None => vec![(None, size)],
+        };
+
+        hotplug_sizes
+            .into_iter()
+            .map(|(numa_node, size)| {
+                let node_zone = ram_regions
+                    .iter()
+                    .rev()
+                    .find(|r| numa_node.is_some() && r.numa_node == numa_node)
+                    .map(|r| &zones[r.zone]);
+                let zone = match node_zone {
+                    Some(zone) => MemoryZoneConfig {
+                        id: String::from("hotplug"),
+                        size,
+                        ..zone.clone()
+                    },
+                    None => MemoryManager::hotplug_zone(config, size),
+                };
+                (numa_node, zone)
+            })
+            .collect()
+    }
+
+    // Split the guest RAM regions so that each region belongs to a single
+    // memory zone and a single NUMA node. The zones are laid out one after
+    // the other, and so are the NUMA nodes, in order of their ids.
+    fn layout_ram_regions(
+        ram_regions: &[(GuestAddress, usize)],
+        zones: &[MemoryZoneConfig],
+        numa_nodes: &Option<Vec<NumaConfig>>,
+    ) -> Vec<RamRegion> {
+        let mut numa_nodes = numa_nodes.clone().unwrap_or_default();
+        numa_nodes.sort_by_key(|n| n.guest_numa_id);
+
+        // Offsets within the guest RAM at which each zone and node end.
+        let zone_ends: Vec<u64> = zones
+            .iter()
+            .scan(0, |end, z| {
+                *end += z.size;
+                Some(*end)
+            })
+            .collect();
+        let node_ends: Vec<(u64, u32)> = numa_nodes
+            .iter()
+            .scan(0, |end, n| {
+                *end += n.size;
+                Some((*end, n.guest_numa_id))
+            })
+            .collect();
+
+        let mut regions = Vec::new();
+        let mut ram_offset = 0;
+        for (start_addr, size) in ram_regions.iter() {
+            let ram_end = ram_offset + *size as u64;
+            let mut offset = ram_offset;
+            while offset < ram_end {
+                let zone = zone_ends.iter().position(|end| *end > offset);
+                let numa_node = node_ends.iter().find(|(end, _)| *end > offset);
+
+                let mut end = ram_end;
+                if let Some(zone) = zone {
+                    end = cmp::min(end, zone_ends[zone]);
+                }
+                if let Some((node_end, _)) = numa_node {
+                    end = cmp::min(end, *node_end);
+                }
+
+                regions.push(RamRegion {
+                    start_addr: start_addr.unchecked_add(offset - ram_offset),
+                    size: (end - offset) as usize,
+                    // Any RAM beyond the last zone belongs to it.
+                    zone: zone.unwrap_or(zones.len() - 1),
+                    numa_node: numa_node.map(|(_, id)| *id),
+                });
+                offset = end;
+            }
+            ram_offset = ram_end;
+        }
+
+        regions
+    }
+
+    fn zone_page_size(zone: &MemoryZoneConfig) -> u64 {
+        if zone.hugepages {
+            zone.hugepage_size.unwrap_or(DEFAULT_HUGEPAGE_SIZE)
+        } else {
+            // Trivially safe
+            unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+        }
+    }
+
+    // Check the host provides enough free hugepages of the right size for the
+    // memory zones backed by hugepages, as running out of hugepages would
+    // otherwise only show up as a SIGBUS once the guest touches its memory.
+    fn check_hugepages(pools_dir: &Path, zones: &[MemoryZoneConfig]) -> Result<(), Error> {
+        // Zones backed by a file get their hugepages from the filesystem.
+        let mut required_sizes: BTreeMap<u64, u64> = BTreeMap::new();
+        for zone in zones.iter().filter(|z| z.hugepages && z.file.is_none()) {
+            *required_sizes
+                .entry(MemoryManager::zone_page_size(zone))
+                .or_default() += zone.size;
+        }
+
+        for (page_size, size) in required_sizes.iter() {
+            let pool = pools_dir.join(format!("hugepages-{}kB", page_size >> 10));
+            let read_pool_value = |name: &str| -> Result<u64, Error> {
+                std::fs::read_to_string(pool.join(name))
+                    .ok()
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+                    .ok_or(Error::HugePageSizeUnsupported(*page_size))
+            };
+
+            // Reserved hugepages are promised to existing mappings.
+            let free_pages = read_pool_value("free_hugepages")?
+                .saturating_sub(read_pool_value("resv_hugepages")?);
+            let required_pages = (size + page_size - 1) / page_size;
+            if required_pages > free_pages {
+                return Err(Error::InsufficientHugePages(
+                    *page_size,
+                    required_pages,
+                    free_pages,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mbind(region: &GuestRegionMmap, host_numa_node: u32) -> Result<(), Error> {
+        let host_numa_node = host_numa_node as usize;
+        let mut node_mask = vec![0u64; host_numa_node / 64 + 1];
+        node_mask[host_numa_node / 64] |= 1 << (host_numa_node % 64);
+        // The kernel expects the number of bits of the mask plus one.
+        let max_node = node_mask.len() * 64 + 1;
+
+        // Pages already faulted in, as with prefault, are moved to the node.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                region.as_ptr() as u64,
+                region.len(),
+                MPOL_BIND,
+                node_mask.as_ptr(),
+                max_node,
+                MPOL_MF_STRICT | MPOL_MF_MOVE,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::ApplyNumaPolicy(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn create_ram_region(
         backing_file: &Option<PathBuf>,
         start_addr: GuestAddress,
@@ -509,6 +1045,7 @@ impl MemoryManager {
         prefault: bool,
         shared: bool,
         hugepages: bool,
+        hugepage_size: Option<u64>,
     ) -> Result<Arc<GuestRegionMmap>, Error> {
         Ok(Arc::new(match backing_file {
             Some(ref file) => {
@@ -554,7 +1091,11 @@ impl MemoryManager {
                 let fd = Self::memfd_create(
                     &ffi::CString::new("ch_ram").unwrap(),
                     if hugepages {
-                        libc::MFD_HUGETLB | libc::MAP_HUGE_2MB as u32
+                        libc::MFD_HUGETLB
+                            | match hugepage_size {
+                                Some(size) => size.trailing_zeros() << libc::MAP_HUGE_SHIFT,
+                                None => libc::MAP_HUGE_2MB as u32,
+                            }
                     } else {
                         0
                     },
@@ -625,9 +1166,11 @@ impl MemoryManager {
         info!("Hotplugging new RAM: {}", size);
 
         // Check that there is a free slot
-        if self.next_hotplug_slot >= HOTPLUG_COUNT {
-            return Err(Error::NoSlotAvailable);
-        }
+        let slot_id = self
+            .hotplug_slots
+            .iter()
+            .position(|slot| !slot.active)
+            .ok_or(Error::NoSlotAvailable)?;
 
         // "Inserted" DIMM must have a size that is a multiple of 128MiB
         if size % (128 << 20) != 0 {
@@ -641,6 +1184,19 @@ impl MemoryManager {
             return Err(Error::InsufficientHotplugRAM);
         }
 
+        MemoryManager::check_hugepages(
+            Path::new(HUGEPAGES_SYSFS_DIR),
+            &[MemoryZoneConfig {
+                id: String::from("hotplug"),
+                size: size as u64,
+                file: self.backing_file.clone(),
+                shared: self.shared,
+                hugepages: self.hugepages,
+                hugepage_size: self.hugepage_size,
+                mergeable: self.mergeable,
+            }],
+        )?;
+
         // Allocate memory for the region
         let region = MemoryManager::create_ram_region(
             &self.backing_file,
@@ -650,16 +1206,11 @@ impl MemoryManager {
             false,
             self.shared,
             self.hugepages,
+            self.hugepage_size,
         )?;
 
         // Map it into the guest
-        self.create_userspace_mapping(
-            region.start_addr().0,
-            region.len() as u64,
-            region.as_ptr() as u64,
-            self.mergeable,
-            false,
-        )?;
+        self.create_ram_mapping(&region, self.mergeable)?;
 
         // Tell the allocator
         self.allocator
@@ -669,19 +1220,121 @@ impl MemoryManager {
             .ok_or(Error::MemoryRangeAllocation)?;
 
         // Update the slot so that it can be queried via the I/O port
-        let mut slot = &mut self.hotplug_slots[self.next_hotplug_slot];
+        let mut slot = &mut self.hotplug_slots[slot_id];
         slot.active = true;
         slot.inserting = true;
         slot.base = region.start_addr().0;
         slot.length = region.len() as u64;
 
-        self.next_hotplug_slot += 1;
-
         self.add_region(Arc::clone(&region))?;
 
         Ok(region)
     }
 
+    // Guest RAM of the hotplugged slots marked for removal, not ejected by
+    // the guest yet.
+    fn ejecting_ram(&self) -> u64 {
+        self.hotplug_slots
+            .iter()
+            .filter(|slot| slot.active && slot.ejecting)
+            .map(|slot| slot.length)
+            .sum()
+    }
+
+    // Mark the hotplugged slots to remove, starting from the last one, so
+    // that the guest RAM shrinks to the desired size. The actual removal
+    // happens on ejection, once the guest has offlined the memory.
+    fn mark_hotplug_slots_for_removal(&mut self, desired_ram: u64) -> Result<(), Error> {
+        let mut ram = self.current_ram - self.ejecting_ram();
+        let mut slot_ids = Vec::new();
+        for (slot_id, slot) in self.hotplug_slots.iter().enumerate().rev() {
+            if ram == desired_ram {
+                break;
+            }
+            if !slot.active || slot.ejecting {
+                continue;
+            }
+            if ram - slot.length < desired_ram {
+                break;
+            }
+            ram -= slot.length;
+            slot_ids.push(slot_id);
+        }
+
+        // Only whole slots can be removed.
+        if ram != desired_ram {
+            return Err(Error::InvalidHotUnplugSize);
+        }
+
+        for slot_id in slot_ids {
+            let mut slot = &mut self.hotplug_slots[slot_id];
+            slot.removing = true;
+            slot.ejecting = true;
+        }
+
+        Ok(())
+    }
+
+    fn remove_hotplug_slot(&mut self, slot_id: usize) -> Result<(), Error> {
+        let slot = &self.hotplug_slots[slot_id];
+        if !slot.active {
+            return Ok(());
+        }
+        let (base, length) = (slot.base, slot.length);
+        info!("Removing hotplugged RAM: 0x{:x} {}", base, length);
+
+        // Unmap it from the guest
+        if let Some(index) = self
+            .guest_ram_mappings
+            .iter()
+            .position(|mapping| mapping.gpa == base)
+        {
+            let mapping = self.guest_ram_mappings.remove(index);
+            self.remove_userspace_mapping(
+                mapping.gpa,
+                mapping.size,
+                mapping.host_addr,
+                self.mergeable,
+                mapping.slot,
+            )?;
+        }
+
+        // The region is unmapped from the VMM once the devices have been
+        // updated, dropping the last reference to it.
+        let (guest_memory, region) = self
+            .guest_memory
+            .memory()
+            .remove_region(GuestAddress(base), length)
+            .map_err(Error::GuestMemory)?;
+        self.guest_memory.lock().unwrap().replace(guest_memory);
+
+        // Tell the allocator
+        self.allocator
+            .lock()
+            .unwrap()
+            .free_mmio_addresses(GuestAddress(base), length);
+
+        self.current_ram -= length;
+        self.hotplug_slots[slot_id] = HotPlugState::default();
+
+        self.ejected_regions.push(region);
+        if let Some(eject_evt) = &self.eject_evt {
+            eject_evt.write(1).map_err(Error::EventFdFail)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_eject_evt(&mut self, eject_evt: EventFd) {
+        self.eject_evt = Some(eject_evt);
+    }
+
+    /// Regions ejected by the guest since the last call, which the devices
+    /// must stop accessing.
+    pub fn take_ejected_regions(&mut self) -> Vec<Arc<GuestRegionMmap>> {
+        self.ejected_regions.drain(..).collect()
+    }
+
     pub fn set_balloon(&mut self, balloon: Arc<Mutex<virtio_devices::Balloon>>) {
         self.balloon = Some(balloon);
     }
@@ -690,10 +1343,30 @@ impl MemoryManager {
         self.guest_memory.clone()
     }
 
+    pub fn numa_regions(&self) -> &BTreeMap<u32, Vec<Arc<GuestRegionMmap>>> {
+        &self.numa_regions
+    }
+
+    // Memory hotplugged through ACPI extends the boot RAM, hence it belongs to
+    // the NUMA node owning the highest boot RAM region.
+    #[cfg(feature = "acpi")]
+    fn acpi_hotplug_numa_node(&self) -> u32 {
+        self.numa_regions
+            .iter()
+            .flat_map(|(node, regions)| regions.iter().map(move |r| (r.last_addr(), *node)))
+            .max()
+            .map(|(_, node)| node)
+            .unwrap_or(0)
+    }
+
     pub fn allocator(&self) -> Arc<Mutex<SystemAllocator>> {
         self.allocator.clone()
     }
 
+    pub fn current_ram(&self) -> u64 {
+        self.current_ram
+    }
+
     pub fn start_of_device_area(&self) -> GuestAddress {
         self.start_of_device_area
     }
@@ -762,6 +1435,31 @@ impl MemoryManager {
         Ok(slot)
     }
 
+    // Map a guest RAM region, keeping track of its memory slot so that the
+    // pages written to can be retrieved.
+    fn create_ram_mapping(
+        &mut self,
+        region: &GuestRegionMmap,
+        mergeable: bool,
+    ) -> Result<(), Error> {
+        let slot = self.create_userspace_mapping(
+            region.start_addr().raw_value(),
+            region.len() as u64,
+            region.as_ptr() as u64,
+            mergeable,
+            false,
+        )?;
+
+        self.guest_ram_mappings.push(GuestRamMapping {
+            slot,
+            gpa: region.start_addr().raw_value(),
+            size: region.len() as u64,
+            host_addr: region.as_ptr() as u64,
+        });
+
+        Ok(())
+    }
+
     pub fn remove_userspace_mapping(
         &mut self,
         guest_phys_addr: u64,
@@ -816,61 +1514,240 @@ impl MemoryManager {
         Ok(())
     }
 
-    pub fn virtiomem_resize(&mut self, size: u64) -> Result<(), Error> {
-        let region = self.virtiomem_region.take();
-        if let Some(region) = region {
+    pub fn virtio_mem_zones(&self) -> &Vec<VirtioMemZone> {
+        &self.virtio_mem_zones
+    }
+
+    // Resize the virtio-mem device of the given NUMA node, or the only
+    // virtio-mem device if no node is given, so that the guest RAM reaches
+    // the desired size.
+    fn virtiomem_resize(&mut self, desired_ram: u64, numa_node: Option<u32>) -> Result<(), Error> {
+        let index = match numa_node {
+            Some(numa_node) => self
+                .virtio_mem_zones
+                .iter()
+                .position(|z| z.numa_node == Some(numa_node))
+                .ok_or(Error::UnknownVirtioMemNumaNode(numa_node))?,
+            None if self.virtio_mem_zones.len() == 1 => 0,
+            None => return Err(Error::VirtioMemNumaNodeRequired),
+        };
+
+        let size = (self.virtio_mem_zones[index].hotplugged_size + desired_ram)
+            .checked_sub(self.current_ram)
+            .ok_or(Error::InvalidSize)?;
+
+        // The region is only added to the guest memory once the device is
+        // first resized, so that it is not reported as boot RAM.
+        let region = self.virtio_mem_zones[index].region.clone();
+        if self
+            .guest_memory
+            .memory()
+            .find_region(region.start_addr())
+            .is_none()
+        {
             self.add_region(region)?;
         }
 
-        if let Some(resize) = &self.virtiomem_resize {
-            resize.work(size).map_err(Error::VirtioMemResizeFail)?;
-        } else {
-            panic!("should not fail here");
-        }
+        let zone = &mut self.virtio_mem_zones[index];
+        zone.resize.work(size).map_err(Error::VirtioMemResizeFail)?;
+        zone.hotplugged_size = size;
 
         Ok(())
     }
 
+    /// Returns the size of the balloon as seen by the device, which is page
+    /// aligned.
     pub fn balloon_resize(&mut self, expected_ram: u64) -> Result<u64, Error> {
-        let mut balloon_size = 0;
         if let Some(balloon) = &self.balloon {
-            if expected_ram < self.current_ram {
-                balloon_size = self.current_ram - expected_ram;
-            }
+            let balloon_size = self.current_ram.saturating_sub(expected_ram);
+            let balloon = balloon.lock().unwrap();
             balloon
-                .lock()
-                .unwrap()
                 .resize(balloon_size)
                 .map_err(Error::VirtioBalloonResizeFail)?;
+            return Ok(balloon.get_size());
+        }
+
+        Ok(0)
+    }
+
+    /// Requested size of the balloon, which follows the guest deflating it
+    /// on its own when running out of memory with deflate_on_oom.
+    pub fn balloon_size(&self) -> u64 {
+        if let Some(balloon) = &self.balloon {
+            return balloon.lock().unwrap().get_size();
         }
 
-        Ok(balloon_size)
+        0
     }
 
     /// In case this function resulted in adding a new memory region to the
     /// guest memory, the new region is returned to the caller. The virtio-mem
     /// use case never adds a new region as the whole hotpluggable memory has
-    /// already been allocated at boot time.
-    pub fn resize(&mut self, desired_ram: u64) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
+    /// already been allocated at boot time. Shrinking the memory with ACPI
+    /// only marks the hotplugged slots for removal, which happens once the
+    /// guest has ejected them. With virtio-mem, the NUMA node selects the
+    /// device the memory is added to or removed from.
+    pub fn resize(
+        &mut self,
+        desired_ram: u64,
+        numa_node: Option<u32>,
+    ) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
+        if let (HotplugMethod::Acpi, Some(numa_node)) = (&self.hotplug_method, numa_node) {
+            return Err(Error::NumaNodeResizeRequiresVirtioMem(numa_node));
+        }
+
         let mut region: Option<Arc<GuestRegionMmap>> = None;
         match self.hotplug_method {
             HotplugMethod::VirtioMem => {
-                if desired_ram >= self.boot_ram {
-                    self.virtiomem_resize(desired_ram - self.boot_ram)?;
-                    self.current_ram = desired_ram;
+                if desired_ram < self.boot_ram {
+                    return Err(Error::ResizeBelowBootRam);
                 }
+                self.virtiomem_resize(desired_ram, numa_node)?;
+                self.current_ram = desired_ram;
             }
             HotplugMethod::Acpi => {
-                if desired_ram >= self.current_ram {
-                    region =
-                        Some(self.hotplug_ram_region((desired_ram - self.current_ram) as usize)?);
-                    self.current_ram = desired_ram;
+                // The slots marked for removal only leave the current RAM
+                // once ejected by the guest.
+                let ram = self.current_ram - self.ejecting_ram();
+                match desired_ram.cmp(&ram) {
+                    cmp::Ordering::Greater => {
+                        region = Some(self.hotplug_ram_region((desired_ram - ram) as usize)?);
+                        self.current_ram += desired_ram - ram;
+                    }
+                    cmp::Ordering::Less => self.mark_hotplug_slots_for_removal(desired_ram)?,
+                    cmp::Ordering::Equal => {}
                 }
             }
         }
         Ok(region)
     }
 
+    /// Returns a table covering the whole guest RAM.
+    pub fn memory_range_table(&self) -> result::Result<MemoryRangeTable, MigratableError> {
+        let mut table = MemoryRangeTable::default();
+        self.guest_memory.memory().with_regions_mut(|_, region| {
+            table.push(MemoryRange {
+                gpa: region.start_addr().raw_value(),
+                length: region.len() as u64,
+            });
+            Ok(())
+        })?;
+
+        Ok(table)
+    }
+
+    /// Write the content of each guest memory range from the table.
+    pub fn send_memory_regions(
+        &self,
+        ranges: &MemoryRangeTable,
+        fd: &mut dyn Write,
+    ) -> result::Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+
+        for range in ranges.regions() {
+            guest_memory
+                .write_all_to(GuestAddress(range.gpa), fd, range.length as usize)
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Error transferring memory range {:x}-{:x} to socket: {}",
+                        range.gpa,
+                        range.gpa + range.length,
+                        e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill each guest memory range from the table with the content read
+    /// from `fd`.
+    pub fn receive_memory_regions(
+        &self,
+        ranges: &MemoryRangeTable,
+        fd: &mut dyn Read,
+    ) -> result::Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+
+        for range in ranges.regions() {
+            guest_memory
+                .read_exact_from(GuestAddress(range.gpa), fd, range.length as usize)
+                .map_err(|e| {
+                    MigratableError::MigrateReceive(anyhow!(
+                        "Error receiving memory range {:x}-{:x} from socket: {}",
+                        range.gpa,
+                        range.gpa + range.length,
+                        e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Select the parent of the next snapshot. When set, the snapshot is
+    /// incremental and only carries the memory changed since the parent
+    /// snapshot, which must be the last one taken from this VM.
+    pub fn set_snapshot_parent(
+        &mut self,
+        parent_url: Option<&str>,
+    ) -> result::Result<(), MigratableError> {
+        // The memory changes are consumed below, meaning the next snapshot
+        // can only be incremental if this one succeeds.
+        let last_snapshot_url = self.last_snapshot_url.take();
+        self.snapshot_parent = None;
+
+        if let Some(parent_url) = parent_url {
+            if last_snapshot_url.as_deref() != Some(parent_url) {
+                return Err(MigratableError::Snapshot(anyhow!(
+                    "{} is not the last snapshot taken from this VM with the memory changes tracked",
+                    parent_url
+                )));
+            }
+
+            let table = self.dirty_log()?;
+            self.snapshot_parent = Some((parent_url.to_string(), table));
+        }
+
+        Ok(())
+    }
+
+    /// Track the memory changes from the snapshot which has just been sent
+    /// to `snapshot_url`, so that the next snapshot can be incremental.
+    pub fn track_snapshot_changes(&mut self, snapshot_url: &str) {
+        self.snapshot_parent = None;
+
+        let tracking = if self.dirty_log_active {
+            // Start over from the snapshot which has just been taken.
+            self.dirty_log().map(|_| ())
+        } else {
+            self.start_dirty_log()
+        };
+        if let Err(e) = tracking {
+            warn!(
+                "Memory changes cannot be tracked for incremental snapshots: {}",
+                e
+            );
+            return;
+        }
+
+        self.last_snapshot_url = Some(snapshot_url.to_string());
+    }
+
+    /// Stop tracking the memory changes from the previous snapshot, meaning
+    /// the next snapshot can't be incremental.
+    pub fn untrack_snapshot_changes(&mut self) {
+        self.snapshot_parent = None;
+
+        if !self.dirty_log_active {
+            return;
+        }
+
+        if let Err(e) = self.stop_dirty_log() {
+            warn!("Could not stop tracking the memory changes: {}", e);
+        }
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn setup_sgx(&mut self, sgx_epc_config: Vec<SgxEpcConfig>) -> Result<(), Error> {
         // Go over each EPC section and verify its size is a 4k multiple. At
@@ -979,6 +1856,7 @@ impl Aml for MemoryNotify {
 #[cfg(feature = "acpi")]
 struct MemorySlot {
     slot_id: usize,
+    proximity_domain: u32,
 }
 
 #[cfg(feature = "acpi")]
@@ -1019,13 +1897,23 @@ impl Aml for MemorySlot {
                         vec![&self.slot_id],
                     ))],
                 ),
-                // We don't expose any NUMA characteristics so all memory is in the same "proximity domain"
+                // Trigger memory ejection
+                &aml::Method::new(
+                    "_EJ0".into(),
+                    1,
+                    false,
+                    // Call into MEJT method which will actually eject device
+                    vec![&aml::Return::new(&aml::MethodCall::new(
+                        "MEJT".into(),
+                        vec![&self.slot_id],
+                    ))],
+                ),
+                // Hotplugged memory belongs to the NUMA node at the top of the boot RAM
                 &aml::Method::new(
                     "_PXM".into(),
                     0,
                     false,
-                    // We aren't NUMA so associate all RAM into the same proximity region (zero)
-                    vec![&aml::Return::new(&0u32)],
+                    vec![&aml::Return::new(&self.proximity_domain)],
                 ),
             ],
         )
@@ -1036,6 +1924,7 @@ impl Aml for MemorySlot {
 #[cfg(feature = "acpi")]
 struct MemorySlots {
     slots: usize,
+    proximity_domain: u32,
 }
 
 #[cfg(feature = "acpi")]
@@ -1044,7 +1933,13 @@ impl Aml for MemorySlots {
         let mut bytes = Vec::new();
 
         for slot_id in 0..self.slots {
-            bytes.extend_from_slice(&MemorySlot { slot_id }.to_aml_bytes());
+            bytes.extend_from_slice(
+                &MemorySlot {
+                    slot_id,
+                    proximity_domain: self.proximity_domain,
+                }
+                .to_aml_bytes(),
+            );
         }
 
         bytes
@@ -1075,6 +1970,26 @@ impl Aml for MemoryMethods {
             &aml::Method::new("MTFY".into(), 2, true, memory_notifies_refs).to_aml_bytes(),
         );
 
+        // MEJT method
+        bytes.extend_from_slice(
+            &aml::Method::new(
+                "MEJT".into(),
+                1,
+                true,
+                vec![
+                    // Take lock defined above
+                    &aml::Acquire::new("MLCK".into(), 0xfff),
+                    // Write slot number (in first argument) to I/O port via field
+                    &aml::Store::new(&aml::Path::new("\\_SB_.MHPC.MSEL"), &aml::Arg(0)),
+                    // Set MEJ0 bit
+                    &aml::Store::new(&aml::Path::new("\\_SB_.MHPC.MEJ0"), &aml::ONE),
+                    // Release lock
+                    &aml::Release::new("MLCK".into()),
+                ],
+            )
+            .to_aml_bytes(),
+        );
+
         // MSCN method
         bytes.extend_from_slice(
             &aml::Method::new(
@@ -1283,6 +2198,7 @@ impl Aml for MemoryManager {
                     },
                     &MemorySlots {
                         slots: self.hotplug_slots.len(),
+                        proximity_domain: self.acpi_hotplug_numa_node(),
                     },
                 ],
             )
@@ -1339,19 +2255,38 @@ pub struct MemoryRegion {
     #[serde(with = "GuestAddressDef")]
     start_addr: GuestAddress,
     size: GuestUsize,
+    /// Set for incremental snapshots only, in which case the backing file
+    /// contains the content of these ranges, one after the other.
+    #[serde(default)]
+    ranges: Option<MemoryRangeTable>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct MemoryManagerSnapshotData {
     memory_regions: Vec<MemoryRegion>,
+    /// The URL of the snapshot an incremental snapshot is based on.
+    #[serde(default)]
+    parent_url: Option<String>,
 }
 
+const MEMORY_MANAGER_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for MemoryManager {
     fn id(&self) -> String {
         MEMORY_MANAGER_SNAPSHOT_ID.to_string()
     }
 
     fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        // The SGX EPC content is not accessible from the VMM.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.sgx_epc_region.is_some() {
+                return Err(MigratableError::Snapshot(anyhow!(
+                    "SGX EPC sections cannot be snapshotted"
+                )));
+            }
+        }
+
         let mut memory_manager_snapshot = Snapshot::new(MEMORY_MANAGER_SNAPSHOT_ID);
         let guest_memory = self.guest_memory.memory();
 
@@ -1362,21 +2297,38 @@ impl Snapshottable for MemoryManager {
                 return Err(MigratableError::Snapshot(anyhow!("Zero length region")));
             }
 
+            // Only keep the ranges changed since the parent snapshot.
+            let ranges = self.snapshot_parent.as_ref().map(|(_, table)| {
+                let start = region.start_addr().raw_value();
+                let end = start + region.len();
+                let mut ranges = MemoryRangeTable::default();
+                for range in table.regions() {
+                    if range.gpa >= start && range.gpa < end {
+                        ranges.push(*range);
+                    }
+                }
+                ranges
+            });
+
             memory_regions.push(MemoryRegion {
                 backing_file: PathBuf::from(format!("memory-region-{}", index)),
                 start_addr: region.start_addr(),
                 size: region.len(),
+                ranges,
             });
 
             Ok(())
         })?;
 
-        let snapshot_data_section =
-            serde_json::to_vec(&MemoryManagerSnapshotData { memory_regions })
-                .map_err(|e| MigratableError::Snapshot(e.into()))?;
+        let snapshot_data_section = serde_json::to_vec(&MemoryManagerSnapshotData {
+            memory_regions,
+            parent_url: self.snapshot_parent.as_ref().map(|(url, _)| url.clone()),
+        })
+        .map_err(|e| MigratableError::Snapshot(e.into()))?;
 
         memory_manager_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID),
+            version: MEMORY_MANAGER_SNAPSHOT_VERSION,
             snapshot: snapshot_data_section,
         });
 
@@ -1390,12 +2342,15 @@ impl Snapshottable for MemoryManager {
 impl Transportable for MemoryManager {
     fn send(
         &self,
-        _snapshot: &Snapshot,
+        snapshot: &Snapshot,
         destination_url: &str,
     ) -> result::Result<(), MigratableError> {
         let url = Url::parse(destination_url).map_err(|e| {
             MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
         })?;
+        let memory_regions = MemoryManager::snapshot_data(snapshot)
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?
+            .memory_regions;
 
         match url.scheme() {
             "file" => {
@@ -1428,13 +2383,27 @@ impl Transportable for MemoryManager {
                             .open(memory_region_path)
                             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
-                        guest_memory
-                            .write_to(
-                                region.start_addr(),
-                                &mut memory_region_file,
-                                region.len().try_into().unwrap(),
-                            )
-                            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                        if let Some(ranges) = &memory_regions[index].ranges {
+                            // Incremental snapshot, only the changed ranges
+                            // are written.
+                            for range in ranges.regions() {
+                                guest_memory
+                                    .write_all_to(
+                                        GuestAddress(range.gpa),
+                                        &mut memory_region_file,
+                                        range.length as usize,
+                                    )
+                                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                            }
+                        } else {
+                            guest_memory
+                                .write_all_to(
+                                    region.start_addr(),
+                                    &mut memory_region_file,
+                                    region.len().try_into().unwrap(),
+                                )
+                                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                        }
 
                         Ok(())
                     })?;
@@ -1450,4 +2419,536 @@ impl Transportable for MemoryManager {
         Ok(())
     }
 }
-impl Migratable for MemoryManager {}
+// Reset the soft-dirty bits of all the VMM pages.
+fn clear_soft_dirty() -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open("/proc/self/clear_refs")?
+        .write_all(b"4")
+}
+
+// Build the bitmap of the pages from the host mapping which have been
+// written to since the soft-dirty bits were last cleared.
+fn soft_dirty_bitmap(
+    pagemap: &File,
+    host_addr: u64,
+    size: u64,
+    page_size: u64,
+) -> io::Result<Vec<u64>> {
+    let pages = size / page_size;
+    let mut bitmap = vec![0u64; ((pages + 63) / 64) as usize];
+    let mut entries = vec![0u8; (PAGEMAP_CHUNK_PAGES * 8) as usize];
+
+    let mut page = 0;
+    while page < pages {
+        let count = std::cmp::min(pages - page, PAGEMAP_CHUNK_PAGES);
+        let entries = &mut entries[..(count * 8) as usize];
+        pagemap.read_exact_at(entries, (host_addr / page_size + page) * 8)?;
+
+        for (i, entry) in entries.chunks_exact(8).enumerate() {
+            let mut value = [0u8; 8];
+            value.copy_from_slice(entry);
+            if u64::from_ne_bytes(value) & PAGEMAP_SOFT_DIRTY != 0 {
+                let index = page as usize + i;
+                bitmap[index / 64] |= 1 << (index % 64);
+            }
+        }
+
+        page += count;
+    }
+
+    Ok(bitmap)
+}
+
+impl Migratable for MemoryManager {
+    fn start_dirty_log(&mut self) -> result::Result<(), MigratableError> {
+        // Hugetlbfs pages carry no soft-dirty information, so the pages
+        // written by the VMM could not be tracked.
+        if self.hugepages {
+            return Err(MigratableError::StartDirtyLog(anyhow!(
+                "Dirty pages tracking is not supported with hugepages"
+            )));
+        }
+
+        self.vm
+            .start_dirty_log()
+            .map_err(|e| MigratableError::StartDirtyLog(e.into()))?;
+
+        clear_soft_dirty().map_err(|e| MigratableError::StartDirtyLog(e.into()))?;
+        self.dirty_log_active = true;
+
+        Ok(())
+    }
+
+    fn stop_dirty_log(&mut self) -> result::Result<(), MigratableError> {
+        // Without the dirty log, the changes since the last snapshot are
+        // lost.
+        self.dirty_log_active = false;
+        self.last_snapshot_url = None;
+
+        self.vm
+            .stop_dirty_log()
+            .map_err(|e| MigratableError::StopDirtyLog(e.into()))
+    }
+
+    // The hypervisor only reports the pages written by the guest, while the
+    // pages written by the VMM, e.g. from the virtio device emulation, are
+    // found through the soft-dirty bits of the VMM page tables.
+    //
+    // Reading and resetting the soft-dirty bits can't be done atomically, so
+    // this must be called while the devices are paused, otherwise the pages
+    // they write in between would be missing from the next pass.
+    fn dirty_log(&mut self) -> result::Result<MemoryRangeTable, MigratableError> {
+        // Trivially safe
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let pagemap =
+            File::open("/proc/self/pagemap").map_err(|e| MigratableError::DirtyLog(e.into()))?;
+
+        // Collect the soft-dirty bits of every mapping first, and reset them
+        // right away, to keep both steps as close as possible.
+        let mut vmm_dirty_bitmaps = Vec::with_capacity(self.guest_ram_mappings.len());
+        for mapping in self.guest_ram_mappings.iter() {
+            vmm_dirty_bitmaps.push(
+                soft_dirty_bitmap(&pagemap, mapping.host_addr, mapping.size, page_size)
+                    .map_err(|e| MigratableError::DirtyLog(e.into()))?,
+            );
+        }
+        clear_soft_dirty().map_err(|e| MigratableError::DirtyLog(e.into()))?;
+
+        let mut table = MemoryRangeTable::default();
+        for (mapping, vmm_dirty_bitmap) in self.guest_ram_mappings.iter().zip(vmm_dirty_bitmaps) {
+            let vm_dirty_bitmap = self
+                .vm
+                .get_dirty_log(mapping.slot, mapping.gpa, mapping.size)
+                .map_err(|e| MigratableError::DirtyLog(e.into()))?;
+
+            let dirty_bitmap = vm_dirty_bitmap
+                .iter()
+                .zip(vmm_dirty_bitmap.iter())
+                .map(|(vm, vmm)| vm | vmm)
+                .collect();
+
+            table.extend(MemoryRangeTable::from_bitmap(
+                dirty_bitmap,
+                mapping.gpa,
+                page_size,
+            ));
+        }
+
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_dirty_bitmap() {
+        let page_size = 0x1000;
+        // The mapping starts at page 2, and spans more than one chunk of
+        // pagemap entries.
+        let host_addr = 2 * page_size;
+        let pages = PAGEMAP_CHUNK_PAGES + 70;
+        let dirty_pages = [0, 1, 63, 64, PAGEMAP_CHUNK_PAGES, pages - 1];
+
+        let mut entries = vec![0u64; (pages + 2) as usize];
+        for page in dirty_pages.iter() {
+            entries[(page + 2) as usize] = PAGEMAP_SOFT_DIRTY | 0x1234;
+        }
+        // Only the soft-dirty bit matters.
+        entries[(3 + 2) as usize] = !PAGEMAP_SOFT_DIRTY;
+        // Outside of the mapping.
+        entries[1] = PAGEMAP_SOFT_DIRTY;
+
+        let mut pagemap = tempfile::tempfile().unwrap();
+        for entry in entries.iter() {
+            pagemap.write_all(&entry.to_ne_bytes()).unwrap();
+        }
+
+        let bitmap = soft_dirty_bitmap(&pagemap, host_addr, pages * page_size, page_size).unwrap();
+        assert_eq!(bitmap.len() as u64, (pages + 63) / 64);
+        for page in 0..pages {
+            let dirty = bitmap[(page / 64) as usize] & (1 << (page % 64)) != 0;
+            assert_eq!(dirty, dirty_pages.contains(&page), "page {}", page);
+        }
+    }
+
+    #[test]
+    fn test_soft_dirty_bitmap_short_pagemap() {
+        let pagemap = tempfile::tempfile().unwrap();
+        assert!(soft_dirty_bitmap(&pagemap, 0, 0x4000, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_check_hugepages() {
+        let pools_dir = tempfile::tempdir().unwrap();
+        let pool = pools_dir.path().join("hugepages-2048kB");
+        std::fs::create_dir(&pool).unwrap();
+        std::fs::write(pool.join("free_hugepages"), "10\n").unwrap();
+        std::fs::write(pool.join("resv_hugepages"), "2\n").unwrap();
+
+        let zone = |size, hugepages, hugepage_size| MemoryZoneConfig {
+            id: String::from("mem0"),
+            size,
+            file: None,
+            shared: false,
+            hugepages,
+            hugepage_size,
+            mergeable: false,
+        };
+
+        // Only the free hugepages not reserved yet can be used.
+        assert!(
+            MemoryManager::check_hugepages(pools_dir.path(), &[zone(16 << 20, true, None)]).is_ok()
+        );
+        assert!(matches!(
+            MemoryManager::check_hugepages(pools_dir.path(), &[zone(17 << 20, true, None)]),
+            Err(Error::InsufficientHugePages(0x20_0000, 9, 8))
+        ));
+        // The zones using the same hugepage size share the pool.
+        assert!(matches!(
+            MemoryManager::check_hugepages(
+                pools_dir.path(),
+                &[
+                    zone(8 << 20, true, None),
+                    zone(10 << 20, true, Some(2 << 20))
+                ]
+            ),
+            Err(Error::InsufficientHugePages(0x20_0000, 9, 8))
+        ));
+
+        // Zones without hugepages, or backed by a file, don't need the pools.
+        let mut file_zone = zone(1 << 30, true, None);
+        file_zone.file = Some(PathBuf::from("/dev/hugepages"));
+        assert!(MemoryManager::check_hugepages(
+            pools_dir.path(),
+            &[zone(1 << 30, false, None), file_zone]
+        )
+        .is_ok());
+
+        assert!(matches!(
+            MemoryManager::check_hugepages(pools_dir.path(), &[zone(1 << 30, true, Some(1 << 30))]),
+            Err(Error::HugePageSizeUnsupported(0x4000_0000))
+        ));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_check_hugepages_hotplug() {
+        // Only the hotpluggable memory is backed by hugepages, and whatever
+        // the host pools, they can't provide that much of it.
+        let config = MemoryConfig {
+            size: 16 << 20,
+            hotplug_method: HotplugMethod::VirtioMem,
+            hotplug_size: Some(1 << 50),
+            hugepages: true,
+            zones: Some(vec![MemoryZoneConfig {
+                id: String::from("mem0"),
+                size: 16 << 20,
+                file: None,
+                shared: false,
+                hugepages: false,
+                hugepage_size: None,
+                mergeable: false,
+            }]),
+            ..Default::default()
+        };
+        let vm = Arc::new(hypervisor::mock::MockVm::new());
+        assert!(matches!(
+            MemoryManager::new(vm, &config, &None, None, false),
+            Err(Error::InsufficientHugePages(..)) | Err(Error::HugePageSizeUnsupported(_))
+        ));
+    }
+
+    #[cfg(feature = "mock")]
+    fn create_mock_memory_manager(vm: &Arc<hypervisor::mock::MockVm>) -> Arc<Mutex<MemoryManager>> {
+        let config = MemoryConfig {
+            size: 16 << 20,
+            ..Default::default()
+        };
+        MemoryManager::new(vm.clone(), &config, &None, None, false).unwrap()
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_snapshot_parent_needs_tracked_changes() {
+        let vm = Arc::new(hypervisor::mock::MockVm::new());
+        let memory_manager = create_mock_memory_manager(&vm);
+        let mut memory_manager = memory_manager.lock().unwrap();
+
+        // Nothing is tracked until asked for.
+        assert!(memory_manager
+            .set_snapshot_parent(Some("file:///snapshot-1"))
+            .is_err());
+        assert!(!vm.dirty_log());
+
+        memory_manager.track_snapshot_changes("file:///snapshot-1");
+        assert!(vm.dirty_log());
+
+        // Only the last snapshot can be a parent.
+        assert!(memory_manager
+            .set_snapshot_parent(Some("file:///snapshot-0"))
+            .is_err());
+        // The failed attempt consumed the memory changes.
+        assert!(memory_manager
+            .set_snapshot_parent(Some("file:///snapshot-1"))
+            .is_err());
+
+        memory_manager.track_snapshot_changes("file:///snapshot-1");
+        assert!(memory_manager
+            .set_snapshot_parent(Some("file:///snapshot-1"))
+            .is_ok());
+        assert_eq!(
+            memory_manager.snapshot_parent.as_ref().unwrap().0,
+            "file:///snapshot-1"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_untrack_snapshot_changes() {
+        let vm = Arc::new(hypervisor::mock::MockVm::new());
+        let memory_manager = create_mock_memory_manager(&vm);
+        let mut memory_manager = memory_manager.lock().unwrap();
+
+        memory_manager.track_snapshot_changes("file:///snapshot-1");
+        assert!(vm.dirty_log());
+
+        // A full snapshot taken without tracking the changes stops the
+        // dirty pages logging.
+        memory_manager.set_snapshot_parent(None).unwrap();
+        memory_manager.untrack_snapshot_changes();
+        assert!(!vm.dirty_log());
+        assert!(memory_manager
+            .set_snapshot_parent(Some("file:///snapshot-1"))
+            .is_err());
+    }
+
+    #[cfg(feature = "mock")]
+    fn eject_hotplug_slot(memory_manager: &mut MemoryManager, slot_id: u8) {
+        memory_manager.write(0, SELECTION_OFFSET, &[slot_id]);
+        memory_manager.write(0, STATUS_OFFSET, &[1 << EJECT_FLAG]);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_acpi_hot_unplug() {
+        let vm = Arc::new(hypervisor::mock::MockVm::new());
+        let config = MemoryConfig {
+            size: 16 << 20,
+            hotplug_method: HotplugMethod::Acpi,
+            hotplug_size: Some(1 << 30),
+            ..Default::default()
+        };
+        let memory_manager = MemoryManager::new(vm.clone(), &config, &None, None, false).unwrap();
+        let mut memory_manager = memory_manager.lock().unwrap();
+        let eject_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        memory_manager.set_eject_evt(eject_evt.try_clone().unwrap());
+
+        let boot_ram = 16 << 20;
+        let slot_size = 128 << 20;
+        let first = memory_manager
+            .resize(boot_ram + slot_size, None)
+            .unwrap()
+            .unwrap();
+        let second = memory_manager
+            .resize(boot_ram + 2 * slot_size, None)
+            .unwrap()
+            .unwrap();
+        let mapped_slots = vm.memory_regions().len();
+
+        // Only whole slots can be removed.
+        assert!(matches!(
+            memory_manager.resize(boot_ram + slot_size / 2, None),
+            Err(Error::InvalidHotUnplugSize)
+        ));
+
+        // Nothing is removed until the guest ejects the slot.
+        assert!(memory_manager
+            .resize(boot_ram + slot_size, None)
+            .unwrap()
+            .is_none());
+        assert_eq!(memory_manager.current_ram(), boot_ram + 2 * slot_size);
+        assert!(memory_manager.hotplug_slots[1].removing);
+        assert!(eject_evt.read().is_err());
+
+        eject_hotplug_slot(&mut memory_manager, 1);
+        assert_eq!(memory_manager.current_ram(), boot_ram + slot_size);
+        assert_eq!(eject_evt.read().unwrap(), 1);
+        assert_eq!(vm.memory_regions().len(), mapped_slots - 1);
+        assert!(memory_manager
+            .guest_memory()
+            .memory()
+            .find_region(second.start_addr())
+            .is_none());
+        let ejected = memory_manager.take_ejected_regions();
+        assert_eq!(ejected.len(), 1);
+        assert!(Arc::ptr_eq(&ejected[0], &second));
+        assert!(memory_manager.take_ejected_regions().is_empty());
+
+        // Growing again while the last slot is being ejected only accounts
+        // for the slots staying.
+        memory_manager.resize(boot_ram, None).unwrap();
+        let third = memory_manager
+            .resize(boot_ram + slot_size, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(memory_manager.current_ram(), boot_ram + 2 * slot_size);
+        eject_hotplug_slot(&mut memory_manager, 0);
+        assert_eq!(memory_manager.current_ram(), boot_ram + slot_size);
+        let ejected = memory_manager.take_ejected_regions();
+        assert!(Arc::ptr_eq(&ejected[0], &first));
+        assert!(memory_manager
+            .guest_memory()
+            .memory()
+            .find_region(third.start_addr())
+            .is_some());
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_acpi_hot_unplug_by_guest() {
+        let vm = Arc::new(hypervisor::mock::MockVm::new());
+        let config = MemoryConfig {
+            size: 16 << 20,
+            hotplug_method: HotplugMethod::Acpi,
+            hotplug_size: Some(1 << 30),
+            ..Default::default()
+        };
+        let memory_manager = MemoryManager::new(vm, &config, &None, None, false).unwrap();
+        let mut memory_manager = memory_manager.lock().unwrap();
+
+        memory_manager.resize(144 << 20, None).unwrap().unwrap();
+
+        // The guest can eject a slot without being asked to.
+        eject_hotplug_slot(&mut memory_manager, 0);
+        assert_eq!(memory_manager.current_ram(), 16 << 20);
+        assert_eq!(memory_manager.take_ejected_regions().len(), 1);
+
+        // Ejecting an empty slot does nothing.
+        eject_hotplug_slot(&mut memory_manager, 0);
+        assert_eq!(memory_manager.current_ram(), 16 << 20);
+        assert!(memory_manager.take_ejected_regions().is_empty());
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_resize_numa_node() {
+        let vm = Arc::new(hypervisor::mock::MockVm::new());
+        let numa_node = |guest_numa_id, hotplug_size| NumaConfig {
+            guest_numa_id,
+            cpus: None,
+            size: 8 << 20,
+            distances: None,
+            host_numa_node: None,
+            hotplug_size,
+        };
+
+        // The memory hotplugged through ACPI doesn't belong to any node.
+        let config = MemoryConfig {
+            size: 16 << 20,
+            hotplug_method: HotplugMethod::Acpi,
+            hotplug_size: Some(1 << 30),
+            ..Default::default()
+        };
+        let memory_manager = MemoryManager::new(vm.clone(), &config, &None, None, false).unwrap();
+        assert!(matches!(
+            memory_manager.lock().unwrap().resize(144 << 20, Some(0)),
+            Err(Error::NumaNodeResizeRequiresVirtioMem(0))
+        ));
+
+        // A single virtio-mem device is used without selecting a node.
+        let config = MemoryConfig {
+            size: 16 << 20,
+            hotplug_method: HotplugMethod::VirtioMem,
+            hotplug_size: Some(128 << 20),
+            ..Default::default()
+        };
+        let memory_manager = MemoryManager::new(vm.clone(), &config, &None, None, false).unwrap();
+        assert!(matches!(
+            memory_manager.lock().unwrap().resize(144 << 20, Some(1)),
+            Err(Error::UnknownVirtioMemNumaNode(1))
+        ));
+        assert!(matches!(
+            memory_manager.lock().unwrap().resize(8 << 20, None),
+            Err(Error::ResizeBelowBootRam)
+        ));
+
+        // With several virtio-mem devices, the node selects the device.
+        let numa_nodes = Some(vec![
+            numa_node(0, Some(64 << 20)),
+            numa_node(1, Some(64 << 20)),
+        ]);
+        let memory_manager = MemoryManager::new(vm, &config, &numa_nodes, None, false).unwrap();
+        let mut memory_manager = memory_manager.lock().unwrap();
+        assert_eq!(memory_manager.virtio_mem_zones().len(), 2);
+        assert!(matches!(
+            memory_manager.resize(144 << 20, None),
+            Err(Error::VirtioMemNumaNodeRequired)
+        ));
+        assert!(matches!(
+            memory_manager.resize(144 << 20, Some(2)),
+            Err(Error::UnknownVirtioMemNumaNode(2))
+        ));
+    }
+
+    #[test]
+    fn test_virtio_mem_hotplug_zones() {
+        let zone = |id: &str, shared, hugepages| MemoryZoneConfig {
+            id: String::from(id),
+            size: 1 << 30,
+            file: None,
+            shared,
+            hugepages,
+            hugepage_size: None,
+            mergeable: false,
+        };
+        let numa_node = |guest_numa_id, hotplug_size| NumaConfig {
+            guest_numa_id,
+            cpus: None,
+            size: 1 << 30,
+            distances: None,
+            host_numa_node: None,
+            hotplug_size,
+        };
+        let config = MemoryConfig {
+            size: 2 << 30,
+            shared: true,
+            hotplug_method: HotplugMethod::VirtioMem,
+            hotplug_size: Some(2 << 30),
+            zones: Some(vec![zone("mem0", false, false), zone("mem1", true, true)]),
+            ..Default::default()
+        };
+        let zones = MemoryManager::memory_zones(&config);
+
+        // Without NUMA, the hotplugged memory follows the --memory parameters.
+        let ram_regions =
+            MemoryManager::layout_ram_regions(&[(GuestAddress(0), 2 << 30)], &zones, &None);
+        let hotplug_zones =
+            MemoryManager::virtio_mem_hotplug_zones(&config, &zones, &ram_regions, &None);
+        assert_eq!(hotplug_zones.len(), 1);
+        assert_eq!(hotplug_zones[0].0, None);
+        assert_eq!(hotplug_zones[0].1.size, 2 << 30);
+        assert!(hotplug_zones[0].1.shared);
+        assert!(!hotplug_zones[0].1.hugepages);
+
+        // Each node hotplugs memory backed like its own zone.
+        let numa_nodes = Some(vec![
+            numa_node(0, Some(1 << 30)),
+            numa_node(1, Some(1 << 30)),
+        ]);
+        let ram_regions =
+            MemoryManager::layout_ram_regions(&[(GuestAddress(0), 2 << 30)], &zones, &numa_nodes);
+        let hotplug_zones =
+            MemoryManager::virtio_mem_hotplug_zones(&config, &zones, &ram_regions, &numa_nodes);
+        assert_eq!(hotplug_zones.len(), 2);
+        assert_eq!(hotplug_zones[0].0, Some(0));
+        assert!(!hotplug_zones[0].1.shared);
+        assert!(!hotplug_zones[0].1.hugepages);
+        assert_eq!(hotplug_zones[1].0, Some(1));
+        assert_eq!(hotplug_zones[1].1.size, 1 << 30);
+        assert!(hotplug_zones[1].1.shared);
+        assert!(hotplug_zones[1].1.hugepages);
+    }
+}
@@ -2,15 +2,33 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::vm::{VmSnapshot, VM_SNAPSHOT_ID};
+#[cfg(target_arch = "x86_64")]
+use crate::cpu::CpuFeatures;
+use crate::vm::{Vm, VmSnapshot, VM_SNAPSHOT_ID, VM_SNAPSHOT_VERSION};
 use anyhow::anyhow;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use url::Url;
-use vm_migration::{MigratableError, Snapshot};
+use vm_migration::{MigratableError, Snapshot, SnapshotDataSection};
 
 pub const VM_SNAPSHOT_FILE: &str = "vm.json";
+pub const SNAPSHOT_MANIFEST_FILE: &str = "manifest.json";
+
+const VMM_SNAPSHOT_ID: &str = "vmm";
+const VMM_SNAPSHOT_VERSION: u16 = 1;
+
+// Maximum number of memory pre-copy rounds performed while the guest is
+// still running, before pausing it to transfer the last dirty pages.
+pub const MAX_MIGRATION_ROUNDS: usize = 5;
+
+// Once the amount of memory dirtied during a pre-copy round goes below this
+// threshold, the VM is paused and the migration completed.
+pub const MIGRATION_DOWNTIME_THRESHOLD: u64 = 64 << 20;
 
 pub fn url_to_path(url: &Url) -> std::result::Result<PathBuf, MigratableError> {
     match url.scheme() {
@@ -35,22 +53,61 @@ pub fn url_to_path(url: &Url) -> std::result::Result<PathBuf, MigratableError> {
     }
 }
 
-pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, MigratableError> {
+/// The integrity checks run against the manifest of a snapshot when reading
+/// it. The data sections are always checked.
+#[derive(Clone, Copy, Default)]
+pub struct SnapshotChecks {
+    /// Check the digest of every file of the snapshot, which means reading
+    /// the whole guest memory up front.
+    pub files: bool,
+
+    /// Accept a snapshot without manifest, skipping all the checks.
+    pub allow_unverified: bool,
+}
+
+pub fn recv_vm_snapshot(
+    source_url: &str,
+    checks: SnapshotChecks,
+) -> std::result::Result<Snapshot, MigratableError> {
     let url = Url::parse(source_url).map_err(|e| {
         MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
     })?;
 
     match url.scheme() {
         "file" => {
-            let mut vm_snapshot_path = url_to_path(&url)?;
+            let snapshot_dir = url_to_path(&url)?;
+
+            // Make sure the snapshot files are intact before using them.
+            let manifest = SnapshotManifest::read(&snapshot_dir)?;
+            match &manifest {
+                Some(manifest) if checks.files => manifest.verify_files(&snapshot_dir)?,
+                Some(_) => {}
+                None if checks.allow_unverified => warn!(
+                    "No manifest in {:?}, skipping the snapshot integrity checks",
+                    snapshot_dir
+                ),
+                None => {
+                    return Err(MigratableError::Integrity(anyhow!(
+                        "Missing snapshot manifest in {:?}",
+                        snapshot_dir
+                    )))
+                }
+            }
+
+            let mut vm_snapshot_path = snapshot_dir;
             vm_snapshot_path.push(VM_SNAPSHOT_FILE);
 
             // Try opening the snapshot file
             let vm_snapshot_file =
                 File::open(vm_snapshot_path).map_err(|e| MigratableError::MigrateSend(e.into()))?;
             let vm_snapshot_reader = BufReader::new(vm_snapshot_file);
-            let vm_snapshot = serde_json::from_reader(vm_snapshot_reader)
+            let vm_snapshot: Snapshot = serde_json::from_reader(vm_snapshot_reader)
                 .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+            vm_snapshot.check_version()?;
+
+            if let Some(manifest) = manifest {
+                manifest.verify_sections(&vm_snapshot)?;
+            }
 
             Ok(vm_snapshot)
         }
@@ -61,11 +118,163 @@ pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, Migra
     }
 }
 
+/// Check the integrity of a snapshot, including all its files, without
+/// restoring it.
+pub fn verify_vm_snapshot(source_url: &str) -> std::result::Result<(), MigratableError> {
+    recv_vm_snapshot(
+        source_url,
+        SnapshotChecks {
+            files: true,
+            allow_unverified: false,
+        },
+    )
+    .map(|_| ())
+}
+
+/// The digests of the files making a snapshot, and of the data sections of
+/// its snapshot tree. The manifest is written last, once the snapshot is
+/// complete, and checked before the snapshot is restored.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    /// BLAKE2b digest of each file, indexed by file name.
+    files: BTreeMap<String, String>,
+
+    /// BLAKE2b digest of each data section, indexed by its path in the
+    /// snapshot tree.
+    sections: BTreeMap<String, String>,
+}
+
+impl SnapshotManifest {
+    /// Compute the manifest of a snapshot written to `snapshot_dir`, made of
+    /// the given files.
+    pub fn new(
+        snapshot: &Snapshot,
+        snapshot_dir: &Path,
+        files: &[PathBuf],
+    ) -> std::result::Result<Self, MigratableError> {
+        let mut manifest = SnapshotManifest::default();
+        for file in files.iter() {
+            let digest = file_digest(&snapshot_dir.join(file))
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            manifest
+                .files
+                .insert(file.to_string_lossy().into_owned(), digest);
+        }
+        section_digests(snapshot, "", &mut manifest.sections);
+
+        Ok(manifest)
+    }
+
+    pub fn write(&self, snapshot_dir: &Path) -> std::result::Result<(), MigratableError> {
+        let manifest_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(snapshot_dir.join(SNAPSHOT_MANIFEST_FILE))
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+        serde_json::to_writer(manifest_file, self)
+            .map_err(|e| MigratableError::MigrateSend(e.into()))
+    }
+
+    fn read(snapshot_dir: &Path) -> std::result::Result<Option<Self>, MigratableError> {
+        let manifest_path = snapshot_dir.join(SNAPSHOT_MANIFEST_FILE);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+
+        let manifest_file =
+            File::open(manifest_path).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+        serde_json::from_reader(BufReader::new(manifest_file))
+            .map(Some)
+            .map_err(|e| MigratableError::Integrity(anyhow!("Invalid manifest: {}", e)))
+    }
+
+    fn verify_files(&self, snapshot_dir: &Path) -> std::result::Result<(), MigratableError> {
+        for (file, digest) in self.files.iter() {
+            let actual_digest = file_digest(&snapshot_dir.join(file))
+                .map_err(|e| MigratableError::Integrity(anyhow!("Cannot read {}: {}", file, e)))?;
+            if actual_digest != *digest {
+                return Err(MigratableError::Integrity(anyhow!(
+                    "Digest mismatch for {}",
+                    file
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verify_sections(&self, snapshot: &Snapshot) -> std::result::Result<(), MigratableError> {
+        let mut sections = BTreeMap::new();
+        section_digests(snapshot, "", &mut sections);
+
+        for (section, digest) in self.sections.iter() {
+            match sections.remove(section) {
+                Some(actual_digest) if actual_digest == *digest => {}
+                Some(_) => {
+                    return Err(MigratableError::Integrity(anyhow!(
+                        "Digest mismatch for section {}",
+                        section
+                    )))
+                }
+                None => {
+                    return Err(MigratableError::Integrity(anyhow!(
+                        "Missing section {}",
+                        section
+                    )))
+                }
+            }
+        }
+
+        if let Some(section) = sections.keys().next() {
+            return Err(MigratableError::Integrity(anyhow!(
+                "Unexpected section {}",
+                section
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn file_digest(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut state = blake2b_simd::State::new();
+    io::copy(&mut file, &mut state)?;
+
+    Ok(state.finalize().to_hex().to_string())
+}
+
+// Collect the digests of the data sections of a snapshot tree, indexed by
+// their path, e.g. "vm/cpu-manager/0/vcpu-section".
+fn section_digests(snapshot: &Snapshot, parent: &str, digests: &mut BTreeMap<String, String>) {
+    let path = if parent.is_empty() {
+        snapshot.id.clone()
+    } else {
+        format!("{}/{}", parent, snapshot.id)
+    };
+
+    for (id, section) in snapshot.snapshot_data.iter() {
+        digests.insert(
+            format!("{}/{}", path, id),
+            blake2b_simd::blake2b(&section.snapshot)
+                .to_hex()
+                .to_string(),
+        );
+    }
+
+    for child in snapshot.snapshots.values() {
+        section_digests(child, &path, digests);
+    }
+}
+
 pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, MigratableError> {
     if let Some(vm_section) = snapshot
         .snapshot_data
         .get(&format!("{}-section", VM_SNAPSHOT_ID))
     {
+        vm_section.check_version(VM_SNAPSHOT_VERSION)?;
+
         return serde_json::from_slice(&vm_section.snapshot).map_err(|e| {
             MigratableError::Restore(anyhow!("Could not deserialize VM snapshot {}", e))
         });
@@ -75,3 +284,233 @@ pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, M
         "Could not find VM config snapshot section"
     )))
 }
+
+/// Description of the VMM and of the host a VM snapshot was taken on, stored
+/// at the root of the snapshot.
+#[derive(Serialize, Deserialize)]
+pub struct VmmSnapshot {
+    /// The version of the VMM which took the snapshot.
+    pub version: String,
+
+    /// The CPU features exposed to the guest and the MSRs saved along with
+    /// its vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    pub cpu_features: CpuFeatures,
+}
+
+impl VmmSnapshot {
+    /// Describe the VMM and the CPU features of the paused `vm`.
+    #[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+    pub fn new(version: &str, vm: &Vm) -> Self {
+        VmmSnapshot {
+            version: version.to_string(),
+            #[cfg(target_arch = "x86_64")]
+            cpu_features: vm.cpu_features(),
+        }
+    }
+
+    /// Add the VMM description to the root of a VM snapshot.
+    pub fn add_to(&self, snapshot: &mut Snapshot) -> std::result::Result<(), MigratableError> {
+        snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", VMM_SNAPSHOT_ID),
+            version: VMM_SNAPSHOT_VERSION,
+            snapshot: serde_json::to_vec(self).map_err(|e| MigratableError::Snapshot(e.into()))?,
+        });
+
+        Ok(())
+    }
+}
+
+/// Check a VM snapshot can be restored by this VMM, on this host.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+pub fn check_vm_snapshot(
+    snapshot: &Snapshot,
+    version: &str,
+    hypervisor: &Arc<dyn hypervisor::Hypervisor>,
+) -> std::result::Result<(), MigratableError> {
+    snapshot.check_version()?;
+
+    let vmm_section = match snapshot
+        .snapshot_data
+        .get(&format!("{}-section", VMM_SNAPSHOT_ID))
+    {
+        Some(section) => section,
+        None => {
+            warn!("Snapshot does not describe its origin, skipping host compatibility checks");
+            return Ok(());
+        }
+    };
+    vmm_section.check_version(VMM_SNAPSHOT_VERSION)?;
+
+    let vmm_snapshot: VmmSnapshot = serde_json::from_slice(&vmm_section.snapshot).map_err(|e| {
+        MigratableError::Restore(anyhow!("Could not deserialize VMM snapshot {}", e))
+    })?;
+
+    if vmm_snapshot.version != version {
+        info!(
+            "Restoring a snapshot taken by VMM version {}, from version {}",
+            vmm_snapshot.version, version
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    vmm_snapshot.cpu_features.check_compatibility(
+        &CpuFeatures::host(hypervisor).map_err(|e| MigratableError::Restore(e.into()))?,
+    )?;
+
+    Ok(())
+}
+
+/// A live migration connection, either over a UNIX domain socket or over a
+/// TCP socket.
+pub enum MigrationSocket {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Read for MigrationSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MigrationSocket::Unix(s) => s.read(buf),
+            MigrationSocket::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MigrationSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MigrationSocket::Unix(s) => s.write(buf),
+            MigrationSocket::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MigrationSocket::Unix(s) => s.flush(),
+            MigrationSocket::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+fn parse_migration_url(migration_url: &str) -> std::result::Result<Url, MigratableError> {
+    Url::parse(migration_url)
+        .map_err(|e| MigratableError::MigrateSend(anyhow!("Could not parse migration URL: {}", e)))
+}
+
+fn tcp_address(url: &Url) -> std::result::Result<String, MigratableError> {
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => Ok(format!("{}:{}", host, port)),
+        _ => Err(MigratableError::MigrateSend(anyhow!(
+            "Missing host or port from TCP URL: {}",
+            url
+        ))),
+    }
+}
+
+/// Connect to a migration destination, described either as
+/// `unix:///path/to/socket` or as `tcp://<host>:<port>`.
+pub fn connect_migration_socket(
+    destination_url: &str,
+) -> std::result::Result<MigrationSocket, MigratableError> {
+    let url = parse_migration_url(destination_url)?;
+
+    match url.scheme() {
+        "unix" => UnixStream::connect(url.path())
+            .map(MigrationSocket::Unix)
+            .map_err(MigratableError::MigrateSocket),
+        "tcp" => TcpStream::connect(tcp_address(&url)?)
+            .map(MigrationSocket::Tcp)
+            .map_err(MigratableError::MigrateSocket),
+        _ => Err(MigratableError::MigrateSend(anyhow!(
+            "Unsupported migration URL scheme: {}",
+            url.scheme()
+        ))),
+    }
+}
+
+/// Wait for a migration source to connect, either on `unix:///path/to/socket`
+/// or on `tcp://<address>:<port>`. Only one connection is accepted.
+pub fn accept_migration_socket(
+    receiver_url: &str,
+) -> std::result::Result<MigrationSocket, MigratableError> {
+    let url = parse_migration_url(receiver_url)?;
+
+    match url.scheme() {
+        "unix" => {
+            let listener =
+                UnixListener::bind(url.path()).map_err(MigratableError::MigrateSocket)?;
+            let (socket, _) = listener.accept().map_err(MigratableError::MigrateSocket)?;
+            std::fs::remove_file(url.path()).map_err(MigratableError::MigrateSocket)?;
+
+            Ok(MigrationSocket::Unix(socket))
+        }
+        "tcp" => {
+            let listener =
+                TcpListener::bind(tcp_address(&url)?).map_err(MigratableError::MigrateSocket)?;
+            let (socket, _) = listener.accept().map_err(MigratableError::MigrateSocket)?;
+
+            Ok(MigrationSocket::Tcp(socket))
+        }
+        _ => Err(MigratableError::MigrateReceive(anyhow!(
+            "Unsupported migration URL scheme: {}",
+            url.scheme()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_manifest() {
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let files = vec![PathBuf::from("memory-region-0")];
+        std::fs::write(snapshot_dir.path().join(&files[0]), vec![0xaau8; 0x1000]).unwrap();
+
+        let mut device_snapshot = Snapshot::new("device");
+        device_snapshot.add_data_section(SnapshotDataSection {
+            id: "device-section".to_string(),
+            version: 1,
+            snapshot: vec![1, 2, 3],
+        });
+        let mut snapshot = Snapshot::new("vm");
+        snapshot.add_snapshot(device_snapshot);
+
+        let manifest = SnapshotManifest::new(&snapshot, snapshot_dir.path(), &files).unwrap();
+        assert!(manifest.sections.contains_key("vm/device/device-section"));
+        manifest.write(snapshot_dir.path()).unwrap();
+
+        let manifest = SnapshotManifest::read(snapshot_dir.path())
+            .unwrap()
+            .unwrap();
+        manifest.verify_files(snapshot_dir.path()).unwrap();
+        manifest.verify_sections(&snapshot).unwrap();
+
+        // Altered section
+        let mut altered_snapshot = snapshot.clone();
+        altered_snapshot
+            .snapshots
+            .get_mut("device")
+            .unwrap()
+            .snapshot_data
+            .get_mut("device-section")
+            .unwrap()
+            .snapshot[0] = 0;
+        assert!(manifest.verify_sections(&altered_snapshot).is_err());
+
+        // Missing section
+        let mut altered_snapshot = snapshot.clone();
+        altered_snapshot.snapshots.clear();
+        assert!(manifest.verify_sections(&altered_snapshot).is_err());
+
+        // Truncated file
+        std::fs::write(snapshot_dir.path().join(&files[0]), vec![0xaau8; 0x800]).unwrap();
+        assert!(manifest.verify_files(snapshot_dir.path()).is_err());
+
+        // Missing file
+        std::fs::remove_file(snapshot_dir.path().join(&files[0])).unwrap();
+        assert!(manifest.verify_files(snapshot_dir.path()).is_err());
+    }
+}
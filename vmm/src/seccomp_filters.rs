@@ -74,6 +74,11 @@ const VFIO_IOMMU_MAP_DMA: u64 = 0x3b71;
 const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 const VFIO_DEVICE_IOEVENTFD: u64 = 0x3b74;
 
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFDIO_API: u64 = 0xc018_aa3f;
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
+
 fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
     // See include/uapi/linux/kvm.h in the kernel code.
     const KVM_GET_API_VERSION: u64 = 0xae00;
@@ -97,6 +102,7 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
     const KVM_GET_DEVICE_ATTR: u64 = 0x4018_aee2;
     const KVM_GET_VCPU_EVENTS: u64 = 0x8040_ae9f;
     const KVM_GET_ONE_REG: u64 = 0x4010_aeab;
+    const KVM_GET_DIRTY_LOG: u64 = 0x4010_ae42;
     const KVM_GET_REGS: u64 = 0x8090_ae81;
     const KVM_GET_SUPPORTED_CPUID: u64 = 0xc008_ae05;
     const KVM_CREATE_DEVICE: u64 = 0xc00c_aee0;
@@ -112,6 +118,7 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_ENABLE_CAP)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_API_VERSION,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_DEVICE_ATTR,)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_DIRTY_LOG)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_MP_STATE)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_ONE_REG)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_REGS)?],
@@ -141,6 +148,9 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_API)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_COPY)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_REGISTER)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_GET_API_VERSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_CHECK_EXTENSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_SET_IOMMU)?],
@@ -179,6 +189,8 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     const KVM_SET_CLOCK: u64 = 0x4030_ae7b;
     const KVM_SET_CPUID2: u64 = 0x4008_ae90;
     const KVM_SET_FPU: u64 = 0x41a0_ae8d;
+    #[cfg(feature = "gdb")]
+    const KVM_SET_GUEST_DEBUG: u64 = 0x4048_ae9b;
     const KVM_SET_LAPIC: u64 = 0x4400_ae8f;
     const KVM_SET_MSRS: u64 = 0x4008_ae89;
     const KVM_SET_SREGS: u64 = 0x4138_ae84;
@@ -208,6 +220,8 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XCRS,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XSAVE,)?],
     ];
+    #[cfg(feature = "gdb")]
+    arch_rules.push(and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_GUEST_DEBUG)?]);
     arch_rules.extend(common_rules);
 
     Ok(arch_rules)
@@ -278,10 +292,14 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_gettid),
             allow_syscall(libc::SYS_gettimeofday),
             allow_syscall(libc::SYS_getuid),
+            allow_syscall(libc::SYS_io_uring_enter),
+            allow_syscall(libc::SYS_io_uring_register),
+            allow_syscall(libc::SYS_io_uring_setup),
             allow_syscall_if(libc::SYS_ioctl, create_vmm_ioctl_seccomp_rule()?),
             allow_syscall(libc::SYS_listen),
             allow_syscall(libc::SYS_lseek),
             allow_syscall(libc::SYS_madvise),
+            allow_syscall(libc::SYS_mbind),
             allow_syscall(libc::SYS_memfd_create),
             allow_syscall(libc::SYS_mmap),
             allow_syscall(libc::SYS_mprotect),
@@ -306,16 +324,19 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_rt_sigprocmask),
             allow_syscall(libc::SYS_rt_sigreturn),
             allow_syscall(libc::SYS_sched_getaffinity),
+            allow_syscall(libc::SYS_sched_setaffinity),
             allow_syscall(libc::SYS_sendmsg),
             allow_syscall(libc::SYS_sendto),
             allow_syscall(libc::SYS_set_robust_list),
             allow_syscall(libc::SYS_set_tid_address),
+            allow_syscall(libc::SYS_setsockopt),
             allow_syscall(libc::SYS_sigaltstack),
             allow_syscall_if(
                 libc::SYS_socket,
                 or![
                     and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
                     and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                    and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
                 ],
             ),
             allow_syscall(libc::SYS_socketpair),
@@ -323,6 +344,8 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_stat),
             allow_syscall(libc::SYS_statx),
             allow_syscall(libc::SYS_tgkill),
+            allow_syscall(libc::SYS_timerfd_create),
+            allow_syscall(libc::SYS_timerfd_settime),
             allow_syscall(libc::SYS_tkill),
             allow_syscall_if(
                 libc::SYS_umask,
@@ -332,6 +355,7 @@ pub fn vmm_thread_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_unlink),
             #[cfg(target_arch = "aarch64")]
             allow_syscall(libc::SYS_unlinkat),
+            allow_syscall(libc::SYS_userfaultfd),
             allow_syscall(libc::SYS_wait4),
             allow_syscall(libc::SYS_write),
         ]
@@ -0,0 +1,302 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Lazy loading of the guest memory, relying on userfaultfd.
+//!
+//! The guest RAM is registered with userfaultfd, so that any access to a
+//! page which has not been loaded yet is reported to a dedicated thread. This
+//! thread serves each fault by copying the page from its backing file, while
+//! loading the remaining pages in the background.
+//!
+//! If the memory can't be loaded, the VM is shut down. The userfaultfd is
+//! kept open until then, as closing it would let the kernel back the pages
+//! which have not been loaded with zeroed pages, silently corrupting the
+//! guest memory.
+
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFD_API: u64 = 0xaa;
+const UFFDIO: u32 = 0xaa;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+// Only the page fault message layout is needed.
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u64,
+}
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+
+// Amount of memory loaded at once in the background.
+const BACKGROUND_CHUNK_SIZE: u64 = 1 << 20;
+
+/// A host memory mapping to be filled from the content of a file.
+pub struct LazyRegion {
+    pub host_addr: u64,
+    pub size: u64,
+    pub file: File,
+}
+
+/// Handle on the thread loading the memory, which is stopped when dropped.
+pub struct LazyLoader {
+    stop: Arc<AtomicBool>,
+    thread: thread::Thread,
+}
+
+impl LazyLoader {
+    /// Register the regions with userfaultfd and start the thread loading
+    /// them. The regions must not have been accessed yet. `exit_evt` is
+    /// signalled if the memory can't be loaded.
+    pub fn new(regions: Vec<LazyRegion>, page_size: u64, exit_evt: EventFd) -> io::Result<Self> {
+        // Safe because we check the return value.
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the file descriptor was just created.
+        let uffd = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the kernel only updates the structure we pass.
+        let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for region in regions.iter() {
+            let mut register = UffdioRegister {
+                range: UffdioRange {
+                    start: region.host_addr,
+                    len: region.size,
+                },
+                mode: UFFDIO_REGISTER_MODE_MISSING,
+                ..Default::default()
+            };
+            // Safe because the kernel only updates the structure we pass.
+            let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_REGISTER(), &mut register) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = LazyHandler {
+            uffd,
+            regions,
+            page_size,
+            stop: stop.clone(),
+        };
+        let handle = thread::Builder::new()
+            .name("lazy_loader".to_string())
+            .spawn(move || {
+                if let Err(e) = handler.run() {
+                    error!("Error loading guest memory, shutting down the VM: {}", e);
+                    if let Err(e) = exit_evt.write(1) {
+                        error!("Error signalling the VM exit: {}", e);
+                    }
+
+                    // The pending and future faults are left unserved until
+                    // the VM is gone.
+                    while !handler.stop.load(Ordering::SeqCst) {
+                        thread::park();
+                    }
+                }
+            })?;
+
+        Ok(LazyLoader {
+            stop,
+            thread: handle.thread().clone(),
+        })
+    }
+}
+
+impl Drop for LazyLoader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+struct LazyHandler {
+    uffd: File,
+    regions: Vec<LazyRegion>,
+    page_size: u64,
+    stop: Arc<AtomicBool>,
+}
+
+impl LazyHandler {
+    fn run(&self) -> io::Result<()> {
+        let chunk_size = std::cmp::max(BACKGROUND_CHUNK_SIZE, self.page_size);
+        let mut buf = vec![0u8; chunk_size as usize];
+
+        // Once every page has been loaded, no fault can be reported anymore,
+        // and closing the userfaultfd unregisters the regions.
+        for index in 0..self.regions.len() {
+            let mut offset = 0;
+            while offset < self.regions[index].size {
+                if self.stop.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+
+                // Faults are served first, as a vCPU or a device is waiting.
+                while let Some(address) = self.read_fault()? {
+                    self.serve_fault(address, &mut buf)?;
+                }
+
+                let len = std::cmp::min(chunk_size, self.regions[index].size - offset);
+                self.load(index, offset, len, &mut buf)?;
+                offset += len;
+            }
+        }
+
+        info!("Guest memory fully loaded");
+
+        Ok(())
+    }
+
+    // Return the address of the next pending page fault, if any.
+    fn read_fault(&self) -> io::Result<Option<u64>> {
+        loop {
+            let mut msg = UffdMsg::default();
+            // Safe because the size of the buffer matches the message size.
+            let ret = unsafe {
+                libc::read(
+                    self.uffd.as_raw_fd(),
+                    &mut msg as *mut UffdMsg as *mut libc::c_void,
+                    size_of::<UffdMsg>(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(libc::EAGAIN) => Ok(None),
+                    Some(libc::EINTR) => continue,
+                    _ => Err(err),
+                };
+            }
+
+            if msg.event == UFFD_EVENT_PAGEFAULT {
+                return Ok(Some(msg.address));
+            }
+        }
+    }
+
+    fn serve_fault(&self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        let address = address & !(self.page_size - 1);
+        for (index, region) in self.regions.iter().enumerate() {
+            if address >= region.host_addr && address < region.host_addr + region.size {
+                return self.load(index, address - region.host_addr, self.page_size, buf);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unexpected page fault at {:#x}", address),
+        ))
+    }
+
+    // Copy `len` bytes from the region file into the region mapping, both
+    // at `offset`, skipping the pages which have already been loaded.
+    fn load(&self, index: usize, offset: u64, len: u64, buf: &mut [u8]) -> io::Result<()> {
+        let region = &self.regions[index];
+        let buf = &mut buf[..len as usize];
+        region.file.read_exact_at(buf, offset)?;
+
+        if self.copy(region.host_addr + offset, buf)? {
+            return Ok(());
+        }
+
+        // Part of the range has already been loaded, go page by page.
+        for page in buf.chunks(self.page_size as usize) {
+            let page_offset = page.as_ptr() as u64 - buf.as_ptr() as u64;
+            self.copy(region.host_addr + offset + page_offset, page)?;
+        }
+
+        Ok(())
+    }
+
+    // Returns false if some of the pages were already present.
+    fn copy(&self, dst: u64, src: &[u8]) -> io::Result<bool> {
+        let mut copy = UffdioCopy {
+            dst,
+            src: src.as_ptr() as u64,
+            len: src.len() as u64,
+            ..Default::default()
+        };
+        // Safe because the source buffer is valid for the given length, and
+        // the destination is part of a registered range.
+        loop {
+            let ret = unsafe { ioctl_with_mut_ref(&self.uffd, UFFDIO_COPY(), &mut copy) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EEXIST) => return Ok(false),
+                    // The memory layout is being updated, try again.
+                    Some(libc::EAGAIN) => continue,
+                    _ => return Err(err),
+                }
+            }
+
+            return Ok(true);
+        }
+    }
+}
@@ -24,13 +24,18 @@ extern crate vm_allocator;
 extern crate vm_memory;
 
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig, ValidationError,
-    VmConfig, VsockConfig,
+    validate_rate_limiter_config, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig,
+    NumaConfig, PmemConfig, ValidationError, VmConfig, VsockConfig,
 };
+use crate::coredump;
 use crate::cpu;
 use crate::device_manager::{self, get_win_size, Console, DeviceManager, DeviceManagerError};
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use crate::gdb::{self, GdbStub};
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
-use crate::migration::{get_vm_snapshot, url_to_path, VM_SNAPSHOT_FILE};
+use crate::migration::{
+    get_vm_snapshot, url_to_path, SnapshotChecks, SnapshotManifest, VM_SNAPSHOT_FILE,
+};
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
 };
@@ -45,8 +50,9 @@ use linux_loader::loader::elf::Error::InvalidElfMagicNumber;
 #[cfg(target_arch = "x86_64")]
 use linux_loader::loader::elf::PvhBootCapability::PvhEntryPresent;
 use linux_loader::loader::KernelLoader;
+use rate_limiter::RateLimiterConfig;
 use signal_hook::{iterator::Signals, SIGINT, SIGTERM, SIGWINCH};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
 use std::ffi::CString;
 use std::fs::{File, OpenOptions};
@@ -58,12 +64,16 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
 use std::{result, str, thread};
 use url::Url;
-use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap, GuestRegionMmap,
+};
 use vm_migration::{
-    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    protocol::MemoryRangeTable, Migratable, MigratableError, Pausable, Snapshot,
+    SnapshotDataSection, Snapshottable, Transportable,
 };
 use vmm_sys_util::eventfd::EventFd;
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use vmm_sys_util::eventfd::EFD_NONBLOCK;
 use vmm_sys_util::terminal::Terminal;
 
 // 64 bit direct boot entry offset for bzImage
@@ -183,6 +193,18 @@ pub enum Error {
     /// Cannot send VM snapshot
     SnapshotSend(MigratableError),
 
+    /// Cannot verify VM snapshot
+    SnapshotVerify(MigratableError),
+
+    /// Cannot send VM for live migration
+    MigrateSend(MigratableError),
+
+    /// Cannot receive VM from live migration
+    MigrateReceive(MigratableError),
+
+    /// Cannot write the guest core dump
+    Coredump(io::Error),
+
     /// Cannot convert source URL from Path into &str
     RestoreSourceUrlPathToStr,
 
@@ -194,6 +216,14 @@ pub enum Error {
 
     /// Failed serializing into JSON
     SerializeJson(serde_json::Error),
+
+    /// Cannot create the GDB stub exit EventFd
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    GdbExitEventFd(io::Error),
+
+    /// Cannot start the GDB stub
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    GdbStub(gdb::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -239,6 +269,34 @@ impl VmState {
     }
 }
 
+#[derive(Default)]
+pub struct NumaNode {
+    memory_regions: Vec<Arc<GuestRegionMmap>>,
+    hotplug_regions: Vec<Arc<GuestRegionMmap>>,
+    cpus: Vec<u8>,
+    distances: BTreeMap<u32, u8>,
+}
+
+impl NumaNode {
+    pub fn memory_regions(&self) -> &Vec<Arc<GuestRegionMmap>> {
+        &self.memory_regions
+    }
+
+    pub fn hotplug_regions(&self) -> &Vec<Arc<GuestRegionMmap>> {
+        &self.hotplug_regions
+    }
+
+    pub fn cpus(&self) -> &Vec<u8> {
+        &self.cpus
+    }
+
+    pub fn distances(&self) -> &BTreeMap<u32, u8> {
+        &self.distances
+    }
+}
+
+pub type NumaNodes = BTreeMap<u32, NumaNode>;
+
 pub struct Vm {
     kernel: File,
     initramfs: Option<File>,
@@ -250,12 +308,17 @@ pub struct Vm {
     state: RwLock<VmState>,
     cpu_manager: Arc<Mutex<cpu::CpuManager>>,
     memory_manager: Arc<Mutex<MemoryManager>>,
+    #[cfg_attr(not(all(target_arch = "x86_64", feature = "acpi")), allow(dead_code))]
+    numa_nodes: NumaNodes,
     #[cfg(target_arch = "x86_64")]
     #[cfg_attr(not(feature = "kvm"), allow(dead_code))]
     // The hypervisor abstracted virtual machine.
     vm: Arc<dyn hypervisor::Vm>,
     #[cfg(target_arch = "x86_64")]
     saved_clock: Option<hypervisor::ClockData>,
+    // Used to tell the GDB stub thread to terminate.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    gdb_exit_evt: Option<EventFd>,
 }
 
 impl Vm {
@@ -266,6 +329,7 @@ impl Vm {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        memory_eject_evt: EventFd,
         vmm_path: PathBuf,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         _saved_clock: Option<hypervisor::ClockData>,
@@ -276,6 +340,11 @@ impl Vm {
             .validate()
             .map_err(Error::ConfigValidation)?;
 
+        memory_manager
+            .lock()
+            .unwrap()
+            .set_eject_evt(memory_eject_evt);
+
         let device_manager = DeviceManager::new(
             vm.clone(),
             config.clone(),
@@ -296,6 +365,12 @@ impl Vm {
         )
         .map_err(Error::CpuManager)?;
 
+        let numa_nodes = Vm::create_numa_nodes(
+            &config.lock().unwrap().numa,
+            config.lock().unwrap().cpus.max_vcpus,
+            &memory_manager,
+        );
+
         let on_tty = unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0;
         let kernel = File::open(&config.lock().unwrap().kernel.as_ref().unwrap().path)
             .map_err(Error::KernelFile)?;
@@ -320,17 +395,75 @@ impl Vm {
             state: RwLock::new(VmState::Created),
             cpu_manager,
             memory_manager,
+            numa_nodes,
             #[cfg(target_arch = "x86_64")]
             vm,
             #[cfg(target_arch = "x86_64")]
             saved_clock: _saved_clock,
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            gdb_exit_evt: None,
         })
     }
 
+    fn create_numa_nodes(
+        configs: &Option<Vec<NumaConfig>>,
+        max_vcpus: u8,
+        memory_manager: &Arc<Mutex<MemoryManager>>,
+    ) -> NumaNodes {
+        let mut numa_nodes = BTreeMap::new();
+
+        if let Some(configs) = configs {
+            let memory_manager = memory_manager.lock().unwrap();
+
+            for config in configs.iter() {
+                let mut node = NumaNode::default();
+
+                if let Some(memory_regions) =
+                    memory_manager.numa_regions().get(&config.guest_numa_id)
+                {
+                    node.memory_regions = memory_regions.clone();
+                }
+
+                node.hotplug_regions = memory_manager
+                    .virtio_mem_zones()
+                    .iter()
+                    .filter(|z| z.numa_node() == Some(config.guest_numa_id))
+                    .map(|z| z.region().clone())
+                    .collect();
+
+                if let Some(cpus) = &config.cpus {
+                    node.cpus = cpus.clone();
+                }
+
+                if let Some(distances) = &config.distances {
+                    for distance in distances.iter() {
+                        node.distances
+                            .insert(distance.destination, distance.distance);
+                    }
+                }
+
+                numa_nodes.insert(config.guest_numa_id, node);
+            }
+
+            // vCPUs not assigned to any node belong to the first one.
+            let assigned_cpus: Vec<u8> = numa_nodes.values().flat_map(|n| n.cpus.clone()).collect();
+            if let Some(node) = numa_nodes.get_mut(&0) {
+                for cpu in 0..max_vcpus {
+                    if !assigned_cpus.contains(&cpu) {
+                        node.cpus.push(cpu);
+                    }
+                }
+            }
+        }
+
+        numa_nodes
+    }
+
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        memory_eject_evt: EventFd,
         vmm_path: PathBuf,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
     ) -> Result<Self> {
@@ -342,6 +475,7 @@ impl Vm {
         let memory_manager = MemoryManager::new(
             vm.clone(),
             &config.lock().unwrap().memory.clone(),
+            &config.lock().unwrap().numa.clone(),
             None,
             false,
         )
@@ -364,6 +498,7 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            memory_eject_evt,
             vmm_path,
             hypervisor,
             None,
@@ -381,13 +516,17 @@ impl Vm {
         Ok(new_vm)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_snapshot(
         snapshot: &Snapshot,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        memory_eject_evt: EventFd,
         vmm_path: PathBuf,
         source_url: &str,
         prefault: bool,
+        lazy: bool,
+        checks: SnapshotChecks,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
     ) -> Result<Self> {
         #[cfg(target_arch = "x86_64")]
@@ -405,8 +544,12 @@ impl Vm {
                 memory_manager_snapshot,
                 vm.clone(),
                 &config.lock().unwrap().memory.clone(),
+                &config.lock().unwrap().numa.clone(),
                 source_url,
                 prefault,
+                lazy,
+                checks,
+                &exit_evt,
             )
             .map_err(Error::MemoryManager)?
         } else {
@@ -421,6 +564,39 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            memory_eject_evt,
+            vmm_path,
+            hypervisor,
+            #[cfg(target_arch = "x86_64")]
+            vm_snapshot.clock,
+            #[cfg(target_arch = "aarch64")]
+            None,
+        )
+    }
+
+    /// Create the VM receiving a live migration, from the memory manager
+    /// holding the already transferred guest RAM and from the VM snapshot.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_from_migration(
+        config: Arc<Mutex<VmConfig>>,
+        memory_manager: Arc<Mutex<MemoryManager>>,
+        vm: Arc<dyn hypervisor::Vm>,
+        snapshot: &Snapshot,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        memory_eject_evt: EventFd,
+        vmm_path: PathBuf,
+        hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    ) -> Result<Self> {
+        let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::MigrateReceive)?;
+
+        Vm::new_from_memory_manager(
+            config,
+            memory_manager,
+            vm,
+            exit_evt,
+            reset_evt,
+            memory_eject_evt,
             vmm_path,
             hypervisor,
             #[cfg(target_arch = "x86_64")]
@@ -576,6 +752,7 @@ impl Vm {
                 &self.device_manager,
                 &self.cpu_manager,
                 &self.memory_manager,
+                &self.numa_nodes,
             ));
         }
 
@@ -704,6 +881,14 @@ impl Vm {
             signals.close();
         }
 
+        // Trigger the termination of the GDB stub thread
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        {
+            if let Some(gdb_exit_evt) = self.gdb_exit_evt.take() {
+                gdb_exit_evt.write(1).map_err(Error::GdbExitEventFd)?;
+            }
+        }
+
         // Wake up the DeviceManager threads so they will get terminated cleanly
         self.device_manager
             .lock()
@@ -731,6 +916,7 @@ impl Vm {
         desired_vcpus: Option<u8>,
         desired_memory: Option<u64>,
         desired_ram_w_balloon: Option<u64>,
+        numa_node: Option<u32>,
     ) -> Result<()> {
         if let Some(desired_vcpus) = desired_vcpus {
             if self
@@ -750,12 +936,14 @@ impl Vm {
         }
 
         if let Some(desired_memory) = desired_memory {
-            let new_region = self
-                .memory_manager
-                .lock()
-                .unwrap()
-                .resize(desired_memory)
-                .map_err(Error::MemoryManager)?;
+            let (new_region, removing) = {
+                let mut memory_manager = self.memory_manager.lock().unwrap();
+                let removing = desired_memory < memory_manager.current_ram();
+                let new_region = memory_manager
+                    .resize(desired_memory, numa_node)
+                    .map_err(Error::MemoryManager)?;
+                (new_region, removing)
+            };
 
             if let Some(new_region) = &new_region {
                 self.device_manager
@@ -763,24 +951,32 @@ impl Vm {
                     .unwrap()
                     .update_memory(&new_region)
                     .map_err(Error::DeviceManager)?;
+            }
 
-                let memory_config = &self.config.lock().unwrap().memory;
-                match memory_config.hotplug_method {
-                    HotplugMethod::Acpi => {
+            // Both added and removed ACPI memory slots must be notified to
+            // the guest, the latter being ejected once offlined.
+            let hotplug_method = self.config.lock().unwrap().memory.hotplug_method.clone();
+            match hotplug_method {
+                HotplugMethod::Acpi => {
+                    if new_region.is_some() || removing {
                         self.device_manager
                             .lock()
                             .unwrap()
                             .notify_hotplug(HotPlugNotificationFlags::MEMORY_DEVICES_CHANGED)
                             .map_err(Error::DeviceManager)?;
                     }
-                    HotplugMethod::VirtioMem => {}
                 }
+                HotplugMethod::VirtioMem => {}
             }
 
             // We update the VM config regardless of the actual guest resize
             // operation result (happened or not), so that if the VM reboots
             // it will be running with the last configure memory size.
-            self.config.lock().unwrap().memory.size = desired_memory;
+            // Removed ACPI memory slots are only accounted for once ejected
+            // by the guest, see remove_ejected_memory().
+            if !(removing && hotplug_method == HotplugMethod::Acpi) {
+                self.update_memory_size(desired_memory);
+            }
         }
 
         if let Some(desired_ram_w_balloon) = desired_ram_w_balloon {
@@ -797,6 +993,41 @@ impl Vm {
         Ok(())
     }
 
+    // Memory zones and NUMA nodes describe the whole guest RAM, in which case
+    // the boot memory size is kept as is.
+    fn update_memory_size(&self, size: u64) {
+        let mut config = self.config.lock().unwrap();
+        if config.memory.zones.is_none() && config.numa.is_none() {
+            config.memory.size = size;
+        }
+    }
+
+    /// Remove the hotplugged memory slots the guest has ejected from the
+    /// devices accessing the guest memory, and from the VM config.
+    pub fn remove_ejected_memory(&self) -> Result<()> {
+        let (regions, current_ram) = {
+            let mut memory_manager = self.memory_manager.lock().unwrap();
+            (
+                memory_manager.take_ejected_regions(),
+                memory_manager.current_ram(),
+            )
+        };
+
+        for region in regions.iter() {
+            self.device_manager
+                .lock()
+                .unwrap()
+                .remove_memory(region)
+                .map_err(Error::DeviceManager)?;
+        }
+
+        if !regions.is_empty() {
+            self.update_memory_size(current_ram);
+        }
+
+        Ok(())
+    }
+
     #[cfg(not(feature = "pci_support"))]
     pub fn add_device(&mut self, mut _device_cfg: DeviceConfig) -> Result<PciDeviceInfo> {
         Err(Error::NoPciSupport)
@@ -1056,6 +1287,73 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn update_disk_rate_limiter(
+        &mut self,
+        id: String,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> Result<()> {
+        if let Some(rate_limiter_config) = &rate_limiter_config {
+            validate_rate_limiter_config(rate_limiter_config).map_err(Error::ConfigValidation)?;
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .update_disk_rate_limiter(&id, rate_limiter_config)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig with the new limits. This is important to ensure
+        // they would still apply in case of a reboot.
+        let mut config = self.config.lock().unwrap();
+        if let Some(disks) = config.disks.as_mut() {
+            for disk in disks.iter_mut() {
+                if disk.id.as_ref() == Some(&id) {
+                    disk.rate_limiter_config = rate_limiter_config;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn update_net_rate_limiter(
+        &mut self,
+        id: String,
+        rx_rate_limiter_config: Option<RateLimiterConfig>,
+        tx_rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> Result<()> {
+        for rate_limiter_config in rx_rate_limiter_config
+            .iter()
+            .chain(tx_rate_limiter_config.iter())
+        {
+            validate_rate_limiter_config(rate_limiter_config).map_err(Error::ConfigValidation)?;
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .update_net_rate_limiter(&id, rx_rate_limiter_config, tx_rate_limiter_config)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig with the new limits. This is important to ensure
+        // they would still apply in case of a reboot.
+        let mut config = self.config.lock().unwrap();
+        if let Some(nets) = config.net.as_mut() {
+            for net in nets.iter_mut() {
+                if net.id.as_ref() == Some(&id) {
+                    net.rx_rate_limiter_config = rx_rate_limiter_config;
+                    net.tx_rate_limiter_config = tx_rate_limiter_config;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn balloon_size(&self) -> u64 {
+        self.memory_manager.lock().unwrap().balloon_size()
+    }
+
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
         Ok(self.device_manager.lock().unwrap().counters())
     }
@@ -1081,6 +1379,29 @@ impl Vm {
         }
     }
 
+    // Starts the GDB stub thread if requested by the configuration, returning
+    // whether it has been started.
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    fn start_gdb_stub(&mut self) -> Result<bool> {
+        let socket = match self.config.lock().unwrap().gdb.as_ref() {
+            Some(gdb) => gdb.socket.clone(),
+            None => return Ok(false),
+        };
+
+        let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::GdbExitEventFd)?;
+        let gdb_stub = GdbStub::new(
+            &socket,
+            self.cpu_manager.clone(),
+            self.memory_manager.lock().unwrap().guest_memory(),
+            exit_evt.try_clone().map_err(Error::GdbExitEventFd)?,
+        )
+        .map_err(Error::GdbStub)?;
+        self.threads.push(gdb_stub.start().map_err(Error::GdbStub)?);
+        self.gdb_exit_evt = Some(exit_evt);
+
+        Ok(true)
+    }
+
     pub fn boot(&mut self) -> Result<()> {
         let current_state = self.get_state()?;
         if current_state == VmState::Paused {
@@ -1101,10 +1422,17 @@ impl Vm {
 
         self.configure_system(entry_point)?;
 
+        // When a GDB stub is requested, the vCPUs wait for the debugger to
+        // resume them, so that it can catch the very first guest instructions.
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        let start_paused = self.start_gdb_stub()?;
+        #[cfg(not(all(target_arch = "x86_64", feature = "gdb")))]
+        let start_paused = false;
+
         self.cpu_manager
             .lock()
             .unwrap()
-            .start_boot_vcpus()
+            .start_boot_vcpus(start_paused)
             .map_err(Error::CpuManager)?;
 
         if self
@@ -1175,6 +1503,109 @@ impl Vm {
         Arc::clone(&self.config)
     }
 
+    /// Returns a table covering the whole guest RAM.
+    pub fn memory_range_table(&self) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        self.memory_manager.lock().unwrap().memory_range_table()
+    }
+
+    /// Write the content of each guest memory range from the table to `fd`.
+    pub fn send_memory_regions(
+        &self,
+        ranges: &MemoryRangeTable,
+        fd: &mut dyn Write,
+    ) -> std::result::Result<(), MigratableError> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .send_memory_regions(ranges, fd)
+    }
+
+    /// Returns the CPU features exposed to the guest, along with the MSRs
+    /// saved when the vCPUs were paused.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpu_features(&self) -> cpu::CpuFeatures {
+        self.cpu_manager.lock().unwrap().cpu_features()
+    }
+
+    /// Select the parent of the next snapshot, making it incremental.
+    pub fn set_snapshot_parent(
+        &mut self,
+        parent_url: Option<&str>,
+    ) -> std::result::Result<(), MigratableError> {
+        let current_state = self
+            .get_state()
+            .map_err(|e| MigratableError::Snapshot(anyhow!("Could not get VM state: {:#?}", e)))?;
+        if current_state != VmState::Paused {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Trying to snapshot while VM is running"
+            )));
+        }
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .set_snapshot_parent(parent_url)
+    }
+
+    /// Track the memory changes since the snapshot sent to `snapshot_url`.
+    pub fn track_snapshot_changes(&mut self, snapshot_url: &str) {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .track_snapshot_changes(snapshot_url)
+    }
+
+    /// Stop tracking the memory changes since the last snapshot.
+    pub fn untrack_snapshot_changes(&mut self) {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .untrack_snapshot_changes()
+    }
+
+    /// Write an ELF core dump of the guest to the file at `destination_url`.
+    /// The VM is paused while the dump is written, if it is running.
+    pub fn coredump(&mut self, destination_url: &str) -> Result<()> {
+        let running = self.get_state()? == VmState::Running;
+        if running {
+            self.pause().map_err(Error::Pause)?;
+        }
+
+        let result = self.write_coredump(destination_url);
+
+        if running {
+            self.resume().map_err(Error::Resume)?;
+        }
+
+        result
+    }
+
+    fn write_coredump(&self, destination_url: &str) -> Result<()> {
+        let path = Url::parse(destination_url)
+            .ok()
+            .filter(|url| url.scheme() == "file")
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| {
+                Error::Coredump(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid core dump destination: {}", destination_url),
+                ))
+            })?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(Error::Coredump)?;
+
+        let vcpu_states = self.cpu_manager.lock().unwrap().saved_vcpu_states();
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+
+        coredump::write_coredump(&mut file, &guest_memory.memory(), &vcpu_states)
+            .map_err(Error::Coredump)
+    }
+
     /// Get the VM state. Returns an error if the state is poisoned.
     pub fn get_state(&self) -> Result<VmState> {
         self.state
@@ -1251,6 +1682,8 @@ pub struct VmSnapshot {
 }
 
 pub const VM_SNAPSHOT_ID: &str = "vm";
+pub const VM_SNAPSHOT_VERSION: u16 = 1;
+
 impl Snapshottable for Vm {
     fn id(&self) -> String {
         VM_SNAPSHOT_ID.to_string()
@@ -1277,6 +1710,7 @@ impl Snapshottable for Vm {
         vm_snapshot.add_snapshot(self.device_manager.lock().unwrap().snapshot()?);
         vm_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", VM_SNAPSHOT_ID),
+            version: VM_SNAPSHOT_VERSION,
             snapshot: vm_snapshot_data,
         });
 
@@ -1385,7 +1819,8 @@ impl Transportable for Vm {
 
         match url.scheme() {
             "file" => {
-                let mut vm_snapshot_path = url_to_path(&url)?;
+                let snapshot_dir = url_to_path(&url)?;
+                let mut vm_snapshot_path = snapshot_dir.clone();
                 vm_snapshot_path.push(VM_SNAPSHOT_FILE);
 
                 // Create the snapshot file
@@ -1401,10 +1836,11 @@ impl Transportable for Vm {
                     .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
                 vm_snapshot_file
-                    .write(&vm_snapshot)
+                    .write_all(&vm_snapshot)
                     .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
                 // Tell the memory manager to also send/write its own snapshot.
+                let mut snapshot_files = vec![PathBuf::from(VM_SNAPSHOT_FILE)];
                 if let Some(memory_manager_snapshot) =
                     snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID)
                 {
@@ -1412,11 +1848,17 @@ impl Transportable for Vm {
                         .lock()
                         .unwrap()
                         .send(&*memory_manager_snapshot.clone(), destination_url)?;
+                    snapshot_files.extend(MemoryManager::snapshot_files(memory_manager_snapshot)?);
                 } else {
                     return Err(MigratableError::Restore(anyhow!(
                         "Missing memory manager snapshot"
                     )));
                 }
+
+                // The manifest comes last, as it marks the snapshot as
+                // complete.
+                SnapshotManifest::new(snapshot, &snapshot_dir, &snapshot_files)?
+                    .write(&snapshot_dir)?;
             }
             _ => {
                 return Err(MigratableError::MigrateSend(anyhow!(
@@ -1428,7 +1870,35 @@ impl Transportable for Vm {
         Ok(())
     }
 }
-impl Migratable for Vm {}
+impl Migratable for Vm {
+    fn start_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        self.memory_manager.lock().unwrap().start_dirty_log()
+    }
+
+    fn stop_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        self.memory_manager.lock().unwrap().stop_dirty_log()
+    }
+
+    fn dirty_log(&mut self) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        // The devices are briefly paused so that none of the pages they
+        // write to can be missed while the dirty pages are being collected.
+        let running = self
+            .get_state()
+            .map_err(|e| MigratableError::DirtyLog(anyhow!("Could not get VM state: {}", e)))?
+            == VmState::Running;
+        if running {
+            self.device_manager.lock().unwrap().pause()?;
+        }
+
+        let table = self.memory_manager.lock().unwrap().dirty_log();
+
+        if running {
+            self.device_manager.lock().unwrap().resume()?;
+        }
+
+        table
+    }
+}
 
 #[cfg(target_arch = "x86_64")]
 #[cfg(test)]
@@ -1490,6 +1960,7 @@ mod tests {
 }
 
 #[cfg(target_arch = "aarch64")]
+#[cfg(feature = "kvm")]
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1569,6 +2040,7 @@ mod tests {
 }
 
 #[cfg(target_arch = "x86_64")]
+#[cfg(feature = "kvm")]
 #[test]
 pub fn test_vm() {
     use hypervisor::VmExit;